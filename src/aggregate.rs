@@ -0,0 +1,1042 @@
+// btleplug Source Code File
+//
+// Copyright 2020 Nonpolynomial Labs LLC. All rights reserved.
+//
+// Licensed under the BSD 3-Clause license. See LICENSE file in the project root
+// for full license information.
+
+//! A [`Central`] over several adapters, for when one adapter doesn't cover the whole area.
+//!
+//! An [`AggregateCentral`] scans on all of its adapters and presents the peripherals they find as
+//! one set, merging the peripherals with the same address that several adapters see. Each
+//! [`AggregatePeripheral`] connects through one of the adapters that see it, chosen by a
+//! [`RoutingPolicy`], and is then used through that adapter until it disconnects.
+//!
+//! ## Usage
+//! ```
+//! use btleplug::aggregate::{AggregateCentral, RoutingPolicy};
+//! use btleplug::api::{Central as _, Manager as _, ScanFilter};
+//! use btleplug::platform::Manager;
+//! # use std::error::Error;
+//!
+//! # async fn example() -> Result<(), Box<dyn Error>> {
+//! let manager = Manager::new().await?;
+//! let central = AggregateCentral::new(manager.adapters().await?, RoutingPolicy::StrongestSignal);
+//! central.start_scan(ScanFilter::default()).await?;
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::api::{
+    AdapterCapabilities, AdapterInfo, AddressType, AdvertisementRecord, BDAddr, Central,
+    CentralEvent, CentralState, Characteristic, Descriptor, EventBufferConfig, HonouredScanOptions,
+    PairingOptions, Peripheral, PeripheralProperties, PropertiesDiff, ScanFilter, ScanOptions,
+    ScanSession, Service, Subscription, SubscriptionKind, ValueNotification, WriteType,
+};
+use crate::common::scan_session::ScanSessions;
+use crate::platform::PeripheralId;
+use crate::{Error, Result};
+use async_trait::async_trait;
+use futures::future::{self, try_join_all};
+use futures::stream::{self, Stream, StreamExt};
+use std::cmp::Reverse;
+use std::collections::{BTreeSet, HashMap};
+use std::fmt::{self, Debug, Formatter};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// How an [`AggregatePeripheral`] chooses the adapter to connect through, among those that have
+/// seen it.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum RoutingPolicy {
+    /// The adapter that received the strongest signal from the peripheral, or with equal signal,
+    /// the one with the fewest connections.
+    #[default]
+    StrongestSignal,
+    /// The adapter with the fewest connections made through the aggregate, or with as many
+    /// connections, the one that received the strongest signal.
+    FewestConnections,
+}
+
+/// What identifies the same device across adapters: its address, or if the platform doesn't
+/// report one, its ID.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+enum DeviceKey {
+    Address(BDAddr),
+    Id(PeripheralId),
+}
+
+impl DeviceKey {
+    fn of(peripheral: &impl Peripheral) -> Self {
+        let address = peripheral.address();
+        if address == BDAddr::default() {
+            Self::Id(peripheral.id())
+        } else {
+            Self::Address(address)
+        }
+    }
+}
+
+/// The RSSI an adapter received from a device, and when.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct RssiReading {
+    pub rssi: i16,
+    pub received_at: Instant,
+}
+
+#[derive(Debug)]
+struct Device<P> {
+    /// The ID the aggregate uses for the device, which is that of the first adapter to see it.
+    id: PeripheralId,
+    address: BDAddr,
+    /// The peripheral on each adapter, indexed like the adapters.
+    peripherals: Vec<Option<P>>,
+    /// The latest RSSI received by each adapter.
+    rssi: Vec<Option<RssiReading>>,
+    /// The adapter the device is connected through.
+    connected_via: Option<usize>,
+}
+
+impl<P: Clone> Device<P> {
+    fn peripheral(&self, adapter: usize) -> Option<P> {
+        self.peripherals[adapter].clone()
+    }
+
+    /// The adapters that have seen the device.
+    fn adapters(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..self.peripherals.len()).filter(|&adapter| self.peripherals[adapter].is_some())
+    }
+}
+
+/// The devices of all adapters, keeping track of which are the same.
+#[derive(Debug)]
+struct Registry<P> {
+    devices: HashMap<DeviceKey, Device<P>>,
+    /// The device each peripheral ID refers to, both the adapters' IDs and the aggregate's.
+    keys: HashMap<PeripheralId, DeviceKey>,
+    /// The number of connections made through each adapter.
+    connections: Vec<usize>,
+}
+
+impl<P: Peripheral> Registry<P> {
+    fn new(adapters: usize) -> Self {
+        Self {
+            devices: HashMap::new(),
+            keys: HashMap::new(),
+            connections: vec![0; adapters],
+        }
+    }
+
+    /// Records that an adapter has seen a peripheral, returning the aggregate's ID for it and
+    /// whether it is new to the aggregate.
+    fn observe(
+        &mut self,
+        adapter: usize,
+        peripheral: P,
+        rssi: Option<i16>,
+    ) -> (PeripheralId, bool) {
+        let adapters = self.connections.len();
+        let key = DeviceKey::of(&peripheral);
+        let id = peripheral.id();
+        let mut new = false;
+        let device = self.devices.entry(key.clone()).or_insert_with(|| {
+            new = true;
+            Device {
+                id: id.clone(),
+                address: peripheral.address(),
+                peripherals: vec![None; adapters],
+                rssi: vec![None; adapters],
+                connected_via: None,
+            }
+        });
+        device.peripherals[adapter] = Some(peripheral);
+        if let Some(rssi) = rssi {
+            device.rssi[adapter] = Some(RssiReading {
+                rssi,
+                received_at: Instant::now(),
+            });
+        }
+        let aggregate_id = device.id.clone();
+        self.keys.insert(id, key.clone());
+        self.keys.insert(aggregate_id.clone(), key);
+        (aggregate_id, new)
+    }
+
+    /// Records that an adapter has forgotten a peripheral, returning the aggregate's ID for it if
+    /// no adapter has it any more.
+    fn forget(&mut self, adapter: usize, id: &PeripheralId) -> Option<PeripheralId> {
+        let key = self.keys.get(id)?.clone();
+        let device = self.devices.get_mut(&key)?;
+        device.peripherals[adapter] = None;
+        device.rssi[adapter] = None;
+        if device.connected_via == Some(adapter) {
+            device.connected_via = None;
+            self.connections[adapter] -= 1;
+        }
+        if device.adapters().next().is_some() {
+            return None;
+        }
+        let device = self.devices.remove(&key)?;
+        self.keys.retain(|_, device_key| *device_key != key);
+        Some(device.id)
+    }
+
+    fn device(&self, id: &PeripheralId) -> Option<&Device<P>> {
+        self.devices.get(self.keys.get(id)?)
+    }
+
+    fn aggregate_id(&self, id: &PeripheralId) -> Option<PeripheralId> {
+        self.device(id).map(|device| device.id.clone())
+    }
+
+    /// Records that a device has connected or disconnected through an adapter.
+    fn set_connected(&mut self, id: &PeripheralId, adapter: usize, connected: bool) {
+        let Some(device) = self.keys.get(id).and_then(|key| self.devices.get_mut(key)) else {
+            return;
+        };
+        if connected && device.connected_via.is_none() {
+            device.connected_via = Some(adapter);
+            self.connections[adapter] += 1;
+        } else if !connected && device.connected_via == Some(adapter) {
+            device.connected_via = None;
+            self.connections[adapter] -= 1;
+        }
+    }
+
+    /// The adapter to use for a device: the one it is connected through, or else the one the
+    /// policy chooses.
+    fn route(&self, id: &PeripheralId, policy: RoutingPolicy) -> Option<usize> {
+        let device = self.device(id)?;
+        if device.connected_via.is_some() {
+            return device.connected_via;
+        }
+        let rssi = |adapter: usize| device.rssi[adapter].map(|reading| reading.rssi);
+        let connections = |adapter: usize| self.connections[adapter];
+        match policy {
+            RoutingPolicy::StrongestSignal => device
+                .adapters()
+                .max_by_key(|&adapter| (rssi(adapter), Reverse(connections(adapter)))),
+            RoutingPolicy::FewestConnections => device
+                .adapters()
+                .min_by_key(|&adapter| (connections(adapter), Reverse(rssi(adapter)))),
+        }
+    }
+}
+
+struct Shared<C: Central> {
+    adapters: Vec<C>,
+    routing: RoutingPolicy,
+    registry: Mutex<Registry<C::Peripheral>>,
+    scan_sessions: ScanSessions,
+}
+
+impl<C: Central> Shared<C> {
+    /// Records a peripheral of an adapter, with its current RSSI.
+    async fn observe(&self, adapter: usize, peripheral: C::Peripheral) -> (PeripheralId, bool) {
+        let rssi = match peripheral.properties().await {
+            Ok(Some(properties)) => properties.rssi,
+            _ => None,
+        };
+        self.registry
+            .lock()
+            .unwrap()
+            .observe(adapter, peripheral, rssi)
+    }
+
+    /// Updates the registry with the current peripherals of all adapters.
+    async fn refresh(&self) -> Result<()> {
+        for (adapter, central) in self.adapters.iter().enumerate() {
+            for peripheral in central.peripherals().await? {
+                self.observe(adapter, peripheral).await;
+            }
+        }
+        Ok(())
+    }
+
+    /// The event of the aggregate for a device an adapter discovered or updated, with the changes
+    /// the adapter reported if any.
+    async fn observe_event(
+        &self,
+        adapter: usize,
+        id: PeripheralId,
+        changes: Option<PropertiesDiff>,
+    ) -> Option<CentralEvent> {
+        let peripheral = self.adapters[adapter].peripheral(&id).await.ok()?;
+        let (id, new) = self.observe(adapter, peripheral).await;
+        Some(if new {
+            CentralEvent::DeviceDiscovered(id)
+        } else {
+            CentralEvent::DeviceUpdated {
+                id,
+                changes: changes.unwrap_or_default(),
+            }
+        })
+    }
+
+    /// Translates an event of an adapter to one of the aggregate, if it should have one.
+    async fn translate(&self, adapter: usize, event: CentralEvent) -> Option<CentralEvent> {
+        match event {
+            CentralEvent::DeviceDiscovered(id) => self.observe_event(adapter, id, None).await,
+            CentralEvent::DeviceUpdated { id, changes } => {
+                self.observe_event(adapter, id, Some(changes)).await
+            }
+            CentralEvent::DeviceConnected(id) => {
+                let mut registry = self.registry.lock().unwrap();
+                registry.set_connected(&id, adapter, true);
+                registry
+                    .aggregate_id(&id)
+                    .map(CentralEvent::DeviceConnected)
+            }
+            CentralEvent::DeviceDisconnected(id) => {
+                let mut registry = self.registry.lock().unwrap();
+                registry.set_connected(&id, adapter, false);
+                registry
+                    .aggregate_id(&id)
+                    .map(CentralEvent::DeviceDisconnected)
+            }
+            CentralEvent::DeviceLost(id) => self
+                .registry
+                .lock()
+                .unwrap()
+                .forget(adapter, &id)
+                .map(CentralEvent::DeviceLost),
+            CentralEvent::ManufacturerDataAdvertisement {
+                id,
+                manufacturer_data,
+            } => Some(CentralEvent::ManufacturerDataAdvertisement {
+                id: self.registry.lock().unwrap().aggregate_id(&id)?,
+                manufacturer_data,
+            }),
+            CentralEvent::ServiceDataAdvertisement { id, service_data } => {
+                Some(CentralEvent::ServiceDataAdvertisement {
+                    id: self.registry.lock().unwrap().aggregate_id(&id)?,
+                    service_data,
+                })
+            }
+            CentralEvent::ServicesAdvertisement { id, services } => {
+                Some(CentralEvent::ServicesAdvertisement {
+                    id: self.registry.lock().unwrap().aggregate_id(&id)?,
+                    services,
+                })
+            }
+            CentralEvent::EventsLost(n) => Some(CentralEvent::EventsLost(n)),
+            CentralEvent::StateUpdate(_) => self.state().await.ok().map(CentralEvent::StateUpdate),
+        }
+    }
+
+    /// The state of the most usable adapter, as the aggregate can be used if any of them can.
+    /// Adapters whose state can't be read are passed over, unless none can be read.
+    async fn state(&self) -> Result<CentralState> {
+        let mut states = Vec::new();
+        let mut error = None;
+        for central in &self.adapters {
+            match central.state().await {
+                Ok(state) => states.push(state),
+                Err(e) => error = Some(e),
+            }
+        }
+        match error {
+            Some(e) if states.is_empty() => Err(e),
+            _ => Ok(most_usable(states)),
+        }
+    }
+}
+
+fn most_usable(states: impl IntoIterator<Item = CentralState>) -> CentralState {
+    let usability = |state: &CentralState| match state {
+        CentralState::Unsupported => 0,
+        CentralState::Unauthorized => 1,
+        CentralState::Unknown => 2,
+        CentralState::PoweredOff => 3,
+        CentralState::PoweredOn => 4,
+    };
+    states
+        .into_iter()
+        .max_by_key(usability)
+        .unwrap_or(CentralState::Unsupported)
+}
+
+/// The information about several adapters as one: they have all of their roles and features, and
+/// are powered or discovering if any of them is. The information about a single adapter is kept.
+fn combined_info(mut infos: Vec<AdapterInfo>) -> AdapterInfo {
+    if infos.len() == 1 {
+        return infos.remove(0);
+    }
+    let any = |flag: fn(&AdapterInfo) -> Option<bool>| {
+        let flags: Vec<bool> = infos.iter().filter_map(flag).collect();
+        (!flags.is_empty()).then(|| flags.contains(&true))
+    };
+    AdapterInfo {
+        id: infos
+            .iter()
+            .map(|info| info.id.as_str())
+            .collect::<Vec<_>>()
+            .join("; "),
+        powered: any(|info| info.powered),
+        discovering: any(|info| info.discovering),
+        roles: infos
+            .iter()
+            .filter_map(|info| info.roles)
+            .reduce(|a, b| a | b),
+        capabilities: AdapterCapabilities {
+            features: infos
+                .iter()
+                .map(|info| info.capabilities.features)
+                .collect(),
+            max_connections: infos
+                .iter()
+                .map(|info| info.capabilities.max_connections)
+                .try_fold(0u32, |total, max| Some(total.saturating_add(max?))),
+            max_advertisements: infos
+                .iter()
+                .map(|info| info.capabilities.max_advertisements)
+                .try_fold(0u8, |total, max| Some(total.saturating_add(max?))),
+        },
+        ..Default::default()
+    }
+}
+
+/// Implementation of [`Central`] over several adapters. See the [module documentation](self).
+pub struct AggregateCentral<C: Central> {
+    shared: Arc<Shared<C>>,
+}
+
+impl<C: Central> Clone for AggregateCentral<C> {
+    fn clone(&self) -> Self {
+        Self {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<C: Central> AggregateCentral<C> {
+    pub fn new(adapters: Vec<C>, routing: RoutingPolicy) -> Self {
+        let registry = Mutex::new(Registry::new(adapters.len()));
+        Self {
+            shared: Arc::new(Shared {
+                adapters,
+                routing,
+                registry,
+                scan_sessions: ScanSessions::default(),
+            }),
+        }
+    }
+
+    /// The adapters this aggregates.
+    pub fn adapters(&self) -> &[C] {
+        &self.shared.adapters
+    }
+
+    fn peripheral_for(&self, id: PeripheralId, address: BDAddr) -> AggregatePeripheral<C> {
+        AggregatePeripheral {
+            id,
+            address,
+            shared: self.shared.clone(),
+        }
+    }
+
+    fn known_peripheral(&self, id: &PeripheralId) -> Option<AggregatePeripheral<C>> {
+        let registry = self.shared.registry.lock().unwrap();
+        let device = registry.device(id)?;
+        Some(self.peripheral_for(device.id.clone(), device.address))
+    }
+}
+
+#[async_trait]
+impl<C: Central + 'static> Central for AggregateCentral<C> {
+    type Peripheral = AggregatePeripheral<C>;
+
+    async fn events(&self) -> Result<Pin<Box<dyn Stream<Item = CentralEvent> + Send>>> {
+        let mut streams = Vec::new();
+        for (adapter, central) in self.shared.adapters.iter().enumerate() {
+            let events = central.events().await?;
+            streams.push(events.map(move |event| (adapter, event)));
+        }
+        let shared = self.shared.clone();
+        Ok(Box::pin(
+            stream::select_all(streams)
+                .then(move |(adapter, event)| {
+                    let shared = shared.clone();
+                    async move { shared.translate(adapter, event).await }
+                })
+                .filter_map(future::ready),
+        ))
+    }
+
+    async fn set_event_buffer(&self, config: EventBufferConfig) -> Result<()> {
+        for central in &self.shared.adapters {
+            central.set_event_buffer(config).await?;
+        }
+        Ok(())
+    }
+
+    async fn set_peripheral_retention(&self, retention: Option<Duration>) -> Result<()> {
+        for central in &self.shared.adapters {
+            central.set_peripheral_retention(retention).await?;
+        }
+        Ok(())
+    }
+
+    /// Scans on every adapter, reporting an option as followed only if all of them follow it.
+    async fn start_scan_with_options(
+        &self,
+        filter: ScanFilter,
+        options: ScanOptions,
+    ) -> Result<HonouredScanOptions> {
+        let honoured = try_join_all(
+            self.shared
+                .adapters
+                .iter()
+                .map(|central| central.start_scan_with_options(filter.clone(), options.clone())),
+        )
+        .await?;
+        Ok(honoured.into_iter().fold(
+            HonouredScanOptions {
+                mode: true,
+                duplicates: true,
+                min_rssi: true,
+                max_pathloss: true,
+                transport: true,
+                interval: true,
+                window: true,
+                rssi_monitor: true,
+            },
+            |all, adapter| HonouredScanOptions {
+                mode: all.mode && adapter.mode,
+                duplicates: all.duplicates && adapter.duplicates,
+                min_rssi: all.min_rssi && adapter.min_rssi,
+                max_pathloss: all.max_pathloss && adapter.max_pathloss,
+                transport: all.transport && adapter.transport,
+                interval: all.interval && adapter.interval,
+                window: all.window && adapter.window,
+                rssi_monitor: all.rssi_monitor && adapter.rssi_monitor,
+            },
+        ))
+    }
+
+    async fn stop_scan(&self) -> Result<()> {
+        try_join_all(
+            self.shared
+                .adapters
+                .iter()
+                .map(|central| central.stop_scan()),
+        )
+        .await?;
+        Ok(())
+    }
+
+    async fn scan(&self, filter: ScanFilter) -> Result<ScanSession<AggregatePeripheral<C>>> {
+        self.shared.scan_sessions.scan(self, filter).await
+    }
+
+    async fn peripherals(&self) -> Result<Vec<AggregatePeripheral<C>>> {
+        self.shared.refresh().await?;
+        let registry = self.shared.registry.lock().unwrap();
+        Ok(registry
+            .devices
+            .values()
+            .map(|device| self.peripheral_for(device.id.clone(), device.address))
+            .collect())
+    }
+
+    async fn peripheral(&self, id: &PeripheralId) -> Result<AggregatePeripheral<C>> {
+        if let Some(peripheral) = self.known_peripheral(id) {
+            return Ok(peripheral);
+        }
+        self.shared.refresh().await?;
+        self.known_peripheral(id).ok_or(Error::DeviceNotFound)
+    }
+
+    async fn add_peripheral(&self, address: &PeripheralId) -> Result<AggregatePeripheral<C>> {
+        let mut result = Err(Error::DeviceNotFound);
+        for (adapter, central) in self.shared.adapters.iter().enumerate() {
+            match central.add_peripheral(address).await {
+                Ok(peripheral) => {
+                    let address = peripheral.address();
+                    let (id, _) = self.shared.observe(adapter, peripheral).await;
+                    return Ok(self.peripheral_for(id, address));
+                }
+                Err(e) => result = Err(e),
+            }
+        }
+        result
+    }
+
+    async fn peripheral_by_address(
+        &self,
+        address: BDAddr,
+        address_type: AddressType,
+    ) -> Result<AggregatePeripheral<C>> {
+        let mut result = Err(Error::DeviceNotFound);
+        for (adapter, central) in self.shared.adapters.iter().enumerate() {
+            match central.peripheral_by_address(address, address_type).await {
+                Ok(peripheral) => {
+                    let (id, _) = self.shared.observe(adapter, peripheral).await;
+                    return Ok(self.peripheral_for(id, address));
+                }
+                Err(e) => result = Err(e),
+            }
+        }
+        result
+    }
+
+    async fn state(&self) -> Result<CentralState> {
+        self.shared.state().await
+    }
+
+    /// Powers all of the adapters on or off.
+    async fn set_powered(&self, powered: bool) -> Result<()> {
+        for central in &self.shared.adapters {
+            central.set_powered(powered).await?;
+        }
+        Ok(())
+    }
+
+    async fn adapter_info(&self) -> Result<AdapterInfo> {
+        let mut infos = Vec::new();
+        for central in &self.shared.adapters {
+            infos.push(central.adapter_info().await?);
+        }
+        Ok(combined_info(infos))
+    }
+}
+
+/// Implementation of [`Peripheral`] for a device seen by one or more of the adapters of an
+/// [`AggregateCentral`]. Until it is connected, it is used through the adapter the central's
+/// [`RoutingPolicy`] chooses.
+pub struct AggregatePeripheral<C: Central> {
+    id: PeripheralId,
+    address: BDAddr,
+    shared: Arc<Shared<C>>,
+}
+
+impl<C: Central> Clone for AggregatePeripheral<C> {
+    fn clone(&self) -> Self {
+        Self {
+            id: self.id.clone(),
+            address: self.address,
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<C: Central> Debug for AggregatePeripheral<C> {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.debug_struct("AggregatePeripheral")
+            .field("id", &self.id)
+            .field("address", &self.address)
+            .finish()
+    }
+}
+
+impl<C: Central> AggregatePeripheral<C> {
+    /// The adapter the device is used through, and its peripheral on that adapter.
+    fn route(&self) -> Result<(usize, C::Peripheral)> {
+        let registry = self.shared.registry.lock().unwrap();
+        let device = registry.device(&self.id).ok_or(Error::DeviceNotFound)?;
+        let adapter = registry
+            .route(&self.id, self.shared.routing)
+            .ok_or(Error::DeviceNotFound)?;
+        Ok((
+            adapter,
+            device.peripheral(adapter).ok_or(Error::DeviceNotFound)?,
+        ))
+    }
+
+    fn peripheral(&self) -> Result<C::Peripheral> {
+        self.route().map(|(_, peripheral)| peripheral)
+    }
+
+    /// The RSSI each adapter last received from the device, indexed like the adapters, or `None`
+    /// for those that haven't seen it.
+    pub fn adapter_rssi(&self) -> Vec<Option<i16>> {
+        self.adapter_readings()
+            .iter()
+            .map(|reading| reading.map(|reading| reading.rssi))
+            .collect()
+    }
+
+    /// Like [`adapter_rssi`](Self::adapter_rssi), with when each adapter received the RSSI.
+    pub fn adapter_readings(&self) -> Vec<Option<RssiReading>> {
+        let registry = self.shared.registry.lock().unwrap();
+        match registry.device(&self.id) {
+            Some(device) => device.rssi.clone(),
+            None => vec![None; self.shared.adapters.len()],
+        }
+    }
+
+    /// The peripherals of all the adapters that have seen the device.
+    fn all_peripherals(&self) -> Vec<C::Peripheral> {
+        let registry = self.shared.registry.lock().unwrap();
+        registry
+            .device(&self.id)
+            .map(|device| device.peripherals.iter().flatten().cloned().collect())
+            .unwrap_or_default()
+    }
+}
+
+#[async_trait]
+impl<C: Central + 'static> Peripheral for AggregatePeripheral<C> {
+    fn id(&self) -> PeripheralId {
+        self.id.clone()
+    }
+
+    fn address(&self) -> BDAddr {
+        self.address
+    }
+
+    /// The properties from the adapter the device is used through.
+    async fn properties(&self) -> Result<Option<PeripheralProperties>> {
+        self.peripheral()?.properties().await
+    }
+
+    fn last_seen(&self) -> Option<Instant> {
+        self.all_peripherals()
+            .iter()
+            .filter_map(|peripheral| peripheral.last_seen())
+            .max()
+    }
+
+    /// The advertisements received by all adapters, oldest first.
+    fn advertisement_history(&self) -> Vec<AdvertisementRecord> {
+        let mut records: Vec<_> = self
+            .all_peripherals()
+            .iter()
+            .flat_map(|peripheral| peripheral.advertisement_history())
+            .collect();
+        records.sort_by_key(|record| record.received_at);
+        records
+    }
+
+    fn services(&self) -> BTreeSet<Service> {
+        self.peripheral()
+            .map(|peripheral| peripheral.services())
+            .unwrap_or_default()
+    }
+
+    async fn is_connected(&self) -> Result<bool> {
+        let connected_via = self
+            .shared
+            .registry
+            .lock()
+            .unwrap()
+            .device(&self.id)
+            .and_then(|device| device.connected_via);
+        match connected_via {
+            Some(_) => self.peripheral()?.is_connected().await,
+            None => Ok(false),
+        }
+    }
+
+    async fn connect(&self) -> Result<()> {
+        let (adapter, peripheral) = self.route()?;
+        peripheral.connect().await?;
+        self.shared
+            .registry
+            .lock()
+            .unwrap()
+            .set_connected(&self.id, adapter, true);
+        Ok(())
+    }
+
+    async fn disconnect(&self) -> Result<()> {
+        let (adapter, peripheral) = self.route()?;
+        peripheral.disconnect().await?;
+        self.shared
+            .registry
+            .lock()
+            .unwrap()
+            .set_connected(&self.id, adapter, false);
+        Ok(())
+    }
+
+    async fn discover_services(&self) -> Result<()> {
+        self.peripheral()?.discover_services().await
+    }
+
+    async fn write(
+        &self,
+        characteristic: &Characteristic,
+        data: &[u8],
+        write_type: WriteType,
+    ) -> Result<()> {
+        self.peripheral()?
+            .write(characteristic, data, write_type)
+            .await
+    }
+
+    async fn read(&self, characteristic: &Characteristic) -> Result<Vec<u8>> {
+        self.peripheral()?.read(characteristic).await
+    }
+
+    async fn subscribe(&self, characteristic: &Characteristic) -> Result<()> {
+        self.peripheral()?.subscribe(characteristic).await
+    }
+
+    async fn subscribe_with(
+        &self,
+        characteristic: &Characteristic,
+        kind: SubscriptionKind,
+    ) -> Result<()> {
+        self.peripheral()?
+            .subscribe_with(characteristic, kind)
+            .await
+    }
+
+    async fn unsubscribe(&self, characteristic: &Characteristic) -> Result<()> {
+        self.peripheral()?.unsubscribe(characteristic).await
+    }
+
+    async fn notifications(&self) -> Result<Pin<Box<dyn Stream<Item = ValueNotification> + Send>>> {
+        self.peripheral()?.notifications().await
+    }
+
+    async fn subscribe_stream(&self, characteristic: &Characteristic) -> Result<Subscription> {
+        self.peripheral()?.subscribe_stream(characteristic).await
+    }
+
+    async fn write_descriptor(&self, descriptor: &Descriptor, data: &[u8]) -> Result<()> {
+        self.peripheral()?.write_descriptor(descriptor, data).await
+    }
+
+    async fn read_descriptor(&self, descriptor: &Descriptor) -> Result<Vec<u8>> {
+        self.peripheral()?.read_descriptor(descriptor).await
+    }
+
+    async fn pair(&self, options: PairingOptions) -> Result<()> {
+        self.peripheral()?.pair(options).await
+    }
+
+    async fn unpair(&self) -> Result<()> {
+        self.peripheral()?.unpair().await
+    }
+
+    async fn is_bonded(&self) -> Result<bool> {
+        self.peripheral()?.is_bonded().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::{AdapterFeatures, AdapterRoles};
+    use crate::common::mock::{peripheral_id, MockCentral, MockPeripheral};
+
+    fn address(n: u8) -> BDAddr {
+        BDAddr::from([0x11, 0x22, 0x33, 0x44, 0x55, n])
+    }
+
+    #[test]
+    fn merges_peripherals_by_address() {
+        let mut registry = Registry::new(2);
+        let first = MockPeripheral::with_address(1, address(1));
+        let second = MockPeripheral::with_address(2, address(1));
+        assert_eq!(
+            registry.observe(0, first, Some(-70)),
+            (peripheral_id(1), true)
+        );
+        assert_eq!(
+            registry.observe(1, second, Some(-60)),
+            (peripheral_id(1), false)
+        );
+        assert_eq!(registry.devices.len(), 1);
+        assert_eq!(
+            registry.aggregate_id(&peripheral_id(2)),
+            Some(peripheral_id(1))
+        );
+
+        // Without an address, peripherals can't be told to be the same.
+        registry.observe(0, MockPeripheral::new(3), None);
+        registry.observe(1, MockPeripheral::new(4), None);
+        assert_eq!(registry.devices.len(), 3);
+    }
+
+    #[test]
+    fn device_is_kept_until_all_adapters_forget_it() {
+        let mut registry = Registry::new(2);
+        registry.observe(0, MockPeripheral::with_address(1, address(1)), None);
+        registry.observe(1, MockPeripheral::with_address(2, address(1)), None);
+        registry.set_connected(&peripheral_id(1), 0, true);
+        assert_eq!(registry.forget(0, &peripheral_id(1)), None);
+        assert_eq!(registry.connections, vec![0, 0]);
+        assert_eq!(
+            registry.route(&peripheral_id(1), RoutingPolicy::default()),
+            Some(1)
+        );
+        assert_eq!(
+            registry.forget(1, &peripheral_id(2)),
+            Some(peripheral_id(1))
+        );
+        assert!(registry.device(&peripheral_id(1)).is_none());
+        assert!(registry.keys.is_empty());
+    }
+
+    #[test]
+    fn routes_by_policy() {
+        let mut registry = Registry::new(3);
+        // Device 1 is heard best by adapter 1, which device 2 is connected through.
+        registry.observe(0, MockPeripheral::with_address(1, address(1)), Some(-80));
+        registry.observe(1, MockPeripheral::with_address(2, address(1)), Some(-50));
+        registry.observe(2, MockPeripheral::with_address(3, address(1)), Some(-70));
+        registry.observe(1, MockPeripheral::with_address(4, address(2)), Some(-60));
+        registry.set_connected(&peripheral_id(4), 1, true);
+
+        let device = peripheral_id(1);
+        assert_eq!(
+            registry.route(&device, RoutingPolicy::StrongestSignal),
+            Some(1)
+        );
+        assert_eq!(
+            registry.route(&device, RoutingPolicy::FewestConnections),
+            Some(2)
+        );
+
+        // Once connected, the device stays on its adapter.
+        registry.set_connected(&device, 0, true);
+        assert_eq!(registry.connections, vec![1, 1, 0]);
+        assert_eq!(
+            registry.route(&device, RoutingPolicy::StrongestSignal),
+            Some(0)
+        );
+        registry.set_connected(&device, 0, false);
+        assert_eq!(registry.connections, vec![0, 1, 0]);
+    }
+
+    #[test]
+    fn state_is_that_of_the_most_usable_adapter() {
+        assert_eq!(
+            most_usable([CentralState::PoweredOff, CentralState::PoweredOn]),
+            CentralState::PoweredOn
+        );
+        assert_eq!(
+            most_usable([CentralState::Unsupported, CentralState::Unauthorized]),
+            CentralState::Unauthorized
+        );
+        assert_eq!(most_usable([]), CentralState::Unsupported);
+    }
+
+    #[test]
+    fn combines_adapter_info() {
+        let mut first = AdapterInfo {
+            id: "hci0".to_string(),
+            alias: Some("Kitchen".to_string()),
+            powered: Some(false),
+            roles: Some(AdapterRoles::CENTRAL),
+            ..Default::default()
+        };
+        first.capabilities.max_advertisements = Some(4);
+        assert_eq!(combined_info(vec![first.clone()]), first);
+
+        let mut second = AdapterInfo {
+            id: "hci1".to_string(),
+            powered: Some(true),
+            roles: Some(AdapterRoles::PERIPHERAL),
+            ..Default::default()
+        };
+        second.capabilities.features = AdapterFeatures::LE_CODED_PHY;
+        second.capabilities.max_advertisements = Some(1);
+        let info = combined_info(vec![first, second]);
+        assert_eq!(info.id, "hci0; hci1");
+        assert_eq!(info.alias, None);
+        assert_eq!(info.powered, Some(true));
+        assert_eq!(info.discovering, None);
+        assert_eq!(
+            info.roles,
+            Some(AdapterRoles::CENTRAL | AdapterRoles::PERIPHERAL)
+        );
+        assert_eq!(info.capabilities.features, AdapterFeatures::LE_CODED_PHY);
+        assert_eq!(info.capabilities.max_advertisements, Some(5));
+        assert_eq!(info.capabilities.max_connections, None);
+
+        // Counts too big to add up stop at the most there can be.
+        let mut third = info.clone();
+        third.capabilities.max_advertisements = Some(u8::MAX);
+        let info = combined_info(vec![info, third]);
+        assert_eq!(info.capabilities.max_advertisements, Some(u8::MAX));
+    }
+
+    /// Two adapters that both see a device at the same address, the second with the stronger
+    /// signal.
+    fn adapters() -> (MockCentral, MockPeripheral, MockCentral, MockPeripheral) {
+        let with_rssi = |n, rssi| {
+            let peripheral = MockPeripheral::with_address(n, address(1));
+            peripheral.set_properties(PeripheralProperties {
+                rssi: Some(rssi),
+                ..Default::default()
+            });
+            peripheral
+        };
+        let (first, second) = (MockCentral::default(), MockCentral::default());
+        let (weak, strong) = (with_rssi(1, -80), with_rssi(2, -50));
+        first.add(weak.clone());
+        second.add(strong.clone());
+        (first, weak, second, strong)
+    }
+
+    #[tokio::test]
+    async fn merges_events_of_all_adapters() {
+        let (first, _, second, _) = adapters();
+        let central = AggregateCentral::new(
+            vec![first.clone(), second.clone()],
+            RoutingPolicy::default(),
+        );
+        let mut events = central.events().await.unwrap();
+
+        first.emit(CentralEvent::DeviceDiscovered(peripheral_id(1)));
+        assert!(matches!(
+            events.next().await,
+            Some(CentralEvent::DeviceDiscovered(id)) if id == peripheral_id(1)
+        ));
+        // The second adapter's peripheral is the same device.
+        second.emit(CentralEvent::DeviceDiscovered(peripheral_id(2)));
+        assert!(matches!(
+            events.next().await,
+            Some(CentralEvent::DeviceUpdated { id, .. }) if id == peripheral_id(1)
+        ));
+        assert_eq!(central.peripherals().await.unwrap().len(), 1);
+        let peripheral = central.peripheral(&peripheral_id(2)).await.unwrap();
+        assert_eq!(peripheral.id(), peripheral_id(1));
+        assert_eq!(peripheral.adapter_rssi(), vec![Some(-80), Some(-50)]);
+
+        // The device is only lost once neither adapter has it.
+        first.emit(CentralEvent::DeviceLost(peripheral_id(1)));
+        second.emit(CentralEvent::DeviceLost(peripheral_id(2)));
+        assert!(matches!(
+            events.next().await,
+            Some(CentralEvent::DeviceLost(id)) if id == peripheral_id(1)
+        ));
+    }
+
+    #[tokio::test]
+    async fn connects_through_the_routed_adapter() {
+        let (first, weak, second, strong) = adapters();
+        let central = AggregateCentral::new(
+            vec![first.clone(), second.clone()],
+            RoutingPolicy::StrongestSignal,
+        );
+        let mut events = central.events().await.unwrap();
+        let peripheral = central.peripheral(&peripheral_id(1)).await.unwrap();
+        assert!(!peripheral.is_connected().await.unwrap());
+
+        peripheral.connect().await.unwrap();
+        assert_eq!(strong.calls(), vec!["connect"]);
+        assert!(weak.calls().is_empty());
+        assert!(peripheral.is_connected().await.unwrap());
+
+        // Connections made outside the aggregate are followed too.
+        second.emit(CentralEvent::DeviceDisconnected(peripheral_id(2)));
+        assert!(matches!(
+            events.next().await,
+            Some(CentralEvent::DeviceDisconnected(id)) if id == peripheral_id(1)
+        ));
+        first.emit(CentralEvent::DeviceConnected(peripheral_id(1)));
+        assert!(matches!(
+            events.next().await,
+            Some(CentralEvent::DeviceConnected(id)) if id == peripheral_id(1)
+        ));
+        peripheral.disconnect().await.unwrap();
+        assert_eq!(weak.calls(), vec!["disconnect"]);
+        assert_eq!(strong.calls(), vec!["connect"]);
+    }
+}
@@ -0,0 +1,117 @@
+use bitflags::bitflags;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use std::fmt::{self, Display, Formatter};
+
+use super::BDAddr;
+
+bitflags! {
+    /// The roles an adapter can take in Bluetooth LE connections.
+    #[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+    #[derive(Default, Debug, PartialEq, Eq, Hash, Clone, Copy)]
+    pub struct AdapterRoles: u8 {
+        /// Scanning for and connecting to peripherals.
+        const CENTRAL = 0x01;
+        /// Advertising and accepting connections from centrals.
+        const PERIPHERAL = 0x02;
+        /// Both roles at the same time.
+        const CENTRAL_PERIPHERAL = 0x04;
+    }
+}
+
+bitflags! {
+    /// Optional Bluetooth LE features an adapter supports.
+    #[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+    #[derive(Default, Debug, PartialEq, Eq, Hash, Clone, Copy)]
+    pub struct AdapterFeatures: u16 {
+        /// Advertising sets with more than 31 bytes of data, on the secondary advertising channels.
+        const EXTENDED_ADVERTISING = 0x0001;
+        /// The LE 2M PHY, for twice the data rate.
+        const LE_2M_PHY = 0x0002;
+        /// The LE Coded PHY, for longer range.
+        const LE_CODED_PHY = 0x0004;
+        /// Choosing the transmit power of advertisements.
+        const ADVERTISING_TX_POWER = 0x0008;
+        /// Rotating advertisements in the controller rather than the host.
+        const ADVERTISING_OFFLOAD = 0x0010;
+    }
+}
+
+/// What an adapter can do, as far as the platform reports it. A feature the platform doesn't
+/// report is left out, so it may still be supported.
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct AdapterCapabilities {
+    pub features: AdapterFeatures,
+    /// How many peripherals can be connected at once. None of the platforms report this yet.
+    pub max_connections: Option<u32>,
+    /// How many advertisements can be broadcast at once.
+    pub max_advertisements: Option<u8>,
+}
+
+/// Information about a Bluetooth adapter, from
+/// [`Central::adapter_info`](super::Central::adapter_info). Anything the platform doesn't report is
+/// `None`.
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct AdapterInfo {
+    /// A platform-specific identifier, such as `hci0` on BlueZ.
+    pub id: String,
+    pub address: Option<BDAddr>,
+    /// The name the system gives the adapter.
+    pub name: Option<String>,
+    /// The name the user gave the adapter, which is the name otherwise.
+    pub alias: Option<String>,
+    /// The company identifier of the manufacturer, as assigned by the Bluetooth SIG.
+    pub manufacturer: Option<u16>,
+    pub powered: Option<bool>,
+    /// Whether the adapter is scanning, for this application or any other.
+    pub discovering: Option<bool>,
+    pub roles: Option<AdapterRoles>,
+    pub capabilities: AdapterCapabilities,
+}
+
+impl AdapterInfo {
+    pub(crate) fn new(id: impl Into<String>) -> Self {
+        Self {
+            id: id.into(),
+            ..Default::default()
+        }
+    }
+
+    /// Whether the adapter has the given name or alias.
+    pub fn is_named(&self, name: &str) -> bool {
+        self.name.as_deref() == Some(name) || self.alias.as_deref() == Some(name)
+    }
+}
+
+impl Display for AdapterInfo {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "{}", self.id)?;
+        if let Some(name) = self.alias.as_ref().or(self.name.as_ref()) {
+            write!(f, " \"{}\"", name)?;
+        }
+        if let Some(address) = self.address {
+            write!(f, " ({})", address)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn display() {
+        let mut info = AdapterInfo::new("hci0");
+        assert_eq!(info.to_string(), "hci0");
+        info.name = Some("laptop".to_string());
+        info.address = Some(BDAddr::from([0x11, 0x22, 0x33, 0x44, 0x55, 0x66]));
+        assert_eq!(info.to_string(), "hci0 \"laptop\" (11:22:33:44:55:66)");
+        info.alias = Some("Kitchen".to_string());
+        assert_eq!(info.to_string(), "hci0 \"Kitchen\" (11:22:33:44:55:66)");
+        assert!(info.is_named("laptop") && info.is_named("Kitchen"));
+        assert!(!info.is_named("hci0"));
+    }
+}
@@ -21,24 +21,33 @@
 //! use btleplug::platform::{Adapter, Manager, Peripheral};
 //! ```
 
+pub(crate) mod adapter_info;
 pub(crate) mod bdaddr;
 pub mod bleuuid;
+pub mod oob;
+pub(crate) mod pairing;
 
-use crate::Result;
+use crate::{Error, Result};
 use async_trait::async_trait;
 use bitflags::bitflags;
-use futures::stream::Stream;
+use futures::stream::{Stream, StreamExt};
 #[cfg(feature = "serde")]
 use serde::{Deserialize, Serialize};
 use std::{
     collections::{BTreeSet, HashMap},
     fmt::{self, Debug, Display, Formatter},
     pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::{Duration, Instant},
 };
 use uuid::Uuid;
 
+pub use self::adapter_info::{AdapterCapabilities, AdapterFeatures, AdapterInfo, AdapterRoles};
 pub use self::bdaddr::{BDAddr, ParseBDAddrError};
+pub use self::pairing::{IoCapability, OobData, PairingAgent, PairingOptions};
 
+use self::oob::LeOobRecord;
 use crate::platform::PeripheralId;
 
 #[cfg_attr(
@@ -78,13 +87,238 @@ impl AddressType {
     }
 }
 
+/// An advertisement received from a peripheral, as kept in
+/// [`Peripheral::advertisement_history`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct AdvertisementRecord {
+    /// When the advertisement was received.
+    pub received_at: Instant,
+    /// The signal strength of the advertisement, if reported.
+    pub rssi: Option<i16>,
+    /// A hash of the advertised data. Records with the same hash advertised the same data. Hashes
+    /// are only comparable within the same process.
+    pub payload_hash: u64,
+}
+
 /// A notification sent from a peripheral due to a change in a value.
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub struct ValueNotification {
     /// UUID of the characteristic that fired the notification.
     pub uuid: Uuid,
+    /// UUID of the service containing the characteristic.
+    pub service_uuid: Uuid,
     /// The new value of the characteristic.
     pub value: Vec<u8>,
+    /// Whether the value was delivered as a notification or an indication. This is
+    /// [`SubscriptionKind::Both`] when both were enabled on the characteristic, as none of the
+    /// platforms report which of the two the peripheral used for an individual value.
+    pub kind: SubscriptionKind,
+    /// When the value was received from the platform's Bluetooth stack. Notifications may sit in a
+    /// buffer for a while before they are read from the stream, so use this rather than the time
+    /// they are read to reconstruct sample timing.
+    pub received_at: Instant,
+    /// Sequence number of the notification among all values received from the peripheral,
+    /// increasing by one for each. On Android, where every call to
+    /// [`Peripheral::notifications`] starts its own stream from the platform, numbering starts
+    /// over for each stream.
+    pub sequence: u64,
+    /// Number of notifications dropped immediately before this one because the stream was not
+    /// read quickly enough and its buffer overflowed.
+    pub missed: u64,
+}
+
+/// Which kind of value updates to enable when subscribing to a characteristic.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum SubscriptionKind {
+    /// Notifications, which the central does not acknowledge.
+    Notify,
+    /// Indications, which the central acknowledges before the peripheral sends the next one.
+    Indicate,
+    /// Both notifications and indications. The peripheral picks one of them for each value.
+    Both,
+}
+
+impl SubscriptionKind {
+    /// The characteristic properties required to enable this kind of subscription.
+    pub fn required_properties(self) -> CharPropFlags {
+        match self {
+            SubscriptionKind::Notify => CharPropFlags::NOTIFY,
+            SubscriptionKind::Indicate => CharPropFlags::INDICATE,
+            SubscriptionKind::Both => CharPropFlags::NOTIFY | CharPropFlags::INDICATE,
+        }
+    }
+
+    /// The value written to the Client Characteristic Configuration descriptor to enable this kind
+    /// of subscription.
+    pub fn descriptor_value(self) -> [u8; 2] {
+        match self {
+            SubscriptionKind::Notify => [0x01, 0x00],
+            SubscriptionKind::Indicate => [0x02, 0x00],
+            SubscriptionKind::Both => [0x03, 0x00],
+        }
+    }
+
+    /// The kind enabled by platforms which choose for themselves and favour notifications, i.e.
+    /// notify if the characteristic supports it and indicate otherwise.
+    pub(crate) fn preferring_notify(properties: CharPropFlags) -> Option<Self> {
+        if properties.contains(CharPropFlags::NOTIFY) {
+            Some(SubscriptionKind::Notify)
+        } else if properties.contains(CharPropFlags::INDICATE) {
+            Some(SubscriptionKind::Indicate)
+        } else {
+            None
+        }
+    }
+
+    /// The kind enabled by platforms which choose for themselves and favour indications, i.e.
+    /// indicate if the characteristic supports it and notify otherwise.
+    #[cfg(any(target_os = "windows", test))]
+    pub(crate) fn preferring_indicate(properties: CharPropFlags) -> Option<Self> {
+        if properties.contains(CharPropFlags::INDICATE) {
+            Some(SubscriptionKind::Indicate)
+        } else if properties.contains(CharPropFlags::NOTIFY) {
+            Some(SubscriptionKind::Notify)
+        } else {
+            None
+        }
+    }
+
+    /// Returns an error if `characteristic` doesn't support this kind of subscription.
+    pub(crate) fn check_supported(self, characteristic: &Characteristic) -> Result<()> {
+        if characteristic
+            .properties
+            .contains(self.required_properties())
+        {
+            Ok(())
+        } else {
+            Err(Error::NotSupported(format!(
+                "Characteristic {} does not support {:?} subscriptions",
+                characteristic.uuid, self
+            )))
+        }
+    }
+
+    /// Returns an error unless `kind` is the one a platform which can't be told what to enable
+    /// picks for `characteristic`, as given by `platform_choice`.
+    pub(crate) fn check_platform_choice(
+        self,
+        characteristic: &Characteristic,
+        platform_choice: fn(CharPropFlags) -> Option<Self>,
+    ) -> Result<()> {
+        self.check_supported(characteristic)?;
+        if platform_choice(characteristic.properties) == Some(self) {
+            Ok(())
+        } else {
+            Err(Error::NotSupported(format!(
+                "Only {:?} subscriptions can be enabled for characteristic {} on this platform",
+                platform_choice(characteristic.properties),
+                characteristic.uuid
+            )))
+        }
+    }
+}
+
+/// A stream of [`ValueNotification`]s for a single characteristic, returned by
+/// [`Peripheral::subscribe_stream`].
+///
+/// Notify or indicate stays enabled on the characteristic for as long as at least one
+/// `Subscription` for it is alive. When the last one is dropped, the characteristic is
+/// unsubscribed in the background.
+pub struct Subscription {
+    characteristic: Characteristic,
+    notifications: Pin<Box<dyn Stream<Item = ValueNotification> + Send>>,
+    /// Lag reported on values for other characteristics. The dropped notifications may have
+    /// included ours, so the count is carried over to our next value.
+    missed: u64,
+    _handle: Arc<crate::common::subscription::SubscriptionHandle>,
+}
+
+impl Subscription {
+    pub(crate) fn new(
+        characteristic: Characteristic,
+        notifications: Pin<Box<dyn Stream<Item = ValueNotification> + Send>>,
+        handle: Arc<crate::common::subscription::SubscriptionHandle>,
+    ) -> Self {
+        Self {
+            characteristic,
+            notifications,
+            missed: 0,
+            _handle: handle,
+        }
+    }
+
+    /// The characteristic this subscription delivers values for.
+    pub fn characteristic(&self) -> &Characteristic {
+        &self.characteristic
+    }
+}
+
+impl Stream for Subscription {
+    type Item = ValueNotification;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        loop {
+            match self.notifications.as_mut().poll_next(cx) {
+                Poll::Ready(Some(notification))
+                    if notification.uuid != self.characteristic.uuid
+                        || notification.service_uuid != self.characteristic.service_uuid =>
+                {
+                    self.missed += notification.missed;
+                }
+                Poll::Ready(Some(mut notification)) => {
+                    notification.missed += std::mem::take(&mut self.missed);
+                    return Poll::Ready(Some(notification));
+                }
+                poll => return poll,
+            }
+        }
+    }
+}
+
+impl Debug for Subscription {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.debug_struct("Subscription")
+            .field("characteristic", &self.characteristic)
+            .finish()
+    }
+}
+
+/// A stream of the peripherals matching a filter, with their properties, returned by
+/// [`Central::scan`]. Each peripheral is yielded once it is discovered and matches the filter,
+/// and again if it matches after having been lost.
+///
+/// The adapter scans for as long as at least one `ScanSession` of it is alive, with a filter
+/// letting through the devices of all of them. When the last one is dropped, the scan is stopped
+/// in the background.
+pub struct ScanSession<P> {
+    peripherals: Pin<Box<dyn Stream<Item = (P, PeripheralProperties)> + Send>>,
+    _handle: crate::common::scan_session::ScanSessionHandle,
+}
+
+impl<P> ScanSession<P> {
+    pub(crate) fn new(
+        peripherals: Pin<Box<dyn Stream<Item = (P, PeripheralProperties)> + Send>>,
+        handle: crate::common::scan_session::ScanSessionHandle,
+    ) -> Self {
+        Self {
+            peripherals,
+            _handle: handle,
+        }
+    }
+}
+
+impl<P> Stream for ScanSession<P> {
+    type Item = (P, PeripheralProperties);
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.peripherals.as_mut().poll_next(cx)
+    }
+}
+
+impl<P> Debug for ScanSession<P> {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.debug_struct("ScanSession").finish_non_exhaustive()
+    }
 }
 
 bitflags! {
@@ -192,16 +426,249 @@ pub struct PeripheralProperties {
     pub class: Option<u32>,
 }
 
+/// The fields of [`PeripheralProperties`] that changed in a [`CentralEvent::DeviceUpdated`], with
+/// their new values. Fields that didn't change, or are no longer known, are `None`.
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize)
+)]
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct PropertiesDiff {
+    pub local_name: Option<String>,
+    pub tx_power_level: Option<i16>,
+    pub rssi: Option<i16>,
+    pub manufacturer_data: Option<HashMap<u16, Vec<u8>>>,
+    pub service_data: Option<HashMap<Uuid, Vec<u8>>>,
+    pub services: Option<Vec<Uuid>>,
+}
+
+impl PropertiesDiff {
+    /// The changes from `old` to `new`.
+    pub fn between(old: &PeripheralProperties, new: &PeripheralProperties) -> Self {
+        fn changed<T: Clone + PartialEq>(old: &T, new: &T) -> Option<T> {
+            (old != new).then(|| new.clone())
+        }
+        Self {
+            local_name: changed(&old.local_name, &new.local_name).flatten(),
+            tx_power_level: changed(&old.tx_power_level, &new.tx_power_level).flatten(),
+            rssi: changed(&old.rssi, &new.rssi).flatten(),
+            manufacturer_data: changed(&old.manufacturer_data, &new.manufacturer_data),
+            service_data: changed(&old.service_data, &new.service_data),
+            services: changed(&old.services, &new.services),
+        }
+    }
+
+    /// Whether nothing changed.
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// Sets the changed fields on the given properties.
+    pub fn apply(&self, properties: &mut PeripheralProperties) {
+        if let Some(local_name) = &self.local_name {
+            properties.local_name = Some(local_name.clone());
+        }
+        if let Some(tx_power_level) = self.tx_power_level {
+            properties.tx_power_level = Some(tx_power_level);
+        }
+        if let Some(rssi) = self.rssi {
+            properties.rssi = Some(rssi);
+        }
+        if let Some(manufacturer_data) = &self.manufacturer_data {
+            properties.manufacturer_data = manufacturer_data.clone();
+        }
+        if let Some(service_data) = &self.service_data {
+            properties.service_data = service_data.clone();
+        }
+        if let Some(services) = &self.services {
+            properties.services = services.clone();
+        }
+    }
+
+    /// Adds the changes of a later diff, whose values win over the ones in this one.
+    pub fn merge(&mut self, later: PropertiesDiff) {
+        self.local_name = later.local_name.or(self.local_name.take());
+        self.tx_power_level = later.tx_power_level.or(self.tx_power_level);
+        self.rssi = later.rssi.or(self.rssi);
+        self.manufacturer_data = later.manufacturer_data.or(self.manufacturer_data.take());
+        self.service_data = later.service_data.or(self.service_data.take());
+        self.services = later.services.or(self.services.take());
+    }
+}
+
 #[cfg_attr(
     feature = "serde",
     derive(Serialize, Deserialize)
 )]
 /// The filter used when scanning for BLE devices.
+///
+/// A device has to match both the services and the condition. Each platform filters natively by
+/// what it can, and btleplug checks the rest against the device's properties before emitting
+/// events about it, so that every platform reports the same devices. A device that only starts
+/// matching after it was first seen, e.g. once its RSSI is strong enough, is announced with a
+/// [`CentralEvent::DeviceDiscovered`] at that point.
 #[derive(Clone, Debug, Default, Eq, PartialEq)]
 pub struct ScanFilter {
     /// If the filter contains at least one service UUID, only devices supporting at least one of
     /// the given services will be available.
     pub services: Vec<Uuid>,
+    /// A further condition devices have to match.
+    pub condition: Option<ScanCondition>,
+}
+
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+/// A condition on the advertised properties of a device, for [`ScanFilter::condition`].
+///
+/// The data conditions compare the start of the advertised data with `data`, after applying
+/// `mask` to both. The mask must be as long as the data, or empty to compare all bits.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum ScanCondition {
+    /// The local name is exactly the given one.
+    NameEquals(String),
+    /// The local name starts with the given prefix.
+    NamePrefix(String),
+    /// The local name matches the given regular expression, anywhere in the name unless the
+    /// expression is anchored.
+    NameMatches(String),
+    /// The device advertises the given service, in its service UUIDs or service data.
+    Service(Uuid),
+    /// The device advertises manufacturer data for the company ID matching the given data.
+    ManufacturerData {
+        company_id: u16,
+        data: Vec<u8>,
+        mask: Vec<u8>,
+    },
+    /// The device advertises service data for the service matching the given data.
+    ServiceData {
+        uuid: Uuid,
+        data: Vec<u8>,
+        mask: Vec<u8>,
+    },
+    /// The address of the device is one of the given ones.
+    Address(Vec<BDAddr>),
+    /// The device was last received with at least the given RSSI, in dBm.
+    MinRssi(i16),
+    /// The device is known to have the given type of address.
+    AddressType(AddressType),
+    /// All of the conditions hold, which is the case if there are none.
+    All(Vec<ScanCondition>),
+    /// At least one of the conditions holds, which is never the case if there are none.
+    Any(Vec<ScanCondition>),
+}
+
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+/// Whether a scan asks devices for more data, for [`ScanOptions::mode`].
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq)]
+pub enum ScanMode {
+    /// Scan requests are sent to devices, which answer with scan responses carrying further
+    /// advertisement data, such as their name.
+    #[default]
+    Active,
+    /// Only advertisements are listened to, so that the adapter doesn't transmit while scanning.
+    /// On Linux, this needs a filter on services, names, manufacturer data or service data, which
+    /// BlueZ can match advertisements against.
+    Passive,
+}
+
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+/// Which kinds of devices a scan looks for, for [`ScanOptions::transport`].
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq)]
+pub enum ScanTransport {
+    /// Whatever the platform scans for by default, which is at least LE devices.
+    #[default]
+    Auto,
+    /// LE devices only.
+    LowEnergy,
+    /// Bluetooth Classic (BR/EDR) devices only.
+    BrEdr,
+}
+
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+/// How to scan, for [`Central::start_scan_with_options`]. Not every platform supports every
+/// option; the ones that were applied are reported in a [`HonouredScanOptions`]. The default is
+/// how [`Central::start_scan`] scans.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ScanOptions {
+    pub mode: ScanMode,
+    /// Whether every advertisement received from a device is reported, rather than only ones
+    /// with changed data. On macOS and iOS a device that was connected is only reported again
+    /// after disconnecting if this is set.
+    pub duplicates: bool,
+    /// Only report devices received with an RSSI of at least this many dBm.
+    pub min_rssi: Option<i16>,
+    /// Only report devices whose path loss, the difference between their advertised TX power and
+    /// the RSSI, is at most this many dB. On Linux this is ignored if `min_rssi` is set.
+    pub max_pathloss: Option<u16>,
+    pub transport: ScanTransport,
+    /// How often the adapter starts listening on the next advertising channel.
+    pub interval: Option<Duration>,
+    /// How long the adapter listens on each advertising channel, which is at most the interval.
+    pub window: Option<Duration>,
+    /// Only report devices while their RSSI is strong enough, with hysteresis. Only supported by
+    /// passive scans on Linux.
+    pub rssi_monitor: Option<RssiMonitor>,
+}
+
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+/// RSSI thresholds for [`ScanOptions::rssi_monitor`]. A device is found once its RSSI has been at
+/// least `high_threshold` for `high_timeout`, and lost once it has been below `low_threshold`
+/// for `low_timeout`. The thresholds are in dBm, between -127 and 20, and the timeouts are
+/// rounded down to whole seconds, between 1 and 300.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct RssiMonitor {
+    pub high_threshold: i16,
+    pub high_timeout: Duration,
+    pub low_threshold: i16,
+    pub low_timeout: Duration,
+    /// How often advertisements of a found device are reported: `Duration::ZERO` reports all of
+    /// them, longer periods report one per period, rounded to 100 ms, up to 25.4 s. With `None`,
+    /// only the first is reported.
+    pub sampling_period: Option<Duration>,
+}
+
+impl Default for ScanOptions {
+    fn default() -> Self {
+        Self {
+            mode: ScanMode::default(),
+            duplicates: true,
+            min_rssi: None,
+            max_pathloss: None,
+            transport: ScanTransport::default(),
+            interval: None,
+            window: None,
+            rssi_monitor: None,
+        }
+    }
+}
+
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+/// Which of the [`ScanOptions`] given to [`Central::start_scan_with_options`] the scan follows.
+/// Options the platform can't apply are ignored rather than failing the scan, and reported as
+/// `false` here. Options that weren't set, such as a `min_rssi` of `None`, are trivially followed.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct HonouredScanOptions {
+    pub mode: bool,
+    pub duplicates: bool,
+    pub min_rssi: bool,
+    pub max_pathloss: bool,
+    pub transport: bool,
+    pub interval: bool,
+    pub window: bool,
+    pub rssi_monitor: bool,
+}
+
+impl HonouredScanOptions {
+    /// Whether the scan follows all of the options.
+    pub fn all(&self) -> bool {
+        self.mode
+            && self.duplicates
+            && self.min_rssi
+            && self.max_pathloss
+            && self.transport
+            && self.interval
+            && self.window
+            && self.rssi_monitor
+    }
 }
 
 /// The type of write operation to use.
@@ -229,6 +696,17 @@ pub trait Peripheral: Send + Sync + Clone + Debug {
     /// as additional advertising reports are received.
     async fn properties(&self) -> Result<Option<PeripheralProperties>>;
 
+    /// When an advertisement from the peripheral was last received, if any has been since it was
+    /// discovered.
+    fn last_seen(&self) -> Option<Instant>;
+
+    /// The most recent advertisements received from the peripheral, oldest first. Only a limited
+    /// number of them are kept.
+    ///
+    /// BlueZ reports changes to the RSSI and advertised data of a device rather than individual
+    /// advertisements, so on Linux there is a record for each such change instead.
+    fn advertisement_history(&self) -> Vec<AdvertisementRecord>;
+
     /// The set of services we've discovered for this device. This will be empty until
     /// `discover_services` is called.
     fn services(&self) -> BTreeSet<Service>;
@@ -272,6 +750,19 @@ pub trait Peripheral: Send + Sync + Clone + Debug {
     /// Enables either notify or indicate (depending on support) for the specified characteristic.
     async fn subscribe(&self, characteristic: &Characteristic) -> Result<()>;
 
+    /// Enables exactly the given kind of value updates for the specified characteristic.
+    ///
+    /// Unlike [`subscribe`](Self::subscribe), this never falls back to a different kind. If the
+    /// characteristic doesn't support `kind`, or the platform can't enable exactly `kind` for it,
+    /// [`Error::NotSupported`] is returned. BlueZ, CoreBluetooth and WebBluetooth always enable
+    /// notifications when a characteristic supports both, so on those platforms `Indicate` is only
+    /// available for characteristics which don't support notifications, and `Both` not at all.
+    async fn subscribe_with(
+        &self,
+        characteristic: &Characteristic,
+        kind: SubscriptionKind,
+    ) -> Result<()>;
+
     /// Disables either notify or indicate (depending on support) for the specified characteristic.
     async fn unsubscribe(&self, characteristic: &Characteristic) -> Result<()>;
 
@@ -281,6 +772,12 @@ pub trait Peripheral: Send + Sync + Clone + Debug {
     /// is made.
     async fn notifications(&self) -> Result<Pin<Box<dyn Stream<Item = ValueNotification> + Send>>>;
 
+    /// Enables notify or indicate for the specified characteristic and returns a [`Subscription`]
+    /// stream of its value notifications. Several subscriptions to the same characteristic share
+    /// a single notify/indicate registration, which is disabled again once the last of them is
+    /// dropped.
+    async fn subscribe_stream(&self, characteristic: &Characteristic) -> Result<Subscription>;
+
     /// Write some data to the descriptor. Returns an error if the write couldn't be sent or (in
     /// the case of a write-with-response) if the device returns an error.
     async fn write_descriptor(&self, descriptor: &Descriptor, data: &[u8]) -> Result<()>;
@@ -288,6 +785,21 @@ pub trait Peripheral: Send + Sync + Clone + Debug {
     /// Sends a read descriptor request to the device. Returns either an error if the request
     /// was not accepted or the response from the device.
     async fn read_descriptor(&self, descriptor: &Descriptor) -> Result<Vec<u8>>;
+
+    /// Pairs with the device, connecting to it if needed, with the agent in `options` answering
+    /// the prompts. Succeeds straight away if the device is already paired. Fails with
+    /// [`Error::PermissionDenied`] if the agent or the device rejects pairing. Only supported on
+    /// Linux; the other platforms pair on their own when a characteristic needs it.
+    async fn pair(&self, options: PairingOptions) -> Result<()>;
+
+    /// Removes the bond with the device, if any, so that it must be paired with again. BlueZ only
+    /// removes a bond along with the device, so on Linux the peripheral is gone until it is
+    /// discovered again.
+    async fn unpair(&self) -> Result<()>;
+
+    /// Returns true iff the keys from pairing with the device are stored, so that it needn't be
+    /// paired with again.
+    async fn is_bonded(&self) -> Result<bool>;
 }
 
 #[cfg_attr(
@@ -297,9 +809,16 @@ pub trait Peripheral: Send + Sync + Clone + Debug {
 #[derive(Debug, Clone)]
 pub enum CentralEvent {
     DeviceDiscovered(PeripheralId),
-    DeviceUpdated(PeripheralId),
+    /// Emitted when properties of a device changed, with the fields that did.
+    DeviceUpdated {
+        id: PeripheralId,
+        changes: PropertiesDiff,
+    },
     DeviceConnected(PeripheralId),
     DeviceDisconnected(PeripheralId),
+    /// Emitted when a peripheral is forgotten after not being heard from for longer than the
+    /// retention period set with [`Central::set_peripheral_retention`].
+    DeviceLost(PeripheralId),
     /// Emitted when a Manufacturer Data advertisement has been received from a device
     ManufacturerDataAdvertisement {
         id: PeripheralId,
@@ -315,6 +834,84 @@ pub enum CentralEvent {
         id: PeripheralId,
         services: Vec<Uuid>,
     },
+    /// Emitted in place of events that were dropped because the stream wasn't read quickly enough,
+    /// with the number of events lost. The stream's view of the adapter may be incomplete from
+    /// here on; [`Central::peripherals`] gives the current state.
+    EventsLost(u64),
+    /// Emitted when the adapter is powered on or off, or otherwise becomes usable or unusable.
+    StateUpdate(CentralState),
+}
+
+/// Whether an adapter can be used. See [`Central::state`].
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize)
+)]
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum CentralState {
+    /// The platform hasn't reported the state yet, or doesn't report it.
+    Unknown,
+    /// The platform or adapter doesn't support Bluetooth LE, or the adapter has been removed.
+    Unsupported,
+    /// The application isn't allowed to use Bluetooth.
+    Unauthorized,
+    PoweredOff,
+    PoweredOn,
+}
+
+impl CentralState {
+    /// Whether the adapter is powered, if the state tells.
+    pub fn is_powered(self) -> Option<bool> {
+        match self {
+            CentralState::PoweredOn => Some(true),
+            CentralState::PoweredOff => Some(false),
+            _ => None,
+        }
+    }
+}
+
+/// A change to the adapters of a [`Manager`]. See [`Manager::events`].
+#[derive(Clone, Debug)]
+pub enum ManagerEvent<A> {
+    /// An adapter was plugged in or enabled.
+    AdapterAdded(A),
+    /// An adapter was unplugged or disabled. Its handles stop working, and report
+    /// [`CentralState::Unsupported`] from [`Central::state`].
+    AdapterRemoved(A),
+}
+
+/// What an event stream does when it isn't read quickly enough and its buffer fills up.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum EventOverflowPolicy {
+    /// Drop the oldest buffered events, and report how many were dropped with a
+    /// [`CentralEvent::EventsLost`].
+    #[default]
+    DropOldest,
+    /// Replace the buffered [`CentralEvent::DeviceUpdated`] or advertisement event for a device
+    /// with a newer one of the same kind, as the newer one carries the device's latest state. The
+    /// changes of replaced [`CentralEvent::DeviceUpdated`] events are merged into the newer one.
+    /// Discovery and connection events are never coalesced. If there is nothing to replace, the
+    /// oldest event is dropped as with [`DropOldest`](Self::DropOldest).
+    CoalescePerDevice,
+}
+
+/// How the event streams of an adapter buffer events that haven't been read yet. See
+/// [`Central::set_event_buffer`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct EventBufferConfig {
+    /// The number of events each stream buffers before the overflow policy applies. Must be at
+    /// least 1.
+    pub capacity: usize,
+    pub overflow: EventOverflowPolicy,
+}
+
+impl Default for EventBufferConfig {
+    fn default() -> Self {
+        Self {
+            capacity: 16,
+            overflow: EventOverflowPolicy::default(),
+        }
+    }
 }
 
 /// Central is the "client" of BLE. It's able to scan for and establish connections to peripherals.
@@ -327,6 +924,20 @@ pub trait Central: Send + Sync + Clone {
     /// occur for this Central module. See [`CentralEvent`] for the full set of possible events.
     async fn events(&self) -> Result<Pin<Box<dyn Stream<Item = CentralEvent> + Send>>>;
 
+    /// Sets how the streams returned by [`events`](Self::events) buffer events that haven't been
+    /// read yet. This applies to existing streams as well as new ones, from the next event on.
+    async fn set_event_buffer(&self, config: EventBufferConfig) -> Result<()>;
+
+    /// Sets how long a peripheral that isn't connected is kept after it was last heard from, by
+    /// advertising or disconnecting. Once it has been quiet for longer, it's removed from
+    /// [`peripherals`](Self::peripherals) and a [`CentralEvent::DeviceLost`] is emitted. With
+    /// `None`, which is the default, peripherals are kept for as long as the adapter.
+    ///
+    /// Staleness is checked whenever the adapter receives an event or is asked for its
+    /// peripherals. Not supported on Linux, where BlueZ decides when to forget devices, nor on
+    /// wasm, which has no clock to tell how long a peripheral has been quiet.
+    async fn set_peripheral_retention(&self, retention: Option<Duration>) -> Result<()>;
+
     /// Starts a scan for BLE devices. This scan will generally continue until explicitly stopped,
     /// although this may depend on your Bluetooth adapter. Discovered devices will be announced
     /// to subscribers of `events` and will be available via `peripherals()`.
@@ -334,11 +945,31 @@ pub trait Central: Send + Sync + Clone {
     /// ignore (parts of) the filter and make additional devices available, other implementations
     /// might require at least one filter for security reasons. Cross-platform code should provide
     /// a filter, but must be able to handle devices, which do not fit into the filter.
-    async fn start_scan(&self, filter: ScanFilter) -> Result<()>;
+    async fn start_scan(&self, filter: ScanFilter) -> Result<()> {
+        self.start_scan_with_options(filter, ScanOptions::default())
+            .await
+            .map(|_| ())
+    }
+
+    /// Starts a scan for BLE devices like [`start_scan`](Self::start_scan), scanning as the
+    /// options ask where the platform allows it. Returns which of the options the scan follows.
+    async fn start_scan_with_options(
+        &self,
+        filter: ScanFilter,
+        options: ScanOptions,
+    ) -> Result<HonouredScanOptions>;
 
     /// Stops scanning for BLE devices.
     async fn stop_scan(&self) -> Result<()>;
 
+    /// Starts a scan session: scans for as long as the returned [`ScanSession`] is alive, yielding
+    /// the peripherals matching the filter. Unlike [`start_scan`](Self::start_scan) and
+    /// [`stop_scan`](Self::stop_scan), sessions don't interfere with each other: the adapter scans
+    /// with a filter merging those of all open sessions, and only stops when the last one is
+    /// dropped. Calling `start_scan` or `stop_scan` while sessions are open replaces their scan,
+    /// until a session is next opened or closed.
+    async fn scan(&self, filter: ScanFilter) -> Result<ScanSession<Self::Peripheral>>;
+
     /// Returns the list of [`Peripheral`]s that have been discovered so far. Note that this list
     /// may contain peripherals that are no longer available.
     async fn peripherals(&self) -> Result<Vec<Self::Peripheral>>;
@@ -346,14 +977,98 @@ pub trait Central: Send + Sync + Clone {
     /// Returns a particular [`Peripheral`] by its address if it has been discovered.
     async fn peripheral(&self, id: &PeripheralId) -> Result<Self::Peripheral>;
 
+    /// Waits for a peripheral whose properties satisfy the predicate, checking the peripherals
+    /// discovered so far, then the ones discovered or updated from now on. This doesn't start a
+    /// scan. Fails with [`Error::TimedOut`] if no peripheral matches within the timeout.
+    async fn find_peripheral<F>(&self, predicate: F, timeout: Duration) -> Result<Self::Peripheral>
+    where
+        F: Fn(&PeripheralProperties) -> bool + Send + Sync,
+    {
+        crate::common::timeout::timeout(timeout, async {
+            // Get the stream first, so that no peripheral is missed between checking the known
+            // ones and watching for new ones.
+            let mut events = self.events().await?;
+            for peripheral in self.peripherals().await? {
+                // Leave out peripherals lost since they were listed, as for those found later.
+                if let Ok(Some(properties)) = peripheral.properties().await
+                    && predicate(&properties)
+                {
+                    return Ok(peripheral);
+                }
+            }
+            while let Some(event) = events.next().await {
+                let (CentralEvent::DeviceDiscovered(id)
+                | CentralEvent::DeviceUpdated { id, .. }
+                | CentralEvent::ManufacturerDataAdvertisement { id, .. }
+                | CentralEvent::ServiceDataAdvertisement { id, .. }
+                | CentralEvent::ServicesAdvertisement { id, .. }) = event
+                else {
+                    continue;
+                };
+                // The peripheral may have been lost again in the meantime.
+                let Ok(peripheral) = self.peripheral(&id).await else {
+                    continue;
+                };
+                if let Ok(Some(properties)) = peripheral.properties().await
+                    && predicate(&properties)
+                {
+                    return Ok(peripheral);
+                }
+            }
+            Err(Error::DeviceNotFound)
+        })
+        .await?
+    }
+
     /// Add a [`Peripheral`] from a MAC address without a scan result. Not supported on all Bluetooth systems.
     async fn add_peripheral(&self, address: &PeripheralId) -> Result<Self::Peripheral>;
 
-    /// Get information about the Bluetooth adapter being used, such as the model or type.
+    /// Returns the [`Peripheral`] with the given address. On BlueZ and Android it is added without
+    /// a scan result if it hasn't been discovered, as with [`Central::add_peripheral`]; elsewhere
+    /// this fails with [`Error::DeviceNotFound`] unless it has. BlueZ needs the address type to
+    /// connect to an LE device it hasn't seen; the other platforms ignore it.
+    async fn peripheral_by_address(
+        &self,
+        address: BDAddr,
+        address_type: AddressType,
+    ) -> Result<Self::Peripheral> {
+        let _ = address_type;
+        for peripheral in self.peripherals().await? {
+            if peripheral.address() == address {
+                return Ok(peripheral);
+            }
+        }
+        Err(Error::DeviceNotFound)
+    }
+
+    /// Whether the adapter is powered on, or why it can't be used.
+    async fn state(&self) -> Result<CentralState>;
+
+    /// Powers the adapter on or off. Only supported on BlueZ and Windows, where turning the radio
+    /// on or off may need the user's or the system's consent.
+    async fn set_powered(&self, powered: bool) -> Result<()> {
+        let _ = powered;
+        Err(Error::NotSupported(
+            "Powering the adapter on or off".to_string(),
+        ))
+    }
+
+    /// Generates this adapter's out-of-band data for pairing over LE, to hand to a peripheral over
+    /// another channel such as NFC with [`LeOobRecord::to_ndef_message`]. Only supported on BlueZ,
+    /// where it needs the `CAP_NET_ADMIN` capability.
     ///
-    /// The details of this are platform-specific andyou should not attempt to parse it, but it may
-    /// be useful for debug logs.
-    async fn adapter_info(&self) -> Result<String>;
+    /// The adapter only keeps the values it generated last, so a peripheral given them must pair
+    /// before this is called again.
+    async fn local_oob_data(&self) -> Result<LeOobRecord> {
+        Err(Error::NotSupported(
+            "Generating out-of-band data".to_string(),
+        ))
+    }
+
+    /// Get information about the Bluetooth adapter being used, such as its address and what it
+    /// supports. How much of it is known depends on the platform; its `Display` implementation is
+    /// useful for debug logs.
+    async fn adapter_info(&self) -> Result<AdapterInfo>;
 }
 
 /// The Manager is the entry point to the library, providing access to all the Bluetooth adapters on
@@ -381,4 +1096,166 @@ pub trait Manager {
 
     /// Get a list of all Bluetooth adapters on the system. Each adapter implements [`Central`].
     async fn adapters(&self) -> Result<Vec<Self::Adapter>>;
+
+    /// Get the adapter with the given address, if there is one. Not every platform reports the
+    /// addresses of its adapters.
+    async fn adapter_by_address(&self, address: BDAddr) -> Result<Option<Self::Adapter>>
+    where
+        Self: Sync,
+    {
+        for adapter in self.adapters().await? {
+            if adapter.adapter_info().await?.address == Some(address) {
+                return Ok(Some(adapter));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Get the first adapter with the given name or alias, if there is one.
+    async fn adapter_by_name(&self, name: &str) -> Result<Option<Self::Adapter>>
+    where
+        Self: Sync,
+    {
+        for adapter in self.adapters().await? {
+            if adapter.adapter_info().await?.is_named(name) {
+                return Ok(Some(adapter));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Retrieve a stream of the adapters being added and removed from now on, such as when a USB
+    /// dongle is plugged in or out. Only supported on BlueZ.
+    async fn events(
+        &self,
+    ) -> Result<Pin<Box<dyn Stream<Item = ManagerEvent<Self::Adapter>> + Send>>> {
+        Err(Error::NotSupported("Adapter events".to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::mock::{peripheral_id, MockCentral, MockPeripheral};
+
+    fn characteristic_with(properties: CharPropFlags) -> Characteristic {
+        Characteristic {
+            uuid: Uuid::from_u128(0x1234),
+            service_uuid: Uuid::from_u128(0x5678),
+            properties,
+            descriptors: BTreeSet::new(),
+        }
+    }
+
+    #[test]
+    fn properties_diff() {
+        let old = PeripheralProperties {
+            local_name: Some("old".to_string()),
+            rssi: Some(-70),
+            services: vec![Uuid::from_u128(1)],
+            ..Default::default()
+        };
+        let mut new = PeripheralProperties {
+            local_name: Some("new".to_string()),
+            rssi: None,
+            ..old.clone()
+        };
+        new.manufacturer_data.insert(0x004c, vec![1, 2]);
+        let diff = PropertiesDiff::between(&old, &new);
+        assert_eq!(
+            diff,
+            PropertiesDiff {
+                local_name: Some("new".to_string()),
+                manufacturer_data: Some(new.manufacturer_data.clone()),
+                ..Default::default()
+            }
+        );
+        assert!(PropertiesDiff::between(&new, &new).is_empty());
+
+        let mut applied = old.clone();
+        diff.apply(&mut applied);
+        assert_eq!(applied.local_name, new.local_name);
+        assert_eq!(applied.manufacturer_data, new.manufacturer_data);
+        // The RSSI is no longer known, which the diff doesn't report.
+        assert_eq!(applied.rssi, old.rssi);
+    }
+
+    #[test]
+    fn platform_preferences() {
+        let both = CharPropFlags::NOTIFY | CharPropFlags::INDICATE;
+        assert_eq!(
+            SubscriptionKind::preferring_notify(both),
+            Some(SubscriptionKind::Notify)
+        );
+        assert_eq!(
+            SubscriptionKind::preferring_indicate(both),
+            Some(SubscriptionKind::Indicate)
+        );
+        assert_eq!(
+            SubscriptionKind::preferring_notify(CharPropFlags::INDICATE),
+            Some(SubscriptionKind::Indicate)
+        );
+        assert_eq!(
+            SubscriptionKind::preferring_notify(CharPropFlags::READ),
+            None
+        );
+    }
+
+    #[test]
+    fn subscription_kind_support() {
+        let notify_only = characteristic_with(CharPropFlags::NOTIFY);
+        assert!(SubscriptionKind::Notify
+            .check_supported(&notify_only)
+            .is_ok());
+        assert!(SubscriptionKind::Indicate
+            .check_supported(&notify_only)
+            .is_err());
+        assert!(SubscriptionKind::Both
+            .check_supported(&notify_only)
+            .is_err());
+
+        let both = characteristic_with(CharPropFlags::NOTIFY | CharPropFlags::INDICATE);
+        assert!(SubscriptionKind::Both.check_supported(&both).is_ok());
+        assert!(SubscriptionKind::Indicate
+            .check_platform_choice(&both, SubscriptionKind::preferring_notify)
+            .is_err());
+        assert!(SubscriptionKind::Indicate
+            .check_platform_choice(&both, SubscriptionKind::preferring_indicate)
+            .is_ok());
+    }
+
+    #[tokio::test]
+    async fn finds_peripherals_known_or_found_later() {
+        let central = MockCentral::default();
+        let named = |n, name: &str| {
+            let peripheral = MockPeripheral::new(n);
+            peripheral.set_properties(PeripheralProperties {
+                local_name: Some(name.to_string()),
+                ..Default::default()
+            });
+            peripheral
+        };
+        let lost = named(1, "sensor");
+        lost.set_lost();
+        central.add(lost);
+        central.add(named(2, "lamp"));
+        let is = |name: &'static str| {
+            move |properties: &PeripheralProperties| properties.local_name.as_deref() == Some(name)
+        };
+        let timeout = Duration::from_secs(10);
+
+        let found = central.find_peripheral(is("lamp"), timeout).await.unwrap();
+        assert_eq!(found.id(), peripheral_id(2));
+        let (found, ()) = futures::join!(central.find_peripheral(is("sensor"), timeout), async {
+            central.add(named(3, "sensor"));
+            central.emit(CentralEvent::DeviceDiscovered(peripheral_id(3)));
+        });
+        assert_eq!(found.unwrap().id(), peripheral_id(3));
+        assert!(matches!(
+            central
+                .find_peripheral(is("door"), Duration::from_millis(10))
+                .await,
+            Err(Error::TimedOut(_))
+        ));
+    }
 }
@@ -0,0 +1,468 @@
+//! The record devices hand each other out of band, such as over NFC, to pair over LE.
+//!
+//! NFC Forum connection handover messages carry it as an NDEF record of MIME type
+//! [`LE_OOB_MIME_TYPE`], whose payload is a sequence of data structures in the same format as
+//! advertising data.
+
+use super::{AddressType, BDAddr, OobData};
+
+/// The MIME type of the NDEF record carrying an [`LeOobRecord`].
+pub const LE_OOB_MIME_TYPE: &str = "application/vnd.bluetooth.le.oob";
+
+const AD_COMPLETE_LOCAL_NAME: u8 = 0x09;
+const AD_APPEARANCE: u8 = 0x19;
+const AD_LE_ADDRESS: u8 = 0x1B;
+const AD_LE_ROLE: u8 = 0x1C;
+const AD_SC_CONFIRM: u8 = 0x22;
+const AD_SC_RANDOM: u8 = 0x23;
+
+const NDEF_MB: u8 = 0x80;
+const NDEF_ME: u8 = 0x40;
+const NDEF_CF: u8 = 0x20;
+const NDEF_SR: u8 = 0x10;
+const NDEF_IL: u8 = 0x08;
+const NDEF_TNF: u8 = 0x07;
+const TNF_WELL_KNOWN: u8 = 0x01;
+const TNF_MIME: u8 = 0x02;
+/// Connection handover version 1.3.
+const HANDOVER_VERSION: u8 = 0x13;
+/// The carrier power state of a carrier that is ready to use.
+const CPS_ACTIVE: u8 = 0x01;
+/// The ID linking the alternative carrier in a handover select message to the LE OOB record.
+const CARRIER_ID: &[u8] = b"0";
+
+/// Which roles a device supports for connecting over LE.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum LeRole {
+    PeripheralOnly,
+    CentralOnly,
+    /// Both, preferring to be the peripheral when connecting.
+    PeripheralPreferred,
+    /// Both, preferring to be the central when connecting.
+    CentralPreferred,
+}
+
+impl LeRole {
+    fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0x00 => Some(LeRole::PeripheralOnly),
+            0x01 => Some(LeRole::CentralOnly),
+            0x02 => Some(LeRole::PeripheralPreferred),
+            0x03 => Some(LeRole::CentralPreferred),
+            _ => None,
+        }
+    }
+
+    fn num(self) -> u8 {
+        match self {
+            LeRole::PeripheralOnly => 0x00,
+            LeRole::CentralOnly => 0x01,
+            LeRole::PeripheralPreferred => 0x02,
+            LeRole::CentralPreferred => 0x03,
+        }
+    }
+}
+
+/// An error decoding an [`LeOobRecord`] or the NDEF message carrying it.
+#[derive(Debug, thiserror::Error, Clone, PartialEq, Eq)]
+pub enum ParseOobError {
+    #[error("Data structure runs past the end of the record")]
+    Truncated,
+    #[error("Data structure of type {0:#04x} has invalid length {1}")]
+    InvalidLength(u8, usize),
+    #[error("LE OOB record has no {0}")]
+    Missing(&'static str),
+    #[error("Invalid LE role {0:#04x}")]
+    InvalidRole(u8),
+    #[error("Malformed NDEF message")]
+    MalformedNdef,
+    #[error("NDEF message has no LE OOB record")]
+    NoLeOobRecord,
+}
+
+/// The data a device hands over out of band for pairing over LE.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct LeOobRecord {
+    pub address: BDAddr,
+    pub address_type: AddressType,
+    pub role: LeRole,
+    /// The values for pairing with LE Secure Connections, if the device offers them.
+    pub oob_data: Option<OobData>,
+    pub local_name: Option<String>,
+    pub appearance: Option<u16>,
+}
+
+impl LeOobRecord {
+    /// A record for the given address and role, without any of the optional data.
+    pub fn new(address: BDAddr, address_type: AddressType, role: LeRole) -> Self {
+        Self {
+            address,
+            address_type,
+            role,
+            oob_data: None,
+            local_name: None,
+            appearance: None,
+        }
+    }
+
+    /// Encodes the record as the data structures it consists of.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        let mut address = self.address.into_inner();
+        address.reverse();
+        let mut address = address.to_vec();
+        address.push(match self.address_type {
+            AddressType::Public => 0x00,
+            AddressType::Random => 0x01,
+        });
+        push_structure(&mut bytes, AD_LE_ADDRESS, &address);
+        push_structure(&mut bytes, AD_LE_ROLE, &[self.role.num()]);
+        if let Some(oob_data) = &self.oob_data {
+            push_structure(&mut bytes, AD_SC_CONFIRM, &oob_data.confirm);
+            push_structure(&mut bytes, AD_SC_RANDOM, &oob_data.random);
+        }
+        if let Some(appearance) = self.appearance {
+            push_structure(&mut bytes, AD_APPEARANCE, &appearance.to_le_bytes());
+        }
+        if let Some(local_name) = &self.local_name {
+            // A structure's length takes one byte, which includes its type.
+            let mut end = local_name.len().min(usize::from(u8::MAX) - 1);
+            while !local_name.is_char_boundary(end) {
+                end -= 1;
+            }
+            push_structure(
+                &mut bytes,
+                AD_COMPLETE_LOCAL_NAME,
+                &local_name.as_bytes()[..end],
+            );
+        }
+        bytes
+    }
+
+    /// Decodes a record from the data structures it consists of, ignoring those of other types.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, ParseOobError> {
+        let mut address = None;
+        let mut role = None;
+        let mut confirm = None;
+        let mut random = None;
+        let mut local_name = None;
+        let mut appearance = None;
+        let mut rest = bytes;
+        while let Some((&length, after_length)) = rest.split_first() {
+            let length = usize::from(length);
+            // A structure of length 0 pads out the rest of the data.
+            if length == 0 {
+                break;
+            }
+            if after_length.len() < length {
+                return Err(ParseOobError::Truncated);
+            }
+            let (structure, after) = after_length.split_at(length);
+            rest = after;
+            let (&data_type, data) = structure.split_first().unwrap();
+            let invalid_length = || ParseOobError::InvalidLength(data_type, data.len());
+            match data_type {
+                AD_LE_ADDRESS => {
+                    let data: [u8; 7] = data.try_into().map_err(|_| invalid_length())?;
+                    let mut bytes: [u8; 6] = data[..6].try_into().unwrap();
+                    bytes.reverse();
+                    let address_type = if data[6] & 0x01 == 0 {
+                        AddressType::Public
+                    } else {
+                        AddressType::Random
+                    };
+                    address = Some((BDAddr::from(bytes), address_type));
+                }
+                AD_LE_ROLE => {
+                    let [value] = data else {
+                        return Err(invalid_length());
+                    };
+                    role = Some(LeRole::from_u8(*value).ok_or(ParseOobError::InvalidRole(*value))?);
+                }
+                AD_SC_CONFIRM => confirm = Some(data.try_into().map_err(|_| invalid_length())?),
+                AD_SC_RANDOM => random = Some(data.try_into().map_err(|_| invalid_length())?),
+                AD_APPEARANCE => {
+                    let data = data.try_into().map_err(|_| invalid_length())?;
+                    appearance = Some(u16::from_le_bytes(data));
+                }
+                AD_COMPLETE_LOCAL_NAME => {
+                    local_name = Some(String::from_utf8_lossy(data).into_owned());
+                }
+                _ => {}
+            }
+        }
+        let (address, address_type) = address.ok_or(ParseOobError::Missing("LE address"))?;
+        let oob_data = match (confirm, random) {
+            (Some(confirm), Some(random)) => Some(OobData { confirm, random }),
+            (None, None) => None,
+            (None, Some(_)) => return Err(ParseOobError::Missing("confirmation value")),
+            (Some(_), None) => return Err(ParseOobError::Missing("random value")),
+        };
+        Ok(Self {
+            address,
+            address_type,
+            role: role.ok_or(ParseOobError::Missing("LE role"))?,
+            oob_data,
+            local_name,
+            appearance,
+        })
+    }
+
+    /// Encodes the record in a static connection handover select message, as a tag or a device
+    /// offering to pair would present it over NFC.
+    pub fn to_ndef_message(&self) -> Vec<u8> {
+        let mut alternative_carrier = vec![CPS_ACTIVE, CARRIER_ID.len() as u8];
+        alternative_carrier.extend_from_slice(CARRIER_ID);
+        // There is no auxiliary data.
+        alternative_carrier.push(0);
+        let mut handover_select = vec![HANDOVER_VERSION];
+        push_record(
+            &mut handover_select,
+            NDEF_MB | NDEF_ME | TNF_WELL_KNOWN,
+            b"ac",
+            &[],
+            &alternative_carrier,
+        );
+        let mut message = Vec::new();
+        push_record(
+            &mut message,
+            NDEF_MB | TNF_WELL_KNOWN,
+            b"Hs",
+            &[],
+            &handover_select,
+        );
+        push_record(
+            &mut message,
+            NDEF_ME | TNF_MIME,
+            LE_OOB_MIME_TYPE.as_bytes(),
+            CARRIER_ID,
+            &self.to_bytes(),
+        );
+        message
+    }
+
+    /// Decodes the record from an NDEF message, such as a connection handover message, which
+    /// carries it in a record of MIME type [`LE_OOB_MIME_TYPE`].
+    pub fn from_ndef_message(message: &[u8]) -> Result<Self, ParseOobError> {
+        let mut rest = message;
+        while !rest.is_empty() {
+            let (record, after) = NdefRecord::read(rest).ok_or(ParseOobError::MalformedNdef)?;
+            rest = after;
+            if record.tnf == TNF_MIME
+                && record
+                    .record_type
+                    .eq_ignore_ascii_case(LE_OOB_MIME_TYPE.as_bytes())
+            {
+                return Self::from_bytes(record.payload);
+            }
+        }
+        Err(ParseOobError::NoLeOobRecord)
+    }
+}
+
+fn push_structure(bytes: &mut Vec<u8>, data_type: u8, data: &[u8]) {
+    bytes.push(data.len() as u8 + 1);
+    bytes.push(data_type);
+    bytes.extend_from_slice(data);
+}
+
+fn push_record(message: &mut Vec<u8>, flags: u8, record_type: &[u8], id: &[u8], payload: &[u8]) {
+    let short = payload.len() <= usize::from(u8::MAX);
+    let mut header = flags;
+    if short {
+        header |= NDEF_SR;
+    }
+    if !id.is_empty() {
+        header |= NDEF_IL;
+    }
+    message.push(header);
+    message.push(record_type.len() as u8);
+    if short {
+        message.push(payload.len() as u8);
+    } else {
+        message.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    }
+    if !id.is_empty() {
+        message.push(id.len() as u8);
+    }
+    message.extend_from_slice(record_type);
+    message.extend_from_slice(id);
+    message.extend_from_slice(payload);
+}
+
+/// A record of an NDEF message, borrowing from the message.
+struct NdefRecord<'a> {
+    tnf: u8,
+    record_type: &'a [u8],
+    payload: &'a [u8],
+}
+
+impl<'a> NdefRecord<'a> {
+    /// Reads the record at the start of a message, returning it and the rest of the message.
+    /// Chunked records aren't supported.
+    fn read(message: &'a [u8]) -> Option<(Self, &'a [u8])> {
+        let (&header, rest) = message.split_first()?;
+        if header & NDEF_CF != 0 {
+            return None;
+        }
+        let (&type_length, rest) = rest.split_first()?;
+        let (payload_length, rest) = if header & NDEF_SR != 0 {
+            let (&length, rest) = rest.split_first()?;
+            (usize::from(length), rest)
+        } else {
+            let (length, rest) = split_at_checked(rest, 4)?;
+            let length = u32::from_be_bytes(length.try_into().unwrap());
+            (usize::try_from(length).ok()?, rest)
+        };
+        let (id_length, rest) = if header & NDEF_IL != 0 {
+            let (&length, rest) = rest.split_first()?;
+            (usize::from(length), rest)
+        } else {
+            (0, rest)
+        };
+        let (record_type, rest) = split_at_checked(rest, usize::from(type_length))?;
+        let (_id, rest) = split_at_checked(rest, id_length)?;
+        let (payload, rest) = split_at_checked(rest, payload_length)?;
+        let record = Self {
+            tnf: header & NDEF_TNF,
+            record_type,
+            payload,
+        };
+        Some((record, rest))
+    }
+}
+
+fn split_at_checked(bytes: &[u8], mid: usize) -> Option<(&[u8], &[u8])> {
+    (mid <= bytes.len()).then(|| bytes.split_at(mid))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record() -> LeOobRecord {
+        LeOobRecord {
+            oob_data: Some(OobData {
+                confirm: [0x11; 16],
+                random: [0x22; 16],
+            }),
+            local_name: Some("Thermo".to_string()),
+            appearance: Some(0x0341),
+            ..LeOobRecord::new(
+                BDAddr::from([0xC0, 0x11, 0x22, 0x33, 0x44, 0x55]),
+                AddressType::Random,
+                LeRole::PeripheralOnly,
+            )
+        }
+    }
+
+    #[test]
+    fn encodes_data_structures() {
+        let bytes = LeOobRecord::new(
+            BDAddr::from([0x01, 0x02, 0x03, 0x04, 0x05, 0x06]),
+            AddressType::Public,
+            LeRole::CentralPreferred,
+        )
+        .to_bytes();
+        assert_eq!(
+            bytes,
+            [0x08, 0x1B, 0x06, 0x05, 0x04, 0x03, 0x02, 0x01, 0x00, 0x02, 0x1C, 0x03]
+        );
+    }
+
+    #[test]
+    fn decodes_data_structures() {
+        assert_eq!(LeOobRecord::from_bytes(&record().to_bytes()), Ok(record()));
+
+        // Structures of other types are skipped, and padding ends the data.
+        let bytes = [
+            0x02, 0x01, 0x06, // Flags
+            0x08, 0x1B, 0x55, 0x44, 0x33, 0x22, 0x11, 0xC0, 0x01, // LE address
+            0x02, 0x1C, 0x00, // LE role
+            0x00, 0xFF, 0xFF, // Padding
+        ];
+        assert_eq!(
+            LeOobRecord::from_bytes(&bytes),
+            Ok(LeOobRecord::new(
+                BDAddr::from([0xC0, 0x11, 0x22, 0x33, 0x44, 0x55]),
+                AddressType::Random,
+                LeRole::PeripheralOnly,
+            ))
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_data_structures() {
+        let bytes = record().to_bytes();
+        assert_eq!(
+            LeOobRecord::from_bytes(&bytes[..bytes.len() - 1]),
+            Err(ParseOobError::Truncated)
+        );
+        assert_eq!(
+            LeOobRecord::from_bytes(&[0x03, 0x1C, 0x00, 0x00]),
+            Err(ParseOobError::InvalidLength(0x1C, 2))
+        );
+        assert_eq!(
+            LeOobRecord::from_bytes(&[0x02, 0x1C, 0x04]),
+            Err(ParseOobError::InvalidRole(0x04))
+        );
+        assert_eq!(
+            LeOobRecord::from_bytes(&[0x02, 0x1C, 0x00]),
+            Err(ParseOobError::Missing("LE address"))
+        );
+        // The confirmation value is only of use with the random value.
+        let mut without_random = record();
+        without_random.oob_data = None;
+        let mut bytes = without_random.to_bytes();
+        bytes.extend_from_slice(&[0x11, 0x22]);
+        bytes.extend_from_slice(&[0x11; 16]);
+        assert_eq!(
+            LeOobRecord::from_bytes(&bytes),
+            Err(ParseOobError::Missing("random value"))
+        );
+    }
+
+    #[test]
+    fn encodes_handover_select_messages() {
+        let record = LeOobRecord::new(
+            BDAddr::from([0x01, 0x02, 0x03, 0x04, 0x05, 0x06]),
+            AddressType::Public,
+            LeRole::PeripheralOnly,
+        );
+        let mut expected = vec![
+            0x91, 0x02, 0x0A, b'H', b's', 0x13, // Handover select record
+            0xD1, 0x02, 0x04, b'a', b'c', 0x01, 0x01, b'0', 0x00, // Alternative carrier
+            0x5A, 0x20, 0x0C, 0x01, // LE OOB record
+        ];
+        expected.extend_from_slice(LE_OOB_MIME_TYPE.as_bytes());
+        expected.push(b'0');
+        expected.extend_from_slice(&record.to_bytes());
+        assert_eq!(record.to_ndef_message(), expected);
+    }
+
+    #[test]
+    fn decodes_ndef_messages() {
+        assert_eq!(
+            LeOobRecord::from_ndef_message(&record().to_ndef_message()),
+            Ok(record())
+        );
+
+        // A message with just the LE OOB record, with a long payload length and without an ID.
+        let payload = record().to_bytes();
+        let mut message = vec![0xC2, 0x20];
+        message.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+        message.extend_from_slice(LE_OOB_MIME_TYPE.to_uppercase().as_bytes());
+        message.extend_from_slice(&payload);
+        assert_eq!(LeOobRecord::from_ndef_message(&message), Ok(record()));
+
+        assert_eq!(
+            LeOobRecord::from_ndef_message(&message[..message.len() - 1]),
+            Err(ParseOobError::MalformedNdef)
+        );
+        let text = [0xD1, 0x01, 0x03, b'T', 0x02, b'e', b'n'];
+        assert_eq!(
+            LeOobRecord::from_ndef_message(&text),
+            Err(ParseOobError::NoLeOobRecord)
+        );
+    }
+}
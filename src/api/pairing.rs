@@ -0,0 +1,87 @@
+use async_trait::async_trait;
+use std::fmt::{self, Debug, Formatter};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::platform::PeripheralId;
+
+/// The input and output a [`PairingAgent`] offers the user, from which the platform and the
+/// peripheral choose how pairing authenticates.
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq)]
+pub enum IoCapability {
+    /// Can show a passkey, but can't take an answer.
+    DisplayOnly,
+    /// Can show a passkey and take a yes or no answer.
+    DisplayYesNo,
+    /// Can take a passkey, but can't show one.
+    KeyboardOnly,
+    /// Can neither show nor take anything, so pairing isn't protected against MITM attacks.
+    #[default]
+    NoInputNoOutput,
+    /// Can show and take a passkey.
+    KeyboardDisplay,
+}
+
+/// Out-of-band data for LE Secure Connections pairing, exchanged over another channel such as NFC.
+/// The values are in the byte order an [`LeOobRecord`](crate::api::oob::LeOobRecord) carries them
+/// in, least significant byte first.
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq)]
+pub struct OobData {
+    /// The confirmation value, which commits to the random value and the public key.
+    pub confirm: [u8; 16],
+    /// The random value.
+    pub random: [u8; 16],
+}
+
+/// Answers the prompts for pairing with a peripheral. Which prompts there are depends on the
+/// agent's [`IoCapability`] and the peripheral's. Every prompt is rejected unless the agent
+/// answers it, so an agent only needs the methods its capability calls for.
+#[async_trait]
+pub trait PairingAgent: Send + Sync {
+    /// The input and output this agent offers.
+    fn io_capability(&self) -> IoCapability;
+
+    /// Asks for the passkey the peripheral displays, from 0 to 999999. Returns `None` to reject
+    /// pairing.
+    async fn request_passkey(&self, _peripheral: &PeripheralId) -> Option<u32> {
+        None
+    }
+
+    /// Shows the passkey to be entered on the peripheral.
+    async fn display_passkey(&self, _peripheral: &PeripheralId, _passkey: u32) {}
+
+    /// Asks whether the peripheral displays the same passkey, for numeric comparison. Returns
+    /// whether to accept pairing.
+    async fn confirm_passkey(&self, _peripheral: &PeripheralId, _passkey: u32) -> bool {
+        false
+    }
+
+    /// The out-of-band data received from the peripheral, if there is any to pair with. It is
+    /// asked for before pairing starts, and on BlueZ supplying it needs the `CAP_NET_ADMIN`
+    /// capability.
+    async fn remote_oob_data(&self, _peripheral: &PeripheralId) -> Option<OobData> {
+        None
+    }
+}
+
+/// How to pair with a peripheral. See [`Peripheral::pair`](crate::api::Peripheral::pair).
+#[derive(Clone, Default)]
+pub struct PairingOptions {
+    /// Answers the prompts for pairing. Without one, pairing only succeeds with peripherals that
+    /// need no input, as if with an agent with [`IoCapability::NoInputNoOutput`].
+    pub agent: Option<Arc<dyn PairingAgent>>,
+    /// How long to wait for pairing to finish, or the platform's default if `None`.
+    pub timeout: Option<Duration>,
+}
+
+impl Debug for PairingOptions {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PairingOptions")
+            .field(
+                "agent",
+                &self.agent.as_ref().map(|agent| agent.io_capability()),
+            )
+            .field("timeout", &self.timeout)
+            .finish()
+    }
+}
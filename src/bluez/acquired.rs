@@ -0,0 +1,260 @@
+//! Sockets acquired from BlueZ for a characteristic, which carry its values without a D-Bus round
+//! trip for each of them.
+
+use super::bus::BluezBus;
+use crate::{Error, Result};
+use dbus::arg::{OwnedFd, PropMap};
+use dbus::Path;
+use std::io;
+use std::os::unix::io::{FromRawFd, IntoRawFd};
+use std::os::unix::net;
+use tokio::net::UnixStream;
+
+const CHARACTERISTIC_INTERFACE: &str = "org.bluez.GattCharacteristic1";
+
+/// What a socket is acquired for.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Acquire {
+    /// Writing values without response.
+    Write,
+    /// Receiving notifications.
+    Notify,
+}
+
+impl Acquire {
+    fn method(self) -> &'static str {
+        match self {
+            Acquire::Write => "AcquireWrite",
+            Acquire::Notify => "AcquireNotify",
+        }
+    }
+}
+
+/// A socket carrying the values of a characteristic, one per packet. BlueZ releases the
+/// characteristic once it is closed.
+#[derive(Debug)]
+pub struct AcquiredSocket {
+    socket: UnixStream,
+    mtu: u16,
+}
+
+impl AcquiredSocket {
+    /// Wraps a socket that BlueZ reported the given MTU for. This calls tokio functions, so it
+    /// must be called from the context of a Tokio Runtime.
+    pub fn new(socket: net::UnixStream, mtu: u16) -> io::Result<Self> {
+        socket.set_nonblocking(true)?;
+        Ok(Self {
+            socket: UnixStream::from_std(socket)?,
+            mtu,
+        })
+    }
+
+    /// The largest value the socket carries in one packet.
+    pub fn mtu(&self) -> u16 {
+        self.mtu
+    }
+
+    /// Sends a value, which must fit in one packet.
+    pub async fn send(&self, value: &[u8]) -> io::Result<()> {
+        if value.len() > usize::from(self.mtu) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} bytes don't fit the MTU of {}", value.len(), self.mtu),
+            ));
+        }
+        loop {
+            self.socket.writable().await?;
+            match self.socket.try_write(value) {
+                Ok(written) if written == value.len() => return Ok(()),
+                Ok(_) => return Err(io::ErrorKind::WriteZero.into()),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Receives the next value, or `None` once BlueZ has closed the socket, as it does when the
+    /// device disconnects.
+    pub async fn recv(&self) -> io::Result<Option<Vec<u8>>> {
+        let mut buffer = vec![0; usize::from(self.mtu)];
+        loop {
+            self.socket.readable().await?;
+            match self.socket.try_read(&mut buffer) {
+                Ok(0) => return Ok(None),
+                Ok(len) => {
+                    buffer.truncate(len);
+                    return Ok(Some(buffer));
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+/// Acquires a socket for the characteristic at the given path. BlueZ refuses when the
+/// characteristic doesn't support it, or is already acquired or notifying.
+pub async fn acquire(
+    bus: &BluezBus,
+    characteristic: Path<'static>,
+    purpose: Acquire,
+) -> Result<AcquiredSocket> {
+    let (fd, mtu): (OwnedFd, u16) = bus
+        .proxy(characteristic)
+        .await?
+        .method_call(
+            CHARACTERISTIC_INTERFACE,
+            purpose.method(),
+            (PropMap::new(),),
+        )
+        .await
+        .map_err(|e| Error::Other(Box::new(e)))?;
+    // SAFETY: The descriptor is owned, and ownership passes to the socket.
+    let socket = unsafe { net::UnixStream::from_raw_fd(fd.into_raw_fd()) };
+    AcquiredSocket::new(socket, mtu).map_err(|e| Error::Other(Box::new(e)))
+}
+
+/// A pair of connected sockets which, like those BlueZ hands out, carry one value per packet.
+#[cfg(test)]
+pub(super) fn seqpacket_pair() -> (net::UnixStream, net::UnixStream) {
+    let mut fds = [0; 2];
+    // SAFETY: There is room for the two descriptors.
+    let result =
+        unsafe { libc::socketpair(libc::AF_UNIX, libc::SOCK_SEQPACKET, 0, fds.as_mut_ptr()) };
+    assert_eq!(result, 0, "{}", io::Error::last_os_error());
+    // SAFETY: The descriptors are new, and ownership passes to the sockets.
+    unsafe {
+        (
+            net::UnixStream::from_raw_fd(fds[0]),
+            net::UnixStream::from_raw_fd(fds[1]),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bluez::monitor::BLUEZ;
+    use crate::bluez::test_bus::PrivateBus;
+    use dbus::channel::{MatchingReceiver, Sender};
+    use dbus::message::MatchRule;
+    use dbus::nonblock::SyncConnection;
+    use std::io::{Read, Write};
+    use std::sync::{Arc, Mutex};
+
+    const CHARACTERISTIC: &str = "/org/bluez/hci0/dev_11_22_33_44_55_66/service0010/char0011";
+
+    /// Stands in for BlueZ on the bus, handing out one end of a socketpair for each acquisition
+    /// of the characteristic and keeping the other, or refusing when there are no more MTUs.
+    async fn stand_in_bluez(
+        bus: &PrivateBus,
+        mtus: Vec<u16>,
+    ) -> (
+        Arc<SyncConnection>,
+        Arc<Mutex<Vec<(String, net::UnixStream)>>>,
+    ) {
+        let connection = bus.connect();
+        connection
+            .request_name(BLUEZ, false, true, true)
+            .await
+            .unwrap();
+        let peers = Arc::new(Mutex::new(Vec::new()));
+        let kept = peers.clone();
+        let mut mtus = mtus.into_iter();
+        let mut rule = MatchRule::new_method_call();
+        rule.path = Some(Path::from(CHARACTERISTIC));
+        connection.start_receive(
+            rule,
+            Box::new(move |message, connection| {
+                let reply = match mtus.next() {
+                    Some(mtu) => {
+                        let (ours, theirs) = seqpacket_pair();
+                        let member = message.member().unwrap().to_string();
+                        kept.lock().unwrap().push((member, ours));
+                        // SAFETY: The descriptor is owned, and ownership passes to the message.
+                        let fd = unsafe { OwnedFd::from_raw_fd(theirs.into_raw_fd()) };
+                        message.method_return().append2(fd, mtu)
+                    }
+                    None => dbus::Message::error(
+                        &message,
+                        &"org.bluez.Error.NotPermitted".into(),
+                        &std::ffi::CString::new("Already acquired").unwrap(),
+                    ),
+                };
+                connection.send(reply).unwrap();
+                true
+            }),
+        );
+        (connection, peers)
+    }
+
+    #[tokio::test]
+    async fn acquires_sockets_from_stand_in_bluez() {
+        let Some(bus) = PrivateBus::start() else {
+            eprintln!("Skipping, as dbus-daemon isn't available");
+            return;
+        };
+        let (_bluez, peers) = stand_in_bluez(&bus, vec![244, 20]).await;
+        let bluez_bus = BluezBus::on(bus.connect());
+
+        let writer = acquire(&bluez_bus, Path::from(CHARACTERISTIC), Acquire::Write)
+            .await
+            .unwrap();
+        assert_eq!(writer.mtu(), 244);
+        writer.send(&[1, 2, 3]).await.unwrap();
+        assert_eq!(
+            writer.send(&[0; 245]).await.unwrap_err().kind(),
+            io::ErrorKind::InvalidInput
+        );
+
+        let notifier = acquire(&bluez_bus, Path::from(CHARACTERISTIC), Acquire::Notify)
+            .await
+            .unwrap();
+        assert_eq!(notifier.mtu(), 20);
+
+        let (mut write_peer, mut notify_peer) = {
+            let mut peers = peers.lock().unwrap();
+            let (notify_method, notify_peer) = peers.pop().unwrap();
+            let (write_method, write_peer) = peers.pop().unwrap();
+            assert_eq!(write_method, "AcquireWrite");
+            assert_eq!(notify_method, "AcquireNotify");
+            (write_peer, notify_peer)
+        };
+        let mut written = [0; 3];
+        write_peer.read_exact(&mut written).unwrap();
+        assert_eq!(written, [1, 2, 3]);
+
+        notify_peer.write_all(&[4, 5]).unwrap();
+        assert_eq!(notifier.recv().await.unwrap(), Some(vec![4, 5]));
+        drop(notify_peer);
+        assert_eq!(notifier.recv().await.unwrap(), None);
+
+        // The stand-in refuses once it has run out of MTUs, as BlueZ does for characteristics
+        // that are already acquired.
+        assert!(
+            acquire(&bluez_bus, Path::from(CHARACTERISTIC), Acquire::Write)
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn keeps_values_sent_back_to_back_apart() {
+        let (ours, theirs) = seqpacket_pair();
+        let socket = AcquiredSocket::new(theirs, 20).unwrap();
+        let mut peer = ours;
+
+        peer.write_all(&[1, 2]).unwrap();
+        peer.write_all(&[3]).unwrap();
+        assert_eq!(socket.recv().await.unwrap(), Some(vec![1, 2]));
+        assert_eq!(socket.recv().await.unwrap(), Some(vec![3]));
+
+        socket.send(&[4, 5]).await.unwrap();
+        socket.send(&[6]).await.unwrap();
+        let mut value = [0; 20];
+        let len = peer.read(&mut value).unwrap();
+        assert_eq!(value[..len], [4, 5]);
+        let len = peer.read(&mut value).unwrap();
+        assert_eq!(value[..len], [6]);
+    }
+}
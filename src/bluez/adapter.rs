@@ -1,27 +1,132 @@
+use super::bus::BluezBus;
+use super::devices::{DeviceState, DeviceStore};
+use super::mgmt;
+use super::monitor::{MonitorEvent, PassiveScanner};
 use super::peripheral::{Peripheral, PeripheralId};
-use crate::api::{Central, CentralEvent, ScanFilter};
+use crate::api::oob::LeOobRecord;
+use crate::api::{
+    AdapterFeatures, AdapterInfo, AdapterRoles, AddressType, BDAddr, Central, CentralEvent,
+    CentralState, EventBufferConfig, HonouredScanOptions, ScanFilter, ScanMode, ScanOptions,
+    ScanSession, ScanTransport,
+};
+use crate::common::{event_buffer, scan_filter::ActiveScanFilter, scan_session::ScanSessions};
 use crate::{Error, Result};
 use async_trait::async_trait;
 use bluez_async::{
-    AdapterId, BluetoothError, BluetoothEvent, BluetoothSession, DeviceEvent, DiscoveryFilter,
-    Transport,
+    AdapterId, BluetoothError, BluetoothSession, DeviceId, DiscoveryFilter, Transport,
 };
+use dbus::arg::{prop_cast, PropMap, RefArg, Variant};
+use dbus::nonblock::stdintf::org_freedesktop_dbus::Properties;
+use dbus::Path;
+use futures::future;
 use futures::stream::{self, Stream, StreamExt};
 use std::pin::Pin;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tokio_stream::wrappers::errors::BroadcastStreamRecvError;
+use tokio_stream::wrappers::BroadcastStream;
+
+pub(super) const ADAPTER_INTERFACE: &str = "org.bluez.Adapter1";
+const ADVERTISING_MANAGER_INTERFACE: &str = "org.bluez.LEAdvertisingManager1";
 
-/// Implementation of [api::Central](crate::api::Central).
+/// Implementation of [api::Central](crate::api::Central). Handles are equal if they are for the
+/// same adapter.
 #[derive(Clone, Debug)]
 pub struct Adapter {
     session: BluetoothSession,
     adapter: AdapterId,
+    event_buffer: Arc<RwLock<EventBufferConfig>>,
+    devices: Arc<DeviceStore>,
+    bus: Arc<BluezBus>,
+    scan_filter: ActiveScanFilter,
+    passive_scanner: Arc<PassiveScanner>,
+    scan_sessions: ScanSessions,
 }
 
 impl Adapter {
-    pub(crate) fn new(session: BluetoothSession, adapter: AdapterId) -> Self {
-        Self { session, adapter }
+    // This calls tokio::spawn, so it must be called from the context of a Tokio Runtime.
+    pub(crate) fn new(session: BluetoothSession, adapter: AdapterId, bus: Arc<BluezBus>) -> Self {
+        let devices = DeviceStore::spawn(session.clone(), adapter.clone(), bus.clone());
+        Self::with_devices(session, adapter, devices, bus)
+    }
+
+    /// A handle for an adapter that has been removed, which doesn't track its devices.
+    pub(crate) fn removed(
+        session: BluetoothSession,
+        adapter: AdapterId,
+        bus: Arc<BluezBus>,
+    ) -> Self {
+        let devices = DeviceStore::new(session.clone(), adapter.clone());
+        Self::with_devices(session, adapter, devices, bus)
+    }
+
+    fn with_devices(
+        session: BluetoothSession,
+        adapter: AdapterId,
+        devices: Arc<DeviceStore>,
+        bus: Arc<BluezBus>,
+    ) -> Self {
+        let passive_scanner = Arc::new(PassiveScanner::new(adapter.clone().into(), bus.clone()));
+        Self {
+            session,
+            adapter,
+            event_buffer: Default::default(),
+            devices,
+            bus,
+            scan_filter: ActiveScanFilter::default(),
+            passive_scanner,
+            scan_sessions: ScanSessions::default(),
+        }
+    }
+
+    /// Adds a device BlueZ hasn't discovered, connecting to it.
+    async fn connect_device(
+        &self,
+        address: BDAddr,
+        address_type: AddressType,
+    ) -> Result<Peripheral> {
+        let path = connect_device(
+            &self.bus,
+            self.adapter.clone().into(),
+            address,
+            address_type,
+        )
+        .await?;
+        let (id, device) = self
+            .devices
+            .device_by_path(&path)
+            .await?
+            .ok_or(Error::DeviceNotFound)?;
+        Ok(self.peripheral_from(id, device))
+    }
+
+    /// Stops discovery, if this application started it.
+    async fn stop_discovery(&self) -> Result<()> {
+        match self.session.stop_discovery_on_adapter(&self.adapter).await {
+            Err(BluetoothError::DbusError(e)) if is_not_discovering(&e) => Ok(()),
+            result => Ok(result?),
+        }
+    }
+
+    fn peripheral_from(&self, id: DeviceId, device: DeviceState) -> Peripheral {
+        Peripheral::new(
+            self.session.clone(),
+            id,
+            device,
+            self.devices.clone(),
+            self.bus.clone(),
+        )
     }
 }
 
+impl PartialEq for Adapter {
+    fn eq(&self, other: &Self) -> bool {
+        self.adapter == other.adapter
+    }
+}
+
+impl Eq for Adapter {}
+
 #[async_trait]
 impl Central for Adapter {
     type Peripheral = Peripheral;
@@ -30,80 +135,189 @@ impl Central for Adapter {
         // There's a race between getting this event stream and getting the current set of devices.
         // Get the stream first, on the basis that it's better to have a duplicate DeviceDiscovered
         // event than to miss one. It's unlikely to happen in any case.
-        let events = self.session.adapter_event_stream(&self.adapter).await?;
+        let events = BroadcastStream::new(self.devices.events()).map(|event| match event {
+            Ok(event) => event,
+            Err(BroadcastStreamRecvError::Lagged(n)) => CentralEvent::EventsLost(n),
+        });
+        let monitor_events = BroadcastStream::new(self.passive_scanner.events());
 
         // Synthesise `DeviceDiscovered' and `DeviceConnected` events for existing peripherals.
-        let devices = self.session.get_devices().await?;
-        let adapter_id = self.adapter.clone();
-        let initial_events = stream::iter(
-            devices
-                .into_iter()
-                .filter(move |device| device.id.adapter() == adapter_id)
-                .flat_map(|device| {
-                    let mut events = vec![CentralEvent::DeviceDiscovered(device.id.clone().into())];
-                    if device.connected {
-                        events.push(CentralEvent::DeviceConnected(device.id.into()));
-                    }
-                    events.into_iter()
-                }),
-        );
+        let devices = self.devices.devices().await?;
+        let initial_events = stream::iter(devices.into_iter().flat_map(|(id, device)| {
+            let mut events = vec![CentralEvent::DeviceDiscovered(id.clone().into())];
+            if device.connected {
+                events.push(CentralEvent::DeviceConnected(id.into()));
+            }
+            events.into_iter()
+        }));
+
+        let devices = self.devices.clone();
+        let monitor_events = monitor_events
+            .filter_map(|event| future::ready(event.ok()))
+            .filter_map(move |event| future::ready(monitor_event(event, &devices)));
+        let mut events = Box::pin(self.scan_filter.filter_events(
+            self.clone(),
+            stream::select(initial_events.chain(events), monitor_events),
+        ));
+
+        // D-Bus signals queue up without limit, so read them as they arrive and buffer the events
+        // the same way the other platforms do.
+        let (sender, stream) = event_buffer::channel();
+        let event_buffer = self.event_buffer.clone();
+        tokio::spawn(async move {
+            loop {
+                // Stop as soon as the stream is dropped, rather than with the next event, which
+                // may never come on a quiet adapter.
+                let event = tokio::select! {
+                    event = events.next() => event,
+                    _ = sender.closed() => None,
+                };
+                let Some(event) = event else {
+                    break;
+                };
+                let config = *event_buffer.read().unwrap();
+                if !sender.send(event, &config) {
+                    break;
+                }
+            }
+        });
+        Ok(Box::pin(stream))
+    }
 
-        let session = self.session.clone();
-        let adapter_id = self.adapter.clone();
-        let events = events
-            .filter_map(move |event| central_event(event, session.clone(), adapter_id.clone()));
+    async fn set_event_buffer(&self, config: EventBufferConfig) -> Result<()> {
+        event_buffer::check_config(&config)?;
+        *self.event_buffer.write().unwrap() = config;
+        Ok(())
+    }
 
-        Ok(Box::pin(initial_events.chain(events)))
+    async fn set_peripheral_retention(&self, _retention: Option<Duration>) -> Result<()> {
+        Err(Error::NotSupported(
+            "BlueZ decides when to forget devices, see TemporaryTimeout in main.conf".to_string(),
+        ))
     }
 
-    async fn start_scan(&self, filter: ScanFilter) -> Result<()> {
+    async fn start_scan_with_options(
+        &self,
+        filter: ScanFilter,
+        options: ScanOptions,
+    ) -> Result<HonouredScanOptions> {
+        self.scan_filter.set(&filter)?;
+        if options.mode == ScanMode::Passive {
+            // A discovery left running would keep scanning actively.
+            self.stop_discovery().await?;
+            self.passive_scanner
+                .start(&filter, options.rssi_monitor)
+                .await?;
+            // Monitors match LE advertisements as they come, without changing the scan.
+            return Ok(HonouredScanOptions {
+                mode: true,
+                duplicates: !options.duplicates,
+                min_rssi: options.min_rssi.is_none(),
+                max_pathloss: options.max_pathloss.is_none(),
+                transport: options.transport != ScanTransport::BrEdr,
+                interval: options.interval.is_none(),
+                window: options.window.is_none(),
+                rssi_monitor: true,
+            });
+        }
+        self.passive_scanner.stop().await?;
+        // BlueZ rejects a filter with both an RSSI and a pathloss threshold.
+        let pathloss_threshold = options.max_pathloss.filter(|_| options.min_rssi.is_none());
         let filter = DiscoveryFilter {
             service_uuids: filter.services,
-            duplicate_data: Some(true),
-            transport: Some(Transport::Auto),
+            rssi_threshold: options.min_rssi,
+            pathloss_threshold,
+            duplicate_data: Some(options.duplicates),
+            transport: Some(match options.transport {
+                ScanTransport::Auto => Transport::Auto,
+                ScanTransport::LowEnergy => Transport::Le,
+                ScanTransport::BrEdr => Transport::BrEdr,
+            }),
             ..Default::default()
         };
         self.session
             .start_discovery_on_adapter_with_filter(&self.adapter, &filter)
             .await?;
-        Ok(())
+        // Discovery always scans actively, with the kernel's scan parameters.
+        Ok(HonouredScanOptions {
+            mode: options.mode == ScanMode::Active,
+            duplicates: true,
+            min_rssi: true,
+            max_pathloss: pathloss_threshold == options.max_pathloss,
+            transport: true,
+            interval: options.interval.is_none(),
+            window: options.window.is_none(),
+            rssi_monitor: options.rssi_monitor.is_none(),
+        })
     }
 
     async fn stop_scan(&self) -> Result<()> {
-        self.session
-            .stop_discovery_on_adapter(&self.adapter)
-            .await?;
-        Ok(())
+        self.passive_scanner.stop().await?;
+        self.stop_discovery().await
+    }
+
+    async fn scan(&self, filter: ScanFilter) -> Result<ScanSession<Peripheral>> {
+        self.scan_sessions.scan(self, filter).await
     }
 
     async fn peripherals(&self) -> Result<Vec<Peripheral>> {
-        let devices = self.session.get_devices_on_adapter(&self.adapter).await?;
+        let devices = self.devices.devices().await?;
         Ok(devices
             .into_iter()
-            .map(|device| Peripheral::new(self.session.clone(), device))
+            .map(|(id, device)| self.peripheral_from(id, device))
             .collect())
     }
 
     async fn peripheral(&self, id: &PeripheralId) -> Result<Peripheral> {
-        let device = self.session.get_device_info(&id.0).await.map_err(|e| {
-            if let BluetoothError::DbusError(_) = e {
-                Error::DeviceNotFound
-            } else {
-                e.into()
-            }
-        })?;
-        Ok(Peripheral::new(self.session.clone(), device))
+        let device = self.devices.device(&id.0).await?;
+        Ok(self.peripheral_from(id.0.clone(), device))
     }
 
-    async fn add_peripheral(&self, _address: &PeripheralId) -> Result<Peripheral> {
-        Err(Error::NotSupported(
-            "Can't add a Peripheral from a PeripheralId".to_string(),
-        ))
+    async fn add_peripheral(&self, address: &PeripheralId) -> Result<Peripheral> {
+        match self.peripheral(address).await {
+            Err(Error::DeviceNotFound) => {}
+            result => return result,
+        }
+        let device_address = device_address(&address.0)
+            .filter(|_| address.0.adapter() == self.adapter)
+            .ok_or(Error::DeviceNotFound)?;
+        // A PeripheralId doesn't say which type of address the device has, so assume the more
+        // common one.
+        self.connect_device(device_address, AddressType::Public)
+            .await
+    }
+
+    async fn peripheral_by_address(
+        &self,
+        address: BDAddr,
+        address_type: AddressType,
+    ) -> Result<Peripheral> {
+        let device = self
+            .devices
+            .devices()
+            .await?
+            .into_iter()
+            .find(|(_, device)| device.properties.address == address);
+        match device {
+            Some((id, device)) => Ok(self.peripheral_from(id, device)),
+            None => self.connect_device(address, address_type).await,
+        }
     }
 
-    async fn adapter_info(&self) -> Result<String> {
-        let adapter_info = self.session.get_adapter_info(&self.adapter).await?;
-        Ok(format!("{} ({})", adapter_info.id, adapter_info.modalias))
+    async fn state(&self) -> Result<CentralState> {
+        adapter_state(&self.bus, self.adapter.clone().into()).await
+    }
+
+    async fn set_powered(&self, powered: bool) -> Result<()> {
+        set_adapter_powered(&self.bus, self.adapter.clone().into(), powered).await
+    }
+
+    async fn local_oob_data(&self) -> Result<LeOobRecord> {
+        mgmt::read_local_oob_data(&self.adapter).await
+    }
+
+    async fn adapter_info(&self) -> Result<AdapterInfo> {
+        read_adapter_info(&self.bus, self.adapter.clone().into()).await
     }
 }
 
@@ -113,55 +327,442 @@ impl From<BluetoothError> for Error {
     }
 }
 
-async fn central_event(
-    event: BluetoothEvent,
-    session: BluetoothSession,
-    adapter_id: AdapterId,
-) -> Option<CentralEvent> {
-    match event {
-        BluetoothEvent::Device {
-            id,
-            event: device_event,
-        } if id.adapter() == adapter_id => match device_event {
-            DeviceEvent::Discovered => {
-                let device = session.get_device_info(&id).await.ok()?;
-                Some(CentralEvent::DeviceDiscovered(device.id.into()))
-            }
-            DeviceEvent::Connected { connected } => {
-                let device = session.get_device_info(&id).await.ok()?;
-                if connected {
-                    Some(CentralEvent::DeviceConnected(device.id.into()))
-                } else {
-                    Some(CentralEvent::DeviceDisconnected(device.id.into()))
-                }
-            }
-            DeviceEvent::Rssi { rssi: _ } => {
-                let device = session.get_device_info(&id).await.ok()?;
-                Some(CentralEvent::DeviceUpdated(device.id.into()))
-            }
-            DeviceEvent::ManufacturerData { manufacturer_data } => {
-                let device = session.get_device_info(&id).await.ok()?;
-                Some(CentralEvent::ManufacturerDataAdvertisement {
-                    id: device.id.into(),
-                    manufacturer_data,
-                })
-            }
-            DeviceEvent::ServiceData { service_data } => {
-                let device = session.get_device_info(&id).await.ok()?;
-                Some(CentralEvent::ServiceDataAdvertisement {
-                    id: device.id.into(),
-                    service_data,
-                })
-            }
-            DeviceEvent::Services { services } => {
-                let device = session.get_device_info(&id).await.ok()?;
-                Some(CentralEvent::ServicesAdvertisement {
-                    id: device.id.into(),
-                    services,
-                })
-            }
-            _ => None,
+/// Asks BlueZ to connect to a device by its address, which adds the device if BlueZ doesn't know
+/// it, and returns the path of the device.
+async fn connect_device(
+    bus: &BluezBus,
+    adapter: Path<'static>,
+    address: BDAddr,
+    address_type: AddressType,
+) -> Result<Path<'static>> {
+    let address_type = match address_type {
+        AddressType::Public => "public",
+        AddressType::Random => "random",
+    };
+    let mut device = PropMap::new();
+    device.insert(
+        "Address".to_string(),
+        Variant(Box::new(address.to_string())),
+    );
+    device.insert(
+        "AddressType".to_string(),
+        Variant(Box::new(address_type.to_string())),
+    );
+    let (path,): (Path<'static>,) = bus
+        .proxy(adapter)
+        .await?
+        .method_call(ADAPTER_INTERFACE, "ConnectDevice", (device,))
+        .await
+        .map_err(|e| match e.name() {
+            Some("org.freedesktop.DBus.Error.UnknownMethod") => Error::NotSupported(
+                "ConnectDevice needs bluetoothd to run with --experimental".to_string(),
+            ),
+            _ => Error::Other(Box::new(e)),
+        })?;
+    Ok(path)
+}
+
+/// Whether the adapter at the given path is powered, or why it can't be used.
+async fn adapter_state(bus: &BluezBus, adapter: Path<'static>) -> Result<CentralState> {
+    let powered = bus
+        .proxy(adapter)
+        .await?
+        .get::<bool>(ADAPTER_INTERFACE, "Powered")
+        .await;
+    match powered {
+        Ok(true) => Ok(CentralState::PoweredOn),
+        Ok(false) => Ok(CentralState::PoweredOff),
+        Err(e) => match e.name() {
+            // The adapter has been removed.
+            Some("org.freedesktop.DBus.Error.UnknownObject")
+            | Some("org.freedesktop.DBus.Error.UnknownMethod") => Ok(CentralState::Unsupported),
+            Some("org.freedesktop.DBus.Error.AccessDenied") => Ok(CentralState::Unauthorized),
+            _ => Err(Error::Other(Box::new(e))),
         },
-        _ => None,
+    }
+}
+
+/// Powers the adapter at the given path on or off.
+async fn set_adapter_powered(bus: &BluezBus, adapter: Path<'static>, powered: bool) -> Result<()> {
+    bus.proxy(adapter)
+        .await?
+        .set(ADAPTER_INTERFACE, "Powered", powered)
+        .await
+        .map_err(|e| match e.name() {
+            Some("org.freedesktop.DBus.Error.AccessDenied") => Error::PermissionDenied,
+            _ => Error::Other(Box::new(e)),
+        })
+}
+
+/// Reads what BlueZ knows about the adapter at the given path. It has no advertising manager while
+/// it is powered off, or if it can't advertise.
+async fn read_adapter_info(bus: &BluezBus, adapter: Path<'static>) -> Result<AdapterInfo> {
+    let proxy = bus.proxy(adapter.clone()).await?;
+    let properties = proxy
+        .get_all(ADAPTER_INTERFACE)
+        .await
+        .map_err(|e| Error::Other(Box::new(e)))?;
+    let advertising = proxy
+        .get_all(ADVERTISING_MANAGER_INTERFACE)
+        .await
+        .unwrap_or_default();
+    Ok(adapter_info(&adapter, &properties, &advertising))
+}
+
+/// The information about an adapter, from the properties of its `Adapter1` and
+/// `LEAdvertisingManager1` interfaces.
+fn adapter_info(adapter: &Path, properties: &PropMap, advertising: &PropMap) -> AdapterInfo {
+    let mut info = AdapterInfo::new(adapter.rsplit('/').next().unwrap_or_default());
+    info.address = prop_cast::<String>(properties, "Address").and_then(|a| a.parse().ok());
+    info.name = prop_cast::<String>(properties, "Name").cloned();
+    info.alias = prop_cast::<String>(properties, "Alias").cloned();
+    // Only reported by recent versions of BlueZ.
+    info.manufacturer = prop_cast::<u16>(properties, "Manufacturer").copied();
+    info.powered = prop_cast::<bool>(properties, "Powered").copied();
+    info.discovering = prop_cast::<bool>(properties, "Discovering").copied();
+    if properties.contains_key("Roles") {
+        info.roles = Some(
+            strings(properties, "Roles")
+                .map(|role| match role {
+                    "central" => AdapterRoles::CENTRAL,
+                    "peripheral" => AdapterRoles::PERIPHERAL,
+                    "central-peripheral" => AdapterRoles::CENTRAL_PERIPHERAL,
+                    _ => AdapterRoles::empty(),
+                })
+                .collect(),
+        );
+    }
+
+    let capabilities = &mut info.capabilities;
+    // BlueZ only lists the secondary channels if the adapter supports extended advertising, and
+    // only those on the PHYs it supports.
+    for channel in strings(advertising, "SupportedSecondaryChannels") {
+        capabilities.features |= AdapterFeatures::EXTENDED_ADVERTISING
+            | match channel {
+                "2M" => AdapterFeatures::LE_2M_PHY,
+                "Coded" => AdapterFeatures::LE_CODED_PHY,
+                _ => AdapterFeatures::empty(),
+            };
+    }
+    capabilities.features |= strings(advertising, "SupportedFeatures")
+        .map(|feature| match feature {
+            "CanSetTxPower" => AdapterFeatures::ADVERTISING_TX_POWER,
+            "HardwareOffload" => AdapterFeatures::ADVERTISING_OFFLOAD,
+            _ => AdapterFeatures::empty(),
+        })
+        .collect();
+    capabilities.max_advertisements = prop_cast::<u8>(advertising, "SupportedInstances").copied();
+    info
+}
+
+/// The strings in an array property, or none if it is missing.
+fn strings<'a>(properties: &'a PropMap, key: &str) -> impl Iterator<Item = &'a str> {
+    properties
+        .get(key)
+        .and_then(|value| value.0.as_iter())
+        .into_iter()
+        .flatten()
+        .filter_map(|value| value.as_str())
+}
+
+/// The address of a device, from the name BlueZ gives its object, e.g. `dev_AA_BB_CC_DD_EE_FF`.
+fn device_address(id: &DeviceId) -> Option<BDAddr> {
+    let path = Path::from(id.clone());
+    let name = path.rsplit('/').next()?.strip_prefix("dev_")?;
+    name.replace('_', ":").parse().ok()
+}
+
+/// Whether BlueZ failed to stop discovery because it wasn't started, or the adapter is off.
+fn is_not_discovering(e: &dbus::Error) -> bool {
+    e.name() == Some("org.bluez.Error.NotReady") || e.message() == Some("No discovery started")
+}
+
+/// The event for a device that a passive scan's monitor found or lost.
+fn monitor_event(event: MonitorEvent, devices: &DeviceStore) -> Option<CentralEvent> {
+    let (MonitorEvent::DeviceFound(path) | MonitorEvent::DeviceLost(path)) = &event;
+    let id = devices.device_at(path)?;
+    Some(match event {
+        MonitorEvent::DeviceFound(_) => CentralEvent::DeviceDiscovered(id.into()),
+        MonitorEvent::DeviceLost(_) => CentralEvent::DeviceLost(id.into()),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::Manager as _;
+    use crate::bluez::manager::Manager;
+    use crate::bluez::monitor::BLUEZ;
+    use crate::bluez::test_bus::{stand_in_objects, PrivateBus};
+    use dbus::channel::{MatchingReceiver, Sender};
+    use dbus::message::MatchRule;
+    use dbus::nonblock::SyncConnection;
+    use std::sync::Mutex;
+
+    /// Stands in for BlueZ's adapter on `/org/bluez/hci0`, recording the devices it is asked to
+    /// connect to, or answering as BlueZ does without its experimental interfaces.
+    async fn stand_in_bluez(
+        bus: &PrivateBus,
+        experimental: bool,
+    ) -> (Arc<SyncConnection>, Arc<Mutex<Vec<String>>>) {
+        let connection = bus.connect();
+        connection
+            .request_name(BLUEZ, false, true, true)
+            .await
+            .unwrap();
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let recorded = calls.clone();
+        let mut rule = MatchRule::new_method_call();
+        rule.path = Some(Path::from("/org/bluez/hci0"));
+        connection.start_receive(
+            rule,
+            Box::new(move |message, connection| {
+                let reply = if experimental {
+                    let device: PropMap = message.read1().unwrap();
+                    let address = device["Address"].0.as_str().unwrap().to_string();
+                    recorded.lock().unwrap().push(format!(
+                        "{} {} {}",
+                        message.member().unwrap(),
+                        address,
+                        device["AddressType"].0.as_str().unwrap()
+                    ));
+                    let path = format!("/org/bluez/hci0/dev_{}", address.replace(':', "_"));
+                    message.method_return().append1(Path::from(path))
+                } else {
+                    dbus::Message::error(
+                        &message,
+                        &"org.freedesktop.DBus.Error.UnknownMethod".into(),
+                        &std::ffi::CString::new("Unknown method").unwrap(),
+                    )
+                };
+                connection.send(reply).unwrap();
+                true
+            }),
+        );
+        (connection, calls)
+    }
+
+    #[tokio::test]
+    async fn connects_devices_on_stand_in_bluez() {
+        let Some(bus) = PrivateBus::start() else {
+            eprintln!("Skipping, as dbus-daemon isn't available");
+            return;
+        };
+        let (_bluez, calls) = stand_in_bluez(&bus, true).await;
+        let bluez_bus = BluezBus::on(bus.connect());
+        let address: BDAddr = "11:22:33:44:55:66".parse().unwrap();
+
+        let path = connect_device(
+            &bluez_bus,
+            Path::from("/org/bluez/hci0"),
+            address,
+            AddressType::Random,
+        )
+        .await
+        .unwrap();
+        assert_eq!(path, Path::from("/org/bluez/hci0/dev_11_22_33_44_55_66"));
+        assert_eq!(
+            calls.lock().unwrap().clone(),
+            vec!["ConnectDevice 11:22:33:44:55:66 random".to_string()]
+        );
+    }
+
+    #[tokio::test]
+    async fn needs_experimental_interfaces_to_connect_devices() {
+        let Some(bus) = PrivateBus::start() else {
+            eprintln!("Skipping, as dbus-daemon isn't available");
+            return;
+        };
+        let (_bluez, _) = stand_in_bluez(&bus, false).await;
+        let bluez_bus = BluezBus::on(bus.connect());
+
+        let result = connect_device(
+            &bluez_bus,
+            Path::from("/org/bluez/hci0"),
+            BDAddr::from([0x11, 0x22, 0x33, 0x44, 0x55, 0x66]),
+            AddressType::Public,
+        )
+        .await;
+        assert!(matches!(result, Err(Error::NotSupported(_))));
+    }
+
+    /// Stands in for BlueZ with a powered off adapter on `/org/bluez/hci0`, and no other objects.
+    async fn stand_in_adapter(bus: &PrivateBus) -> (Arc<SyncConnection>, Arc<Mutex<bool>>) {
+        let connection = bus.connect();
+        connection
+            .request_name(BLUEZ, false, true, true)
+            .await
+            .unwrap();
+        let powered = Arc::new(Mutex::new(false));
+        let state = powered.clone();
+        connection.start_receive(
+            MatchRule::new_method_call(),
+            Box::new(move |message, connection| {
+                let reply = match (message.path().as_deref(), message.member().as_deref()) {
+                    (Some("/org/bluez/hci0"), Some("Get")) => message
+                        .method_return()
+                        .append1(Variant(*state.lock().unwrap())),
+                    (Some("/org/bluez/hci0"), Some("Set")) => {
+                        let (_, _, Variant(powered)): (String, String, Variant<bool>) =
+                            message.read3().unwrap();
+                        *state.lock().unwrap() = powered;
+                        message.method_return()
+                    }
+                    _ => dbus::Message::error(
+                        &message,
+                        &"org.freedesktop.DBus.Error.UnknownObject".into(),
+                        &std::ffi::CString::new("Unknown object").unwrap(),
+                    ),
+                };
+                connection.send(reply).unwrap();
+                true
+            }),
+        );
+        (connection, powered)
+    }
+
+    #[tokio::test]
+    async fn powers_adapters_on_stand_in_bluez() {
+        let Some(bus) = PrivateBus::start() else {
+            eprintln!("Skipping, as dbus-daemon isn't available");
+            return;
+        };
+        let (_bluez, powered) = stand_in_adapter(&bus).await;
+        let bluez_bus = BluezBus::on(bus.connect());
+        let adapter = Path::from("/org/bluez/hci0");
+
+        let state = adapter_state(&bluez_bus, adapter.clone()).await.unwrap();
+        assert_eq!(state, CentralState::PoweredOff);
+        set_adapter_powered(&bluez_bus, adapter.clone(), true)
+            .await
+            .unwrap();
+        assert!(*powered.lock().unwrap());
+        let state = adapter_state(&bluez_bus, adapter).await.unwrap();
+        assert_eq!(state, CentralState::PoweredOn);
+
+        // An adapter that has been unplugged is gone from the bus.
+        let state = adapter_state(&bluez_bus, Path::from("/org/bluez/hci1"))
+            .await
+            .unwrap();
+        assert_eq!(state, CentralState::Unsupported);
+    }
+
+    #[tokio::test]
+    async fn handles_share_the_state_of_their_adapter() {
+        let test = concat!(module_path!(), "::handles_share_the_state_of_their_adapter");
+        let Some(bus) = PrivateBus::start_as_system_bus(test) else {
+            return;
+        };
+        let _bluez = stand_in_objects(&bus, |_| None).await;
+        let manager = Manager::new().await.unwrap();
+
+        let first = manager.adapters().await.unwrap().remove(0);
+        let second = manager.adapters().await.unwrap().remove(0);
+        assert!(Arc::ptr_eq(&first.devices, &second.devices));
+        assert!(Arc::ptr_eq(&first.bus, &second.bus));
+        assert!(Arc::ptr_eq(&first.passive_scanner, &second.passive_scanner));
+        let (mut devices, mut others) = (
+            first.devices.devices().await.unwrap(),
+            second.devices.devices().await.unwrap(),
+        );
+        assert_eq!((devices.len(), others.len()), (1, 1));
+        let (device, other) = (devices.remove(0).1, others.remove(0).1);
+        assert!(Arc::ptr_eq(&device.shared, &other.shared));
+
+        // A device on another adapter isn't one of this adapter's.
+        let id = |path: &str| -> DeviceId {
+            serde_json::from_value(serde_json::json!({ "object_path": path })).unwrap()
+        };
+        let result = first
+            .devices
+            .device(&id("/org/bluez/hci1/dev_11_22_33_44_55_66"))
+            .await;
+        assert!(matches!(result, Err(Error::DeviceNotFound)));
+    }
+
+    #[test]
+    fn adapter_info_from_properties() {
+        let mut properties = PropMap::new();
+        properties.insert(
+            "Address".to_string(),
+            Variant(Box::new("11:22:33:44:55:66".to_string())),
+        );
+        properties.insert(
+            "Alias".to_string(),
+            Variant(Box::new("Kitchen".to_string())),
+        );
+        properties.insert("Powered".to_string(), Variant(Box::new(true)));
+        properties.insert(
+            "Roles".to_string(),
+            Variant(Box::new(vec![
+                "central".to_string(),
+                "peripheral".to_string(),
+            ])),
+        );
+        let mut advertising = PropMap::new();
+        advertising.insert(
+            "SupportedSecondaryChannels".to_string(),
+            Variant(Box::new(vec!["1M".to_string(), "2M".to_string()])),
+        );
+        advertising.insert(
+            "SupportedFeatures".to_string(),
+            Variant(Box::new(vec!["CanSetTxPower".to_string()])),
+        );
+        advertising.insert("SupportedInstances".to_string(), Variant(Box::new(4u8)));
+
+        let info = adapter_info(&Path::from("/org/bluez/hci0"), &properties, &advertising);
+        assert_eq!(info.id, "hci0");
+        assert_eq!(
+            info.address,
+            Some(BDAddr::from([0x11, 0x22, 0x33, 0x44, 0x55, 0x66]))
+        );
+        assert_eq!(info.alias.as_deref(), Some("Kitchen"));
+        assert_eq!(info.name, None);
+        assert_eq!(info.powered, Some(true));
+        assert_eq!(info.discovering, None);
+        assert_eq!(
+            info.roles,
+            Some(AdapterRoles::CENTRAL | AdapterRoles::PERIPHERAL)
+        );
+        assert_eq!(
+            info.capabilities.features,
+            AdapterFeatures::EXTENDED_ADVERTISING
+                | AdapterFeatures::LE_2M_PHY
+                | AdapterFeatures::ADVERTISING_TX_POWER
+        );
+        assert_eq!(info.capabilities.max_advertisements, Some(4));
+
+        // Without an advertising manager, nothing is known about advertising.
+        let info = adapter_info(&Path::from("/org/bluez/hci0"), &properties, &PropMap::new());
+        assert_eq!(info.capabilities, Default::default());
+    }
+
+    #[test]
+    fn device_addresses_from_paths() {
+        let id = |path: &str| -> DeviceId {
+            serde_json::from_value(serde_json::json!({ "object_path": path })).unwrap()
+        };
+        assert_eq!(
+            device_address(&id("/org/bluez/hci0/dev_AA_BB_CC_DD_EE_FF")),
+            Some(BDAddr::from([0xAA, 0xBB, 0xCC, 0xDD, 0xEE, 0xFF]))
+        );
+        assert_eq!(device_address(&id("/org/bluez/hci0/player0")), None);
+    }
+
+    #[test]
+    fn stopping_discovery_that_was_not_started() {
+        let error = |name, message| dbus::Error::new_custom(name, message);
+        assert!(is_not_discovering(&error(
+            "org.bluez.Error.Failed",
+            "No discovery started"
+        )));
+        assert!(is_not_discovering(&error(
+            "org.bluez.Error.NotReady",
+            "Resource Not Ready"
+        )));
+        assert!(!is_not_discovering(&error(
+            "org.bluez.Error.Failed",
+            "Operation already in progress"
+        )));
     }
 }
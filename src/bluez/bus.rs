@@ -0,0 +1,150 @@
+//! The D-Bus connection for talking to BlueZ where bluez-async doesn't cover what's needed.
+
+use super::monitor::{BLUEZ, DBUS_TIMEOUT};
+use crate::{Error, Result};
+use dbus::arg::ReadAll;
+use dbus::message::MatchRule;
+use dbus::nonblock::{MsgMatch, Proxy, SyncConnection};
+use dbus::{Message, Path};
+use futures::stream::Stream;
+use log::warn;
+use std::fmt::{self, Debug, Formatter};
+use std::sync::Arc;
+use tokio::runtime::Handle;
+use tokio::sync::{Mutex, MutexGuard, OnceCell};
+use tokio::task::JoinHandle;
+
+/// A connection to the system bus for a manager and its adapters, opened when first needed.
+///
+/// BlueZ releases what a connection acquired when it goes away, and bluez-async doesn't give
+/// access to its own, so this is kept for as long as the adapters and their peripherals.
+#[derive(Default)]
+pub struct BluezBus {
+    connection: OnceCell<Connection>,
+    /// BlueZ allows one agent per connection, so pairings take turns registering theirs.
+    agent: Mutex<()>,
+}
+
+/// The connection, with the task driving it, which is stopped when the connection is dropped.
+struct Connection {
+    connection: Arc<SyncConnection>,
+    resource: Option<JoinHandle<()>>,
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        if let Some(resource) = &self.resource {
+            resource.abort();
+        }
+    }
+}
+
+impl Debug for BluezBus {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BluezBus")
+            .field("connected", &self.connection.initialized())
+            .finish()
+    }
+}
+
+impl BluezBus {
+    /// Uses an existing connection, such as one to a private bus with BlueZ stood in on it.
+    #[cfg(test)]
+    pub fn on(connection: Arc<SyncConnection>) -> Self {
+        connection.set_signal_match_mode(true);
+        Self {
+            connection: OnceCell::from(Connection {
+                connection,
+                resource: None,
+            }),
+            agent: Mutex::default(),
+        }
+    }
+
+    /// The connection, opening it if needed. This calls tokio::spawn, so it must be called from
+    /// the context of a Tokio Runtime.
+    pub async fn connection(&self) -> Result<Arc<SyncConnection>> {
+        self.connection
+            .get_or_try_init(|| async {
+                let (resource, connection) = dbus_tokio::connection::new_system_sync()
+                    .map_err(|e| Error::Other(Box::new(e)))?;
+                // Several matches may be for the same signal, and each should receive it.
+                connection.set_signal_match_mode(true);
+                let resource = tokio::spawn(async move {
+                    let e = resource.await;
+                    warn!("Lost the D-Bus connection to BlueZ: {}", e);
+                });
+                Ok(Connection {
+                    connection,
+                    resource: Some(resource),
+                })
+            })
+            .await
+            .map(|connection| connection.connection.clone())
+    }
+
+    /// Receives the signals matching the rule, for as long as the returned match is kept.
+    pub async fn signals<T: ReadAll + Send + 'static>(
+        &self,
+        rule: MatchRule<'static>,
+    ) -> Result<(
+        SignalMatch,
+        impl Stream<Item = (Message, T)> + Send + 'static,
+    )> {
+        let connection = self.connection().await?;
+        let (msg_match, signals) = connection
+            .add_match(rule)
+            .await
+            .map_err(|e| Error::Other(Box::new(e)))?
+            .stream();
+        Ok((
+            SignalMatch {
+                connection,
+                msg_match,
+            },
+            signals,
+        ))
+    }
+
+    /// Waits for the turn to register an agent on the connection, which lasts until the guard is
+    /// dropped.
+    pub async fn agent_turn(&self) -> MutexGuard<'_, ()> {
+        self.agent.lock().await
+    }
+
+    /// A proxy for the BlueZ object at the given path.
+    pub async fn proxy(
+        &self,
+        path: impl Into<Path<'static>>,
+    ) -> Result<Proxy<'static, Arc<SyncConnection>>> {
+        Ok(Proxy::new(
+            BLUEZ,
+            path.into(),
+            DBUS_TIMEOUT,
+            self.connection().await?,
+        ))
+    }
+}
+
+/// A match for signals on the bus, which is removed when dropped.
+pub struct SignalMatch {
+    connection: Arc<SyncConnection>,
+    msg_match: MsgMatch,
+}
+
+impl Drop for SignalMatch {
+    fn drop(&mut self) {
+        // Removing the match takes a round trip to the bus. Without a runtime to make it on, the
+        // match goes away along with the connection.
+        let Ok(runtime) = Handle::try_current() else {
+            return;
+        };
+        let connection = self.connection.clone();
+        let token = self.msg_match.token();
+        runtime.spawn(async move {
+            if let Err(e) = connection.remove_match(token).await {
+                warn!("Failed to remove a D-Bus match: {}", e);
+            }
+        });
+    }
+}
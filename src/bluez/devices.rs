@@ -0,0 +1,587 @@
+use super::adapter::ADAPTER_INTERFACE;
+use super::bus::{BluezBus, SignalMatch};
+use super::monitor::BLUEZ;
+use super::peripheral::Shared;
+use crate::api::{CentralEvent, CentralState, PeripheralProperties, PropertiesDiff};
+use crate::common::advertisement_history::{hash_advertised_data, AdvertisementHistory};
+use crate::{Error, Result};
+use bluez_async::{AdapterId, BluetoothError, BluetoothSession, DeviceId, DeviceInfo};
+use dbus::arg::{cast, prop_cast, PropMap, RefArg, Variant};
+use dbus::message::SignalArgs;
+use dbus::nonblock::stdintf::org_freedesktop_dbus::{
+    ObjectManagerInterfacesAdded, ObjectManagerInterfacesRemoved, PropertiesPropertiesChanged,
+};
+use dbus::Path;
+use futures::future;
+use futures::stream::{self, BoxStream, StreamExt};
+use log::warn;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, Weak};
+use tokio::sync::broadcast;
+use uuid::Uuid;
+
+const DEVICE_INTERFACE: &str = "org.bluez.Device1";
+
+/// How many events are kept for each receiver that is behind, before the oldest are reported as
+/// lost.
+const EVENT_CAPACITY: usize = 256;
+
+/// What BlueZ last reported about a device, and the state its peripherals share.
+#[derive(Clone, Debug)]
+pub struct DeviceState {
+    pub properties: PeripheralProperties,
+    pub connected: bool,
+    pub history: Arc<AdvertisementHistory>,
+    pub shared: Arc<Shared>,
+}
+
+impl DeviceState {
+    fn record(&self) {
+        let payload_hash = hash_advertised_data(
+            &self.properties.manufacturer_data,
+            &self.properties.service_data,
+            &self.properties.services,
+        );
+        self.history.record(self.properties.rssi, payload_hash);
+    }
+
+    /// Changes the properties, returning what changed.
+    fn update(&mut self, change: impl FnOnce(&mut PeripheralProperties)) -> PropertiesDiff {
+        let old = self.properties.clone();
+        change(&mut self.properties);
+        PropertiesDiff::between(&old, &self.properties)
+    }
+}
+
+impl From<DeviceInfo> for DeviceState {
+    fn from(device: DeviceInfo) -> Self {
+        Self {
+            properties: PeripheralProperties {
+                address: device.mac_address.into(),
+                address_type: Some(device.address_type.into()),
+                local_name: device.name,
+                tx_power_level: device.tx_power,
+                rssi: device.rssi,
+                manufacturer_data: device.manufacturer_data,
+                service_data: device.service_data,
+                services: device.services,
+                class: device.class,
+            },
+            connected: device.connected,
+            history: Default::default(),
+            shared: Default::default(),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Devices {
+    /// Whether the devices BlueZ already knew about have been loaded.
+    loaded: bool,
+    devices: HashMap<DeviceId, DeviceState>,
+    /// The devices by their D-Bus path, which BlueZ's signals identify them by.
+    paths: HashMap<String, DeviceId>,
+}
+
+impl Devices {
+    /// Stores a device, unless it is already known, returning its state.
+    fn insert(&mut self, id: &DeviceId, device: impl FnOnce() -> DeviceState) -> &DeviceState {
+        self.paths
+            .entry(Path::from(id.clone()).to_string())
+            .or_insert_with(|| id.clone());
+        self.devices.entry(id.clone()).or_insert_with(device)
+    }
+
+    fn find(&mut self, path: &Path) -> Option<(&DeviceId, &mut DeviceState)> {
+        let id = self.paths.get(&**path)?;
+        Some((id, self.devices.get_mut(id)?))
+    }
+
+    fn remove(&mut self, path: &Path) {
+        if let Some(id) = self.paths.remove(&**path) {
+            self.devices.remove(&id);
+        }
+    }
+
+    /// Applies the properties BlueZ signalled changes of for a known device, returning the events
+    /// to emit for it.
+    fn apply_changed(&mut self, path: &Path, changed: &PropMap) -> Vec<CentralEvent> {
+        let Some((id, device)) = self.find(path) else {
+            return vec![];
+        };
+        let peripheral_id = || id.clone().into();
+        let mut events = Vec::new();
+        if let Some(&connected) = prop_cast::<bool>(changed, "Connected") {
+            device.connected = connected;
+            events.push(if connected {
+                CentralEvent::DeviceConnected(peripheral_id())
+            } else {
+                CentralEvent::DeviceDisconnected(peripheral_id())
+            });
+        }
+        let rssi = prop_cast::<i16>(changed, "RSSI").copied();
+        let manufacturer_data =
+            prop_cast::<HashMap<u16, Variant<Box<dyn RefArg>>>>(changed, "ManufacturerData")
+                .map(manufacturer_data);
+        let service_data = prop_cast::<PropMap>(changed, "ServiceData").map(service_data);
+        let services = prop_cast::<Vec<String>>(changed, "UUIDs").map(|uuids| services(uuids));
+        let name = prop_cast::<String>(changed, "Name");
+        let tx_power = prop_cast::<i16>(changed, "TxPower");
+        let class = prop_cast::<u32>(changed, "Class");
+        let changes = device.update(|properties| {
+            if rssi.is_some() {
+                properties.rssi = rssi;
+            }
+            if let Some(manufacturer_data) = &manufacturer_data {
+                properties.manufacturer_data = manufacturer_data.clone();
+            }
+            if let Some(service_data) = &service_data {
+                properties.service_data = service_data.clone();
+            }
+            if let Some(services) = &services {
+                properties.services = services.clone();
+            }
+            if let Some(name) = name {
+                properties.local_name = Some(name.clone());
+            }
+            if let Some(tx_power) = tx_power {
+                properties.tx_power_level = Some(*tx_power);
+            }
+            if let Some(class) = class {
+                properties.class = Some(*class);
+            }
+        });
+        let advertised = rssi.is_some()
+            || manufacturer_data.is_some()
+            || service_data.is_some()
+            || services.is_some();
+        if advertised {
+            device.record();
+        }
+        if !changes.is_empty() {
+            events.push(CentralEvent::DeviceUpdated {
+                id: peripheral_id(),
+                changes,
+            });
+        }
+        if let Some(manufacturer_data) = manufacturer_data {
+            events.push(CentralEvent::ManufacturerDataAdvertisement {
+                id: peripheral_id(),
+                manufacturer_data,
+            });
+        }
+        if let Some(service_data) = service_data {
+            events.push(CentralEvent::ServiceDataAdvertisement {
+                id: peripheral_id(),
+                service_data,
+            });
+        }
+        if let Some(services) = services {
+            events.push(CentralEvent::ServicesAdvertisement {
+                id: peripheral_id(),
+                services,
+            });
+        }
+        events
+    }
+}
+
+fn manufacturer_data(data: &HashMap<u16, Variant<Box<dyn RefArg>>>) -> HashMap<u16, Vec<u8>> {
+    data.iter()
+        .filter_map(|(&id, value)| Some((id, cast::<Vec<u8>>(&value.0)?.clone())))
+        .collect()
+}
+
+fn service_data(data: &PropMap) -> HashMap<Uuid, Vec<u8>> {
+    data.iter()
+        .filter_map(|(uuid, value)| Some((uuid.parse().ok()?, cast::<Vec<u8>>(&value.0)?.clone())))
+        .collect()
+}
+
+fn services(uuids: &[String]) -> Vec<Uuid> {
+    uuids.iter().filter_map(|uuid| uuid.parse().ok()).collect()
+}
+
+/// An update to the devices of an adapter, from BlueZ's signals.
+enum Update {
+    Added {
+        path: Path<'static>,
+    },
+    Changed {
+        path: Path<'static>,
+        changed: PropMap,
+    },
+    Removed {
+        path: Path<'static>,
+    },
+    Powered(bool),
+    AdapterRemoved,
+}
+
+/// Keeps the properties and advertisement history of the devices on an adapter, from the property
+/// changes BlueZ signals for them, so that reading them doesn't take a D-Bus round trip. Emits the
+/// adapter's events as it applies the changes.
+#[derive(Debug)]
+pub struct DeviceStore {
+    session: BluetoothSession,
+    adapter: AdapterId,
+    devices: Mutex<Devices>,
+    events: broadcast::Sender<CentralEvent>,
+}
+
+impl DeviceStore {
+    /// Creates a store which isn't kept up to date, for an adapter that is gone.
+    pub fn new(session: BluetoothSession, adapter: AdapterId) -> Arc<Self> {
+        Arc::new(Self {
+            session,
+            adapter,
+            devices: Default::default(),
+            events: broadcast::channel(EVENT_CAPACITY).0,
+        })
+    }
+
+    /// Creates a store, and a task that keeps it up to date for as long as it is alive. This calls
+    /// tokio::spawn, so it must be called from the context of a Tokio Runtime.
+    pub fn spawn(session: BluetoothSession, adapter: AdapterId, bus: Arc<BluezBus>) -> Arc<Self> {
+        let store = Self::new(session.clone(), adapter.clone());
+        let weak_store = Arc::downgrade(&store);
+        tokio::spawn(async move {
+            if let Err(e) = run(session, adapter, &bus, weak_store).await {
+                warn!("Stopped tracking devices: {}", e);
+            }
+        });
+        store
+    }
+
+    /// The events for the devices of the adapter from now on.
+    pub fn events(&self) -> broadcast::Receiver<CentralEvent> {
+        self.events.subscribe()
+    }
+
+    /// All devices on the adapter. Until the store has loaded them, they are read from BlueZ.
+    pub async fn devices(&self) -> Result<Vec<(DeviceId, DeviceState)>> {
+        {
+            let devices = self.devices.lock().unwrap();
+            if devices.loaded {
+                return Ok(devices
+                    .devices
+                    .iter()
+                    .map(|(id, device)| (id.clone(), device.clone()))
+                    .collect());
+            }
+        }
+        let infos = self.session.get_devices_on_adapter(&self.adapter).await?;
+        Ok(infos
+            .into_iter()
+            .map(|device| (device.id.clone(), self.insert(device)))
+            .collect())
+    }
+
+    /// A device on the adapter, read from BlueZ if the store doesn't know it (yet). Devices on
+    /// other adapters aren't found, as their state is kept by the stores of those adapters.
+    pub async fn device(&self, id: &DeviceId) -> Result<DeviceState> {
+        if id.adapter() != self.adapter {
+            return Err(Error::DeviceNotFound);
+        }
+        if let Some(device) = self.devices.lock().unwrap().devices.get(id) {
+            return Ok(device.clone());
+        }
+        match self.session.get_device_info(id).await {
+            Ok(device) => Ok(self.insert(device)),
+            Err(BluetoothError::DbusError(_)) => Err(Error::DeviceNotFound),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// The device at the given D-Bus path, read from BlueZ if the store doesn't know it (yet).
+    /// Any other devices the store doesn't know are read along with it.
+    pub async fn device_by_path(&self, path: &Path<'_>) -> Result<Option<(DeviceId, DeviceState)>> {
+        if let Some((id, device)) = self.devices.lock().unwrap().find(path) {
+            return Ok(Some((id.clone(), device.clone())));
+        }
+        let infos = self.session.get_devices_on_adapter(&self.adapter).await?;
+        let mut found = None;
+        for device in infos {
+            let is_path = Path::from(device.id.clone()) == *path;
+            let id = device.id.clone();
+            let device = self.insert(device);
+            if is_path {
+                found = Some((id, device));
+            }
+        }
+        Ok(found)
+    }
+
+    /// The device at the given D-Bus path, if the store knows it.
+    pub fn device_at(&self, path: &Path) -> Option<DeviceId> {
+        let mut devices = self.devices.lock().unwrap();
+        devices.find(path).map(|(id, _)| id.clone())
+    }
+
+    /// Stores a device of the adapter read from BlueZ, unless a more recent state is already
+    /// stored.
+    fn insert(&self, device: DeviceInfo) -> DeviceState {
+        self.devices
+            .lock()
+            .unwrap()
+            .insert(&device.id.clone(), || device.into())
+            .clone()
+    }
+
+    fn apply(&self, update: Update) {
+        let mut devices = self.devices.lock().unwrap();
+        let events = match update {
+            Update::Added { path } => match devices.find(&path) {
+                Some((id, _)) => vec![CentralEvent::DeviceDiscovered(id.clone().into())],
+                None => vec![],
+            },
+            Update::Changed { path, changed } => devices.apply_changed(&path, &changed),
+            Update::Removed { path } => {
+                devices.remove(&path);
+                vec![]
+            }
+            Update::Powered(powered) => vec![CentralEvent::StateUpdate(if powered {
+                CentralState::PoweredOn
+            } else {
+                CentralState::PoweredOff
+            })],
+            Update::AdapterRemoved => vec![CentralEvent::StateUpdate(CentralState::Unsupported)],
+        };
+        drop(devices);
+        for event in events {
+            // There may be no receivers, which is fine.
+            let _ = self.events.send(event);
+        }
+    }
+}
+
+async fn run(
+    session: BluetoothSession,
+    adapter: AdapterId,
+    bus: &BluezBus,
+    store: Weak<DeviceStore>,
+) -> Result<()> {
+    // Start listening before loading the devices, so that no change is missed in between. The
+    // matches are removed once the signals are no longer read.
+    let (_matches, mut updates) = device_signals(bus, adapter.clone().into()).await?;
+
+    let devices = session.get_devices_on_adapter(&adapter).await?;
+    {
+        let Some(store) = store.upgrade() else {
+            return Ok(());
+        };
+        let mut stored = store.devices.lock().unwrap();
+        for device in devices {
+            stored.insert(&device.id.clone(), || device.into());
+        }
+        stored.loaded = true;
+    }
+
+    while let Some(update) = updates.next().await {
+        let Some(store) = store.upgrade() else {
+            break;
+        };
+        if let Update::Added { path } | Update::Changed { path, .. } = &update
+            && store.device_at(path).is_none()
+        {
+            // Start from what BlueZ already knows about the device, so that the first
+            // advertisement record isn't missing the data that hasn't changed.
+            if let Err(e) = store.device_by_path(path).await {
+                warn!("Failed to read the device {}: {}", path, e);
+                continue;
+            }
+        }
+        store.apply(update);
+    }
+    Ok(())
+}
+
+/// Whether the object at the path belongs to the adapter.
+fn is_on_adapter(adapter: &Path, path: &Path) -> bool {
+    path.strip_prefix(&**adapter)
+        .is_some_and(|rest| rest.starts_with('/'))
+}
+
+/// Subscribes to the signals for the devices on an adapter being added, changed or removed, and
+/// for the adapter being powered on or off or removed. The store reads all its changes from these
+/// rather than from the events of bluez-async, so that each signal is parsed once.
+async fn device_signals(
+    bus: &BluezBus,
+    adapter: Path<'static>,
+) -> Result<(Vec<SignalMatch>, BoxStream<'static, Update>)> {
+    let bluez = BLUEZ.into();
+    let mut rule =
+        PropertiesPropertiesChanged::match_rule(Some(&bluez), Some(&adapter)).static_clone();
+    rule.path_is_namespace = true;
+    let (changed_match, changed) = bus.signals::<PropertiesPropertiesChanged>(rule).await?;
+    let rule = ObjectManagerInterfacesAdded::match_rule(Some(&bluez), None).static_clone();
+    let (added_match, added) = bus.signals::<ObjectManagerInterfacesAdded>(rule).await?;
+    let rule = ObjectManagerInterfacesRemoved::match_rule(Some(&bluez), None).static_clone();
+    let (removed_match, removed) = bus.signals::<ObjectManagerInterfacesRemoved>(rule).await?;
+
+    let adapter_path = adapter.clone();
+    let changed = changed.filter_map(move |(message, signal)| {
+        let path = message.path().map(Path::into_static);
+        future::ready(path.and_then(|path| {
+            if signal.interface_name == DEVICE_INTERFACE {
+                Some(Update::Changed {
+                    path,
+                    changed: signal.changed_properties,
+                })
+            } else if signal.interface_name == ADAPTER_INTERFACE && path == adapter_path {
+                prop_cast::<bool>(&signal.changed_properties, "Powered")
+                    .map(|powered| Update::Powered(*powered))
+            } else {
+                None
+            }
+        }))
+    });
+    let adapter_path = adapter.clone();
+    let added = added.filter_map(move |(_, signal)| {
+        let is_device = signal.interfaces.contains_key(DEVICE_INTERFACE)
+            && is_on_adapter(&adapter_path, &signal.object);
+        future::ready(is_device.then_some(Update::Added {
+            path: signal.object,
+        }))
+    });
+    let removed = removed.filter_map(move |(_, signal)| {
+        let removed = |interface| signal.interfaces.iter().any(|i| i == interface);
+        let update = if signal.object == adapter && removed(ADAPTER_INTERFACE) {
+            Some(Update::AdapterRemoved)
+        } else if is_on_adapter(&adapter, &signal.object) && removed(DEVICE_INTERFACE) {
+            Some(Update::Removed {
+                path: signal.object.clone(),
+            })
+        } else {
+            None
+        };
+        future::ready(update)
+    });
+    Ok((
+        vec![changed_match, added_match, removed_match],
+        stream::select_all([changed.boxed(), added.boxed(), removed.boxed()]).boxed(),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn device_id(address: &str) -> DeviceId {
+        let path = format!("/org/bluez/hci0/dev_{}", address);
+        serde_json::from_value(serde_json::json!({ "object_path": path })).unwrap()
+    }
+
+    fn devices_with(id: &DeviceId) -> Devices {
+        let mut devices = Devices::default();
+        devices.insert(id, || DeviceState {
+            properties: PeripheralProperties {
+                rssi: Some(-70),
+                ..Default::default()
+            },
+            connected: false,
+            history: Default::default(),
+            shared: Default::default(),
+        });
+        devices
+    }
+
+    fn properties(properties: Vec<(&str, Box<dyn RefArg>)>) -> PropMap {
+        properties
+            .into_iter()
+            .map(|(name, value)| (name.to_string(), Variant(value)))
+            .collect()
+    }
+
+    fn described(events: Vec<CentralEvent>) -> Vec<String> {
+        events
+            .into_iter()
+            .map(|event| match event {
+                CentralEvent::DeviceUpdated { changes, .. } => format!("updated {:?}", changes),
+                CentralEvent::ServicesAdvertisement { services, .. } => {
+                    format!("services {}", services.len())
+                }
+                event => format!("{:?}", event),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn applies_advertised_properties() {
+        let id = device_id("11_22_33_44_55_66");
+        let path = Path::from(id.clone());
+        let mut devices = devices_with(&id);
+        let rssi = properties(vec![("RSSI", Box::new(-60i16))]);
+        assert_eq!(
+            described(devices.apply_changed(&path, &rssi)),
+            vec![format!(
+                "updated {:?}",
+                PropertiesDiff {
+                    rssi: Some(-60),
+                    ..Default::default()
+                }
+            )]
+        );
+        // An unchanged value is still an advertisement, but not an update.
+        assert!(devices.apply_changed(&path, &rssi).is_empty());
+        let services = vec![Uuid::from_u128(1)];
+        let uuids = properties(vec![("UUIDs", Box::new(vec![services[0].to_string()]))]);
+        let events = devices.apply_changed(&path, &uuids);
+        assert_eq!(described(events).last().unwrap(), "services 1");
+        assert!(devices
+            .apply_changed(&path, &uuids)
+            .iter()
+            .all(|event| !matches!(event, CentralEvent::DeviceUpdated { .. })));
+
+        let device = &devices.devices[&id];
+        assert_eq!(device.properties.services, services);
+        assert_eq!(device.history.records().len(), 4);
+        let other = Path::from(device_id("00_00_00_00_00_00"));
+        assert!(devices.apply_changed(&other, &rssi).is_empty());
+    }
+
+    #[test]
+    fn applies_changed_properties() {
+        let id = device_id("11_22_33_44_55_66");
+        let path = Path::from(id.clone());
+        let mut devices = devices_with(&id);
+        let changed = properties(vec![
+            ("Name", Box::new("Thermo".to_string())),
+            ("RSSI", Box::new(-50i16)),
+            ("Connected", Box::new(true)),
+        ]);
+        let events = devices.apply_changed(&path, &changed);
+        let [CentralEvent::DeviceConnected(_), CentralEvent::DeviceUpdated { changes, .. }] =
+            &events[..]
+        else {
+            panic!("Unexpected events {:?}", events);
+        };
+        // A single signal is a single update, however many of the properties changed.
+        assert_eq!(
+            changes,
+            &PropertiesDiff {
+                local_name: Some("Thermo".to_string()),
+                rssi: Some(-50),
+                ..Default::default()
+            }
+        );
+        let device = &devices.devices[&id];
+        assert!(device.connected);
+        assert_eq!(device.properties.local_name.as_deref(), Some("Thermo"));
+        assert_eq!(device.history.records().len(), 1);
+    }
+
+    #[test]
+    fn finds_devices_on_their_own_adapter() {
+        let adapter = Path::from("/org/bluez/hci1");
+        assert!(is_on_adapter(
+            &adapter,
+            &Path::from("/org/bluez/hci1/dev_11_22_33_44_55_66")
+        ));
+        assert!(!is_on_adapter(
+            &adapter,
+            &Path::from("/org/bluez/hci10/dev_11_22_33_44_55_66")
+        ));
+        assert!(!is_on_adapter(&adapter, &adapter));
+    }
+}
@@ -1,18 +1,73 @@
-use super::adapter::Adapter;
+use super::adapter::{Adapter, ADAPTER_INTERFACE};
+use super::bus::{BluezBus, SignalMatch};
+use super::monitor::BLUEZ;
+use crate::api::ManagerEvent;
 use crate::{api, Result};
 use async_trait::async_trait;
-use bluez_async::BluetoothSession;
+use bluez_async::{AdapterId, BluetoothSession};
+use dbus::message::SignalArgs;
+use dbus::nonblock::stdintf::org_freedesktop_dbus::{
+    ObjectManagerInterfacesAdded, ObjectManagerInterfacesRemoved,
+};
+use dbus::Path;
+use futures::future;
+use futures::stream::{self, BoxStream, Stream, StreamExt};
+use log::warn;
+use std::collections::HashMap;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
 
 /// Implementation of [api::Manager](crate::api::Manager).
 #[derive(Clone, Debug)]
 pub struct Manager {
     session: BluetoothSession,
+    bus: Arc<BluezBus>,
+    adapters: Adapters,
 }
 
 impl Manager {
     pub async fn new() -> Result<Self> {
         let (_, session) = BluetoothSession::new().await?;
-        Ok(Self { session })
+        let bus = Arc::new(BluezBus::default());
+        Ok(Self {
+            session,
+            adapters: Adapters::new(bus.clone()),
+            bus,
+        })
+    }
+}
+
+/// The adapters handed out so far, so that all handles for an adapter share its devices and scans,
+/// and all adapters share the D-Bus connection.
+#[derive(Clone, Debug)]
+struct Adapters {
+    bus: Arc<BluezBus>,
+    adapters: Arc<Mutex<HashMap<AdapterId, Adapter>>>,
+}
+
+impl Adapters {
+    fn new(bus: Arc<BluezBus>) -> Self {
+        Self {
+            bus,
+            adapters: Default::default(),
+        }
+    }
+
+    /// A handle for an adapter. This calls tokio::spawn, so it must be called from the context of
+    /// a Tokio Runtime.
+    fn get(&self, session: &BluetoothSession, id: AdapterId) -> Adapter {
+        self.adapters
+            .lock()
+            .unwrap()
+            .entry(id.clone())
+            .or_insert_with(|| Adapter::new(session.clone(), id, self.bus.clone()))
+            .clone()
+    }
+
+    /// A handle for an adapter that has been removed, which is forgotten.
+    fn remove(&self, session: &BluetoothSession, id: AdapterId) -> Adapter {
+        let adapter = self.adapters.lock().unwrap().remove(&id);
+        adapter.unwrap_or_else(|| Adapter::removed(session.clone(), id, self.bus.clone()))
     }
 }
 
@@ -22,9 +77,154 @@ impl api::Manager for Manager {
 
     async fn adapters(&self) -> Result<Vec<Adapter>> {
         let adapters = self.session.get_adapters().await?;
+        self.adapters
+            .adapters
+            .lock()
+            .unwrap()
+            .retain(|id, _| adapters.iter().any(|adapter| adapter.id == *id));
         Ok(adapters
             .into_iter()
-            .map(|adapter| Adapter::new(self.session.clone(), adapter.id))
+            .map(|adapter| self.adapters.get(&self.session, adapter.id))
             .collect())
     }
+
+    async fn events(&self) -> Result<Pin<Box<dyn Stream<Item = ManagerEvent<Adapter>> + Send>>> {
+        // Start listening before listing the adapters, so that no change is missed in between.
+        let (matches, changes) = adapter_changes(&self.bus).await?;
+        let known: HashMap<Path<'static>, AdapterId> = self
+            .session
+            .get_adapters()
+            .await?
+            .into_iter()
+            .map(|adapter| (adapter.id.clone().into(), adapter.id))
+            .collect();
+        let session = self.session.clone();
+        let cache = self.adapters.clone();
+        let events = stream::unfold(
+            (matches, changes, known),
+            move |(matches, mut changes, mut known)| {
+                let session = session.clone();
+                let cache = cache.clone();
+                async move {
+                    loop {
+                        let event = match changes.next().await? {
+                            AdapterChange::Added(path) if !known.contains_key(&path) => {
+                                let adapters = match session.get_adapters().await {
+                                    Ok(adapters) => adapters,
+                                    Err(e) => {
+                                        warn!("Failed to read the added adapter {}: {}", path, e);
+                                        continue;
+                                    }
+                                };
+                                adapters
+                                    .into_iter()
+                                    .find(|adapter| Path::from(adapter.id.clone()) == path)
+                                    .map(|adapter| {
+                                        known.insert(path, adapter.id.clone());
+                                        ManagerEvent::AdapterAdded(cache.get(&session, adapter.id))
+                                    })
+                            }
+                            AdapterChange::Added(_) => None,
+                            AdapterChange::Removed(path) => known.remove(&path).map(|adapter| {
+                                ManagerEvent::AdapterRemoved(cache.remove(&session, adapter))
+                            }),
+                        };
+                        if let Some(event) = event {
+                            return Some((event, (matches, changes, known)));
+                        }
+                    }
+                }
+            },
+        );
+        Ok(Box::pin(events))
+    }
+}
+
+/// An adapter being added to or removed from BlueZ, by its D-Bus path.
+#[derive(Debug, PartialEq)]
+enum AdapterChange {
+    Added(Path<'static>),
+    Removed(Path<'static>),
+}
+
+/// Subscribes to the signals for adapters being added to or removed from BlueZ.
+/// The matches are removed once the returned ones are dropped.
+async fn adapter_changes(
+    bus: &BluezBus,
+) -> Result<([SignalMatch; 2], BoxStream<'static, AdapterChange>)> {
+    let bluez = BLUEZ.into();
+    let rule = ObjectManagerInterfacesAdded::match_rule(Some(&bluez), None).static_clone();
+    let (added_match, added) = bus.signals::<ObjectManagerInterfacesAdded>(rule).await?;
+    let rule = ObjectManagerInterfacesRemoved::match_rule(Some(&bluez), None).static_clone();
+    let (removed_match, removed) = bus.signals::<ObjectManagerInterfacesRemoved>(rule).await?;
+
+    let added = added.filter_map(|(_, signal)| {
+        let is_adapter = signal.interfaces.contains_key(ADAPTER_INTERFACE);
+        future::ready(is_adapter.then_some(AdapterChange::Added(signal.object)))
+    });
+    let removed = removed.filter_map(|(_, signal)| {
+        let is_adapter = signal.interfaces.iter().any(|i| i == ADAPTER_INTERFACE);
+        future::ready(is_adapter.then_some(AdapterChange::Removed(signal.object)))
+    });
+    Ok((
+        [added_match, removed_match],
+        stream::select(added, removed).boxed(),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bluez::test_bus::PrivateBus;
+    use dbus::arg::PropMap;
+    use dbus::channel::Sender;
+
+    #[tokio::test]
+    async fn reads_adapter_changes_on_stand_in_bluez() {
+        let Some(bus) = PrivateBus::start() else {
+            eprintln!("Skipping, as dbus-daemon isn't available");
+            return;
+        };
+        let bluez = bus.connect();
+        bluez.request_name(BLUEZ, false, true, true).await.unwrap();
+        let bluez_bus = BluezBus::on(bus.connect());
+        let (_matches, mut changes) = adapter_changes(&bluez_bus).await.unwrap();
+
+        let added = |object: &'static str, interface: &str| {
+            let interfaces = HashMap::from([(interface.to_string(), PropMap::new())]);
+            ObjectManagerInterfacesAdded {
+                object: object.into(),
+                interfaces,
+            }
+            .to_emit_message(&"/".into())
+        };
+        let removed = |object: &'static str, interface: &str| {
+            ObjectManagerInterfacesRemoved {
+                object: object.into(),
+                interfaces: vec![interface.to_string()],
+            }
+            .to_emit_message(&"/".into())
+        };
+        // Devices coming and going are left to the adapters.
+        bluez
+            .send(added(
+                "/org/bluez/hci0/dev_11_22_33_44_55_66",
+                "org.bluez.Device1",
+            ))
+            .unwrap();
+        bluez
+            .send(added("/org/bluez/hci1", ADAPTER_INTERFACE))
+            .unwrap();
+        assert_eq!(
+            changes.next().await,
+            Some(AdapterChange::Added("/org/bluez/hci1".into()))
+        );
+        bluez
+            .send(removed("/org/bluez/hci1", ADAPTER_INTERFACE))
+            .unwrap();
+        assert_eq!(
+            changes.next().await,
+            Some(AdapterChange::Removed("/org/bluez/hci1".into()))
+        );
+    }
 }
@@ -0,0 +1,331 @@
+//! Commands to the kernel's Bluetooth management interface, for what BlueZ doesn't offer over
+//! D-Bus. The kernel keeps the state BlueZ pairs with, so BlueZ picks up what these change.
+
+use super::monitor::DBUS_TIMEOUT;
+use crate::api::oob::LeOobRecord;
+use crate::api::{AddressType, BDAddr, OobData};
+use crate::{Error, Result};
+use bluez_async::AdapterId;
+use dbus::Path;
+use std::io;
+use std::mem;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd};
+use tokio::io::unix::AsyncFd;
+
+const AF_BLUETOOTH: libc::c_int = 31;
+const BTPROTO_HCI: libc::c_int = 1;
+const HCI_DEV_NONE: u16 = 0xFFFF;
+const HCI_CHANNEL_CONTROL: u16 = 3;
+
+const MGMT_OP_ADD_REMOTE_OOB_DATA: u16 = 0x0021;
+const MGMT_OP_READ_LOCAL_OOB_EXT_DATA: u16 = 0x003B;
+const MGMT_EV_CMD_COMPLETE: u16 = 0x0001;
+const MGMT_EV_CMD_STATUS: u16 = 0x0002;
+const MGMT_STATUS_NOT_SUPPORTED: u8 = 0x0C;
+const MGMT_STATUS_PERMISSION_DENIED: u8 = 0x14;
+const MGMT_ADDR_LE_PUBLIC: u8 = 0x01;
+const MGMT_ADDR_LE_RANDOM: u8 = 0x02;
+/// The address types to read local out-of-band data for, which are both LE ones.
+const LE_ADDRESS_TYPES: u8 = (1 << MGMT_ADDR_LE_PUBLIC) | (1 << MGMT_ADDR_LE_RANDOM);
+/// Every packet starts with its opcode or event code, the controller index and the length of the
+/// parameters that follow.
+const HEADER_LENGTH: usize = 6;
+
+#[repr(C)]
+struct SockaddrHci {
+    hci_family: libc::sa_family_t,
+    hci_dev: u16,
+    hci_channel: u16,
+}
+
+/// A socket on the management interface's control channel, which needs the `CAP_NET_ADMIN`
+/// capability for commands that change anything.
+struct ManagementSocket(AsyncFd<OwnedFd>);
+
+impl ManagementSocket {
+    fn open() -> io::Result<Self> {
+        // SAFETY: socket takes no pointers, and the descriptor it returns is owned by nothing else.
+        let fd = unsafe {
+            libc::socket(
+                AF_BLUETOOTH,
+                libc::SOCK_RAW | libc::SOCK_CLOEXEC | libc::SOCK_NONBLOCK,
+                BTPROTO_HCI,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+        let address = SockaddrHci {
+            hci_family: AF_BLUETOOTH as libc::sa_family_t,
+            hci_dev: HCI_DEV_NONE,
+            hci_channel: HCI_CHANNEL_CONTROL,
+        };
+        // SAFETY: The address is valid for the length given.
+        let bound = unsafe {
+            libc::bind(
+                fd.as_raw_fd(),
+                (&address as *const SockaddrHci).cast(),
+                mem::size_of::<SockaddrHci>() as libc::socklen_t,
+            )
+        };
+        if bound < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self(AsyncFd::new(fd)?))
+    }
+
+    /// Sends a command for the controller with the given index and waits for its reply,
+    /// returning the parameters the command returns.
+    async fn command(&self, opcode: u16, index: u16, parameters: &[u8]) -> Result<Vec<u8>> {
+        let command = encode_command(opcode, index, parameters);
+        let reply = tokio::time::timeout(DBUS_TIMEOUT, async {
+            self.send(&command).await?;
+            let mut packet = vec![0; HEADER_LENGTH + usize::from(u16::MAX)];
+            loop {
+                let length = self.recv(&mut packet).await?;
+                // The socket also receives events which have nothing to do with the command.
+                if let Some(reply) = parse_reply(opcode, index, &packet[..length]) {
+                    return Ok::<_, io::Error>(reply.map(<[u8]>::to_vec));
+                }
+            }
+        })
+        .await
+        .map_err(|_| Error::TimedOut(DBUS_TIMEOUT))?
+        .map_err(|e| Error::Other(Box::new(e)))?;
+        reply.map_err(|status| status_error(opcode, status))
+    }
+
+    async fn send(&self, packet: &[u8]) -> io::Result<()> {
+        loop {
+            let mut guard = self.0.writable().await?;
+            let written = guard.try_io(|fd| {
+                // SAFETY: The packet is valid for the length given.
+                let written =
+                    unsafe { libc::write(fd.as_raw_fd(), packet.as_ptr().cast(), packet.len()) };
+                if written < 0 {
+                    Err(io::Error::last_os_error())
+                } else {
+                    Ok(())
+                }
+            });
+            if let Ok(result) = written {
+                return result;
+            }
+        }
+    }
+
+    async fn recv(&self, packet: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let mut guard = self.0.readable().await?;
+            let read = guard.try_io(|fd| {
+                // SAFETY: The buffer is valid for the length given.
+                let read =
+                    unsafe { libc::read(fd.as_raw_fd(), packet.as_mut_ptr().cast(), packet.len()) };
+                if read < 0 {
+                    Err(io::Error::last_os_error())
+                } else {
+                    Ok(read as usize)
+                }
+            });
+            if let Ok(result) = read {
+                return result;
+            }
+        }
+    }
+}
+
+/// The index the kernel knows an adapter by, which is the number in its name.
+fn adapter_index(adapter: &AdapterId) -> Result<u16> {
+    let path = Path::from(adapter.clone());
+    path.strip_prefix("/org/bluez/hci")
+        .and_then(|index| index.parse().ok())
+        .ok_or_else(|| Error::Other(format!("Unexpected adapter path {}", path).into()))
+}
+
+/// Generates the adapter's out-of-band data for pairing over LE.
+pub async fn read_local_oob_data(adapter: &AdapterId) -> Result<LeOobRecord> {
+    let index = adapter_index(adapter)?;
+    let socket = ManagementSocket::open().map_err(|e| Error::Other(Box::new(e)))?;
+    let reply = socket
+        .command(MGMT_OP_READ_LOCAL_OOB_EXT_DATA, index, &[LE_ADDRESS_TYPES])
+        .await?;
+    parse_local_oob_data(&reply)
+}
+
+/// Stores the out-of-band data received from a device, for the kernel to use when next pairing
+/// with it over LE Secure Connections.
+pub async fn add_remote_oob_data(
+    adapter: &AdapterId,
+    address: BDAddr,
+    address_type: AddressType,
+    oob_data: &OobData,
+) -> Result<()> {
+    let index = adapter_index(adapter)?;
+    let socket = ManagementSocket::open().map_err(|e| Error::Other(Box::new(e)))?;
+    let parameters = remote_oob_parameters(address, address_type, oob_data);
+    socket
+        .command(MGMT_OP_ADD_REMOTE_OOB_DATA, index, &parameters)
+        .await?;
+    Ok(())
+}
+
+fn encode_command(opcode: u16, index: u16, parameters: &[u8]) -> Vec<u8> {
+    let mut packet = Vec::with_capacity(HEADER_LENGTH + parameters.len());
+    packet.extend_from_slice(&opcode.to_le_bytes());
+    packet.extend_from_slice(&index.to_le_bytes());
+    packet.extend_from_slice(&(parameters.len() as u16).to_le_bytes());
+    packet.extend_from_slice(parameters);
+    packet
+}
+
+/// The outcome of the command with the given opcode, if the packet is its reply: the parameters
+/// it returns, or the status it failed with.
+fn parse_reply(opcode: u16, index: u16, packet: &[u8]) -> Option<std::result::Result<&[u8], u8>> {
+    let u16_at = |offset: usize| {
+        Some(u16::from_le_bytes(
+            packet.get(offset..offset + 2)?.try_into().unwrap(),
+        ))
+    };
+    let event = u16_at(0)?;
+    let parameters = packet.get(HEADER_LENGTH..HEADER_LENGTH + usize::from(u16_at(4)?))?;
+    if (event != MGMT_EV_CMD_COMPLETE && event != MGMT_EV_CMD_STATUS)
+        || u16_at(2)? != index
+        || u16_at(HEADER_LENGTH)? != opcode
+    {
+        return None;
+    }
+    let (&status, returned) = parameters.get(2..)?.split_first()?;
+    Some(if status == 0 {
+        Ok(returned)
+    } else {
+        Err(status)
+    })
+}
+
+fn status_error(opcode: u16, status: u8) -> Error {
+    match status {
+        MGMT_STATUS_PERMISSION_DENIED => Error::PermissionDenied,
+        MGMT_STATUS_NOT_SUPPORTED => {
+            Error::NotSupported(format!("Management command {:#06x}", opcode))
+        }
+        _ => Error::Other(
+            format!(
+                "Management command {:#06x} failed with status {:#04x}",
+                opcode, status
+            )
+            .into(),
+        ),
+    }
+}
+
+/// Decodes the reply to reading local out-of-band data, which is the address types read for,
+/// then the length of the data and the data as an LE OOB record.
+fn parse_local_oob_data(reply: &[u8]) -> Result<LeOobRecord> {
+    let length = reply
+        .get(1..3)
+        .map(|length| usize::from(u16::from_le_bytes(length.try_into().unwrap())))
+        .ok_or_else(|| Error::Other("Truncated local out-of-band data".into()))?;
+    let data = reply
+        .get(3..3 + length)
+        .ok_or_else(|| Error::Other("Truncated local out-of-band data".into()))?;
+    LeOobRecord::from_bytes(data).map_err(|e| Error::Other(Box::new(e)))
+}
+
+/// The parameters for adding a device's out-of-band data: its address, least significant byte
+/// first, and address type, followed by the values for legacy pairing over BR/EDR, which are left
+/// as zeros, and the values for Secure Connections.
+fn remote_oob_parameters(
+    address: BDAddr,
+    address_type: AddressType,
+    oob_data: &OobData,
+) -> Vec<u8> {
+    let mut parameters = address.into_inner().to_vec();
+    parameters.reverse();
+    parameters.push(match address_type {
+        AddressType::Public => MGMT_ADDR_LE_PUBLIC,
+        AddressType::Random => MGMT_ADDR_LE_RANDOM,
+    });
+    parameters.extend_from_slice(&[0; 32]);
+    parameters.extend_from_slice(&oob_data.confirm);
+    parameters.extend_from_slice(&oob_data.random);
+    parameters
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::oob::LeRole;
+
+    #[test]
+    fn encodes_commands() {
+        assert_eq!(
+            encode_command(MGMT_OP_READ_LOCAL_OOB_EXT_DATA, 1, &[LE_ADDRESS_TYPES]),
+            [0x3B, 0x00, 0x01, 0x00, 0x01, 0x00, 0x06]
+        );
+        let oob_data = OobData {
+            confirm: [0x11; 16],
+            random: [0x22; 16],
+        };
+        let parameters = remote_oob_parameters(
+            BDAddr::from([0x01, 0x02, 0x03, 0x04, 0x05, 0x06]),
+            AddressType::Random,
+            &oob_data,
+        );
+        assert_eq!(parameters.len(), 71);
+        assert_eq!(parameters[..7], [0x06, 0x05, 0x04, 0x03, 0x02, 0x01, 0x02]);
+        assert_eq!(parameters[7..39], [0; 32]);
+        assert_eq!(parameters[39..55], [0x11; 16]);
+        assert_eq!(parameters[55..], [0x22; 16]);
+    }
+
+    #[test]
+    fn parses_replies() {
+        let opcode = MGMT_OP_ADD_REMOTE_OOB_DATA;
+        let complete = [0x01, 0x00, 0x00, 0x00, 0x04, 0x00, 0x21, 0x00, 0x00, 0xAA];
+        assert_eq!(parse_reply(opcode, 0, &complete), Some(Ok(&[0xAA][..])));
+        let status = [0x02, 0x00, 0x00, 0x00, 0x03, 0x00, 0x21, 0x00, 0x14];
+        assert_eq!(parse_reply(opcode, 0, &status), Some(Err(0x14)));
+        // Replies to other commands or for other controllers, and other events, are passed over.
+        assert_eq!(
+            parse_reply(MGMT_OP_READ_LOCAL_OOB_EXT_DATA, 0, &complete),
+            None
+        );
+        assert_eq!(parse_reply(opcode, 1, &complete), None);
+        let index_added = [0x04, 0x00, 0x00, 0x00, 0x00, 0x00];
+        assert_eq!(parse_reply(opcode, 0, &index_added), None);
+        assert_eq!(parse_reply(opcode, 0, &complete[..8]), None);
+        assert!(matches!(
+            status_error(opcode, 0x14),
+            Error::PermissionDenied
+        ));
+    }
+
+    #[test]
+    fn parses_local_oob_data() {
+        let record = LeOobRecord {
+            oob_data: Some(OobData {
+                confirm: [0x11; 16],
+                random: [0x22; 16],
+            }),
+            ..LeOobRecord::new(
+                BDAddr::from([0x01, 0x02, 0x03, 0x04, 0x05, 0x06]),
+                AddressType::Public,
+                LeRole::CentralOnly,
+            )
+        };
+        let data = record.to_bytes();
+        let mut reply = vec![LE_ADDRESS_TYPES];
+        reply.extend_from_slice(&(data.len() as u16).to_le_bytes());
+        reply.extend_from_slice(&data);
+        assert_eq!(parse_local_oob_data(&reply).unwrap(), record);
+        assert!(parse_local_oob_data(&reply[..reply.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn adapter_indices_from_paths() {
+        let adapter: AdapterId =
+            serde_json::from_value(serde_json::json!({"object_path": "/org/bluez/hci12"})).unwrap();
+        assert_eq!(adapter_index(&adapter).unwrap(), 12);
+    }
+}
@@ -1,3 +1,11 @@
+mod acquired;
 pub mod adapter;
+mod bus;
+mod devices;
 pub mod manager;
+mod mgmt;
+mod monitor;
+mod pairing;
 pub mod peripheral;
+#[cfg(test)]
+mod test_bus;
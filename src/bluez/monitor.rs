@@ -0,0 +1,627 @@
+//! Passive scanning with BlueZ's advertisement monitor API.
+//!
+//! BlueZ only scans passively for advertisement monitors, which are D-Bus objects implementing
+//! `org.bluez.AdvertisementMonitor1` that an application exports and registers with the
+//! `org.bluez.AdvertisementMonitorManager1` of an adapter. A monitor matches advertisements by
+//! "or patterns": a device is found if any of its advertisement data structures contain one of the
+//! patterns. BlueZ updates the properties of found devices as it would while discovering, and
+//! calls `DeviceFound` and `DeviceLost` on the monitor as devices come and go.
+
+use super::bus::BluezBus;
+use crate::api::bleuuid::BleUuid;
+use crate::api::{RssiMonitor, ScanCondition, ScanFilter};
+use crate::{Error, Result};
+use dbus::arg::{PropMap, RefArg, Variant};
+use dbus::channel::{MatchingReceiver, Sender, Token};
+use dbus::message::{MatchRule, MessageType};
+use dbus::nonblock::{Proxy, SyncConnection};
+use dbus::strings::ErrorName;
+use dbus::{Message, Path};
+use log::{debug, warn};
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::fmt::{self, Debug, Formatter};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{broadcast, Mutex};
+use uuid::Uuid;
+
+pub(super) const BLUEZ: &str = "org.bluez";
+const MANAGER_INTERFACE: &str = "org.bluez.AdvertisementMonitorManager1";
+const MONITOR_INTERFACE: &str = "org.bluez.AdvertisementMonitor1";
+const OBJECT_MANAGER_INTERFACE: &str = "org.freedesktop.DBus.ObjectManager";
+const PROPERTIES_INTERFACE: &str = "org.freedesktop.DBus.Properties";
+pub(super) const DBUS_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The most data an advertisement data structure can hold in a legacy advertisement, after its
+/// length and type.
+const MAX_AD_DATA: usize = 29;
+
+const AD_INCOMPLETE_UUIDS_16: u8 = 0x02;
+const AD_COMPLETE_UUIDS_16: u8 = 0x03;
+const AD_INCOMPLETE_UUIDS_128: u8 = 0x06;
+const AD_COMPLETE_UUIDS_128: u8 = 0x07;
+const AD_SHORT_NAME: u8 = 0x08;
+const AD_COMPLETE_NAME: u8 = 0x09;
+const AD_SERVICE_DATA_16: u8 = 0x16;
+const AD_SERVICE_DATA_128: u8 = 0x21;
+const AD_MANUFACTURER_DATA: u8 = 0xff;
+
+/// Numbers the monitors exported by this process, to give each a path of its own.
+static NEXT_MONITOR: AtomicUsize = AtomicUsize::new(0);
+
+/// A pattern of an or-patterns monitor: an advertisement data structure of the given type
+/// matches if its data contains `content` at `start`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Pattern {
+    pub start: u8,
+    pub ad_type: u8,
+    pub content: Vec<u8>,
+}
+
+impl Pattern {
+    fn new(start: usize, ad_type: u8, mut content: Vec<u8>) -> Self {
+        content.truncate(MAX_AD_DATA - start);
+        Self {
+            start: start as u8,
+            ad_type,
+            content,
+        }
+    }
+}
+
+/// The patterns that a device matching the filter must match at least one of, or `None` if the
+/// filter can't be expressed with patterns. The patterns may match more devices than the filter,
+/// which the scan filter weeds out.
+pub fn patterns(filter: &ScanFilter) -> Option<Vec<Pattern>> {
+    let patterns = if filter.services.is_empty() {
+        condition_patterns(filter.condition.as_ref()?)?
+    } else {
+        filter.services.iter().flat_map(service_patterns).collect()
+    };
+    Some(patterns).filter(|patterns| !patterns.is_empty())
+}
+
+fn condition_patterns(condition: &ScanCondition) -> Option<Vec<Pattern>> {
+    match condition {
+        ScanCondition::NameEquals(name) | ScanCondition::NamePrefix(name) if !name.is_empty() => {
+            Some(
+                [AD_SHORT_NAME, AD_COMPLETE_NAME]
+                    .into_iter()
+                    .map(|ad_type| Pattern::new(0, ad_type, name.as_bytes().to_vec()))
+                    .collect(),
+            )
+        }
+        ScanCondition::Service(uuid) => Some(service_patterns(uuid)),
+        ScanCondition::ManufacturerData {
+            company_id,
+            data,
+            mask,
+        } => {
+            let mut content = company_id.to_le_bytes().to_vec();
+            content.extend(unmasked_prefix(data, mask));
+            Some(vec![Pattern::new(0, AD_MANUFACTURER_DATA, content)])
+        }
+        ScanCondition::ServiceData { uuid, data, mask } => {
+            let (ad_type, mut content) = service_data_prefix(uuid);
+            content.extend(unmasked_prefix(data, mask));
+            Some(vec![Pattern::new(0, ad_type, content)])
+        }
+        // A device matching all the conditions matches the patterns of any one of them.
+        ScanCondition::All(conditions) => conditions.iter().find_map(condition_patterns),
+        ScanCondition::Any(conditions) => conditions
+            .iter()
+            .map(condition_patterns)
+            .collect::<Option<Vec<_>>>()
+            .map(|patterns| patterns.concat()),
+        _ => None,
+    }
+}
+
+/// Patterns for a service in the UUID lists, wherever it is in them, or in service data.
+fn service_patterns(uuid: &Uuid) -> Vec<Pattern> {
+    let (list_types, bytes) = match uuid.to_ble_u16() {
+        Some(short) => (
+            [AD_INCOMPLETE_UUIDS_16, AD_COMPLETE_UUIDS_16],
+            short.to_le_bytes().to_vec(),
+        ),
+        None => (
+            [AD_INCOMPLETE_UUIDS_128, AD_COMPLETE_UUIDS_128],
+            uuid.as_u128().to_le_bytes().to_vec(),
+        ),
+    };
+    let mut patterns: Vec<Pattern> = (0..=MAX_AD_DATA - bytes.len())
+        .step_by(bytes.len())
+        .flat_map(|start| list_types.into_iter().map(move |ad_type| (start, ad_type)))
+        .map(|(start, ad_type)| Pattern::new(start, ad_type, bytes.clone()))
+        .collect();
+    let (ad_type, content) = service_data_prefix(uuid);
+    patterns.push(Pattern::new(0, ad_type, content));
+    patterns
+}
+
+/// The type of the service data structure for the service, and the UUID it starts with.
+fn service_data_prefix(uuid: &Uuid) -> (u8, Vec<u8>) {
+    match uuid.to_ble_u16() {
+        Some(short) => (AD_SERVICE_DATA_16, short.to_le_bytes().to_vec()),
+        None => (AD_SERVICE_DATA_128, uuid.as_u128().to_le_bytes().to_vec()),
+    }
+}
+
+/// The leading bytes of the data that the mask compares in full, as patterns can't be masked.
+fn unmasked_prefix<'a>(data: &'a [u8], mask: &'a [u8]) -> impl Iterator<Item = u8> + 'a {
+    data.iter()
+        .enumerate()
+        .take_while(move |(i, _)| mask.get(*i).is_none_or(|&m| m == 0xff))
+        .map(|(_, &byte)| byte)
+}
+
+/// Checks that the thresholds are ones BlueZ accepts.
+pub fn check_rssi_monitor(rssi: &RssiMonitor) -> Result<()> {
+    let thresholds = -127..=20;
+    let timeouts = Duration::from_secs(1)..=Duration::from_secs(300);
+    if !thresholds.contains(&rssi.high_threshold)
+        || !thresholds.contains(&rssi.low_threshold)
+        || rssi.low_threshold > rssi.high_threshold
+    {
+        return Err(Error::NotSupported(format!(
+            "RSSI thresholds must be between -127 and 20 dBm, the low one no higher than the \
+             high one, not {} and {}",
+            rssi.low_threshold, rssi.high_threshold
+        )));
+    }
+    if !timeouts.contains(&rssi.high_timeout) || !timeouts.contains(&rssi.low_timeout) {
+        return Err(Error::NotSupported(
+            "RSSI timeouts must be between 1 and 300 seconds".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+/// The properties of an `org.bluez.AdvertisementMonitor1`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MonitorSettings {
+    pub patterns: Vec<Pattern>,
+    pub rssi: Option<RssiMonitor>,
+}
+
+impl MonitorSettings {
+    fn properties(&self) -> PropMap {
+        let patterns: Vec<(u8, u8, Vec<u8>)> = self
+            .patterns
+            .iter()
+            .map(|pattern| (pattern.start, pattern.ad_type, pattern.content.clone()))
+            .collect();
+        let mut properties = PropMap::new();
+        properties.insert("Type".to_string(), variant("or_patterns".to_string()));
+        properties.insert("Patterns".to_string(), variant(patterns));
+        if let Some(rssi) = &self.rssi {
+            properties.insert(
+                "RSSIHighThreshold".to_string(),
+                variant(rssi.high_threshold),
+            );
+            properties.insert("RSSILowThreshold".to_string(), variant(rssi.low_threshold));
+            properties.insert(
+                "RSSIHighTimeout".to_string(),
+                variant(rssi.high_timeout.as_secs() as u16),
+            );
+            properties.insert(
+                "RSSILowTimeout".to_string(),
+                variant(rssi.low_timeout.as_secs() as u16),
+            );
+            // 0 propagates every advertisement, 0xff only the first, and anything in between
+            // groups them by that many tenths of a second. BlueZ reads it as a u16.
+            let sampling_period = rssi.sampling_period.map_or(0xff, |period| {
+                (period.as_millis().div_ceil(100)).min(0xfe) as u16
+            });
+            properties.insert("RSSISamplingPeriod".to_string(), variant(sampling_period));
+        }
+        properties
+    }
+}
+
+fn variant<T: RefArg + 'static>(value: T) -> Variant<Box<dyn RefArg>> {
+    Variant(Box::new(value))
+}
+
+/// A device that a monitor was told about, by its object path.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum MonitorEvent {
+    DeviceFound(Path<'static>),
+    DeviceLost(Path<'static>),
+}
+
+/// A monitor exported on a D-Bus connection and registered with an adapter. It stays exported
+/// until it is unregistered.
+pub struct AdvertisementMonitor {
+    connection: Arc<SyncConnection>,
+    adapter: Path<'static>,
+    root: Path<'static>,
+    token: Token,
+}
+
+impl Debug for AdvertisementMonitor {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AdvertisementMonitor")
+            .field("adapter", &self.adapter)
+            .field("root", &self.root)
+            .finish()
+    }
+}
+
+impl AdvertisementMonitor {
+    /// Exports a monitor with the given settings, registers it with the adapter at the given
+    /// path, and sends the devices BlueZ reports to it to `events`.
+    pub async fn register(
+        connection: Arc<SyncConnection>,
+        adapter: Path<'static>,
+        settings: MonitorSettings,
+        events: broadcast::Sender<MonitorEvent>,
+    ) -> Result<Self> {
+        let root = Path::from(format!(
+            "/org/btleplug/advertisement_monitor{}",
+            NEXT_MONITOR.fetch_add(1, Ordering::Relaxed)
+        ));
+        let monitor = Path::from(format!("{}/monitor0", root));
+        let mut rule = MatchRule::new();
+        rule.msg_type = Some(MessageType::MethodCall);
+        rule.path = Some(root.clone());
+        rule.path_is_namespace = true;
+        let handler_root = root.clone();
+        let token = connection.start_receive(
+            rule,
+            Box::new(move |message, connection| {
+                let reply = handle_call(&message, &handler_root, &monitor, &settings, &events);
+                if connection.send(reply).is_err() {
+                    warn!("Failed to reply to {:?}", message);
+                }
+                true
+            }),
+        );
+        let monitor = Self {
+            connection,
+            adapter,
+            root,
+            token,
+        };
+        if let Err(e) = monitor.call("RegisterMonitor").await {
+            monitor.connection.stop_receive(monitor.token);
+            return Err(e);
+        }
+        Ok(monitor)
+    }
+
+    /// Unregisters the monitor from its adapter, and stops exporting it.
+    pub async fn unregister(self) -> Result<()> {
+        let result = self.call("UnregisterMonitor").await;
+        self.connection.stop_receive(self.token);
+        result
+    }
+
+    async fn call(&self, method: &str) -> Result<()> {
+        Proxy::new(BLUEZ, &self.adapter, DBUS_TIMEOUT, self.connection.clone())
+            .method_call(MANAGER_INTERFACE, method, (self.root.clone(),))
+            .await
+            .map_err(|e| Error::Other(Box::new(e)))
+    }
+}
+
+/// Answers a method call to the monitor's objects.
+fn handle_call(
+    message: &Message,
+    root: &Path<'static>,
+    monitor: &Path<'static>,
+    settings: &MonitorSettings,
+    events: &broadcast::Sender<MonitorEvent>,
+) -> Message {
+    let (Some(path), Some(interface), Some(member)) =
+        (message.path(), message.interface(), message.member())
+    else {
+        return unknown_method(message);
+    };
+    match (&*interface, &*member) {
+        (OBJECT_MANAGER_INTERFACE, "GetManagedObjects") if path == *root => {
+            let objects = HashMap::from([(
+                monitor.clone(),
+                HashMap::from([(MONITOR_INTERFACE.to_string(), settings.properties())]),
+            )]);
+            message.method_return().append1(objects)
+        }
+        (PROPERTIES_INTERFACE, "GetAll") if path == *monitor => match message.read1::<&str>() {
+            Ok(MONITOR_INTERFACE) => message.method_return().append1(settings.properties()),
+            _ => message.method_return().append1(PropMap::new()),
+        },
+        (PROPERTIES_INTERFACE, "Get") if path == *monitor => {
+            let property = message
+                .read2::<&str, &str>()
+                .ok()
+                .filter(|(interface, _)| *interface == MONITOR_INTERFACE)
+                .and_then(|(_, name)| settings.properties().remove(name));
+            match property {
+                Some(value) => message.method_return().append1(value),
+                None => unknown_method(message),
+            }
+        }
+        (MONITOR_INTERFACE, "Release" | "Activate") if path == *monitor => {
+            debug!("Advertisement monitor {}: {}", monitor, member);
+            message.method_return()
+        }
+        (MONITOR_INTERFACE, "DeviceFound" | "DeviceLost") if path == *monitor => {
+            let Ok(device) = message.read1::<Path>() else {
+                return unknown_method(message);
+            };
+            let device = device.into_static();
+            let event = if &*member == "DeviceFound" {
+                MonitorEvent::DeviceFound(device)
+            } else {
+                MonitorEvent::DeviceLost(device)
+            };
+            // There may be no one listening, which is fine.
+            let _ = events.send(event);
+            message.method_return()
+        }
+        _ => unknown_method(message),
+    }
+}
+
+pub(super) fn unknown_method(message: &Message) -> Message {
+    message.error(
+        &ErrorName::from("org.freedesktop.DBus.Error.UnknownMethod"),
+        &CString::new("No such method").unwrap(),
+    )
+}
+
+/// Scans passively on an adapter, with at most one monitor at a time.
+pub struct PassiveScanner {
+    adapter: Path<'static>,
+    bus: Arc<BluezBus>,
+    events: broadcast::Sender<MonitorEvent>,
+    state: Mutex<PassiveScanState>,
+}
+
+#[derive(Default)]
+struct PassiveScanState {
+    monitor: Option<AdvertisementMonitor>,
+}
+
+impl Debug for PassiveScanner {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PassiveScanner")
+            .field("adapter", &self.adapter)
+            .finish()
+    }
+}
+
+impl PassiveScanner {
+    pub fn new(adapter: Path<'static>, bus: Arc<BluezBus>) -> Self {
+        Self {
+            adapter,
+            bus,
+            events: broadcast::channel(16).0,
+            state: Default::default(),
+        }
+    }
+
+    /// The devices found and lost by the monitors of this scanner from now on.
+    pub fn events(&self) -> broadcast::Receiver<MonitorEvent> {
+        self.events.subscribe()
+    }
+
+    /// Starts scanning with a monitor for the filter, replacing the current one if any.
+    pub async fn start(&self, filter: &ScanFilter, rssi: Option<RssiMonitor>) -> Result<()> {
+        let patterns = patterns(filter).ok_or_else(|| {
+            Error::NotSupported(
+                "Passive scans need a filter on services, names, manufacturer or service data"
+                    .to_string(),
+            )
+        })?;
+        if let Some(rssi) = &rssi {
+            check_rssi_monitor(rssi)?;
+        }
+        let mut state = self.state.lock().await;
+        if let Some(monitor) = state.monitor.take() {
+            monitor.unregister().await?;
+        }
+        // BlueZ talks to monitors on the connection that registered them, which bluez-async
+        // doesn't give access to, so use the adapter's own.
+        let connection = self.bus.connection().await?;
+        let settings = MonitorSettings { patterns, rssi };
+        state.monitor = Some(
+            AdvertisementMonitor::register(
+                connection,
+                self.adapter.clone(),
+                settings,
+                self.events.clone(),
+            )
+            .await?,
+        );
+        Ok(())
+    }
+
+    /// Stops scanning, returning whether there was a scan to stop.
+    pub async fn stop(&self) -> Result<bool> {
+        match self.state.lock().await.monitor.take() {
+            Some(monitor) => monitor.unregister().await.map(|()| true),
+            None => Ok(false),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::bleuuid::uuid_from_u16;
+    use crate::bluez::test_bus::PrivateBus;
+    use dbus::arg::ArgType;
+    use std::sync::Mutex as StdMutex;
+
+    #[test]
+    fn patterns_from_filter() {
+        let heart_rate = uuid_from_u16(0x180d);
+        let services = patterns(&ScanFilter {
+            services: vec![heart_rate],
+            condition: None,
+        })
+        .unwrap();
+        assert!(services.contains(&Pattern::new(0, AD_COMPLETE_UUIDS_16, vec![0x0d, 0x18])));
+        assert!(services.contains(&Pattern::new(26, AD_INCOMPLETE_UUIDS_16, vec![0x0d, 0x18])));
+        assert!(services.contains(&Pattern::new(0, AD_SERVICE_DATA_16, vec![0x0d, 0x18])));
+
+        let condition = ScanCondition::Any(vec![
+            ScanCondition::NamePrefix("Thermo".to_string()),
+            ScanCondition::All(vec![
+                ScanCondition::MinRssi(-70),
+                ScanCondition::ManufacturerData {
+                    company_id: 0x004c,
+                    data: vec![0x02, 0x15, 0xaa],
+                    mask: vec![0xff, 0xff, 0x0f],
+                },
+            ]),
+        ]);
+        assert_eq!(
+            patterns(&ScanFilter {
+                services: vec![],
+                condition: Some(condition),
+            }),
+            Some(vec![
+                Pattern::new(0, AD_SHORT_NAME, b"Thermo".to_vec()),
+                Pattern::new(0, AD_COMPLETE_NAME, b"Thermo".to_vec()),
+                Pattern::new(0, AD_MANUFACTURER_DATA, vec![0x4c, 0x00, 0x02, 0x15]),
+            ])
+        );
+    }
+
+    #[test]
+    fn filters_without_patterns() {
+        assert_eq!(patterns(&ScanFilter::default()), None);
+        for condition in [
+            ScanCondition::MinRssi(-70),
+            ScanCondition::NameMatches("^Th".to_string()),
+            ScanCondition::Any(vec![
+                ScanCondition::NamePrefix("Thermo".to_string()),
+                ScanCondition::MinRssi(-70),
+            ]),
+            ScanCondition::Any(vec![]),
+        ] {
+            let filter = ScanFilter {
+                services: vec![],
+                condition: Some(condition),
+            };
+            assert_eq!(patterns(&filter), None, "{:?}", filter);
+        }
+    }
+
+    /// Stands in for BlueZ's monitor manager on `/org/bluez/hci0`, recording the registration
+    /// calls it receives.
+    async fn stand_in_bluez(bus: &PrivateBus) -> (Arc<SyncConnection>, Arc<StdMutex<Vec<String>>>) {
+        let connection = bus.connect();
+        connection
+            .request_name(BLUEZ, false, true, true)
+            .await
+            .unwrap();
+        let calls = Arc::new(StdMutex::new(Vec::new()));
+        let recorded = calls.clone();
+        let mut rule = MatchRule::new_method_call();
+        rule.path = Some(Path::from("/org/bluez/hci0"));
+        connection.start_receive(
+            rule,
+            Box::new(move |message, connection| {
+                let member = message.member().unwrap().to_string();
+                let monitor: Path = message.read1().unwrap();
+                recorded.lock().unwrap().push(format!(
+                    "{} {} {}",
+                    member,
+                    message.sender().unwrap(),
+                    monitor
+                ));
+                connection.send(message.method_return()).unwrap();
+                true
+            }),
+        );
+        (connection, calls)
+    }
+
+    #[tokio::test]
+    async fn registers_with_stand_in_bluez() {
+        let Some(bus) = PrivateBus::start() else {
+            eprintln!("Skipping, as dbus-daemon isn't available");
+            return;
+        };
+        let (bluez, calls) = stand_in_bluez(&bus).await;
+        let connection = bus.connect();
+        let settings = MonitorSettings {
+            patterns: vec![Pattern::new(0, AD_COMPLETE_NAME, b"Thermo".to_vec())],
+            rssi: Some(RssiMonitor {
+                high_threshold: -60,
+                high_timeout: Duration::from_secs(2),
+                low_threshold: -80,
+                low_timeout: Duration::from_secs(5),
+                sampling_period: Some(Duration::from_millis(250)),
+            }),
+        };
+        let (sender, mut events) = broadcast::channel(4);
+        let monitor = AdvertisementMonitor::register(
+            connection.clone(),
+            Path::from("/org/bluez/hci0"),
+            settings.clone(),
+            sender,
+        )
+        .await
+        .unwrap();
+        let registration = calls.lock().unwrap().clone();
+        assert_eq!(
+            registration,
+            vec![format!(
+                "RegisterMonitor {} {}",
+                connection.unique_name(),
+                monitor.root
+            )]
+        );
+
+        // Read the monitor back as BlueZ would, and report a device to it.
+        let root = Proxy::new(
+            connection.unique_name().to_string(),
+            monitor.root.clone(),
+            DBUS_TIMEOUT,
+            bluez.clone(),
+        );
+        let (objects,): (HashMap<Path<'static>, HashMap<String, PropMap>>,) = root
+            .method_call(OBJECT_MANAGER_INTERFACE, "GetManagedObjects", ())
+            .await
+            .unwrap();
+        let (path, interfaces) = objects.into_iter().next().unwrap();
+        let properties = &interfaces[MONITOR_INTERFACE];
+        assert_eq!(properties["Type"].0.as_str(), Some("or_patterns"));
+        assert_eq!(properties["RSSIHighThreshold"].0.as_i64(), Some(-60));
+        let sampling_period = &properties["RSSISamplingPeriod"].0;
+        assert_eq!(sampling_period.arg_type(), ArgType::UInt16);
+        assert_eq!(sampling_period.as_u64(), Some(3));
+        let device = Path::from("/org/bluez/hci0/dev_11_22_33_44_55_66");
+        let monitor_object = Proxy::new(
+            connection.unique_name().to_string(),
+            path,
+            DBUS_TIMEOUT,
+            bluez.clone(),
+        );
+        let () = monitor_object
+            .method_call(MONITOR_INTERFACE, "DeviceFound", (device.clone(),))
+            .await
+            .unwrap();
+        assert_eq!(
+            events.recv().await.unwrap(),
+            MonitorEvent::DeviceFound(device)
+        );
+
+        let root_path = monitor.root.clone();
+        monitor.unregister().await.unwrap();
+        assert_eq!(
+            calls.lock().unwrap().last().unwrap(),
+            &format!(
+                "UnregisterMonitor {} {}",
+                connection.unique_name(),
+                root_path
+            )
+        );
+    }
+}
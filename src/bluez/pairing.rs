@@ -0,0 +1,644 @@
+//! Pairing with devices, with a [`PairingAgent`] exported as a BlueZ agent to answer the prompts.
+
+use super::adapter::ADAPTER_INTERFACE;
+use super::bus::BluezBus;
+use super::mgmt;
+use super::monitor::{unknown_method, BLUEZ};
+use super::peripheral::PeripheralId;
+use crate::api::{AddressType, BDAddr, IoCapability, PairingAgent, PairingOptions};
+use crate::{Error, Result};
+use bluez_async::DeviceId;
+use dbus::channel::{MatchingReceiver, Sender, Token};
+use dbus::message::{MatchRule, MessageType};
+use dbus::nonblock::stdintf::org_freedesktop_dbus::Properties;
+use dbus::nonblock::{Proxy, SyncConnection};
+use dbus::strings::ErrorName;
+use dbus::{Message, Path};
+use log::{debug, warn};
+use std::ffi::CString;
+use std::fmt::{self, Debug, Formatter};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+const AGENT_MANAGER_INTERFACE: &str = "org.bluez.AgentManager1";
+const AGENT_INTERFACE: &str = "org.bluez.Agent1";
+const DEVICE_INTERFACE: &str = "org.bluez.Device1";
+const BLUEZ_ROOT: &str = "/org/bluez";
+/// How long to wait for pairing by default, which leaves time for the user to answer a prompt.
+const PAIRING_TIMEOUT: Duration = Duration::from_secs(60);
+const MAX_PASSKEY: u32 = 999_999;
+
+static NEXT_AGENT: AtomicUsize = AtomicUsize::new(0);
+
+/// The agent used when pairing without one, which can't answer any prompts.
+struct NoInputNoOutput;
+
+impl PairingAgent for NoInputNoOutput {
+    fn io_capability(&self) -> IoCapability {
+        IoCapability::NoInputNoOutput
+    }
+}
+
+/// What an agent answers prompts for: pairing with one device.
+struct Prompts {
+    agent: Arc<dyn PairingAgent>,
+    device: Path<'static>,
+    peripheral: PeripheralId,
+}
+
+/// An agent exported on a D-Bus connection and registered with BlueZ. It stays registered until
+/// it is unregistered or dropped.
+struct RegisteredAgent {
+    connection: Arc<SyncConnection>,
+    path: Path<'static>,
+    token: Token,
+    registered: bool,
+}
+
+impl Debug for RegisteredAgent {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RegisteredAgent")
+            .field("path", &self.path)
+            .finish()
+    }
+}
+
+impl RegisteredAgent {
+    /// Exports an agent answering the prompts for pairing with a device, and registers it for the
+    /// connection with BlueZ.
+    async fn register(connection: Arc<SyncConnection>, prompts: Prompts) -> Result<Self> {
+        let path = Path::from(format!(
+            "/org/btleplug/agent{}",
+            NEXT_AGENT.fetch_add(1, Ordering::Relaxed)
+        ));
+        let capability = capability_name(prompts.agent.io_capability());
+        let mut rule = MatchRule::new();
+        rule.msg_type = Some(MessageType::MethodCall);
+        rule.path = Some(path.clone());
+        let prompts = Arc::new(prompts);
+        // Prompts are answered in tasks of their own, which mustn't keep the connection alive.
+        let replies = Arc::downgrade(&connection);
+        let token = connection.start_receive(
+            rule,
+            Box::new(move |message, _| {
+                let prompts = prompts.clone();
+                let replies = replies.clone();
+                let call = read_call(&message);
+                tokio::spawn(async move {
+                    let answer = answer(call, &prompts).await;
+                    let reply = reply(&message, answer);
+                    let sent = replies
+                        .upgrade()
+                        .is_some_and(|connection| connection.send(reply).is_ok());
+                    if !sent {
+                        warn!("Failed to reply to {:?}", message);
+                    }
+                });
+                true
+            }),
+        );
+        let mut agent = Self {
+            connection,
+            path,
+            token,
+            registered: false,
+        };
+        agent
+            .agent_manager()
+            .method_call::<(), _, _, _>(
+                AGENT_MANAGER_INTERFACE,
+                "RegisterAgent",
+                (agent.path.clone(), capability),
+            )
+            .await
+            .map_err(|e| Error::Other(Box::new(e)))?;
+        agent.registered = true;
+        Ok(agent)
+    }
+
+    /// Unregisters the agent from BlueZ, and stops exporting it.
+    async fn unregister(mut self) -> Result<()> {
+        self.registered = false;
+        self.agent_manager()
+            .method_call(
+                AGENT_MANAGER_INTERFACE,
+                "UnregisterAgent",
+                (self.path.clone(),),
+            )
+            .await
+            .map_err(|e| Error::Other(Box::new(e)))
+    }
+
+    fn agent_manager(&self) -> Proxy<'static, Arc<SyncConnection>> {
+        Proxy::new(BLUEZ, BLUEZ_ROOT, PAIRING_TIMEOUT, self.connection.clone())
+    }
+}
+
+impl Drop for RegisteredAgent {
+    fn drop(&mut self) {
+        self.connection.stop_receive(self.token);
+        if self.registered {
+            // Pairing was abandoned part way, so unregister in the background.
+            let manager = self.agent_manager();
+            let path = self.path.clone();
+            tokio::spawn(async move {
+                let result: std::result::Result<(), _> = manager
+                    .method_call(AGENT_MANAGER_INTERFACE, "UnregisterAgent", (path,))
+                    .await;
+                if let Err(e) = result {
+                    debug!("Failed to unregister an abandoned agent: {}", e);
+                }
+            });
+        }
+    }
+}
+
+/// Pairs with a device, with the agent in the options answering the prompts.
+pub async fn pair(
+    bus: &BluezBus,
+    device: &DeviceId,
+    address: BDAddr,
+    address_type: AddressType,
+    options: PairingOptions,
+) -> Result<()> {
+    let agent = options.agent.unwrap_or_else(|| Arc::new(NoInputNoOutput));
+    let peripheral = PeripheralId::from(device.clone());
+    if let Some(oob_data) = agent.remote_oob_data(&peripheral).await {
+        // BlueZ agents can't supply out-of-band data, but BlueZ pairs with what the kernel has.
+        mgmt::add_remote_oob_data(&device.adapter(), address, address_type, &oob_data).await?;
+    }
+    let timeout = options.timeout.unwrap_or(PAIRING_TIMEOUT);
+    let connection = bus.connection().await?;
+    let _turn = bus.agent_turn().await;
+    let prompts = Prompts {
+        agent,
+        device: device.clone().into(),
+        peripheral,
+    };
+    let agent = RegisteredAgent::register(connection.clone(), prompts).await?;
+    // BlueZ asks the agent registered by the connection that asks it to pair.
+    let device = Proxy::new(BLUEZ, Path::from(device.clone()), timeout, connection);
+    let result: std::result::Result<(), _> = device.method_call(DEVICE_INTERFACE, "Pair", ()).await;
+    if let Err(e) = agent.unregister().await {
+        debug!("Failed to unregister agent: {}", e);
+    }
+    match result {
+        Ok(()) => Ok(()),
+        Err(e) => match e.name() {
+            Some("org.bluez.Error.AlreadyExists") => Ok(()),
+            Some("org.bluez.Error.AuthenticationRejected")
+            | Some("org.bluez.Error.AuthenticationCanceled") => Err(Error::PermissionDenied),
+            Some("org.freedesktop.DBus.Error.NoReply") => {
+                // BlueZ carries on pairing unless it's told otherwise.
+                let cancelled: std::result::Result<(), _> = device
+                    .method_call(DEVICE_INTERFACE, "CancelPairing", ())
+                    .await;
+                if let Err(e) = cancelled {
+                    debug!("Failed to cancel pairing: {}", e);
+                }
+                Err(Error::TimedOut(timeout))
+            }
+            _ => Err(Error::Other(Box::new(e))),
+        },
+    }
+}
+
+/// Removes a device along with its bond, which is the only way BlueZ offers to forget the keys.
+/// BlueZ forgets the device itself too, until it is discovered again.
+pub async fn unpair(bus: &BluezBus, device: &DeviceId) -> Result<()> {
+    bus.proxy(device.adapter())
+        .await?
+        .method_call(
+            ADAPTER_INTERFACE,
+            "RemoveDevice",
+            (Path::from(device.clone()),),
+        )
+        .await
+        .map_err(|e| Error::Other(Box::new(e)))
+}
+
+/// Whether the keys from pairing with a device are stored.
+pub async fn is_bonded(bus: &BluezBus, device: &DeviceId) -> Result<bool> {
+    let device = bus.proxy(device.clone()).await?;
+    match device.get(DEVICE_INTERFACE, "Bonded").await {
+        Ok(bonded) => Ok(bonded),
+        // BlueZ only tells bonds apart from pairings since 5.66, and bonds with paired devices
+        // unless it's told not to.
+        Err(e)
+            if matches!(
+                e.name(),
+                Some(
+                    "org.freedesktop.DBus.Error.InvalidArgs"
+                        | "org.freedesktop.DBus.Error.UnknownProperty"
+                )
+            ) =>
+        {
+            device
+                .get(DEVICE_INTERFACE, "Paired")
+                .await
+                .map_err(|e| Error::Other(Box::new(e)))
+        }
+        Err(e) => Err(Error::Other(Box::new(e))),
+    }
+}
+
+fn capability_name(capability: IoCapability) -> &'static str {
+    match capability {
+        IoCapability::DisplayOnly => "DisplayOnly",
+        IoCapability::DisplayYesNo => "DisplayYesNo",
+        IoCapability::KeyboardOnly => "KeyboardOnly",
+        IoCapability::NoInputNoOutput => "NoInputNoOutput",
+        IoCapability::KeyboardDisplay => "KeyboardDisplay",
+    }
+}
+
+/// A method call to the agent, as read before it's answered.
+enum Call {
+    RequestPasskey(Path<'static>),
+    DisplayPasskey {
+        device: Path<'static>,
+        passkey: u32,
+        entered: u16,
+    },
+    RequestConfirmation {
+        device: Path<'static>,
+        passkey: u32,
+    },
+    RequestAuthorization(Path<'static>),
+    /// A call that needs nothing from the agent.
+    Acknowledge,
+    Reject,
+    Unknown,
+}
+
+/// The agent's answer to a call.
+enum Answer {
+    Accept,
+    Passkey(u32),
+    Reject,
+    Unknown,
+}
+
+fn read_call(message: &Message) -> Call {
+    let (Some(interface), Some(member)) = (message.interface(), message.member()) else {
+        return Call::Unknown;
+    };
+    if &*interface != AGENT_INTERFACE {
+        return Call::Unknown;
+    }
+    let call =
+        match &*member {
+            "Release" | "Cancel" => Some(Call::Acknowledge),
+            "RequestPasskey" => message
+                .read1::<Path>()
+                .ok()
+                .map(|device| Call::RequestPasskey(device.into_static())),
+            "DisplayPasskey" => {
+                message
+                    .read3::<Path, u32, u16>()
+                    .ok()
+                    .map(|(device, passkey, entered)| Call::DisplayPasskey {
+                        device: device.into_static(),
+                        passkey,
+                        entered,
+                    })
+            }
+            "RequestConfirmation" => message.read2::<Path, u32>().ok().map(|(device, passkey)| {
+                Call::RequestConfirmation {
+                    device: device.into_static(),
+                    passkey,
+                }
+            }),
+            "RequestAuthorization" => message
+                .read1::<Path>()
+                .ok()
+                .map(|device| Call::RequestAuthorization(device.into_static())),
+            // PIN codes are only for BR/EDR devices, and services are only authorized for incoming
+            // connections.
+            "RequestPinCode" | "DisplayPinCode" | "AuthorizeService" => Some(Call::Reject),
+            _ => None,
+        };
+    call.unwrap_or(Call::Unknown)
+}
+
+/// Asks the agent for the answer to a call, rejecting the prompts for other devices than the one
+/// being paired with.
+async fn answer(call: Call, prompts: &Prompts) -> Answer {
+    let agent = &prompts.agent;
+    let peripheral = &prompts.peripheral;
+    match call {
+        Call::RequestPasskey(device) if device == prompts.device => {
+            match agent.request_passkey(peripheral).await {
+                Some(passkey) if passkey <= MAX_PASSKEY => Answer::Passkey(passkey),
+                _ => Answer::Reject,
+            }
+        }
+        Call::DisplayPasskey {
+            device,
+            passkey,
+            entered,
+        } => {
+            // BlueZ repeats this as keys are entered on the device, with the count entered so far.
+            if device == prompts.device && entered == 0 {
+                agent.display_passkey(peripheral, passkey).await;
+            }
+            Answer::Accept
+        }
+        Call::RequestConfirmation { device, passkey } if device == prompts.device => {
+            if agent.confirm_passkey(peripheral, passkey).await {
+                Answer::Accept
+            } else {
+                Answer::Reject
+            }
+        }
+        // Just Works pairing, which is only asked about when the device initiates it.
+        Call::RequestAuthorization(device) if device == prompts.device => Answer::Accept,
+        Call::Acknowledge => Answer::Accept,
+        Call::Unknown => Answer::Unknown,
+        _ => Answer::Reject,
+    }
+}
+
+fn reply(message: &Message, answer: Answer) -> Message {
+    match answer {
+        Answer::Accept => message.method_return(),
+        Answer::Passkey(passkey) => message.method_return().append1(passkey),
+        Answer::Reject => rejected(message),
+        Answer::Unknown => unknown_method(message),
+    }
+}
+
+fn rejected(message: &Message) -> Message {
+    message.error(
+        &ErrorName::from("org.bluez.Error.Rejected"),
+        &CString::new("Rejected").unwrap(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bluez::test_bus::PrivateBus;
+    use async_trait::async_trait;
+    use std::sync::Mutex;
+
+    const DEVICE: &str = "/org/bluez/hci0/dev_11_22_33_44_55_66";
+
+    /// The prompt the stand-in puts to the agent when asked to pair.
+    #[derive(Clone, Copy)]
+    enum Prompt {
+        Confirmation(u32),
+        Passkey,
+    }
+
+    /// Stands in for BlueZ's agent manager and a device, prompting the registered agent when
+    /// asked to pair and recording the calls it makes and receives.
+    async fn stand_in_bluez(
+        bus: &PrivateBus,
+        prompt: Prompt,
+    ) -> (Arc<SyncConnection>, Arc<Mutex<Vec<String>>>) {
+        let connection = bus.connect();
+        connection
+            .request_name(BLUEZ, false, true, true)
+            .await
+            .unwrap();
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let agent = Arc::new(Mutex::new(None));
+        let recorded = calls.clone();
+        let prompter = connection.clone();
+        let mut rule = MatchRule::new_method_call();
+        rule.path = Some(Path::from(BLUEZ_ROOT));
+        rule.path_is_namespace = true;
+        connection.start_receive(
+            rule,
+            Box::new(move |message, connection| {
+                let member = message.member().unwrap().to_string();
+                match &*member {
+                    "RegisterAgent" => {
+                        let (path, capability): (Path, &str) = message.read2().unwrap();
+                        recorded
+                            .lock()
+                            .unwrap()
+                            .push(format!("{} {}", member, capability));
+                        *agent.lock().unwrap() =
+                            Some((message.sender().unwrap().into_static(), path.into_static()));
+                    }
+                    "UnregisterAgent" => recorded.lock().unwrap().push(member),
+                    "Pair" => {
+                        let (sender, path) = agent.lock().unwrap().clone().unwrap();
+                        let agent = Proxy::new(sender, path, PAIRING_TIMEOUT, prompter.clone());
+                        let recorded = recorded.clone();
+                        let prompter = prompter.clone();
+                        tokio::spawn(async move {
+                            let device = Path::from(DEVICE);
+                            let answer = match prompt {
+                                Prompt::Confirmation(passkey) => agent
+                                    .method_call(
+                                        AGENT_INTERFACE,
+                                        "RequestConfirmation",
+                                        (device, passkey),
+                                    )
+                                    .await
+                                    .map(|()| format!("RequestConfirmation {}", passkey)),
+                                Prompt::Passkey => agent
+                                    .method_call(AGENT_INTERFACE, "RequestPasskey", (device,))
+                                    .await
+                                    .map(|(passkey,): (u32,)| {
+                                        format!("RequestPasskey {}", passkey)
+                                    }),
+                            };
+                            let reply = match answer {
+                                Ok(answer) => {
+                                    recorded.lock().unwrap().push(answer);
+                                    message.method_return()
+                                }
+                                Err(e) => {
+                                    recorded.lock().unwrap().push(e.name().unwrap().to_string());
+                                    message.error(
+                                        &ErrorName::from("org.bluez.Error.AuthenticationRejected"),
+                                        &CString::new("Rejected").unwrap(),
+                                    )
+                                }
+                            };
+                            prompter.send(reply).unwrap();
+                        });
+                        return true;
+                    }
+                    _ => {}
+                }
+                connection.send(message.method_return()).unwrap();
+                true
+            }),
+        );
+        (connection, calls)
+    }
+
+    struct TestAgent {
+        passkey: Option<u32>,
+    }
+
+    #[async_trait]
+    impl PairingAgent for TestAgent {
+        fn io_capability(&self) -> IoCapability {
+            IoCapability::KeyboardDisplay
+        }
+
+        async fn request_passkey(&self, _peripheral: &PeripheralId) -> Option<u32> {
+            self.passkey
+        }
+
+        async fn confirm_passkey(&self, _peripheral: &PeripheralId, passkey: u32) -> bool {
+            passkey == 123456
+        }
+    }
+
+    fn device_id() -> DeviceId {
+        serde_json::from_value(serde_json::json!({ "object_path": DEVICE })).unwrap()
+    }
+
+    fn options(passkey: Option<u32>) -> PairingOptions {
+        PairingOptions {
+            agent: Some(Arc::new(TestAgent { passkey })),
+            timeout: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn pairs_with_numeric_comparison() {
+        let Some(bus) = PrivateBus::start() else {
+            eprintln!("Skipping, as dbus-daemon isn't available");
+            return;
+        };
+        let (_bluez, calls) = stand_in_bluez(&bus, Prompt::Confirmation(123456)).await;
+        let bluez_bus = BluezBus::on(bus.connect());
+
+        pair(
+            &bluez_bus,
+            &device_id(),
+            BDAddr::default(),
+            AddressType::Public,
+            options(None),
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            calls.lock().unwrap().clone(),
+            vec![
+                "RegisterAgent KeyboardDisplay",
+                "RequestConfirmation 123456",
+                "UnregisterAgent"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn pairs_with_passkey_entry() {
+        let Some(bus) = PrivateBus::start() else {
+            eprintln!("Skipping, as dbus-daemon isn't available");
+            return;
+        };
+        let (_bluez, calls) = stand_in_bluez(&bus, Prompt::Passkey).await;
+        let bluez_bus = BluezBus::on(bus.connect());
+
+        pair(
+            &bluez_bus,
+            &device_id(),
+            BDAddr::default(),
+            AddressType::Public,
+            options(Some(654321)),
+        )
+        .await
+        .unwrap();
+        assert_eq!(calls.lock().unwrap()[1], "RequestPasskey 654321");
+    }
+
+    #[tokio::test]
+    async fn fails_when_the_agent_rejects_pairing() {
+        let Some(bus) = PrivateBus::start() else {
+            eprintln!("Skipping, as dbus-daemon isn't available");
+            return;
+        };
+        let (_bluez, calls) = stand_in_bluez(&bus, Prompt::Confirmation(111111)).await;
+        let bluez_bus = BluezBus::on(bus.connect());
+
+        let result = pair(
+            &bluez_bus,
+            &device_id(),
+            BDAddr::default(),
+            AddressType::Public,
+            options(None),
+        )
+        .await;
+        assert!(matches!(result, Err(Error::PermissionDenied)));
+        assert_eq!(
+            calls.lock().unwrap().clone(),
+            vec![
+                "RegisterAgent KeyboardDisplay",
+                "org.bluez.Error.Rejected",
+                "UnregisterAgent"
+            ]
+        );
+
+        // Without an agent of its own, pairing can't answer any prompts.
+        let result = pair(
+            &bluez_bus,
+            &device_id(),
+            BDAddr::default(),
+            AddressType::Public,
+            PairingOptions::default(),
+        )
+        .await;
+        assert!(matches!(result, Err(Error::PermissionDenied)));
+        assert_eq!(calls.lock().unwrap()[3], "RegisterAgent NoInputNoOutput");
+    }
+
+    /// Stands in for a device on a BlueZ without the `Bonded` property, answering reads of it
+    /// with the given error.
+    async fn stand_in_device(bus: &PrivateBus, error: &'static str) -> Arc<SyncConnection> {
+        let connection = bus.connect();
+        connection
+            .request_name(BLUEZ, false, true, true)
+            .await
+            .unwrap();
+        let mut rule = MatchRule::new_method_call();
+        rule.path = Some(Path::from(DEVICE));
+        connection.start_receive(
+            rule,
+            Box::new(move |message, connection| {
+                let (_, property): (&str, &str) = message.read2().unwrap();
+                let reply = match property {
+                    "Paired" => message.method_return().append1(dbus::arg::Variant(true)),
+                    _ => message.error(
+                        &ErrorName::from(error),
+                        &CString::new("No such property").unwrap(),
+                    ),
+                };
+                connection.send(reply).unwrap();
+                true
+            }),
+        );
+        connection
+    }
+
+    #[tokio::test]
+    async fn falls_back_to_pairing_only_without_bonds() {
+        let Some(bus) = PrivateBus::start() else {
+            eprintln!("Skipping, as dbus-daemon isn't available");
+            return;
+        };
+        let _bluez = stand_in_device(&bus, "org.freedesktop.DBus.Error.InvalidArgs").await;
+        let bluez_bus = BluezBus::on(bus.connect());
+        assert!(is_bonded(&bluez_bus, &device_id()).await.unwrap());
+
+        let Some(bus) = PrivateBus::start() else {
+            return;
+        };
+        let _bluez = stand_in_device(&bus, "org.freedesktop.DBus.Error.AccessDenied").await;
+        let bluez_bus = BluezBus::on(bus.connect());
+        assert!(matches!(
+            is_bonded(&bluez_bus, &device_id()).await,
+            Err(Error::Other(_))
+        ));
+    }
+}
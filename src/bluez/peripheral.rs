@@ -1,25 +1,39 @@
 use async_trait::async_trait;
 use bluez_async::{
-    BluetoothEvent, BluetoothSession, CharacteristicEvent, CharacteristicFlags, CharacteristicId,
-    CharacteristicInfo, DescriptorInfo, DeviceId, DeviceInfo, MacAddress, ServiceInfo,
+    BluetoothError, BluetoothEvent, BluetoothSession, CharacteristicEvent, CharacteristicFlags,
+    CharacteristicId, CharacteristicInfo, DescriptorInfo, DeviceId, MacAddress, ServiceInfo,
     WriteOptions,
 };
-use futures::future::{join_all, ready};
-use futures::stream::{Stream, StreamExt};
+use futures::future::join_all;
+use futures::stream::{self, Stream, StreamExt};
+use log::debug;
 #[cfg(feature = "serde")]
 use serde::{Deserialize, Serialize};
 #[cfg(feature = "serde")]
 use serde_cr as serde;
-use std::collections::{BTreeSet, HashMap};
+use std::collections::{BTreeSet, HashMap, VecDeque};
 use std::fmt::{self, Display, Formatter};
 use std::pin::Pin;
-use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::task::{Context, Poll};
+use std::time::Instant;
+use tokio::sync::broadcast;
+use tokio::task::JoinHandle;
 use uuid::Uuid;
 
+use super::acquired::{self, Acquire, AcquiredSocket};
+use super::bus::BluezBus;
+use super::devices::{DeviceState, DeviceStore};
+use super::pairing;
 use crate::api::{
-    self, AddressType, BDAddr, CharPropFlags, Characteristic, Descriptor, PeripheralProperties,
-    Service, ValueNotification, WriteType,
+    self, AddressType, AdvertisementRecord, BDAddr, CharPropFlags, Characteristic, Descriptor,
+    PairingOptions, PeripheralProperties, Service, Subscription, SubscriptionKind,
+    ValueNotification, WriteType,
 };
+use crate::common::advertisement_history::AdvertisementHistory;
+use crate::common::subscription::SubscriptionTracker;
+use crate::common::util::notifications_stream_from_broadcast_receiver;
 use crate::{Error, Result};
 
 #[derive(Clone, Debug)]
@@ -54,13 +68,149 @@ impl Display for PeripheralId {
     }
 }
 
+/// The state of a device that all of its [`Peripheral`]s share, so that services discovered
+/// through one of them are known to the others.
+#[derive(Debug)]
+pub(crate) struct Shared {
+    services: Mutex<HashMap<Uuid, ServiceInternal>>,
+    subscriptions: SubscriptionTracker,
+    notifications_channel: broadcast::Sender<ValueNotification>,
+    /// The task reading the device's values from D-Bus, while anything is subscribed to or
+    /// anyone is listening.
+    dispatcher: Mutex<Option<JoinHandle<()>>>,
+    /// The values received since subscribing, while no one has listened yet, for the first
+    /// notification stream. `None` once there has been a listener.
+    pending: Mutex<Option<VecDeque<ValueNotification>>>,
+    /// The next sequence number for the device's values, however they are received.
+    sequence: AtomicU64,
+    /// How the characteristics written to without response so far are written to.
+    writers: Mutex<HashMap<CharacteristicId, Writer>>,
+    /// The characteristics whose notifications are read from acquired sockets.
+    notifiers: Mutex<HashMap<CharacteristicId, Notifier>>,
+}
+
+/// How a characteristic is written to without response.
+#[derive(Debug)]
+enum Writer {
+    /// Over an acquired socket.
+    Acquired(Arc<AcquiredSocket>),
+    /// Over D-Bus, as the socket with the given MTU was released for a write over D-Bus. It is
+    /// acquired again for the next value that fits.
+    Released { mtu: u16 },
+    /// Over D-Bus, as BlueZ refused to hand out a socket, until the services are discovered again.
+    Refused,
+}
+
+/// The task reading notifications from an acquired socket, which closes the socket when dropped.
+#[derive(Debug)]
+struct Notifier {
+    mtu: u16,
+    reader: JoinHandle<()>,
+}
+
+impl Drop for Notifier {
+    fn drop(&mut self) {
+        self.reader.abort();
+    }
+}
+
+/// How many values are kept for the notification channel and for the first stream.
+const NOTIFICATIONS_CAPACITY: usize = 16;
+
+impl Default for Shared {
+    fn default() -> Self {
+        let (notifications_channel, _) = broadcast::channel(NOTIFICATIONS_CAPACITY);
+        Self {
+            services: Default::default(),
+            subscriptions: Default::default(),
+            notifications_channel,
+            dispatcher: Default::default(),
+            pending: Default::default(),
+            sequence: Default::default(),
+            writers: Default::default(),
+            notifiers: Default::default(),
+        }
+    }
+}
+
+impl Shared {
+    /// Sends a value to the notification streams, or keeps it for the first one if no one has
+    /// listened since subscribing.
+    fn deliver(&self, notification: ValueNotification) {
+        let mut pending = self.pending.lock().unwrap();
+        match pending.as_mut() {
+            Some(pending) if self.notifications_channel.receiver_count() == 0 => {
+                if pending.len() == NOTIFICATIONS_CAPACITY {
+                    pending.pop_front();
+                }
+                pending.push_back(notification);
+            }
+            _ => {
+                // There may be no receivers, which is fine.
+                let _ = self.notifications_channel.send(notification);
+            }
+        }
+    }
+
+    /// Stops reading the device's values from D-Bus once nothing is subscribed to and no one is
+    /// listening. Dropping the task's event stream removes its match from the bus.
+    fn stop_dispatcher_if_unused(&self) {
+        let mut dispatcher = self.dispatcher.lock().unwrap();
+        if self.notifications_channel.receiver_count() == 0 && !self.subscriptions.any_enabled() {
+            if let Some(dispatcher) = dispatcher.take() {
+                dispatcher.abort();
+            }
+            *self.pending.lock().unwrap() = None;
+        }
+    }
+}
+
+impl Drop for Shared {
+    fn drop(&mut self) {
+        if let Some(dispatcher) = self.dispatcher.get_mut().unwrap().take() {
+            dispatcher.abort();
+        }
+    }
+}
+
+/// A stream of a device's values, which stops the device's dispatcher when dropped if nothing
+/// else needs it.
+struct Notifications {
+    values: Option<Pin<Box<dyn Stream<Item = ValueNotification> + Send>>>,
+    shared: Weak<Shared>,
+}
+
+impl Stream for Notifications {
+    type Item = ValueNotification;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        match self.values.as_mut() {
+            Some(values) => values.as_mut().poll_next(cx),
+            None => Poll::Ready(None),
+        }
+    }
+}
+
+impl Drop for Notifications {
+    fn drop(&mut self) {
+        // The receiver must be gone before checking whether anyone is still listening.
+        self.values = None;
+        if let Some(shared) = self.shared.upgrade() {
+            shared.stop_dispatcher_if_unused();
+        }
+    }
+}
+
 /// Implementation of [api::Peripheral](crate::api::Peripheral).
 #[derive(Clone, Debug)]
 pub struct Peripheral {
     session: BluetoothSession,
     device: DeviceId,
     mac_address: BDAddr,
-    services: Arc<Mutex<HashMap<Uuid, ServiceInternal>>>,
+    shared: Arc<Shared>,
+    advertisements: Arc<AdvertisementHistory>,
+    devices: Arc<DeviceStore>,
+    bus: Arc<BluezBus>,
 }
 
 fn get_characteristic<'a>(
@@ -87,17 +237,39 @@ fn get_characteristic<'a>(
 }
 
 impl Peripheral {
-    pub(crate) fn new(session: BluetoothSession, device: DeviceInfo) -> Self {
+    pub(crate) fn new(
+        session: BluetoothSession,
+        id: DeviceId,
+        device: DeviceState,
+        devices: Arc<DeviceStore>,
+        bus: Arc<BluezBus>,
+    ) -> Self {
         Peripheral {
             session,
-            device: device.id,
-            mac_address: device.mac_address.into(),
-            services: Arc::new(Mutex::new(HashMap::new())),
+            device: id,
+            mac_address: device.properties.address,
+            shared: device.shared,
+            advertisements: device.history,
+            devices,
+            bus,
+        }
+    }
+
+    /// The MTU of the socket acquired for writing to or receiving notifications from the
+    /// characteristic, if there is one. Sockets are acquired for writes without response and for
+    /// subscriptions, where BlueZ supports them for the characteristic.
+    pub fn acquired_mtu(&self, characteristic: &Characteristic) -> Option<u16> {
+        let id = self.characteristic_info(characteristic).ok()?.id;
+        if let Some(Writer::Acquired(writer)) = self.shared.writers.lock().unwrap().get(&id) {
+            return Some(writer.mtu());
         }
+        let notifiers = self.shared.notifiers.lock().unwrap();
+        let notifier = notifiers.get(&id)?;
+        (!notifier.reader.is_finished()).then_some(notifier.mtu)
     }
 
     fn characteristic_info(&self, characteristic: &Characteristic) -> Result<CharacteristicInfo> {
-        let services = self.services.lock().unwrap();
+        let services = self.shared.services.lock().unwrap();
         get_characteristic(
             &services,
             &characteristic.service_uuid,
@@ -108,7 +280,7 @@ impl Peripheral {
     }
 
     fn descriptor_info(&self, descriptor: &Descriptor) -> Result<DescriptorInfo> {
-        let services = self.services.lock().unwrap();
+        let services = self.shared.services.lock().unwrap();
         let characteristic = get_characteristic(
             &services,
             &descriptor.service_uuid,
@@ -123,8 +295,142 @@ impl Peripheral {
             .cloned()
     }
 
-    async fn device_info(&self) -> Result<DeviceInfo> {
-        Ok(self.session.get_device_info(&self.device).await?)
+    /// Starts reading the device's values into the notification channel, unless that's already
+    /// happening. This calls tokio::spawn, so it must be called from the context of a Tokio
+    /// Runtime.
+    async fn start_dispatcher(&self) -> Result<()> {
+        let running = |dispatcher: &Option<JoinHandle<()>>| {
+            dispatcher
+                .as_ref()
+                .is_some_and(|dispatcher| !dispatcher.is_finished())
+        };
+        if running(&self.shared.dispatcher.lock().unwrap()) {
+            return Ok(());
+        }
+        let events = self.session.device_event_stream(&self.device).await?;
+        let mut dispatcher = self.shared.dispatcher.lock().unwrap();
+        // If another dispatcher started meanwhile, the events are dropped along with their match.
+        if !running(&dispatcher) {
+            *dispatcher = Some(tokio::spawn(dispatch_notifications(
+                events,
+                self.device.clone(),
+                Arc::downgrade(&self.shared),
+            )));
+        }
+        Ok(())
+    }
+
+    async fn device_state(&self) -> Result<DeviceState> {
+        self.devices.device(&self.device).await
+    }
+
+    /// The socket for writing a value of the given length to the characteristic without
+    /// response. One is acquired unless BlueZ has refused before, or the value is known not to fit.
+    async fn writer(
+        &self,
+        characteristic: &CharacteristicInfo,
+        len: usize,
+    ) -> Option<Arc<AcquiredSocket>> {
+        let mtu = match self.shared.writers.lock().unwrap().get(&characteristic.id) {
+            Some(Writer::Acquired(writer)) => return Some(writer.clone()),
+            Some(Writer::Refused) => return None,
+            Some(Writer::Released { mtu }) => Some(*mtu),
+            None => characteristic.mtu,
+        };
+        if mtu.is_some_and(|mtu| len > usize::from(mtu)) {
+            return None;
+        }
+        let path = characteristic.id.clone().into();
+        let writer = match acquired::acquire(&self.bus, path, Acquire::Write).await {
+            Ok(writer) => Writer::Acquired(Arc::new(writer)),
+            Err(e) => {
+                debug!("Writing to {} over D-Bus: {}", characteristic.id, e);
+                Writer::Refused
+            }
+        };
+        let mut writers = self.shared.writers.lock().unwrap();
+        // If another write acquired a socket meanwhile, this one is closed.
+        if let Some(Writer::Acquired(current)) = writers.get(&characteristic.id) {
+            return Some(current.clone());
+        }
+        let socket = match &writer {
+            Writer::Acquired(socket) => Some(socket.clone()),
+            _ => None,
+        };
+        writers.insert(characteristic.id.clone(), writer);
+        socket
+    }
+
+    /// Writes a value over an acquired socket, returning whether it was written. The socket is
+    /// given up if it fails, as it does once the device disconnects.
+    async fn write_acquired(&self, characteristic: &CharacteristicInfo, data: &[u8]) -> bool {
+        let Some(writer) = self.writer(characteristic, data.len()).await else {
+            return false;
+        };
+        if data.len() > usize::from(writer.mtu()) {
+            return false;
+        }
+        match writer.send(data).await {
+            Ok(()) => true,
+            Err(e) => {
+                debug!(
+                    "Lost the socket for writing to {}: {}",
+                    characteristic.id, e
+                );
+                let mut writers = self.shared.writers.lock().unwrap();
+                if let Some(Writer::Acquired(current)) = writers.get(&characteristic.id)
+                    && Arc::ptr_eq(current, &writer)
+                {
+                    writers.remove(&characteristic.id);
+                }
+                false
+            }
+        }
+    }
+
+    /// Closes the socket acquired for writing to the characteristic, returning whether there was
+    /// one. BlueZ refuses writes over D-Bus while it is open. The next write without response
+    /// that fits acquires it again.
+    fn release_writer(&self, characteristic: &CharacteristicInfo) -> bool {
+        let mut writers = self.shared.writers.lock().unwrap();
+        let Some(Writer::Acquired(writer)) = writers.get(&characteristic.id) else {
+            return false;
+        };
+        let mtu = writer.mtu();
+        writers.insert(characteristic.id.clone(), Writer::Released { mtu });
+        true
+    }
+
+    /// Reads the characteristic's notifications from an acquired socket, returning whether BlueZ
+    /// handed one out.
+    async fn acquire_notify(&self, characteristic: &Characteristic, id: &CharacteristicId) -> bool {
+        if let Some(notifier) = self.shared.notifiers.lock().unwrap().get(id)
+            && !notifier.reader.is_finished()
+        {
+            return true;
+        }
+        let socket = match acquired::acquire(&self.bus, id.clone().into(), Acquire::Notify).await {
+            Ok(socket) => socket,
+            Err(e) => {
+                debug!("Subscribing to {} over D-Bus: {}", id, e);
+                return false;
+            }
+        };
+        let notifier = Notifier {
+            mtu: socket.mtu(),
+            reader: tokio::spawn(read_notifications(
+                socket,
+                characteristic.uuid,
+                characteristic.service_uuid,
+                Arc::downgrade(&self.shared),
+            )),
+        };
+        self.shared
+            .notifiers
+            .lock()
+            .unwrap()
+            .insert(id.clone(), notifier);
+        true
     }
 }
 
@@ -139,22 +445,20 @@ impl api::Peripheral for Peripheral {
     }
 
     async fn properties(&self) -> Result<Option<PeripheralProperties>> {
-        let device_info = self.device_info().await?;
-        Ok(Some(PeripheralProperties {
-            address: device_info.mac_address.into(),
-            address_type: Some(device_info.address_type.into()),
-            local_name: device_info.name,
-            tx_power_level: device_info.tx_power,
-            rssi: device_info.rssi,
-            manufacturer_data: device_info.manufacturer_data,
-            service_data: device_info.service_data,
-            services: device_info.services,
-            class: device_info.class,
-        }))
+        Ok(Some(self.device_state().await?.properties))
+    }
+
+    fn last_seen(&self) -> Option<Instant> {
+        self.advertisements.last_seen()
+    }
+
+    fn advertisement_history(&self) -> Vec<AdvertisementRecord> {
+        self.advertisements.records()
     }
 
     fn services(&self) -> BTreeSet<Service> {
-        self.services
+        self.shared
+            .services
             .lock()
             .unwrap()
             .values()
@@ -163,8 +467,7 @@ impl api::Peripheral for Peripheral {
     }
 
     async fn is_connected(&self) -> Result<bool> {
-        let device_info = self.device_info().await?;
-        Ok(device_info.connected)
+        Ok(self.device_state().await?.connected)
     }
 
     async fn connect(&self) -> Result<()> {
@@ -206,7 +509,14 @@ impl api::Peripheral for Peripheral {
                 },
             );
         }
-        *self.services.lock().unwrap() = services_internal;
+        *self.shared.services.lock().unwrap() = services_internal;
+        // Give BlueZ another chance to hand out sockets for the characteristics, whose MTUs may
+        // have changed too.
+        self.shared
+            .writers
+            .lock()
+            .unwrap()
+            .retain(|_, writer| matches!(writer, Writer::Acquired(_)));
         Ok(())
     }
 
@@ -217,14 +527,33 @@ impl api::Peripheral for Peripheral {
         write_type: WriteType,
     ) -> Result<()> {
         let characteristic_info = self.characteristic_info(characteristic)?;
+        if write_type == WriteType::WithoutResponse
+            && characteristic_info
+                .flags
+                .contains(CharacteristicFlags::WRITE_WITHOUT_RESPONSE)
+            && self.write_acquired(&characteristic_info, data).await
+        {
+            return Ok(());
+        }
         let options = WriteOptions {
             write_type: Some(write_type.into()),
             ..Default::default()
         };
-        Ok(self
-            .session
-            .write_characteristic_value_with_options(&characteristic_info.id, data, options)
-            .await?)
+        let write = || {
+            self.session.write_characteristic_value_with_options(
+                &characteristic_info.id,
+                data,
+                options,
+            )
+        };
+        match write().await {
+            Err(BluetoothError::DbusError(e))
+                if is_write_acquired(&e) && self.release_writer(&characteristic_info) =>
+            {
+                Ok(write().await?)
+            }
+            result => Ok(result?),
+        }
     }
 
     async fn read(&self, characteristic: &Characteristic) -> Result<Vec<u8>> {
@@ -237,21 +566,90 @@ impl api::Peripheral for Peripheral {
 
     async fn subscribe(&self, characteristic: &Characteristic) -> Result<()> {
         let characteristic_info = self.characteristic_info(characteristic)?;
-        Ok(self.session.start_notify(&characteristic_info.id).await?)
+        // The kind is recorded first, so that the dispatcher isn't stopped while subscribing.
+        if let Some(kind) = SubscriptionKind::preferring_notify(characteristic_info.flags.into()) {
+            self.shared.subscriptions.set_kind(characteristic, kind);
+        }
+        // Keep the values from the start for the first stream, even if no one is listening yet.
+        if self.shared.notifications_channel.receiver_count() == 0 {
+            self.shared
+                .pending
+                .lock()
+                .unwrap()
+                .get_or_insert_with(VecDeque::new);
+        }
+        let result = async {
+            self.start_dispatcher().await?;
+            let acquired = characteristic_info
+                .flags
+                .contains(CharacteristicFlags::NOTIFY)
+                && self
+                    .acquire_notify(characteristic, &characteristic_info.id)
+                    .await;
+            if !acquired {
+                self.session.start_notify(&characteristic_info.id).await?;
+            }
+            Ok(())
+        }
+        .await;
+        if result.is_err() {
+            self.shared.subscriptions.clear_kind(characteristic);
+            self.shared.stop_dispatcher_if_unused();
+        }
+        result
+    }
+
+    async fn subscribe_with(
+        &self,
+        characteristic: &Characteristic,
+        kind: SubscriptionKind,
+    ) -> Result<()> {
+        // BlueZ doesn't allow writing the CCCD directly, and StartNotify enables notifications
+        // whenever the characteristic supports them.
+        kind.check_platform_choice(characteristic, SubscriptionKind::preferring_notify)?;
+        self.subscribe(characteristic).await
     }
 
     async fn unsubscribe(&self, characteristic: &Characteristic) -> Result<()> {
         let characteristic_info = self.characteristic_info(characteristic)?;
-        Ok(self.session.stop_notify(&characteristic_info.id).await?)
+        // Closing an acquired socket stops its notifications.
+        let notifier = self
+            .shared
+            .notifiers
+            .lock()
+            .unwrap()
+            .remove(&characteristic_info.id);
+        if notifier.is_none() {
+            self.session.stop_notify(&characteristic_info.id).await?;
+        }
+        self.shared.subscriptions.clear_kind(characteristic);
+        self.shared.stop_dispatcher_if_unused();
+        Ok(())
     }
 
     async fn notifications(&self) -> Result<Pin<Box<dyn Stream<Item = ValueNotification> + Send>>> {
-        let device_id = self.device.clone();
-        let events = self.session.device_event_stream(&device_id).await?;
-        let services = self.services.clone();
-        Ok(Box::pin(events.filter_map(move |event| {
-            ready(value_notification(event, &device_id, services.clone()))
-        })))
+        let (receiver, pending) = {
+            let mut pending = self.shared.pending.lock().unwrap();
+            (
+                self.shared.notifications_channel.subscribe(),
+                pending.take().unwrap_or_default(),
+            )
+        };
+        let notifications = Notifications {
+            values: Some(Box::pin(
+                stream::iter(pending).chain(notifications_stream_from_broadcast_receiver(receiver)),
+            )),
+            shared: Arc::downgrade(&self.shared),
+        };
+        self.start_dispatcher().await?;
+        Ok(Box::pin(notifications))
+    }
+
+    async fn subscribe_stream(&self, characteristic: &Characteristic) -> Result<Subscription> {
+        self.shared
+            .subscriptions
+            .subscribe(self, characteristic)
+            .await
     }
 
     async fn write_descriptor(&self, descriptor: &Descriptor, data: &[u8]) -> Result<()> {
@@ -269,21 +667,114 @@ impl api::Peripheral for Peripheral {
             .read_descriptor_value(&descriptor_info.id)
             .await?)
     }
+
+    async fn pair(&self, options: PairingOptions) -> Result<()> {
+        // Only out-of-band data needs the address type, and it should be known for a device
+        // discovered over LE.
+        let address_type = self
+            .device_state()
+            .await?
+            .properties
+            .address_type
+            .unwrap_or_default();
+        pairing::pair(
+            &self.bus,
+            &self.device,
+            self.mac_address,
+            address_type,
+            options,
+        )
+        .await
+    }
+
+    async fn unpair(&self) -> Result<()> {
+        pairing::unpair(&self.bus, &self.device).await
+    }
+
+    async fn is_bonded(&self) -> Result<bool> {
+        pairing::is_bonded(&self.bus, &self.device).await
+    }
+}
+
+/// Forwards the values of a device to its notification channel, until it is stopped or its
+/// peripherals are gone.
+/// Whether BlueZ refused a write over D-Bus because a socket is acquired for the characteristic.
+fn is_write_acquired(e: &dbus::Error) -> bool {
+    e.name() == Some("org.bluez.Error.NotPermitted") && e.message() == Some("Write acquired")
+}
+
+async fn dispatch_notifications(
+    events: impl Stream<Item = BluetoothEvent>,
+    device_id: DeviceId,
+    shared: Weak<Shared>,
+) {
+    let mut events = std::pin::pin!(events);
+    while let Some(event) = events.next().await {
+        let Some(shared) = shared.upgrade() else {
+            break;
+        };
+        if let Some(notification) = value_notification(event, &device_id, &shared) {
+            shared.deliver(notification);
+        }
+    }
+}
+
+/// Forwards the values read from an acquired socket to the notification channel, until BlueZ
+/// closes it or the peripherals are gone.
+async fn read_notifications(
+    socket: AcquiredSocket,
+    uuid: Uuid,
+    service_uuid: Uuid,
+    shared: Weak<Shared>,
+) {
+    loop {
+        let value = match socket.recv().await {
+            Ok(Some(value)) => value,
+            Ok(None) => break,
+            Err(e) => {
+                debug!("Lost the socket for notifications from {}: {}", uuid, e);
+                break;
+            }
+        };
+        let Some(shared) = shared.upgrade() else {
+            break;
+        };
+        let notification = ValueNotification {
+            uuid,
+            service_uuid,
+            value,
+            kind: shared.subscriptions.kind(service_uuid, uuid),
+            received_at: Instant::now(),
+            sequence: shared.sequence.fetch_add(1, Ordering::Relaxed),
+            missed: 0,
+        };
+        shared.deliver(notification);
+    }
 }
 
 fn value_notification(
     event: BluetoothEvent,
     device_id: &DeviceId,
-    services: Arc<Mutex<HashMap<Uuid, ServiceInternal>>>,
+    shared: &Shared,
 ) -> Option<ValueNotification> {
     match event {
         BluetoothEvent::Characteristic {
             id,
             event: CharacteristicEvent::Value { value },
         } if id.service().device() == *device_id => {
-            let services = services.lock().unwrap();
-            let uuid = find_characteristic_by_id(&services, id)?.uuid;
-            Some(ValueNotification { uuid, value })
+            let received_at = Instant::now();
+            let services = shared.services.lock().unwrap();
+            let (service_uuid, characteristic) = find_characteristic_by_id(&services, id)?;
+            let uuid = characteristic.uuid;
+            Some(ValueNotification {
+                uuid,
+                service_uuid,
+                value,
+                kind: shared.subscriptions.kind(service_uuid, uuid),
+                received_at,
+                sequence: shared.sequence.fetch_add(1, Ordering::Relaxed),
+                missed: 0,
+            })
         }
         _ => None,
     }
@@ -292,11 +783,11 @@ fn value_notification(
 fn find_characteristic_by_id(
     services: &HashMap<Uuid, ServiceInternal>,
     characteristic_id: CharacteristicId,
-) -> Option<&CharacteristicInfo> {
+) -> Option<(Uuid, &CharacteristicInfo)> {
     for service in services.values() {
         for characteristic in service.characteristics.values() {
             if characteristic.info.id == characteristic_id {
-                return Some(&characteristic.info);
+                return Some((service.info.uuid, &characteristic.info));
             }
         }
     }
@@ -405,3 +896,220 @@ impl From<CharacteristicFlags> for CharPropFlags {
         result
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::{Central as _, Manager as _, Peripheral as _};
+    use crate::bluez::manager::Manager;
+    use crate::bluez::test_bus::{stand_in_objects, PrivateBus};
+    use dbus::arg::{OwnedFd, PropMap, RefArg, Variant};
+    use dbus::channel::Sender;
+    use dbus::nonblock::SyncConnection;
+    use dbus::{Message, Path};
+    use std::io::Read;
+    use std::os::unix::io::{FromRawFd, IntoRawFd};
+    use std::os::unix::net::UnixStream;
+    use std::time::Duration;
+
+    const CHARACTERISTIC: &str = "/org/bluez/hci0/dev_11_22_33_44_55_66/service0010/char0011";
+
+    /// Stands in for BlueZ with the device, recording the calls to its characteristic and
+    /// answering those that `answer` does.
+    async fn stand_in_bluez(
+        bus: &PrivateBus,
+        answer: impl Fn(&str, &Message) -> Option<Message> + Send + Sync + 'static,
+    ) -> (Arc<SyncConnection>, Arc<Mutex<Vec<String>>>) {
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let recorded = calls.clone();
+        let bluez = stand_in_objects(bus, move |message| {
+            if message.path().as_deref() != Some(CHARACTERISTIC) {
+                return None;
+            }
+            let member = message.member().unwrap().to_string();
+            recorded.lock().unwrap().push(member.clone());
+            answer(&member, message)
+        })
+        .await;
+        (bluez, calls)
+    }
+
+    /// The device's peripheral, whose services are a single characteristic with the given flags.
+    async fn peripheral(flags: CharacteristicFlags) -> (Peripheral, Characteristic) {
+        let manager = Manager::new().await.unwrap();
+        let adapter = manager.adapters().await.unwrap().remove(0);
+        let peripheral = adapter.peripherals().await.unwrap().remove(0);
+        let info = CharacteristicInfo {
+            id: serde_json::from_value(serde_json::json!({ "object_path": CHARACTERISTIC }))
+                .unwrap(),
+            uuid: Uuid::from_u128(0x11),
+            flags,
+            mtu: None,
+        };
+        let service = ServiceInternal {
+            info: ServiceInfo {
+                id: info.id.service(),
+                uuid: Uuid::from_u128(0x10),
+                primary: true,
+            },
+            characteristics: HashMap::from([(
+                info.uuid,
+                CharacteristicInternal::new(info, HashMap::new()),
+            )]),
+        };
+        let characteristic = make_characteristic(
+            &service.characteristics[&Uuid::from_u128(0x11)],
+            service.info.uuid,
+        );
+        peripheral
+            .shared
+            .services
+            .lock()
+            .unwrap()
+            .insert(service.info.uuid, service);
+        (peripheral, characteristic)
+    }
+
+    /// Sends a value of the characteristic, as BlueZ does for notifications.
+    fn send_value(bluez: &SyncConnection, value: &[u8]) {
+        let changed: PropMap = HashMap::from([(
+            "Value".to_string(),
+            Variant(Box::new(value.to_vec()) as Box<dyn RefArg>),
+        )]);
+        let signal = Message::signal(
+            &Path::from(CHARACTERISTIC),
+            &"org.freedesktop.DBus.Properties".into(),
+            &"PropertiesChanged".into(),
+        )
+        .append3(
+            "org.bluez.GattCharacteristic1",
+            changed,
+            Vec::<String>::new(),
+        );
+        bluez.send(signal).unwrap();
+    }
+
+    #[tokio::test]
+    async fn keeps_values_for_the_first_stream() {
+        let test = concat!(module_path!(), "::keeps_values_for_the_first_stream");
+        let Some(bus) = PrivateBus::start_as_system_bus(test) else {
+            return;
+        };
+        let (bluez, calls) = stand_in_bluez(&bus, |member, message| {
+            matches!(member, "StartNotify" | "StopNotify").then(|| message.method_return())
+        })
+        .await;
+        let (peripheral, characteristic) = peripheral(CharacteristicFlags::NOTIFY).await;
+
+        peripheral.subscribe(&characteristic).await.unwrap();
+        send_value(&bluez, &[1, 2]);
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while peripheral
+                .shared
+                .pending
+                .lock()
+                .unwrap()
+                .as_ref()
+                .is_none_or(VecDeque::is_empty)
+            {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        let mut notifications = peripheral.notifications().await.unwrap();
+        assert_eq!(notifications.next().await.unwrap().value, vec![1, 2]);
+        send_value(&bluez, &[3]);
+        assert_eq!(notifications.next().await.unwrap().value, vec![3]);
+
+        // The device's values are read until nothing is subscribed to and no one is listening.
+        peripheral.unsubscribe(&characteristic).await.unwrap();
+        assert!(peripheral.shared.dispatcher.lock().unwrap().is_some());
+        drop(notifications);
+        assert!(peripheral.shared.dispatcher.lock().unwrap().is_none());
+        assert_eq!(
+            calls.lock().unwrap().clone(),
+            vec!["AcquireNotify", "StartNotify", "StopNotify"]
+        );
+    }
+
+    #[tokio::test]
+    async fn releases_the_acquired_socket_for_writes_over_dbus() {
+        let test = concat!(
+            module_path!(),
+            "::releases_the_acquired_socket_for_writes_over_dbus"
+        );
+        let Some(bus) = PrivateBus::start_as_system_bus(test) else {
+            return;
+        };
+        // Like BlueZ, the stand-in refuses writes over D-Bus while a socket is acquired.
+        let peers = Arc::new(Mutex::new(Vec::<UnixStream>::new()));
+        let written = Arc::new(Mutex::new(Vec::new()));
+        let (kept, received) = (peers.clone(), written.clone());
+        let (_bluez, calls) = stand_in_bluez(&bus, move |member, message| match member {
+            "AcquireWrite" => {
+                let (ours, theirs) = crate::bluez::acquired::seqpacket_pair();
+                kept.lock().unwrap().push(ours);
+                // SAFETY: The descriptor is owned, and ownership passes to the message.
+                let fd = unsafe { OwnedFd::from_raw_fd(theirs.into_raw_fd()) };
+                Some(message.method_return().append2(fd, 20u16))
+            }
+            "WriteValue" => {
+                let mut acquired = false;
+                for peer in kept.lock().unwrap().iter_mut() {
+                    peer.set_nonblocking(true).unwrap();
+                    let mut value = [0; 20];
+                    loop {
+                        match peer.read(&mut value) {
+                            Ok(0) => break,
+                            Ok(n) => received.lock().unwrap().push(value[..n].to_vec()),
+                            Err(_) => {
+                                acquired = true;
+                                break;
+                            }
+                        }
+                    }
+                }
+                if acquired {
+                    return Some(Message::error(
+                        message,
+                        &"org.bluez.Error.NotPermitted".into(),
+                        &std::ffi::CString::new("Write acquired").unwrap(),
+                    ));
+                }
+                let (value, _): (Vec<u8>, PropMap) = message.read2().unwrap();
+                received.lock().unwrap().push(value);
+                Some(message.method_return())
+            }
+            _ => None,
+        })
+        .await;
+        let (peripheral, characteristic) =
+            peripheral(CharacteristicFlags::WRITE | CharacteristicFlags::WRITE_WITHOUT_RESPONSE)
+                .await;
+
+        peripheral
+            .write(&characteristic, &[1, 2, 3], WriteType::WithoutResponse)
+            .await
+            .unwrap();
+        peripheral
+            .write(&characteristic, &[4], WriteType::WithResponse)
+            .await
+            .unwrap();
+        // Values known not to fit the socket are written over D-Bus, without acquiring it.
+        peripheral
+            .write(&characteristic, &[5; 21], WriteType::WithoutResponse)
+            .await
+            .unwrap();
+        assert_eq!(peripheral.acquired_mtu(&characteristic), None);
+        // The socket is only released once BlueZ refuses a write over D-Bus.
+        assert_eq!(
+            calls.lock().unwrap().clone(),
+            vec!["AcquireWrite", "WriteValue", "WriteValue", "WriteValue"]
+        );
+        assert_eq!(
+            written.lock().unwrap().clone(),
+            vec![vec![1, 2, 3], vec![4], vec![5; 21]]
+        );
+    }
+}
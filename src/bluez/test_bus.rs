@@ -0,0 +1,194 @@
+//! A private D-Bus daemon for tests to stand in for BlueZ on.
+
+use dbus::arg::{PropMap, RefArg, Variant};
+use dbus::channel::{MatchingReceiver, Sender};
+use dbus::message::MatchRule;
+use dbus::nonblock::SyncConnection;
+use dbus::{Message, Path};
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader};
+use std::path::PathBuf;
+use std::process::{Child, Command, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use super::adapter::ADAPTER_INTERFACE;
+use super::monitor::BLUEZ;
+
+/// Tells a test process the socket of the bus it is to start as its system bus.
+const SYSTEM_BUS_SOCKET: &str = "BTLEPLUG_TEST_SYSTEM_BUS";
+
+static NEXT_SYSTEM_BUS: AtomicUsize = AtomicUsize::new(0);
+
+/// A private bus, which is shut down when dropped.
+pub struct PrivateBus {
+    daemon: Child,
+    address: String,
+    /// The socket the bus listens on, if it was given one rather than the daemon choosing.
+    socket: Option<PathBuf>,
+}
+
+impl PrivateBus {
+    /// Starts a bus, unless dbus-daemon isn't available.
+    pub fn start() -> Option<Self> {
+        Self::start_with(None)
+    }
+
+    /// Starts a bus to be the system bus for connections such as bluez-async's, for the test with
+    /// the given path, e.g. `concat!(module_path!(), "::test")`.
+    ///
+    /// libdbus reads the system bus address from the environment once per process, so the test is
+    /// run again in a process of its own, told the address of its bus. There, this returns the bus,
+    /// unless dbus-daemon isn't available. In the test's own process, it checks that the other one
+    /// passed and returns `None`.
+    pub fn start_as_system_bus(test: &str) -> Option<Self> {
+        if let Some(socket) = std::env::var_os(SYSTEM_BUS_SOCKET) {
+            let bus = Self::start_with(Some(socket.into()));
+            if bus.is_none() {
+                eprintln!("Skipping, as dbus-daemon isn't available");
+            }
+            return bus;
+        }
+        let socket = std::env::temp_dir().join(format!(
+            "btleplug-{}-{}-bus",
+            std::process::id(),
+            NEXT_SYSTEM_BUS.fetch_add(1, Ordering::Relaxed)
+        ));
+        // Test names leave out the name of the crate.
+        let test = test.split_once("::").map_or(test, |(_, test)| test);
+        let output = Command::new(std::env::current_exe().unwrap())
+            .args([test, "--exact", "--nocapture", "--test-threads=1"])
+            .env(SYSTEM_BUS_SOCKET, &socket)
+            .env(
+                "DBUS_SYSTEM_BUS_ADDRESS",
+                format!("unix:path={}", socket.display()),
+            )
+            .output()
+            .unwrap();
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(
+            output.status.success() && stdout.contains("1 passed"),
+            "{} failed on its own system bus:\n{}{}",
+            test,
+            stdout,
+            stderr
+        );
+        eprint!("{}", stderr);
+        None
+    }
+
+    fn start_with(socket: Option<PathBuf>) -> Option<Self> {
+        let mut daemon = Command::new("dbus-daemon")
+            .args(["--session", "--nofork", "--print-address"])
+            .args(
+                socket
+                    .iter()
+                    .map(|socket| format!("--address=unix:path={}", socket.display())),
+            )
+            .stdout(Stdio::piped())
+            .spawn()
+            .ok()?;
+        let mut address = String::new();
+        BufReader::new(daemon.stdout.as_mut()?)
+            .read_line(&mut address)
+            .ok()?;
+        Some(Self {
+            daemon,
+            address: address.trim().to_string(),
+            socket,
+        })
+    }
+
+    /// Opens a connection to the bus, served by a task on the current Tokio runtime.
+    pub fn connect(&self) -> Arc<SyncConnection> {
+        let mut channel = dbus::channel::Channel::open_private(&self.address).unwrap();
+        channel.register().unwrap();
+        let (resource, connection) = dbus_tokio::connection::from_channel(channel).unwrap();
+        tokio::spawn(resource);
+        connection
+    }
+}
+
+impl Drop for PrivateBus {
+    fn drop(&mut self) {
+        let _ = self.daemon.kill();
+        let _ = self.daemon.wait();
+        if let Some(socket) = &self.socket {
+            let _ = std::fs::remove_file(socket);
+        }
+    }
+}
+
+/// Stands in for BlueZ with an adapter on `/org/bluez/hci0`, which has discovered the device on
+/// `/org/bluez/hci0/dev_11_22_33_44_55_66`. Calls other than GetManagedObjects are answered by
+/// `answer`, or refused as unknown where it returns `None`.
+pub async fn stand_in_objects(
+    bus: &PrivateBus,
+    mut answer: impl FnMut(&Message) -> Option<Message> + Send + Sync + 'static,
+) -> Arc<SyncConnection> {
+    let connection = bus.connect();
+    connection
+        .request_name(BLUEZ, false, true, true)
+        .await
+        .unwrap();
+    let properties = |properties: Vec<(&str, Box<dyn RefArg>)>| -> PropMap {
+        properties
+            .into_iter()
+            .map(|(name, value)| (name.to_string(), Variant(value)))
+            .collect()
+    };
+    let string = |value: &str| -> Box<dyn RefArg> { Box::new(value.to_string()) };
+    let adapter = properties(vec![
+        ("Address", string("00:11:22:33:44:55")),
+        ("AddressType", string("public")),
+        ("Modalias", string("usb:v1D6Bp0246d0537")),
+        ("Name", string("laptop")),
+        ("Alias", string("laptop")),
+        ("Powered", Box::new(true)),
+        ("Discovering", Box::new(false)),
+    ]);
+    let mut device = properties(vec![
+        ("Address", string("11:22:33:44:55:66")),
+        ("AddressType", string("random")),
+    ]);
+    for flag in [
+        "Paired",
+        "Connected",
+        "ServicesResolved",
+        "Trusted",
+        "Blocked",
+        "LegacyPairing",
+    ] {
+        device.insert(flag.to_string(), Variant(Box::new(false)));
+    }
+    let objects: HashMap<Path<'static>, HashMap<String, PropMap>> = HashMap::from([
+        (
+            Path::from("/org/bluez/hci0"),
+            HashMap::from([(ADAPTER_INTERFACE.to_string(), adapter)]),
+        ),
+        (
+            Path::from("/org/bluez/hci0/dev_11_22_33_44_55_66"),
+            HashMap::from([("org.bluez.Device1".to_string(), device)]),
+        ),
+    ]);
+    connection.start_receive(
+        MatchRule::new_method_call(),
+        Box::new(move |message, connection| {
+            let reply = if message.member().as_deref() == Some("GetManagedObjects") {
+                message.method_return().append1(&objects)
+            } else if let Some(reply) = answer(&message) {
+                reply
+            } else {
+                Message::error(
+                    &message,
+                    &"org.freedesktop.DBus.Error.UnknownMethod".into(),
+                    &std::ffi::CString::new("Unknown method").unwrap(),
+                )
+            };
+            connection.send(reply).unwrap();
+            true
+        }),
+    );
+    connection
+}
@@ -11,14 +11,17 @@
 // following copyright:
 //
 // Copyright (c) 2014 The Rust Project Developers
-use crate::api::{CentralEvent, Peripheral};
+use super::event_buffer::{self, EventSender};
+use crate::api::{CentralEvent, EventBufferConfig, Peripheral};
 use crate::platform::PeripheralId;
-use dashmap::{mapref::one::RefMut, DashMap};
-use futures::stream::{Stream, StreamExt};
+use crate::Result;
+use dashmap::DashMap;
+use futures::stream::Stream;
 use log::trace;
+use std::collections::{HashMap, HashSet};
 use std::pin::Pin;
-use tokio::sync::broadcast;
-use tokio_stream::wrappers::BroadcastStream;
+use std::sync::{Mutex, RwLock};
+use std::time::{Duration, Instant};
 
 #[derive(Debug)]
 pub struct AdapterManager<PeripheralType>
@@ -26,15 +29,25 @@ where
     PeripheralType: Peripheral,
 {
     peripherals: DashMap<PeripheralId, PeripheralType>,
-    events_channel: broadcast::Sender<CentralEvent>,
+    /// When each peripheral was last heard from, for deciding when to evict it. Only kept while
+    /// there is a retention period.
+    last_seen: Mutex<HashMap<PeripheralId, Instant>>,
+    /// The peripherals that are connected, which are never evicted.
+    connected: Mutex<HashSet<PeripheralId>>,
+    retention: RwLock<Option<Duration>>,
+    event_senders: Mutex<Vec<EventSender>>,
+    event_buffer: RwLock<EventBufferConfig>,
 }
 
 impl<PeripheralType: Peripheral + 'static> Default for AdapterManager<PeripheralType> {
     fn default() -> Self {
-        let (broadcast_sender, _) = broadcast::channel(16);
         AdapterManager {
             peripherals: DashMap::new(),
-            events_channel: broadcast_sender,
+            last_seen: Mutex::new(HashMap::new()),
+            connected: Mutex::new(HashSet::new()),
+            retention: RwLock::new(None),
+            event_senders: Mutex::new(Vec::new()),
+            event_buffer: RwLock::new(EventBufferConfig::default()),
         }
     }
 }
@@ -43,19 +56,64 @@ impl<PeripheralType> AdapterManager<PeripheralType>
 where
     PeripheralType: Peripheral + 'static,
 {
+    /// Emits an event to all event streams, and evicts the peripherals that haven't been heard from
+    /// for longer than the retention period.
+    ///
+    /// This may remove peripherals from the map, so it must not be called while holding a
+    /// reference into it.
     pub fn emit(&self, event: CentralEvent) {
-        if let CentralEvent::DeviceDisconnected(ref id) = event {
-            self.peripherals.remove(id);
+        // Without a retention period nothing is evicted, so the clock isn't read at all. Not every
+        // platform has one, e.g. `Instant::now` panics on wasm.
+        let now = self.retention.read().unwrap().is_some().then(Instant::now);
+        self.record_activity(&event, now);
+        self.send_event(event);
+        if let Some(now) = now {
+            self.evict_stale_peripherals(now);
         }
+    }
 
-        if let Err(lost) = self.events_channel.send(event) {
-            trace!("Lost central event, while nothing subscribed: {:?}", lost);
+    fn send_event(&self, event: CentralEvent) {
+        let mut senders = self.event_senders.lock().unwrap();
+        if senders.is_empty() {
+            trace!("Lost central event, while nothing subscribed: {:?}", event);
+            return;
         }
+        let config = *self.event_buffer.read().unwrap();
+        senders.retain(|sender| sender.send(event.clone(), &config));
     }
 
     pub fn event_stream(&self) -> Pin<Box<dyn Stream<Item = CentralEvent> + Send>> {
-        let receiver = self.events_channel.subscribe();
-        Box::pin(BroadcastStream::new(receiver).filter_map(|x| async move { x.ok() }))
+        let (sender, stream) = event_buffer::channel();
+        self.event_senders.lock().unwrap().push(sender);
+        Box::pin(stream)
+    }
+
+    pub fn set_event_buffer(&self, config: EventBufferConfig) -> Result<()> {
+        event_buffer::check_config(&config)?;
+        *self.event_buffer.write().unwrap() = config;
+        Ok(())
+    }
+
+    /// Sets how long a peripheral that isn't connected is kept after it was last heard from. With
+    /// `None`, peripherals are kept for as long as the adapter.
+    pub fn set_retention(&self, retention: Option<Duration>) {
+        let previous = std::mem::replace(&mut *self.retention.write().unwrap(), retention);
+        let mut last_seen = self.last_seen.lock().unwrap();
+        match (previous, retention) {
+            (_, None) => last_seen.clear(),
+            // Peripherals weren't tracked until now, so their retention periods start now.
+            (None, Some(_)) => {
+                let now = Instant::now();
+                last_seen.extend(
+                    self.peripherals
+                        .iter()
+                        .map(|entry| (entry.key().clone(), now)),
+                );
+            }
+            (Some(_), Some(_)) => {}
+        }
+        drop(last_seen);
+        self.evict_if_retained();
     }
 
     pub fn add_peripheral(&self, peripheral: PeripheralType) {
@@ -63,24 +121,231 @@ where
             !self.peripherals.contains_key(&peripheral.id()),
             "Adding a peripheral that's already in the map."
         );
+        if self.retention.read().unwrap().is_some() {
+            self.last_seen
+                .lock()
+                .unwrap()
+                .insert(peripheral.id(), Instant::now());
+        }
         self.peripherals.insert(peripheral.id(), peripheral);
     }
 
     pub fn peripherals(&self) -> Vec<PeripheralType> {
+        self.evict_if_retained();
         self.peripherals
             .iter()
             .map(|val| val.value().clone())
             .collect()
     }
 
-    pub fn peripheral_mut(
-        &self,
-        id: &PeripheralId,
-    ) -> Option<RefMut<PeripheralId, PeripheralType>> {
-        self.peripherals.get_mut(id)
-    }
-
     pub fn peripheral(&self, id: &PeripheralId) -> Option<PeripheralType> {
+        self.evict_if_retained();
         self.peripherals.get(id).map(|val| val.value().clone())
     }
+
+    /// Records a change to whether a peripheral is connected, and that it was heard from at `now`,
+    /// if the time is being tracked.
+    fn record_activity(&self, event: &CentralEvent, now: Option<Instant>) {
+        let (id, connected) = match event {
+            CentralEvent::DeviceConnected(id) => (id, Some(true)),
+            CentralEvent::DeviceDisconnected(id) => (id, Some(false)),
+            CentralEvent::DeviceDiscovered(id)
+            | CentralEvent::DeviceUpdated { id, .. }
+            | CentralEvent::ManufacturerDataAdvertisement { id, .. }
+            | CentralEvent::ServiceDataAdvertisement { id, .. }
+            | CentralEvent::ServicesAdvertisement { id, .. } => (id, None),
+            CentralEvent::DeviceLost(_)
+            | CentralEvent::EventsLost(_)
+            | CentralEvent::StateUpdate(_) => return,
+        };
+        match connected {
+            Some(true) => {
+                self.connected.lock().unwrap().insert(id.clone());
+            }
+            Some(false) => {
+                self.connected.lock().unwrap().remove(id);
+            }
+            None => {}
+        }
+        if let Some(now) = now
+            && let Some(last_seen) = self.last_seen.lock().unwrap().get_mut(id)
+        {
+            *last_seen = now;
+        }
+    }
+
+    fn evict_if_retained(&self) {
+        if self.retention.read().unwrap().is_some() {
+            self.evict_stale_peripherals(Instant::now());
+        }
+    }
+
+    /// Removes the peripherals that aren't connected and haven't been heard from for longer than
+    /// the retention period as of `now`, emitting [`CentralEvent::DeviceLost`] for each.
+    fn evict_stale_peripherals(&self, now: Instant) {
+        let Some(retention) = *self.retention.read().unwrap() else {
+            return;
+        };
+        let mut lost = Vec::new();
+        let connected = self.connected.lock().unwrap();
+        self.last_seen.lock().unwrap().retain(|id, last_seen| {
+            let stale =
+                !connected.contains(id) && now.saturating_duration_since(*last_seen) > retention;
+            if stale {
+                lost.push(id.clone());
+            }
+            !stale
+        });
+        drop(connected);
+        for id in lost {
+            if self.peripherals.remove(&id).is_some() {
+                trace!(
+                    "Evicting peripheral {:?} that hasn't been seen for {:?}",
+                    id,
+                    retention
+                );
+                self.send_event(CentralEvent::DeviceLost(id));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::EventOverflowPolicy;
+    use crate::common::mock::{peripheral_id, MockPeripheral};
+    use futures::{FutureExt, StreamExt};
+
+    const RETENTION: Duration = Duration::from_secs(30);
+
+    fn manager_with(peripherals: &[u8]) -> AdapterManager<MockPeripheral> {
+        let manager = AdapterManager::default();
+        manager.set_retention(Some(RETENTION));
+        for &n in peripherals {
+            manager.add_peripheral(MockPeripheral::new(n));
+            manager.emit(CentralEvent::DeviceDiscovered(peripheral_id(n)));
+        }
+        manager
+    }
+
+    fn ids(manager: &AdapterManager<MockPeripheral>) -> Vec<PeripheralId> {
+        let mut ids: Vec<_> = manager.peripherals().iter().map(|p| p.id()).collect();
+        ids.sort();
+        ids
+    }
+
+    #[test]
+    fn disconnected_peripherals_are_kept() {
+        let manager = manager_with(&[1]);
+        manager.emit(CentralEvent::DeviceConnected(peripheral_id(1)));
+        manager.emit(CentralEvent::DeviceDisconnected(peripheral_id(1)));
+        assert!(manager.peripheral(&peripheral_id(1)).is_some());
+    }
+
+    #[test]
+    fn stale_peripherals_are_evicted() {
+        let manager = manager_with(&[1, 2]);
+        let mut events = manager.event_stream();
+        let now = Instant::now();
+
+        manager.evict_stale_peripherals(now + RETENTION / 2);
+        assert_eq!(ids(&manager), vec![peripheral_id(1), peripheral_id(2)]);
+
+        manager.evict_stale_peripherals(now + RETENTION * 2);
+        assert!(ids(&manager).is_empty());
+        let mut lost = Vec::new();
+        while let Some(Some(event)) = events.next().now_or_never() {
+            match event {
+                CentralEvent::DeviceLost(id) => lost.push(id),
+                event => panic!("Unexpected event {:?}", event),
+            }
+        }
+        lost.sort();
+        assert_eq!(lost, vec![peripheral_id(1), peripheral_id(2)]);
+    }
+
+    #[test]
+    fn advertisements_keep_peripherals() {
+        let manager = manager_with(&[1, 2]);
+        let now = Instant::now();
+        manager.record_activity(
+            &CentralEvent::ServicesAdvertisement {
+                id: peripheral_id(2),
+                services: vec![],
+            },
+            Some(now + RETENTION),
+        );
+        manager.evict_stale_peripherals(now + RETENTION * 3 / 2);
+        assert_eq!(ids(&manager), vec![peripheral_id(2)]);
+    }
+
+    #[test]
+    fn connected_peripherals_are_kept() {
+        let manager = manager_with(&[1, 2]);
+        manager.emit(CentralEvent::DeviceConnected(peripheral_id(1)));
+        manager.evict_stale_peripherals(Instant::now() + RETENTION * 2);
+        assert_eq!(ids(&manager), vec![peripheral_id(1)]);
+
+        // The retention period starts over on disconnection.
+        manager.record_activity(
+            &CentralEvent::DeviceDisconnected(peripheral_id(1)),
+            Some(Instant::now() + RETENTION * 3),
+        );
+        manager.evict_stale_peripherals(Instant::now() + RETENTION * 7 / 2);
+        assert_eq!(ids(&manager), vec![peripheral_id(1)]);
+        manager.evict_stale_peripherals(Instant::now() + RETENTION * 5);
+        assert!(ids(&manager).is_empty());
+    }
+
+    #[test]
+    fn peripherals_are_kept_without_retention() {
+        let manager = manager_with(&[1]);
+        manager.set_retention(None);
+        manager.evict_stale_peripherals(Instant::now() + Duration::from_secs(24 * 60 * 60));
+        assert_eq!(ids(&manager), vec![peripheral_id(1)]);
+    }
+
+    #[test]
+    fn activity_is_only_tracked_with_retention() {
+        let manager = AdapterManager::default();
+        manager.add_peripheral(MockPeripheral::new(1));
+        manager.emit(CentralEvent::DeviceDiscovered(peripheral_id(1)));
+        assert!(manager.last_seen.lock().unwrap().is_empty());
+
+        // Peripherals known before there was a retention period are evicted once it has passed.
+        manager.set_retention(Some(RETENTION));
+        manager.evict_stale_peripherals(Instant::now() + RETENTION / 2);
+        assert_eq!(ids(&manager), vec![peripheral_id(1)]);
+        manager.evict_stale_peripherals(Instant::now() + RETENTION * 2);
+        assert!(ids(&manager).is_empty());
+    }
+
+    #[test]
+    fn event_buffer_applies_to_existing_streams() {
+        let manager = manager_with(&[]);
+        let mut events = manager.event_stream();
+        manager
+            .set_event_buffer(EventBufferConfig {
+                capacity: 1,
+                overflow: EventOverflowPolicy::DropOldest,
+            })
+            .unwrap();
+        manager.emit(CentralEvent::DeviceUpdated {
+            id: peripheral_id(1),
+            changes: Default::default(),
+        });
+        manager.emit(CentralEvent::DeviceUpdated {
+            id: peripheral_id(2),
+            changes: Default::default(),
+        });
+        assert!(matches!(
+            events.next().now_or_never(),
+            Some(Some(CentralEvent::EventsLost(1)))
+        ));
+        assert!(matches!(
+            events.next().now_or_never(),
+            Some(Some(CentralEvent::DeviceUpdated { id, .. })) if id == peripheral_id(2)
+        ));
+    }
 }
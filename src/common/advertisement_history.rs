@@ -0,0 +1,112 @@
+// btleplug Source Code File
+//
+// Copyright 2020 Nonpolynomial. All rights reserved.
+//
+// Licensed under the BSD 3-Clause license. See LICENSE file in the project root
+// for full license information.
+
+//! Recent advertisements of a peripheral, for
+//! [`Peripheral::advertisement_history`](crate::api::Peripheral::advertisement_history).
+
+use crate::api::AdvertisementRecord;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, VecDeque};
+use std::hash::{Hash, Hasher};
+use std::sync::Mutex;
+use std::time::Instant;
+use uuid::Uuid;
+
+/// How many advertisements are kept per peripheral.
+const HISTORY_LEN: usize = 32;
+
+#[derive(Debug, Default)]
+pub struct AdvertisementHistory {
+    records: Mutex<VecDeque<AdvertisementRecord>>,
+}
+
+impl AdvertisementHistory {
+    /// Records an advertisement received just now, dropping the oldest one if the history is full.
+    pub fn record(&self, rssi: Option<i16>, payload_hash: u64) {
+        let mut records = self.records.lock().unwrap();
+        if records.len() == HISTORY_LEN {
+            records.pop_front();
+        }
+        records.push_back(AdvertisementRecord {
+            received_at: Instant::now(),
+            rssi,
+            payload_hash,
+        });
+    }
+
+    /// The recorded advertisements, oldest first.
+    pub fn records(&self) -> Vec<AdvertisementRecord> {
+        self.records.lock().unwrap().iter().copied().collect()
+    }
+
+    pub fn last_seen(&self) -> Option<Instant> {
+        self.records
+            .lock()
+            .unwrap()
+            .back()
+            .map(|record| record.received_at)
+    }
+}
+
+/// Hashes the data of an advertisement, independently of the order of the maps' entries.
+pub fn hash_advertised_data(
+    manufacturer_data: &HashMap<u16, Vec<u8>>,
+    service_data: &HashMap<Uuid, Vec<u8>>,
+    services: &[Uuid],
+) -> u64 {
+    let mut manufacturer_data: Vec<_> = manufacturer_data.iter().collect();
+    manufacturer_data.sort();
+    let mut service_data: Vec<_> = service_data.iter().collect();
+    service_data.sort();
+    let mut services = services.to_vec();
+    services.sort();
+
+    let mut hasher = DefaultHasher::new();
+    (manufacturer_data, service_data, services).hash(&mut hasher);
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn history_is_bounded() {
+        let history = AdvertisementHistory::default();
+        assert_eq!(history.last_seen(), None);
+        for n in 0..HISTORY_LEN + 3 {
+            history.record(Some(-(n as i16)), n as u64);
+        }
+        let records = history.records();
+        assert_eq!(records.len(), HISTORY_LEN);
+        assert_eq!(records[0].payload_hash, 3);
+        assert_eq!(
+            records[HISTORY_LEN - 1].rssi,
+            Some(-(HISTORY_LEN as i16 + 2))
+        );
+        assert_eq!(
+            history.last_seen(),
+            Some(records[HISTORY_LEN - 1].received_at)
+        );
+    }
+
+    #[test]
+    fn hash_ignores_map_order() {
+        let a = Uuid::from_u128(1);
+        let b = Uuid::from_u128(2);
+        let first = HashMap::from([(a, vec![1]), (b, vec![2])]);
+        let second = HashMap::from([(b, vec![2]), (a, vec![1])]);
+        assert_eq!(
+            hash_advertised_data(&HashMap::new(), &first, &[a, b]),
+            hash_advertised_data(&HashMap::new(), &second, &[b, a])
+        );
+        assert_ne!(
+            hash_advertised_data(&HashMap::new(), &first, &[a, b]),
+            hash_advertised_data(&HashMap::new(), &first, &[a])
+        );
+    }
+}
@@ -0,0 +1,297 @@
+// btleplug Source Code File
+//
+// Copyright 2020 Nonpolynomial. All rights reserved.
+//
+// Licensed under the BSD 3-Clause license. See LICENSE file in the project root
+// for full license information.
+
+//! Bounded buffering of [`CentralEvent`]s for a single event stream, applying an
+//! [`EventBufferConfig`] when the stream isn't read quickly enough.
+
+use crate::api::{CentralEvent, EventBufferConfig, EventOverflowPolicy};
+use crate::platform::PeripheralId;
+use crate::{Error, Result};
+use futures::future::{self, Future};
+use futures::stream::Stream;
+use std::collections::VecDeque;
+use std::mem::{self, Discriminant};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+
+#[derive(Debug, Default)]
+struct State {
+    events: VecDeque<CentralEvent>,
+    /// Events dropped since the last one the stream returned.
+    lost: u64,
+    /// Set once either side is gone.
+    closed: bool,
+    waker: Option<Waker>,
+    /// Woken when the stream is dropped.
+    close_waker: Option<Waker>,
+}
+
+/// Creates a buffer for one event stream, returning the sending half and the stream.
+pub fn channel() -> (EventSender, EventStream) {
+    let state = Arc::new(Mutex::new(State::default()));
+    (EventSender(state.clone()), EventStream(state))
+}
+
+/// Checks that events can be buffered with the given configuration.
+pub fn check_config(config: &EventBufferConfig) -> Result<()> {
+    if config.capacity == 0 {
+        return Err(Error::NotSupported(
+            "An event buffer must hold at least one event".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+/// Sending half of an event buffer. The stream ends once this is dropped and the remaining events
+/// have been read.
+#[derive(Debug)]
+pub struct EventSender(Arc<Mutex<State>>);
+
+impl EventSender {
+    /// Buffers an event, making room for it according to `config` if the buffer is full. Returns
+    /// false once the stream has been dropped, after which there is no point sending any more.
+    pub fn send(&self, mut event: CentralEvent, config: &EventBufferConfig) -> bool {
+        let capacity = config.capacity.max(1);
+        let mut state = self.0.lock().unwrap();
+        if state.closed {
+            return false;
+        }
+        if state.events.len() >= capacity
+            && config.overflow == EventOverflowPolicy::CoalescePerDevice
+            && let Some(key) = coalesce_key(&event)
+            && let Some(index) = state
+                .events
+                .iter()
+                .position(|pending| coalesce_key(pending) == Some(key))
+        {
+            let replaced = state.events.remove(index);
+            // The newer update only lists what changed since the replaced one.
+            if let (
+                Some(CentralEvent::DeviceUpdated {
+                    changes: mut older, ..
+                }),
+                CentralEvent::DeviceUpdated { changes, .. },
+            ) = (replaced, &mut event)
+            {
+                older.merge(mem::take(changes));
+                *changes = older;
+            }
+        }
+        // The capacity may have been lowered since earlier events were buffered, so this can
+        // drop more than one.
+        while state.events.len() >= capacity {
+            state.events.pop_front();
+            state.lost += 1;
+        }
+        state.events.push_back(event);
+        if let Some(waker) = state.waker.take() {
+            waker.wake();
+        }
+        true
+    }
+
+    /// Completes once the stream has been dropped, so that a sender waiting for events to send
+    /// can stop without waiting for the next one.
+    pub fn closed(&self) -> impl Future<Output = ()> + '_ {
+        future::poll_fn(move |cx| {
+            let mut state = self.0.lock().unwrap();
+            if state.closed {
+                Poll::Ready(())
+            } else {
+                state.close_waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        })
+    }
+}
+
+impl Drop for EventSender {
+    fn drop(&mut self) {
+        let mut state = self.0.lock().unwrap();
+        state.closed = true;
+        if let Some(waker) = state.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+/// Receiving half of an event buffer.
+#[derive(Debug)]
+pub struct EventStream(Arc<Mutex<State>>);
+
+impl Stream for EventStream {
+    type Item = CentralEvent;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let mut state = self.0.lock().unwrap();
+        // The dropped events were older than anything still buffered, so report them first.
+        if state.lost > 0 {
+            return Poll::Ready(Some(CentralEvent::EventsLost(mem::take(&mut state.lost))));
+        }
+        match state.events.pop_front() {
+            Some(event) => Poll::Ready(Some(event)),
+            None if state.closed => Poll::Ready(None),
+            None => {
+                state.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl Drop for EventStream {
+    fn drop(&mut self) {
+        let mut state = self.0.lock().unwrap();
+        state.closed = true;
+        if let Some(waker) = state.close_waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+/// Events that only carry the latest state of a device, so that a newer one of the same kind can
+/// stand in for an older one.
+fn coalesce_key(event: &CentralEvent) -> Option<(Discriminant<CentralEvent>, &PeripheralId)> {
+    match event {
+        CentralEvent::DeviceUpdated { id, .. }
+        | CentralEvent::ManufacturerDataAdvertisement { id, .. }
+        | CentralEvent::ServiceDataAdvertisement { id, .. }
+        | CentralEvent::ServicesAdvertisement { id, .. } => Some((mem::discriminant(event), id)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::PropertiesDiff;
+    use crate::common::mock::peripheral_id;
+    use futures::executor::block_on;
+    use futures::stream::StreamExt;
+
+    fn config(capacity: usize, overflow: EventOverflowPolicy) -> EventBufferConfig {
+        EventBufferConfig { capacity, overflow }
+    }
+
+    fn updated(n: u8, rssi: Option<i16>) -> CentralEvent {
+        CentralEvent::DeviceUpdated {
+            id: peripheral_id(n),
+            changes: PropertiesDiff {
+                rssi,
+                ..Default::default()
+            },
+        }
+    }
+
+    fn received(sender: EventSender, stream: EventStream) -> Vec<String> {
+        drop(sender);
+        block_on(stream.collect::<Vec<_>>())
+            .into_iter()
+            .map(|event| match event {
+                CentralEvent::DeviceDiscovered(_) => "discovered".to_string(),
+                CentralEvent::DeviceUpdated { changes, .. } => {
+                    format!("updated {:?}", changes.rssi)
+                }
+                CentralEvent::ServicesAdvertisement { services, .. } => {
+                    format!("services {}", services.len())
+                }
+                CentralEvent::EventsLost(n) => format!("lost {}", n),
+                event => format!("{:?}", event),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn senders_learn_when_the_stream_is_dropped() {
+        let (sender, stream) = channel();
+        let mut closed = Box::pin(sender.closed());
+        assert!(futures::FutureExt::now_or_never(closed.as_mut()).is_none());
+        drop(stream);
+        block_on(closed);
+        assert!(!sender.send(
+            CentralEvent::DeviceDiscovered(peripheral_id(0)),
+            &EventBufferConfig::default()
+        ));
+    }
+
+    #[test]
+    fn drop_oldest_reports_lost_events() {
+        let config = config(2, EventOverflowPolicy::DropOldest);
+        let (sender, stream) = channel();
+        for n in 0..4 {
+            assert!(sender.send(CentralEvent::DeviceDiscovered(peripheral_id(n)), &config));
+        }
+        assert_eq!(
+            received(sender, stream),
+            vec!["lost 2", "discovered", "discovered"]
+        );
+    }
+
+    #[test]
+    fn coalesce_replaces_pending_state_of_the_same_device() {
+        let config = config(2, EventOverflowPolicy::CoalescePerDevice);
+        let (sender, stream) = channel();
+        let id = peripheral_id(1);
+        sender.send(CentralEvent::DeviceDiscovered(id.clone()), &config);
+        for n in 0..3 {
+            sender.send(
+                CentralEvent::ServicesAdvertisement {
+                    id: id.clone(),
+                    services: vec![Default::default(); n],
+                },
+                &config,
+            );
+        }
+        assert_eq!(received(sender, stream), vec!["discovered", "services 2"]);
+    }
+
+    #[test]
+    fn coalesce_falls_back_to_dropping_the_oldest() {
+        let config = config(2, EventOverflowPolicy::CoalescePerDevice);
+        let (sender, stream) = channel();
+        sender.send(CentralEvent::DeviceDiscovered(peripheral_id(1)), &config);
+        sender.send(updated(1, None), &config);
+        sender.send(updated(2, None), &config);
+        assert_eq!(
+            received(sender, stream),
+            vec!["lost 1", "updated None", "updated None"]
+        );
+    }
+
+    #[test]
+    fn coalesce_merges_the_changes_of_updates() {
+        let config = config(1, EventOverflowPolicy::CoalescePerDevice);
+        let (sender, stream) = channel();
+        let named = PropertiesDiff {
+            local_name: Some("name".to_string()),
+            rssi: Some(-60),
+            ..Default::default()
+        };
+        sender.send(
+            CentralEvent::DeviceUpdated {
+                id: peripheral_id(1),
+                changes: named,
+            },
+            &config,
+        );
+        sender.send(updated(1, Some(-50)), &config);
+        drop(sender);
+        let events = block_on(stream.collect::<Vec<_>>());
+        let [CentralEvent::DeviceUpdated { changes, .. }] = &events[..] else {
+            panic!("Unexpected events {:?}", events);
+        };
+        assert_eq!(changes.rssi, Some(-50));
+        assert_eq!(changes.local_name.as_deref(), Some("name"));
+    }
+
+    #[test]
+    fn capacity_must_not_be_zero() {
+        assert!(check_config(&config(0, EventOverflowPolicy::DropOldest)).is_err());
+        assert!(check_config(&EventBufferConfig::default()).is_ok());
+    }
+}
@@ -0,0 +1,316 @@
+// btleplug Source Code File
+//
+// Copyright 2020 Nonpolynomial. All rights reserved.
+//
+// Licensed under the BSD 3-Clause license. See LICENSE file in the project root
+// for full license information.
+
+//! Stand-ins for platform types in unit tests of the shared code.
+
+use super::scan_session::ScanSessions;
+use crate::api::{
+    self, AdapterInfo, AddressType, AdvertisementRecord, BDAddr, CentralEvent, CentralState,
+    Characteristic, Descriptor, EventBufferConfig, HonouredScanOptions, PairingOptions,
+    PeripheralProperties, ScanFilter, ScanOptions, ScanSession, Service, Subscription,
+    SubscriptionKind, ValueNotification, WriteType,
+};
+use crate::platform::PeripheralId;
+use crate::{Error, Result};
+use async_trait::async_trait;
+use futures::channel::mpsc;
+use futures::stream::{self, Stream};
+use std::collections::BTreeSet;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// A peripheral ID for unit tests, distinct for each value of `n`.
+pub fn peripheral_id(n: u8) -> PeripheralId {
+    #[cfg(target_os = "linux")]
+    {
+        // bluez_async only constructs device IDs itself, but they can be deserialized.
+        let object_path = format!("/org/bluez/hci0/dev_00_00_00_00_00_{:02X}", n);
+        let device_id = serde_json::json!({ "object_path": object_path });
+        serde_json::from_value::<bluez_async::DeviceId>(device_id)
+            .unwrap()
+            .into()
+    }
+    #[cfg(any(target_os = "windows", target_os = "android"))]
+    {
+        BDAddr::from([0, 0, 0, 0, 0, n]).into()
+    }
+    #[cfg(any(target_os = "macos", target_os = "ios", target_arch = "wasm32"))]
+    {
+        uuid::Uuid::from_u128(n.into()).into()
+    }
+}
+
+/// A peripheral that has no services. Connecting, disconnecting, subscribing and unsubscribing
+/// succeed and are recorded, so that the bookkeeping of connections and subscriptions can be
+/// tested.
+#[derive(Clone, Debug)]
+pub struct MockPeripheral {
+    id: PeripheralId,
+    address: BDAddr,
+    calls: Arc<Mutex<Vec<String>>>,
+    properties: Arc<Mutex<Option<PeripheralProperties>>>,
+    /// Whether the platform has forgotten the peripheral, so that its properties can't be read.
+    lost: Arc<AtomicBool>,
+    connected: Arc<AtomicBool>,
+}
+
+impl MockPeripheral {
+    pub fn new(n: u8) -> Self {
+        Self::with_address(n, BDAddr::default())
+    }
+
+    pub fn with_address(n: u8, address: BDAddr) -> Self {
+        Self {
+            id: peripheral_id(n),
+            address,
+            calls: Default::default(),
+            properties: Default::default(),
+            lost: Default::default(),
+            connected: Default::default(),
+        }
+    }
+
+    pub fn set_properties(&self, properties: PeripheralProperties) {
+        *self.properties.lock().unwrap() = Some(properties);
+    }
+
+    /// Makes reading the properties fail, as for a peripheral the platform has forgotten.
+    pub fn set_lost(&self) {
+        self.lost.store(true, Ordering::Relaxed);
+    }
+
+    /// The calls made so far, e.g. `connect` or `subscribe 0000…`.
+    pub fn calls(&self) -> Vec<String> {
+        self.calls.lock().unwrap().clone()
+    }
+
+    fn record(&self, call: &str, characteristic: &Characteristic) -> Result<()> {
+        self.calls
+            .lock()
+            .unwrap()
+            .push(format!("{} {}", call, characteristic.uuid));
+        Ok(())
+    }
+}
+
+fn not_connected<T>() -> Result<T> {
+    Err(Error::NotConnected)
+}
+
+#[async_trait]
+impl api::Peripheral for MockPeripheral {
+    fn id(&self) -> PeripheralId {
+        self.id.clone()
+    }
+
+    fn address(&self) -> BDAddr {
+        self.address
+    }
+
+    async fn properties(&self) -> Result<Option<PeripheralProperties>> {
+        if self.lost.load(Ordering::Relaxed) {
+            return Err(Error::DeviceNotFound);
+        }
+        Ok(self.properties.lock().unwrap().clone())
+    }
+
+    fn last_seen(&self) -> Option<Instant> {
+        None
+    }
+
+    fn advertisement_history(&self) -> Vec<AdvertisementRecord> {
+        Vec::new()
+    }
+
+    fn services(&self) -> BTreeSet<Service> {
+        BTreeSet::new()
+    }
+
+    async fn is_connected(&self) -> Result<bool> {
+        Ok(self.connected.load(Ordering::Relaxed))
+    }
+
+    async fn connect(&self) -> Result<()> {
+        self.connected.store(true, Ordering::Relaxed);
+        self.calls.lock().unwrap().push("connect".to_string());
+        Ok(())
+    }
+
+    async fn disconnect(&self) -> Result<()> {
+        self.connected.store(false, Ordering::Relaxed);
+        self.calls.lock().unwrap().push("disconnect".to_string());
+        Ok(())
+    }
+
+    async fn discover_services(&self) -> Result<()> {
+        not_connected()
+    }
+
+    async fn write(
+        &self,
+        _characteristic: &Characteristic,
+        _data: &[u8],
+        _write_type: WriteType,
+    ) -> Result<()> {
+        not_connected()
+    }
+
+    async fn read(&self, _characteristic: &Characteristic) -> Result<Vec<u8>> {
+        not_connected()
+    }
+
+    async fn subscribe(&self, characteristic: &Characteristic) -> Result<()> {
+        self.record("subscribe", characteristic)
+    }
+
+    async fn subscribe_with(
+        &self,
+        _characteristic: &Characteristic,
+        _kind: SubscriptionKind,
+    ) -> Result<()> {
+        not_connected()
+    }
+
+    async fn unsubscribe(&self, characteristic: &Characteristic) -> Result<()> {
+        self.record("unsubscribe", characteristic)
+    }
+
+    async fn notifications(&self) -> Result<Pin<Box<dyn Stream<Item = ValueNotification> + Send>>> {
+        Ok(Box::pin(stream::empty()))
+    }
+
+    async fn subscribe_stream(&self, _characteristic: &Characteristic) -> Result<Subscription> {
+        not_connected()
+    }
+
+    async fn write_descriptor(&self, _descriptor: &Descriptor, _data: &[u8]) -> Result<()> {
+        not_connected()
+    }
+
+    async fn read_descriptor(&self, _descriptor: &Descriptor) -> Result<Vec<u8>> {
+        not_connected()
+    }
+
+    async fn pair(&self, _options: PairingOptions) -> Result<()> {
+        not_connected()
+    }
+
+    async fn unpair(&self) -> Result<()> {
+        not_connected()
+    }
+
+    async fn is_bonded(&self) -> Result<bool> {
+        Ok(false)
+    }
+}
+
+/// A central whose peripherals and events are up to the test. Scanning is recorded.
+#[derive(Clone, Debug, Default)]
+pub struct MockCentral {
+    peripherals: Arc<Mutex<Vec<MockPeripheral>>>,
+    senders: Arc<Mutex<Vec<mpsc::UnboundedSender<CentralEvent>>>>,
+    calls: Arc<Mutex<Vec<String>>>,
+    scan_sessions: ScanSessions,
+}
+
+impl MockCentral {
+    pub fn add(&self, peripheral: MockPeripheral) {
+        self.peripherals.lock().unwrap().push(peripheral);
+    }
+
+    /// Sends an event to the streams returned by `events` so far.
+    pub fn emit(&self, event: CentralEvent) {
+        self.senders
+            .lock()
+            .unwrap()
+            .retain(|sender| sender.unbounded_send(event.clone()).is_ok());
+    }
+
+    /// The scans started and stopped so far, as `start_scan` and `stop_scan`.
+    pub fn calls(&self) -> Vec<String> {
+        self.calls.lock().unwrap().clone()
+    }
+}
+
+#[async_trait]
+impl api::Central for MockCentral {
+    type Peripheral = MockPeripheral;
+
+    async fn events(&self) -> Result<Pin<Box<dyn Stream<Item = CentralEvent> + Send>>> {
+        let (sender, receiver) = mpsc::unbounded();
+        self.senders.lock().unwrap().push(sender);
+        Ok(Box::pin(receiver))
+    }
+
+    async fn set_event_buffer(&self, _config: EventBufferConfig) -> Result<()> {
+        Ok(())
+    }
+
+    async fn set_peripheral_retention(&self, _retention: Option<Duration>) -> Result<()> {
+        Ok(())
+    }
+
+    async fn start_scan_with_options(
+        &self,
+        _filter: ScanFilter,
+        _options: ScanOptions,
+    ) -> Result<HonouredScanOptions> {
+        self.calls.lock().unwrap().push("start_scan".to_string());
+        Ok(HonouredScanOptions::default())
+    }
+
+    async fn stop_scan(&self) -> Result<()> {
+        self.calls.lock().unwrap().push("stop_scan".to_string());
+        Ok(())
+    }
+
+    async fn scan(&self, filter: ScanFilter) -> Result<ScanSession<MockPeripheral>> {
+        self.scan_sessions.scan(self, filter).await
+    }
+
+    async fn peripherals(&self) -> Result<Vec<MockPeripheral>> {
+        Ok(self.peripherals.lock().unwrap().clone())
+    }
+
+    async fn peripheral(&self, id: &PeripheralId) -> Result<MockPeripheral> {
+        self.peripherals
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|p| p.id == *id)
+            .cloned()
+            .ok_or(Error::DeviceNotFound)
+    }
+
+    async fn add_peripheral(&self, _address: &PeripheralId) -> Result<MockPeripheral> {
+        Err(Error::NotSupported("Adding peripherals".to_string()))
+    }
+
+    async fn peripheral_by_address(
+        &self,
+        address: BDAddr,
+        _address_type: AddressType,
+    ) -> Result<MockPeripheral> {
+        self.peripherals
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|p| p.address == address)
+            .cloned()
+            .ok_or(Error::DeviceNotFound)
+    }
+
+    async fn state(&self) -> Result<CentralState> {
+        Ok(CentralState::PoweredOn)
+    }
+
+    async fn adapter_info(&self) -> Result<AdapterInfo> {
+        Ok(AdapterInfo::new("mock"))
+    }
+}
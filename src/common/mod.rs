@@ -1,2 +1,11 @@
+#[cfg(any(not(target_os = "linux"), test))]
 pub mod adapter_manager;
+pub mod advertisement_history;
+pub mod event_buffer;
+#[cfg(test)]
+pub(crate) mod mock;
+pub mod scan_filter;
+pub mod scan_session;
+pub mod subscription;
+pub mod timeout;
 pub mod util;
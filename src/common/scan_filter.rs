@@ -0,0 +1,416 @@
+// btleplug Source Code File
+//
+// Copyright 2020 Nonpolynomial Labs LLC. All rights reserved.
+//
+// Licensed under the BSD 3-Clause license. See LICENSE file in the project root
+// for full license information.
+
+//! Applying a [`ScanFilter`] in software, for the parts of it the platform can't filter by.
+
+use crate::api::{
+    Central, CentralEvent, Peripheral as _, PeripheralProperties, ScanCondition, ScanFilter,
+};
+use crate::platform::PeripheralId;
+use crate::{Error, Result};
+use futures::stream::{self, Stream, StreamExt};
+use regex::Regex;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex, RwLock};
+use uuid::Uuid;
+
+/// A [`ScanFilter`] ready to be matched against devices.
+#[derive(Debug)]
+pub struct ScanMatcher {
+    filter: ScanFilter,
+    /// The compiled name patterns of the filter, by their source.
+    patterns: HashMap<String, Regex>,
+}
+
+impl ScanMatcher {
+    /// Checks the filter, returning `None` if it lets every device through.
+    pub fn new(filter: &ScanFilter) -> Result<Option<Self>> {
+        if filter.services.is_empty() && filter.condition.is_none() {
+            return Ok(None);
+        }
+        let mut patterns = HashMap::new();
+        if let Some(condition) = &filter.condition {
+            compile(condition, &mut patterns)?;
+        }
+        Ok(Some(Self {
+            filter: filter.clone(),
+            patterns,
+        }))
+    }
+
+    pub fn matches(&self, properties: &PeripheralProperties) -> bool {
+        let services = &self.filter.services;
+        (services.is_empty()
+            || services
+                .iter()
+                .any(|service| advertises_service(properties, service)))
+            && self
+                .filter
+                .condition
+                .as_ref()
+                .is_none_or(|condition| self.condition_matches(condition, properties))
+    }
+
+    fn condition_matches(
+        &self,
+        condition: &ScanCondition,
+        properties: &PeripheralProperties,
+    ) -> bool {
+        let name = properties.local_name.as_deref();
+        match condition {
+            ScanCondition::NameEquals(expected) => name == Some(expected.as_str()),
+            ScanCondition::NamePrefix(prefix) => {
+                name.is_some_and(|name| name.starts_with(prefix.as_str()))
+            }
+            ScanCondition::NameMatches(pattern) => {
+                name.is_some_and(|name| self.patterns[pattern].is_match(name))
+            }
+            ScanCondition::Service(service) => advertises_service(properties, service),
+            ScanCondition::ManufacturerData {
+                company_id,
+                data,
+                mask,
+            } => properties
+                .manufacturer_data
+                .get(company_id)
+                .is_some_and(|advertised| data_matches(advertised, data, mask)),
+            ScanCondition::ServiceData { uuid, data, mask } => properties
+                .service_data
+                .get(uuid)
+                .is_some_and(|advertised| data_matches(advertised, data, mask)),
+            ScanCondition::Address(addresses) => addresses.contains(&properties.address),
+            ScanCondition::MinRssi(min) => properties.rssi.is_some_and(|rssi| rssi >= *min),
+            ScanCondition::AddressType(address_type) => {
+                properties.address_type == Some(*address_type)
+            }
+            ScanCondition::All(conditions) => conditions
+                .iter()
+                .all(|condition| self.condition_matches(condition, properties)),
+            ScanCondition::Any(conditions) => conditions
+                .iter()
+                .any(|condition| self.condition_matches(condition, properties)),
+        }
+    }
+}
+
+/// Checks a condition, compiling its name patterns into `patterns`.
+fn compile(condition: &ScanCondition, patterns: &mut HashMap<String, Regex>) -> Result<()> {
+    match condition {
+        ScanCondition::NameMatches(pattern) => {
+            let regex = Regex::new(pattern).map_err(|e| {
+                Error::NotSupported(format!("Invalid name pattern {:?}: {}", pattern, e))
+            })?;
+            patterns.insert(pattern.clone(), regex);
+        }
+        ScanCondition::ManufacturerData { data, mask, .. }
+        | ScanCondition::ServiceData { data, mask, .. }
+            if !mask.is_empty() && mask.len() != data.len() =>
+        {
+            return Err(Error::NotSupported(
+                "A data mask must be as long as the data".to_string(),
+            ));
+        }
+        ScanCondition::All(conditions) | ScanCondition::Any(conditions) => {
+            for condition in conditions {
+                compile(condition, patterns)?;
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+fn advertises_service(properties: &PeripheralProperties, service: &Uuid) -> bool {
+    properties.services.contains(service) || properties.service_data.contains_key(service)
+}
+
+fn data_matches(advertised: &[u8], data: &[u8], mask: &[u8]) -> bool {
+    advertised.len() >= data.len()
+        && data
+            .iter()
+            .zip(advertised)
+            .enumerate()
+            .all(|(i, (expected, actual))| {
+                let mask = mask.get(i).copied().unwrap_or(0xff);
+                expected & mask == actual & mask
+            })
+}
+
+/// The devices an event stream has let events through for, and those it has held events back
+/// for, so that a device that only starts matching the filter after being discovered is announced
+/// with [`CentralEvent::DeviceDiscovered`] then.
+#[derive(Debug, Default)]
+struct Announced {
+    emitted: HashSet<PeripheralId>,
+    withheld: HashSet<PeripheralId>,
+}
+
+impl Announced {
+    /// The events to emit for an event about a device, given whether it matches the filter, or
+    /// `None` if there is no filter.
+    fn admit(
+        &mut self,
+        id: &PeripheralId,
+        event: CentralEvent,
+        matches: Option<bool>,
+    ) -> Vec<CentralEvent> {
+        match matches {
+            Some(false) => {
+                self.withheld.insert(id.clone());
+                return Vec::new();
+            }
+            Some(true) => {
+                let started_matching = self.withheld.remove(id);
+                if self.emitted.insert(id.clone())
+                    && started_matching
+                    && !matches!(event, CentralEvent::DeviceDiscovered(_))
+                {
+                    return vec![CentralEvent::DeviceDiscovered(id.clone()), event];
+                }
+            }
+            None => {
+                self.withheld.remove(id);
+                self.emitted.insert(id.clone());
+            }
+        }
+        vec![event]
+    }
+
+    /// Whether to emit an event that a device is gone, which is withheld if all events about the
+    /// device were.
+    fn forget(&mut self, id: &PeripheralId) -> bool {
+        let emitted = self.emitted.remove(id);
+        let withheld = self.withheld.remove(id);
+        emitted || !withheld
+    }
+}
+
+/// The filter of an adapter's current scan, shared with its event streams.
+#[derive(Clone, Debug, Default)]
+pub struct ActiveScanFilter(Arc<RwLock<Option<Arc<ScanMatcher>>>>);
+
+impl ActiveScanFilter {
+    /// Checks and sets the filter of a new scan.
+    pub fn set(&self, filter: &ScanFilter) -> Result<()> {
+        let matcher = ScanMatcher::new(filter)?;
+        *self.0.write().unwrap() = matcher.map(Arc::new);
+        Ok(())
+    }
+
+    /// Leaves out the events about devices that don't match the current filter. Connection events
+    /// are always let through, as connecting is up to the application.
+    pub fn filter_events<C: Central + 'static>(
+        &self,
+        central: C,
+        events: impl Stream<Item = CentralEvent> + Send + 'static,
+    ) -> impl Stream<Item = CentralEvent> + Send + 'static {
+        let filter = self.clone();
+        let announced = Arc::new(Mutex::new(Announced::default()));
+        events
+            .then(move |event| {
+                let filter = filter.clone();
+                let central = central.clone();
+                let announced = announced.clone();
+                async move {
+                    let id = match &event {
+                        CentralEvent::DeviceDiscovered(id)
+                        | CentralEvent::DeviceUpdated { id, .. }
+                        | CentralEvent::ManufacturerDataAdvertisement { id, .. }
+                        | CentralEvent::ServiceDataAdvertisement { id, .. }
+                        | CentralEvent::ServicesAdvertisement { id, .. } => id.clone(),
+                        CentralEvent::DeviceLost(id) => {
+                            let announced = announced.lock().unwrap().forget(id);
+                            return if announced { vec![event] } else { Vec::new() };
+                        }
+                        _ => return vec![event],
+                    };
+                    let matcher = filter.0.read().unwrap().clone();
+                    let matches = match matcher {
+                        Some(matcher) => Some(matches_device(&central, &id, &matcher).await),
+                        None => None,
+                    };
+                    announced.lock().unwrap().admit(&id, event, matches)
+                }
+            })
+            .flat_map(stream::iter)
+    }
+}
+
+async fn matches_device<C: Central>(central: &C, id: &PeripheralId, matcher: &ScanMatcher) -> bool {
+    let Ok(peripheral) = central.peripheral(id).await else {
+        return false;
+    };
+    match peripheral.properties().await {
+        Ok(Some(properties)) => matcher.matches(&properties),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::{AddressType, BDAddr};
+    use crate::common::mock::peripheral_id;
+
+    fn matcher(condition: ScanCondition) -> ScanMatcher {
+        ScanMatcher::new(&ScanFilter {
+            condition: Some(condition),
+            ..Default::default()
+        })
+        .unwrap()
+        .unwrap()
+    }
+
+    fn device() -> PeripheralProperties {
+        PeripheralProperties {
+            address: BDAddr::from([1, 2, 3, 4, 5, 6]),
+            address_type: Some(AddressType::Random),
+            local_name: Some("Thermo 42".to_string()),
+            rssi: Some(-70),
+            manufacturer_data: HashMap::from([(0x0059, vec![0x01, 0x02, 0x03])]),
+            service_data: HashMap::from([(Uuid::from_u128(0xfeaa), vec![0x10, 0xee])]),
+            services: vec![Uuid::from_u128(0x180f)],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn empty_filter_lets_everything_through() {
+        assert!(ScanMatcher::new(&ScanFilter::default()).unwrap().is_none());
+    }
+
+    #[test]
+    fn services() {
+        let filter = |services| ScanFilter {
+            services,
+            condition: None,
+        };
+        let matches = |services| {
+            ScanMatcher::new(&filter(services))
+                .unwrap()
+                .unwrap()
+                .matches(&device())
+        };
+        assert!(matches(vec![Uuid::from_u128(1), Uuid::from_u128(0x180f)]));
+        assert!(matches(vec![Uuid::from_u128(0xfeaa)]));
+        assert!(!matches(vec![Uuid::from_u128(1)]));
+        assert!(!matcher(ScanCondition::All(vec![
+            ScanCondition::Service(Uuid::from_u128(0x180f)),
+            ScanCondition::Service(Uuid::from_u128(1)),
+        ]))
+        .matches(&device()));
+    }
+
+    #[test]
+    fn names() {
+        let device = device();
+        assert!(matcher(ScanCondition::NameEquals("Thermo 42".to_string())).matches(&device));
+        assert!(!matcher(ScanCondition::NameEquals("Thermo".to_string())).matches(&device));
+        assert!(matcher(ScanCondition::NamePrefix("Thermo".to_string())).matches(&device));
+        assert!(matcher(ScanCondition::NameMatches(r"^Thermo \d+$".to_string())).matches(&device));
+        assert!(!matcher(ScanCondition::NameMatches("^Hygro".to_string())).matches(&device));
+        assert!(ScanMatcher::new(&ScanFilter {
+            condition: Some(ScanCondition::NameMatches("(".to_string())),
+            ..Default::default()
+        })
+        .is_err());
+    }
+
+    #[test]
+    fn masked_data() {
+        let device = device();
+        let manufacturer_data = |data: Vec<u8>, mask: Vec<u8>| {
+            matcher(ScanCondition::ManufacturerData {
+                company_id: 0x0059,
+                data,
+                mask,
+            })
+            .matches(&device)
+        };
+        assert!(manufacturer_data(vec![0x01, 0x02], vec![]));
+        assert!(manufacturer_data(vec![0x01, 0xff], vec![0xff, 0x00]));
+        assert!(!manufacturer_data(vec![0x01, 0xff], vec![]));
+        assert!(!manufacturer_data(vec![0x01, 0x02, 0x03, 0x04], vec![]));
+        assert!(matcher(ScanCondition::ServiceData {
+            uuid: Uuid::from_u128(0xfeaa),
+            data: vec![0x10],
+            mask: vec![0xf0],
+        })
+        .matches(&device));
+        assert!(ScanMatcher::new(&ScanFilter {
+            condition: Some(ScanCondition::ServiceData {
+                uuid: Uuid::from_u128(0xfeaa),
+                data: vec![0x10],
+                mask: vec![0xf0, 0xf0],
+            }),
+            ..Default::default()
+        })
+        .is_err());
+    }
+
+    #[test]
+    fn combined_conditions() {
+        let device = device();
+        let address = ScanCondition::Address(vec![BDAddr::from([1, 2, 3, 4, 5, 6])]);
+        let strong = ScanCondition::MinRssi(-60);
+        let random = ScanCondition::AddressType(AddressType::Random);
+        assert!(
+            matcher(ScanCondition::All(vec![address.clone(), random.clone()])).matches(&device)
+        );
+        assert!(
+            !matcher(ScanCondition::All(vec![address.clone(), strong.clone()])).matches(&device)
+        );
+        assert!(matcher(ScanCondition::Any(vec![strong.clone(), random])).matches(&device));
+        assert!(!matcher(ScanCondition::Any(vec![strong])).matches(&device));
+        assert!(matcher(ScanCondition::All(vec![])).matches(&device));
+        assert!(!matcher(ScanCondition::Any(vec![])).matches(&device));
+    }
+
+    #[test]
+    fn devices_are_announced_once_they_match() {
+        let mut announced = Announced::default();
+        let mut admit = |n, event, matches| {
+            announced
+                .admit(&peripheral_id(n), event, matches)
+                .iter()
+                .map(|event| match event {
+                    CentralEvent::DeviceDiscovered(_) => "discovered",
+                    CentralEvent::DeviceUpdated { .. } => "updated",
+                    _ => "other",
+                })
+                .collect::<Vec<_>>()
+        };
+        let updated = |n| CentralEvent::DeviceUpdated {
+            id: peripheral_id(n),
+            changes: Default::default(),
+        };
+        // Without a filter, events are let through as they are.
+        assert_eq!(admit(1, updated(1), None), vec!["updated"]);
+
+        let discovered = CentralEvent::DeviceDiscovered(peripheral_id(2));
+        assert!(admit(2, discovered, Some(false)).is_empty());
+        assert_eq!(
+            admit(2, updated(2), Some(true)),
+            vec!["discovered", "updated"]
+        );
+        assert_eq!(admit(2, updated(2), Some(true)), vec!["updated"]);
+        assert!(admit(2, updated(2), Some(false)).is_empty());
+        assert_eq!(admit(2, updated(2), Some(true)), vec!["updated"]);
+
+        // A device that matched from the first event the stream saw about it was discovered
+        // before the stream started.
+        assert_eq!(admit(3, updated(3), Some(true)), vec!["updated"]);
+        assert!(admit(4, updated(4), Some(false)).is_empty());
+
+        assert!(announced.forget(&peripheral_id(1)));
+        assert!(announced.forget(&peripheral_id(2)));
+        assert!(announced.forget(&peripheral_id(3)));
+        assert!(!announced.forget(&peripheral_id(4)));
+        // Neither is a device the stream knows nothing about.
+        assert!(announced.forget(&peripheral_id(5)));
+    }
+}
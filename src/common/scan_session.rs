@@ -0,0 +1,305 @@
+// btleplug Source Code File
+//
+// Copyright 2020 Nonpolynomial Labs LLC. All rights reserved.
+//
+// Licensed under the BSD 3-Clause license. See LICENSE file in the project root
+// for full license information.
+
+//! Bookkeeping of [`ScanSession`]s, shared by all backends: which sessions of an adapter are
+//! open, and the merged filter the adapter scans with for them.
+
+use super::scan_filter::ScanMatcher;
+use super::subscription::spawn_detached;
+use crate::api::{
+    Central, CentralEvent, Peripheral as _, PeripheralProperties, ScanCondition, ScanFilter,
+    ScanSession,
+};
+use crate::platform::PeripheralId;
+use crate::Result;
+use futures::future::BoxFuture;
+use futures::stream::StreamExt;
+use log::{trace, warn};
+use std::collections::{BTreeMap, HashSet};
+use std::sync::{Arc, Mutex};
+use tokio::runtime::Handle;
+
+/// Keeps the adapter scanning for a session. Once dropped, the session is closed, stopping the
+/// scan if it was the last one.
+pub struct ScanSessionHandle {
+    close: Option<BoxFuture<'static, ()>>,
+    /// The runtime the session was opened on, as the handle may be dropped outside of one.
+    runtime: Option<Handle>,
+}
+
+impl Drop for ScanSessionHandle {
+    fn drop(&mut self) {
+        if let Some(close) = self.close.take() {
+            spawn_detached(self.runtime.as_ref(), close);
+        }
+    }
+}
+
+/// The open scan sessions of an adapter.
+#[derive(Clone, Debug, Default)]
+pub struct ScanSessions(Arc<tokio::sync::Mutex<OpenSessions>>);
+
+#[derive(Debug, Default)]
+struct OpenSessions {
+    next_id: u64,
+    filters: BTreeMap<u64, ScanFilter>,
+    /// The filter the adapter was last told to scan with for the sessions, if any. Scans started
+    /// or stopped directly with [`Central::start_scan`] and [`Central::stop_scan`] aren't tracked,
+    /// so they replace the sessions' scan until a session is opened or closed.
+    scanning: Option<ScanFilter>,
+}
+
+impl OpenSessions {
+    /// Restarts the scan if the sessions need a different filter, or stops it if there are none.
+    async fn apply<C: Central>(&mut self, central: &C) -> Result<()> {
+        let filter = merge(self.filters.values());
+        if filter == self.scanning {
+            return Ok(());
+        }
+        if self.scanning.take().is_some() {
+            central.stop_scan().await?;
+        }
+        if let Some(filter) = &filter {
+            central.start_scan(filter.clone()).await?;
+        }
+        self.scanning = filter;
+        Ok(())
+    }
+}
+
+impl ScanSessions {
+    /// Opens a session on the central, scanning for the devices matching the filter.
+    pub async fn scan<C: Central + 'static>(
+        &self,
+        central: &C,
+        filter: ScanFilter,
+    ) -> Result<ScanSession<C::Peripheral>> {
+        let matcher = ScanMatcher::new(&filter)?.map(Arc::new);
+        // Grab the event stream before scanning so that no early discovery is missed.
+        let events = central.events().await?;
+
+        // Holding the lock across starting the scan stops concurrent callers from restarting it
+        // with filters that leave out each other's devices.
+        let mut sessions = self.0.lock().await;
+        let id = sessions.next_id;
+        sessions.next_id += 1;
+        sessions.filters.insert(id, filter);
+        if let Err(e) = sessions.apply(central).await {
+            sessions.filters.remove(&id);
+            return Err(e);
+        }
+        drop(sessions);
+
+        let close_sessions = self.clone();
+        let close_central = central.clone();
+        let handle = ScanSessionHandle {
+            close: Some(Box::pin(async move {
+                let mut sessions = close_sessions.0.lock().await;
+                sessions.filters.remove(&id);
+                match sessions.apply(&close_central).await {
+                    Ok(()) => trace!("Closed scan session {}", id),
+                    Err(e) => warn!("Failed to update the scan for closed session {}: {}", id, e),
+                }
+            })),
+            runtime: Handle::try_current().ok(),
+        };
+
+        let central = central.clone();
+        let yielded = Arc::new(Mutex::new(HashSet::new()));
+        let peripherals = events.filter_map(move |event| {
+            let central = central.clone();
+            let matcher = matcher.clone();
+            let yielded = yielded.clone();
+            async move {
+                let id = match event {
+                    CentralEvent::DeviceDiscovered(id)
+                    | CentralEvent::DeviceUpdated { id, .. }
+                    | CentralEvent::ManufacturerDataAdvertisement { id, .. }
+                    | CentralEvent::ServiceDataAdvertisement { id, .. }
+                    | CentralEvent::ServicesAdvertisement { id, .. } => id,
+                    CentralEvent::DeviceLost(id) => {
+                        yielded.lock().unwrap().remove(&id);
+                        return None;
+                    }
+                    _ => return None,
+                };
+                if yielded.lock().unwrap().contains(&id) {
+                    return None;
+                }
+                let (peripheral, properties) = matching_peripheral(&central, &id).await?;
+                if !matcher.is_none_or(|matcher| matcher.matches(&properties)) {
+                    return None;
+                }
+                yielded
+                    .lock()
+                    .unwrap()
+                    .insert(id)
+                    .then_some((peripheral, properties))
+            }
+        });
+        Ok(ScanSession::new(Box::pin(peripherals), handle))
+    }
+}
+
+async fn matching_peripheral<C: Central>(
+    central: &C,
+    id: &PeripheralId,
+) -> Option<(C::Peripheral, PeripheralProperties)> {
+    let peripheral = central.peripheral(id).await.ok()?;
+    let properties = peripheral.properties().await.ok()??;
+    Some((peripheral, properties))
+}
+
+/// A filter letting through the devices matching any of the given ones, or `None` if there are
+/// none.
+fn merge<'a>(filters: impl Iterator<Item = &'a ScanFilter> + Clone) -> Option<ScanFilter> {
+    let mut all = filters.clone();
+    let first = all.next()?;
+    if all.next().is_none() {
+        return Some(first.clone());
+    }
+    if filters
+        .clone()
+        .any(|filter| filter.services.is_empty() && filter.condition.is_none())
+    {
+        return Some(ScanFilter::default());
+    }
+    if filters.clone().all(|filter| filter.condition.is_none()) {
+        // Keep filtering natively by services where the platform can.
+        let mut services = Vec::new();
+        for service in filters.flat_map(|filter| &filter.services) {
+            if !services.contains(service) {
+                services.push(*service);
+            }
+        }
+        return Some(ScanFilter {
+            services,
+            condition: None,
+        });
+    }
+    Some(ScanFilter {
+        services: vec![],
+        condition: Some(ScanCondition::Any(filters.map(as_condition).collect())),
+    })
+}
+
+/// The filter as a single condition.
+fn as_condition(filter: &ScanFilter) -> ScanCondition {
+    let services = ScanCondition::Any(
+        filter
+            .services
+            .iter()
+            .copied()
+            .map(ScanCondition::Service)
+            .collect(),
+    );
+    match (filter.services.is_empty(), &filter.condition) {
+        (true, Some(condition)) => condition.clone(),
+        (false, None) => services,
+        (false, Some(condition)) => ScanCondition::All(vec![services, condition.clone()]),
+        (true, None) => ScanCondition::All(vec![]),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::bleuuid::uuid_from_u16;
+    use crate::common::mock::{peripheral_id, MockCentral, MockPeripheral};
+
+    fn services(services: &[u16]) -> ScanFilter {
+        ScanFilter {
+            services: services.iter().copied().map(uuid_from_u16).collect(),
+            condition: None,
+        }
+    }
+
+    fn name(prefix: &str) -> ScanFilter {
+        ScanFilter {
+            services: vec![],
+            condition: Some(ScanCondition::NamePrefix(prefix.to_string())),
+        }
+    }
+
+    #[test]
+    fn merges_services() {
+        assert_eq!(merge([].iter()), None);
+        assert_eq!(merge([name("a")].iter()), Some(name("a")));
+        assert_eq!(
+            merge([services(&[1, 2]), services(&[2, 3])].iter()),
+            Some(services(&[1, 2, 3]))
+        );
+        assert_eq!(
+            merge([services(&[1]), ScanFilter::default()].iter()),
+            Some(ScanFilter::default())
+        );
+    }
+
+    #[test]
+    fn merges_conditions() {
+        let mut both = services(&[1]);
+        both.condition = Some(ScanCondition::MinRssi(-70));
+        assert_eq!(
+            merge([name("a"), both, services(&[2])].iter()),
+            Some(ScanFilter {
+                services: vec![],
+                condition: Some(ScanCondition::Any(vec![
+                    ScanCondition::NamePrefix("a".to_string()),
+                    ScanCondition::All(vec![
+                        ScanCondition::Any(vec![ScanCondition::Service(uuid_from_u16(1))]),
+                        ScanCondition::MinRssi(-70),
+                    ]),
+                    ScanCondition::Any(vec![ScanCondition::Service(uuid_from_u16(2))]),
+                ])),
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn scans_until_the_last_session_is_closed() {
+        let central = MockCentral::default();
+        let first = central.scan(services(&[1])).await.unwrap();
+        let second = central.scan(services(&[2])).await.unwrap();
+        assert_eq!(
+            central.calls(),
+            vec!["start_scan", "stop_scan", "start_scan"]
+        );
+
+        drop(first);
+        tokio::task::yield_now().await;
+        assert_eq!(central.calls().last().unwrap(), "start_scan");
+        drop(second);
+        tokio::task::yield_now().await;
+        assert_eq!(central.calls().last().unwrap(), "stop_scan");
+    }
+
+    #[tokio::test]
+    async fn sessions_yield_matching_peripherals() {
+        let central = MockCentral::default();
+        for n in 1..=2 {
+            let peripheral = MockPeripheral::new(n);
+            peripheral.set_properties(PeripheralProperties {
+                services: vec![uuid_from_u16(n.into())],
+                ..Default::default()
+            });
+            central.add(peripheral);
+        }
+        let mut session = central.scan(services(&[2])).await.unwrap();
+
+        for n in [1, 2, 2] {
+            central.emit(CentralEvent::DeviceDiscovered(peripheral_id(n)));
+        }
+        central.emit(CentralEvent::DeviceLost(peripheral_id(2)));
+        central.emit(CentralEvent::DeviceDiscovered(peripheral_id(2)));
+        // Each time the device is found, it is yielded once.
+        for _ in 0..2 {
+            let (peripheral, properties) = session.next().await.unwrap();
+            assert_eq!(peripheral.id(), peripheral_id(2));
+            assert_eq!(properties.services, vec![uuid_from_u16(2)]);
+        }
+    }
+}
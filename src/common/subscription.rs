@@ -0,0 +1,265 @@
+// btleplug Source Code File
+//
+// Copyright 2020 Nonpolynomial. All rights reserved.
+//
+// Licensed under the BSD 3-Clause license. See LICENSE file in the project root
+// for full license information.
+
+//! Bookkeeping of characteristic subscriptions, shared by all backends: which kind of value
+//! updates is enabled on each characteristic, and reference counting for
+//! [`Peripheral::subscribe_stream`](crate::api::Peripheral::subscribe_stream).
+
+use crate::api::{Characteristic, Peripheral, Subscription, SubscriptionKind};
+use crate::Result;
+use futures::future::BoxFuture;
+use log::{trace, warn};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, Weak};
+use tokio::runtime::Handle;
+use uuid::Uuid;
+
+/// Keeps notify/indicate enabled on a characteristic. Once the last [`Subscription`] holding it is
+/// dropped, the characteristic is unsubscribed.
+pub struct SubscriptionHandle {
+    unsubscribe: Mutex<Option<BoxFuture<'static, ()>>>,
+    /// The runtime the subscription was made on, as the handle may be dropped outside of one.
+    runtime: Option<Handle>,
+}
+
+impl Drop for SubscriptionHandle {
+    fn drop(&mut self) {
+        if let Some(unsubscribe) = self.unsubscribe.get_mut().unwrap().take() {
+            spawn_detached(self.runtime.as_ref(), unsubscribe);
+        }
+    }
+}
+
+/// The live handles of a peripheral, by service and characteristic UUID.
+type Handles = HashMap<(Uuid, Uuid), Weak<SubscriptionHandle>>;
+
+/// Tracks the subscriptions of a single peripheral: the kind of value updates enabled on each
+/// characteristic, and the live [`SubscriptionHandle`]s keyed by service and characteristic UUID.
+#[derive(Debug, Default)]
+pub struct SubscriptionTracker {
+    handles: Arc<tokio::sync::Mutex<Handles>>,
+    kinds: Mutex<HashMap<(Uuid, Uuid), SubscriptionKind>>,
+}
+
+impl SubscriptionTracker {
+    /// Records the kind of value updates enabled on the characteristic.
+    pub fn set_kind(&self, characteristic: &Characteristic, kind: SubscriptionKind) {
+        self.kinds
+            .lock()
+            .unwrap()
+            .insert((characteristic.service_uuid, characteristic.uuid), kind);
+    }
+
+    pub fn clear_kind(&self, characteristic: &Characteristic) {
+        self.kinds
+            .lock()
+            .unwrap()
+            .remove(&(characteristic.service_uuid, characteristic.uuid));
+    }
+
+    /// Whether value updates are enabled on any characteristic.
+    pub fn any_enabled(&self) -> bool {
+        !self.kinds.lock().unwrap().is_empty()
+    }
+
+    /// The kind of value updates enabled on the characteristic with the given service and
+    /// characteristic UUID. Values for a characteristic we haven't subscribed to ourselves are
+    /// assumed to be notifications.
+    pub fn kind(&self, service_uuid: Uuid, characteristic_uuid: Uuid) -> SubscriptionKind {
+        self.kinds
+            .lock()
+            .unwrap()
+            .get(&(service_uuid, characteristic_uuid))
+            .copied()
+            .unwrap_or(SubscriptionKind::Notify)
+    }
+
+    pub async fn subscribe<P>(
+        &self,
+        peripheral: &P,
+        characteristic: &Characteristic,
+    ) -> Result<Subscription>
+    where
+        P: Peripheral + 'static,
+    {
+        // Grab the notification stream before enabling notify so that no early value is missed.
+        let notifications = peripheral.notifications().await?;
+
+        // Holding the lock across the subscribe call stops two concurrent callers from both
+        // enabling notify and then tearing down each other's registration.
+        let mut handles = self.handles.lock().await;
+        let key = (characteristic.service_uuid, characteristic.uuid);
+        let handle = match handles.get(&key).and_then(Weak::upgrade) {
+            Some(handle) => handle,
+            None => {
+                peripheral.subscribe(characteristic).await?;
+                let unsubscribe_peripheral = peripheral.clone();
+                let unsubscribe_characteristic = characteristic.clone();
+                let unsubscribe_handles = self.handles.clone();
+                let handle = Arc::new(SubscriptionHandle {
+                    unsubscribe: Mutex::new(Some(Box::pin(async move {
+                        // Someone may have subscribed again since the last handle was dropped,
+                        // and that subscription mustn't be torn down. Holding the lock stops
+                        // anyone from subscribing while this unsubscribes.
+                        let handles = unsubscribe_handles.lock().await;
+                        if handles
+                            .get(&key)
+                            .is_some_and(|handle| handle.strong_count() > 0)
+                        {
+                            return;
+                        }
+                        match unsubscribe_peripheral
+                            .unsubscribe(&unsubscribe_characteristic)
+                            .await
+                        {
+                            Ok(()) => trace!("Unsubscribed from {}", unsubscribe_characteristic),
+                            Err(e) => warn!(
+                                "Failed to unsubscribe from {}: {}",
+                                unsubscribe_characteristic, e
+                            ),
+                        }
+                    }))),
+                    runtime: Handle::try_current().ok(),
+                });
+                handles.insert(key, Arc::downgrade(&handle));
+                handle
+            }
+        };
+        handles.retain(|_, handle| handle.strong_count() > 0);
+
+        Ok(Subscription::new(
+            characteristic.clone(),
+            notifications,
+            handle,
+        ))
+    }
+}
+
+/// Runs a future in the background, from a context that can't await it, such as `drop`. It runs
+/// on the current runtime, or else on the given one, which the work was set up on. The backends'
+/// futures need a runtime, so without either the work is skipped.
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) fn spawn_detached(runtime: Option<&Handle>, future: BoxFuture<'static, ()>) {
+    match Handle::try_current().ok().as_ref().or(runtime) {
+        Some(runtime) => {
+            runtime.spawn(future);
+        }
+        None => warn!("Dropped outside of a Tokio runtime, so the cleanup is skipped"),
+    }
+}
+
+#[cfg(target_arch = "wasm32")]
+pub(crate) fn spawn_detached(_runtime: Option<&Handle>, future: BoxFuture<'static, ()>) {
+    wasm_bindgen_futures::spawn_local(future);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::{CharPropFlags, ValueNotification};
+    use crate::common::mock::MockPeripheral;
+    use futures::stream::{self, StreamExt};
+    use std::collections::BTreeSet;
+    use std::time::Instant;
+
+    fn characteristic(service: u128) -> Characteristic {
+        Characteristic {
+            uuid: Uuid::from_u128(0x2a37),
+            service_uuid: Uuid::from_u128(service),
+            properties: CharPropFlags::NOTIFY,
+            descriptors: BTreeSet::new(),
+        }
+    }
+
+    #[tokio::test]
+    async fn stale_unsubscribe_keeps_a_new_subscription() {
+        let peripheral = MockPeripheral::new(1);
+        let tracker = SubscriptionTracker::default();
+        let characteristic = characteristic(0x180d);
+        let subscribe = format!("subscribe {}", characteristic.uuid);
+        let unsubscribe = format!("unsubscribe {}", characteristic.uuid);
+
+        let subscription = tracker
+            .subscribe(&peripheral, &characteristic)
+            .await
+            .unwrap();
+        let shared = tracker
+            .subscribe(&peripheral, &characteristic)
+            .await
+            .unwrap();
+        drop(subscription);
+        tokio::task::yield_now().await;
+        assert_eq!(peripheral.calls(), vec![subscribe.clone()]);
+
+        // Subscribing again before the unsubscribe for the last handle has run.
+        drop(shared);
+        let subscription = tracker
+            .subscribe(&peripheral, &characteristic)
+            .await
+            .unwrap();
+        tokio::task::yield_now().await;
+        assert_eq!(
+            peripheral.calls(),
+            vec![subscribe.clone(), subscribe.clone()]
+        );
+
+        drop(subscription);
+        tokio::task::yield_now().await;
+        assert_eq!(
+            peripheral.calls(),
+            vec![subscribe.clone(), subscribe, unsubscribe]
+        );
+    }
+
+    #[test]
+    fn kinds_are_kept_per_service() {
+        let tracker = SubscriptionTracker::default();
+        let heart_rate = characteristic(0x180d);
+        let other = characteristic(0x1234);
+        tracker.set_kind(&heart_rate, SubscriptionKind::Indicate);
+        assert_eq!(
+            tracker.kind(heart_rate.service_uuid, heart_rate.uuid),
+            SubscriptionKind::Indicate
+        );
+        assert_eq!(
+            tracker.kind(other.service_uuid, other.uuid),
+            SubscriptionKind::Notify
+        );
+        tracker.clear_kind(&heart_rate);
+        assert_eq!(
+            tracker.kind(heart_rate.service_uuid, heart_rate.uuid),
+            SubscriptionKind::Notify
+        );
+    }
+
+    #[tokio::test]
+    async fn subscriptions_only_yield_their_own_service() {
+        let heart_rate = characteristic(0x180d);
+        let other = characteristic(0x1234);
+        let notification = |characteristic: &Characteristic, value: u8| ValueNotification {
+            uuid: characteristic.uuid,
+            service_uuid: characteristic.service_uuid,
+            value: vec![value],
+            kind: SubscriptionKind::Notify,
+            received_at: Instant::now(),
+            sequence: value.into(),
+            missed: 0,
+        };
+        let notifications = stream::iter([
+            notification(&other, 1),
+            notification(&heart_rate, 2),
+            notification(&other, 3),
+        ]);
+        let handle = Arc::new(SubscriptionHandle {
+            unsubscribe: Mutex::new(None),
+            runtime: None,
+        });
+        let subscription = Subscription::new(heart_rate, Box::pin(notifications), handle);
+        let values: Vec<_> = subscription.map(|n| n.value).collect().await;
+        assert_eq!(values, vec![vec![2]]);
+    }
+}
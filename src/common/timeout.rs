@@ -0,0 +1,48 @@
+// btleplug Source Code File
+//
+// Copyright 2020 Nonpolynomial Labs LLC. All rights reserved.
+//
+// Licensed under the BSD 3-Clause license. See LICENSE file in the project root
+// for full license information.
+
+//! Giving up on a future after a while, on whichever runtime the platform uses.
+
+use crate::{Error, Result};
+use std::future::Future;
+use std::time::Duration;
+
+/// Runs the future to completion, or fails with [`Error::TimedOut`] if that takes longer than
+/// the given duration.
+#[cfg(not(target_arch = "wasm32"))]
+pub async fn timeout<F: Future>(duration: Duration, future: F) -> Result<F::Output> {
+    tokio::time::timeout(duration, future)
+        .await
+        .map_err(|_| Error::TimedOut(duration))
+}
+
+#[cfg(target_arch = "wasm32")]
+pub async fn timeout<F: Future>(duration: Duration, future: F) -> Result<F::Output> {
+    use futures::future::{self, Either};
+    let future = std::pin::pin!(future);
+    let sleep = std::pin::pin!(crate::web::utils::sleep(duration));
+    match future::select(future, sleep).await {
+        Either::Left((output, _)) => Ok(output),
+        Either::Right(_) => Err(Error::TimedOut(duration)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::future;
+
+    #[tokio::test]
+    async fn times_out() {
+        let duration = Duration::from_millis(10);
+        assert_eq!(timeout(duration, async { 42 }).await.unwrap(), 42);
+        assert!(matches!(
+            timeout(duration, future::pending::<()>()).await,
+            Err(Error::TimedOut(d)) if d == duration
+        ));
+    }
+}
@@ -6,13 +6,65 @@
 // for full license information.
 
 use crate::api::ValueNotification;
+use futures::future::ready;
 use futures::stream::{Stream, StreamExt};
 use std::pin::Pin;
 use tokio::sync::broadcast::Receiver;
-use tokio_stream::wrappers::BroadcastStream;
+use tokio_stream::wrappers::{errors::BroadcastStreamRecvError, BroadcastStream};
 
+/// Turns a receiver of the broadcast channel a peripheral sends its notifications on into a stream.
+/// If the receiver lags behind, the number of notifications it dropped is reported in
+/// [`ValueNotification::missed`] of the next one.
 pub fn notifications_stream_from_broadcast_receiver(
     receiver: Receiver<ValueNotification>,
 ) -> Pin<Box<dyn Stream<Item = ValueNotification> + Send>> {
-    Box::pin(BroadcastStream::new(receiver).filter_map(|x| async move { x.ok() }))
+    let mut missed = 0;
+    Box::pin(BroadcastStream::new(receiver).filter_map(move |x| {
+        ready(match x {
+            Ok(mut notification) => {
+                notification.missed += std::mem::take(&mut missed);
+                Some(notification)
+            }
+            Err(BroadcastStreamRecvError::Lagged(n)) => {
+                missed += n;
+                None
+            }
+        })
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::SubscriptionKind;
+    use futures::executor::block_on;
+    use std::time::Instant;
+    use tokio::sync::broadcast;
+    use uuid::Uuid;
+
+    fn notification(sequence: u64) -> ValueNotification {
+        ValueNotification {
+            uuid: Uuid::nil(),
+            service_uuid: Uuid::nil(),
+            value: vec![sequence as u8],
+            kind: SubscriptionKind::Notify,
+            received_at: Instant::now(),
+            sequence,
+            missed: 0,
+        }
+    }
+
+    #[test]
+    fn lagged_notifications_are_reported() {
+        let (sender, receiver) = broadcast::channel(2);
+        let stream = notifications_stream_from_broadcast_receiver(receiver);
+        for sequence in 0..5 {
+            sender.send(notification(sequence)).unwrap();
+        }
+        drop(sender);
+
+        let received: Vec<_> = block_on(stream.collect());
+        let received: Vec<_> = received.iter().map(|n| (n.sequence, n.missed)).collect();
+        assert_eq!(received, vec![(3, 3), (4, 0)]);
+    }
 }
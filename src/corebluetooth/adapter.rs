@@ -1,7 +1,12 @@
 use super::internal::{run_corebluetooth_thread, CoreBluetoothEvent, CoreBluetoothMessage};
 use super::peripheral::{Peripheral, PeripheralId};
-use crate::api::{Central, CentralEvent, ScanFilter};
-use crate::common::adapter_manager::AdapterManager;
+use crate::api::{
+    AdapterInfo, Central, CentralEvent, CentralState, EventBufferConfig, HonouredScanOptions,
+    ScanFilter, ScanMode, ScanOptions, ScanSession, ScanTransport,
+};
+use crate::common::{
+    adapter_manager::AdapterManager, scan_filter::ActiveScanFilter, scan_session::ScanSessions,
+};
 use crate::{Error, Result};
 use async_trait::async_trait;
 use futures::channel::mpsc::{self, Sender};
@@ -9,14 +14,18 @@ use futures::sink::SinkExt;
 use futures::stream::{Stream, StreamExt};
 use log::*;
 use std::pin::Pin;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 use tokio::task;
 
 /// Implementation of [api::Central](crate::api::Central).
 #[derive(Clone, Debug)]
 pub struct Adapter {
     manager: Arc<AdapterManager<Peripheral>>,
+    state: Arc<Mutex<CentralState>>,
     sender: Sender<CoreBluetoothMessage>,
+    scan_filter: ActiveScanFilter,
+    scan_sessions: ScanSessions,
 }
 
 impl Adapter {
@@ -27,18 +36,17 @@ impl Adapter {
         // receiver is dropped after that. We can pick it up here and make it
         // part of our event loop to update our peripherals.
         debug!("Waiting on adapter connect");
-        if !matches!(
-            receiver.next().await,
-            Some(CoreBluetoothEvent::AdapterConnected)
-        ) {
+        let Some(CoreBluetoothEvent::StateUpdated(state)) = receiver.next().await else {
             return Err(Error::Other(
                 "Adapter failed to connect.".to_string().into(),
             ));
-        }
+        };
         debug!("Adapter connected");
         let manager = Arc::new(AdapterManager::default());
+        let state = Arc::new(Mutex::new(state));
 
         let manager_clone = manager.clone();
+        let state_clone = state.clone();
         let adapter_sender_clone = adapter_sender.clone();
         task::spawn(async move {
             while let Some(msg) = receiver.next().await {
@@ -48,33 +56,52 @@ impl Adapter {
                         name,
                         event_receiver,
                     } => {
-                        manager_clone.add_peripheral(Peripheral::new(
-                            uuid,
-                            name,
-                            Arc::downgrade(&manager_clone),
-                            event_receiver,
-                            adapter_sender_clone.clone(),
-                        ));
-                        manager_clone.emit(CentralEvent::DeviceDiscovered(uuid.into()));
+                        let id = uuid.into();
+                        match manager_clone.peripheral(&id) {
+                            // Kept from before it disconnected.
+                            Some(peripheral) => {
+                                peripheral.spawn_event_loop(event_receiver);
+                                let changes = name
+                                    .map(|name| peripheral.update_name(&name))
+                                    .unwrap_or_default();
+                                manager_clone.emit(CentralEvent::DeviceUpdated { id, changes });
+                            }
+                            None => {
+                                manager_clone.add_peripheral(Peripheral::new(
+                                    uuid,
+                                    name,
+                                    Arc::downgrade(&manager_clone),
+                                    event_receiver,
+                                    adapter_sender_clone.clone(),
+                                ));
+                                manager_clone.emit(CentralEvent::DeviceDiscovered(id));
+                            }
+                        }
                     }
                     CoreBluetoothEvent::DeviceUpdated { uuid, name } => {
                         let id = uuid.into();
-                        if let Some(entry) = manager_clone.peripheral_mut(&id) {
-                            entry.value().update_name(&name);
-                            manager_clone.emit(CentralEvent::DeviceUpdated(id));
+                        if let Some(peripheral) = manager_clone.peripheral(&id) {
+                            let changes = peripheral.update_name(&name);
+                            manager_clone.emit(CentralEvent::DeviceUpdated { id, changes });
                         }
                     }
                     CoreBluetoothEvent::DeviceDisconnected { uuid } => {
                         manager_clone.emit(CentralEvent::DeviceDisconnected(uuid.into()));
                     }
-                    _ => {}
+                    CoreBluetoothEvent::StateUpdated(state) => {
+                        *state_clone.lock().unwrap() = state;
+                        manager_clone.emit(CentralEvent::StateUpdate(state));
+                    }
                 }
             }
         });
 
         Ok(Adapter {
             manager,
+            state,
             sender: adapter_sender,
+            scan_filter: ActiveScanFilter::default(),
+            scan_sessions: ScanSessions::default(),
         })
     }
 }
@@ -84,15 +111,45 @@ impl Central for Adapter {
     type Peripheral = Peripheral;
 
     async fn events(&self) -> Result<Pin<Box<dyn Stream<Item = CentralEvent> + Send>>> {
-        Ok(self.manager.event_stream())
+        Ok(Box::pin(
+            self.scan_filter
+                .filter_events(self.clone(), self.manager.event_stream()),
+        ))
     }
 
-    async fn start_scan(&self, filter: ScanFilter) -> Result<()> {
+    async fn set_event_buffer(&self, config: EventBufferConfig) -> Result<()> {
+        self.manager.set_event_buffer(config)
+    }
+
+    async fn set_peripheral_retention(&self, retention: Option<Duration>) -> Result<()> {
+        self.manager.set_retention(retention);
+        Ok(())
+    }
+
+    async fn start_scan_with_options(
+        &self,
+        filter: ScanFilter,
+        options: ScanOptions,
+    ) -> Result<HonouredScanOptions> {
+        self.scan_filter.set(&filter)?;
         self.sender
             .to_owned()
-            .send(CoreBluetoothMessage::StartScanning { filter })
+            .send(CoreBluetoothMessage::StartScanning {
+                filter,
+                allow_duplicates: options.duplicates,
+            })
             .await?;
-        Ok(())
+        // Core Bluetooth always scans actively for LE devices, with parameters of its choosing.
+        Ok(HonouredScanOptions {
+            mode: options.mode == ScanMode::Active,
+            duplicates: true,
+            min_rssi: options.min_rssi.is_none(),
+            max_pathloss: options.max_pathloss.is_none(),
+            transport: options.transport != ScanTransport::BrEdr,
+            interval: options.interval.is_none(),
+            window: options.window.is_none(),
+            rssi_monitor: options.rssi_monitor.is_none(),
+        })
     }
 
     async fn stop_scan(&self) -> Result<()> {
@@ -103,6 +160,10 @@ impl Central for Adapter {
         Ok(())
     }
 
+    async fn scan(&self, filter: ScanFilter) -> Result<ScanSession<Peripheral>> {
+        self.scan_sessions.scan(self, filter).await
+    }
+
     async fn peripherals(&self) -> Result<Vec<Peripheral>> {
         Ok(self.manager.peripherals())
     }
@@ -117,8 +178,14 @@ impl Central for Adapter {
         ))
     }
 
-    async fn adapter_info(&self) -> Result<String> {
-        // TODO: Get information about the adapter.
-        Ok("CoreBluetooth".to_string())
+    async fn state(&self) -> Result<CentralState> {
+        Ok(*self.state.lock().unwrap())
+    }
+
+    async fn adapter_info(&self) -> Result<AdapterInfo> {
+        // CoreBluetooth doesn't tell anything else about the adapter.
+        let mut info = AdapterInfo::new("CoreBluetooth");
+        info.powered = self.state.lock().unwrap().is_powered();
+        Ok(info)
     }
 }
@@ -73,6 +73,11 @@ pub enum CentralDelegateEvent {
         service_uuids: Vec<Uuid>,
         rssi: i16,
     },
+    /// Sent once per advertisement, after the events carrying its data.
+    AdvertisementReceived {
+        peripheral_uuid: Uuid,
+        rssi: i16,
+    },
     // DiscoveredIncludedServices(Uuid, HashMap<Uuid, StrongPtr>),
     DiscoveredCharacteristics {
         peripheral_uuid: Uuid,
@@ -263,6 +268,14 @@ impl Debug for CentralDelegateEvent {
                 .field("service_uuids", service_uuids)
                 .field("rssi", rssi)
                 .finish(),
+            CentralDelegateEvent::AdvertisementReceived {
+                peripheral_uuid,
+                rssi,
+            } => f
+                .debug_struct("AdvertisementReceived")
+                .field("peripheral_uuid", peripheral_uuid)
+                .field("rssi", rssi)
+                .finish(),
             CentralDelegateEvent::DescriptorNotified {
                 peripheral_uuid,
                 service_uuid,
@@ -597,6 +610,14 @@ pub mod CentralDelegate {
                 },
             );
         }
+
+        send_delegate_event(
+            delegate,
+            CentralDelegateEvent::AdvertisementReceived {
+                peripheral_uuid,
+                rssi: rssi_value,
+            },
+        );
     }
 
     ////////////////////////////////////////////////////////////////
@@ -195,6 +195,21 @@ pub mod cb {
         AllowedAlways = 3,
     }
 
+    pub fn manager_state(cbmanager: id) -> CBManagerState {
+        unsafe { msg_send![cbmanager, state] }
+    }
+
+    #[derive(Copy, Clone, Debug, Eq, PartialEq)]
+    #[repr(i64)]
+    pub enum CBManagerState {
+        Unknown = 0,
+        Resetting = 1,
+        Unsupported = 2,
+        Unauthorized = 3,
+        PoweredOff = 4,
+        PoweredOn = 5,
+    }
+
     // CBPeer
 
     pub fn peer_identifier(cbpeer: id) -> id /* NSUUID* */ {
@@ -11,7 +11,7 @@
 use super::{
     central_delegate::{CentralDelegate, CentralDelegateEvent},
     framework::{
-        cb::{self, CBManagerAuthorization, CBPeripheralState},
+        cb::{self, CBManagerAuthorization, CBManagerState, CBPeripheralState},
         ns,
     },
     future::{BtlePlugFuture, BtlePlugFutureStateShared},
@@ -22,8 +22,8 @@ use super::{
     },
 };
 use crate::api::{
-    bleuuid::uuid_from_u16, CharPropFlags, Characteristic, Descriptor, ScanFilter, Service,
-    WriteType,
+    bleuuid::uuid_from_u16, CentralState, CharPropFlags, Characteristic, Descriptor, ScanFilter,
+    Service, WriteType,
 };
 use crate::Error;
 use cocoa::{
@@ -35,12 +35,16 @@ use futures::select;
 use futures::sink::SinkExt;
 use futures::stream::{Fuse, StreamExt};
 use log::{error, trace, warn};
-use objc::{rc::StrongPtr, runtime::YES};
+use objc::{
+    rc::StrongPtr,
+    runtime::{NO, YES},
+};
 use std::{
     collections::{BTreeSet, HashMap, VecDeque},
     fmt::{self, Debug, Formatter},
     ops::Deref,
     thread,
+    time::Instant,
 };
 use tokio::runtime;
 use uuid::Uuid;
@@ -155,10 +159,17 @@ pub enum CoreBluetoothReply {
 #[derive(Debug)]
 pub enum CBPeripheralEvent {
     Disconnected,
-    Notification(Uuid, Vec<u8>),
+    Notification {
+        service_uuid: Uuid,
+        characteristic_uuid: Uuid,
+        data: Vec<u8>,
+        received_at: Instant,
+    },
     ManufacturerData(u16, Vec<u8>, i16),
     ServiceData(HashMap<Uuid, Vec<u8>>, i16),
     Services(Vec<Uuid>, i16),
+    /// The end of an advertisement, whose data has been sent before this.
+    Advertisement(i16),
 }
 
 pub type CoreBluetoothReplyStateShared = BtlePlugFutureStateShared<CoreBluetoothReply>;
@@ -353,6 +364,7 @@ impl Debug for CoreBluetoothInternal {
 pub enum CoreBluetoothMessage {
     StartScanning {
         filter: ScanFilter,
+        allow_duplicates: bool,
     },
     StopScanning,
     ConnectDevice {
@@ -412,7 +424,8 @@ pub enum CoreBluetoothMessage {
 
 #[derive(Debug)]
 pub enum CoreBluetoothEvent {
-    AdapterConnected,
+    /// The state of the central manager, which is first reported once it has started.
+    StateUpdated(CentralState),
     DeviceDiscovered {
         uuid: Uuid,
         name: Option<String>,
@@ -512,6 +525,18 @@ impl CoreBluetoothInternal {
         }
     }
 
+    async fn on_advertisement(&mut self, peripheral_uuid: Uuid, rssi: i16) {
+        if let Some(p) = self.peripherals.get_mut(&peripheral_uuid) {
+            if let Err(e) = p
+                .event_sender
+                .send(CBPeripheralEvent::Advertisement(rssi))
+                .await
+            {
+                error!("Error sending notification event: {}", e);
+            }
+        }
+    }
+
     async fn on_discovered_peripheral(&mut self, peripheral: StrongPtr) {
         let uuid = nsuuid_to_uuid(cb::peer_identifier(*peripheral));
         let name = nsstring_to_string(cb::peripheral_name(*peripheral));
@@ -725,6 +750,7 @@ impl CoreBluetoothInternal {
                 if let Some(characteristic) = service.characteristics.get_mut(&characteristic_uuid)
                 {
                     trace!("Got read event!");
+                    let received_at = Instant::now();
 
                     let mut data_clone = Vec::new();
                     for byte in data.iter() {
@@ -742,7 +768,12 @@ impl CoreBluetoothInternal {
                             .set_reply(CoreBluetoothReply::ReadResult(data_clone));
                     } else if let Err(e) = peripheral
                         .event_sender
-                        .send(CBPeripheralEvent::Notification(characteristic_uuid, data))
+                        .send(CBPeripheralEvent::Notification {
+                            service_uuid,
+                            characteristic_uuid,
+                            data,
+                            received_at,
+                        })
                         .await
                     {
                         error!("Error sending notification event: {}", e);
@@ -1003,14 +1034,12 @@ impl CoreBluetoothInternal {
         select! {
             delegate_msg = self.delegate_receiver.select_next_some() => {
                 match delegate_msg {
-                    // TODO DidUpdateState does not imply that the adapter is
-                    // on, just that it updated state.
-                    //
                     // TODO We should probably also register some sort of
                     // "ready" variable in our adapter that will cause scans/etc
                     // to fail if this hasn't updated.
                     CentralDelegateEvent::DidUpdateState => {
-                        self.dispatch_event(CoreBluetoothEvent::AdapterConnected).await
+                        let state = central_state(cb::manager_state(*self.manager));
+                        self.dispatch_event(CoreBluetoothEvent::StateUpdated(state)).await
                     }
                     CentralDelegateEvent::DiscoveredPeripheral{cbperipheral} => {
                         self.on_discovered_peripheral(cbperipheral).await
@@ -1063,6 +1092,9 @@ impl CoreBluetoothInternal {
                     CentralDelegateEvent::Services{peripheral_uuid, service_uuids, rssi} => {
                         self.on_services(peripheral_uuid, service_uuids, rssi).await
                     },
+                    CentralDelegateEvent::AdvertisementReceived{peripheral_uuid, rssi} => {
+                        self.on_advertisement(peripheral_uuid, rssi).await
+                    },
                     CentralDelegateEvent::DescriptorNotified{
                         peripheral_uuid,
                         service_uuid,
@@ -1081,7 +1113,9 @@ impl CoreBluetoothInternal {
             adapter_msg = self.message_receiver.select_next_some() => {
                 trace!("Adapter message!");
                 match adapter_msg {
-                    CoreBluetoothMessage::StartScanning{filter} => self.start_discovery(filter),
+                    CoreBluetoothMessage::StartScanning{filter, allow_duplicates} => {
+                        self.start_discovery(filter, allow_duplicates)
+                    }
                     CoreBluetoothMessage::StopScanning => self.stop_discovery(),
                     CoreBluetoothMessage::ConnectDevice{peripheral_uuid, future} => {
                         trace!("got connectdevice msg!");
@@ -1124,15 +1158,18 @@ impl CoreBluetoothInternal {
         }
     }
 
-    fn start_discovery(&mut self, filter: ScanFilter) {
+    fn start_discovery(&mut self, filter: ScanFilter, allow_duplicates: bool) {
         trace!("BluetoothAdapter::start_discovery");
         let service_uuids = scan_filter_to_service_uuids(filter);
         let options = ns::mutabledictionary();
         // NOTE: If duplicates are not allowed then a peripheral will not show
         // up again once connected and then disconnected.
-        ns::mutabledictionary_setobject_forkey(options, ns::number_withbool(YES), unsafe {
-            cb::CENTRALMANAGERSCANOPTIONALLOWDUPLICATESKEY
-        });
+        let allow_duplicates = if allow_duplicates { YES } else { NO };
+        ns::mutabledictionary_setobject_forkey(
+            options,
+            ns::number_withbool(allow_duplicates),
+            unsafe { cb::CENTRALMANAGERSCANOPTIONALLOWDUPLICATESKEY },
+        );
         cb::centralmanager_scanforperipheralswithservices_options(
             *self.manager,
             service_uuids,
@@ -1148,6 +1185,17 @@ impl CoreBluetoothInternal {
 
 /// Convert a `ScanFilter` to the appropriate `NSArray<CBUUID *> *` to use for discovery. If the
 /// filter has an empty list of services then this will return `nil`, to discover all devices.
+fn central_state(state: CBManagerState) -> CentralState {
+    match state {
+        // Resetting means the connection to the system service was lost, and is being restored.
+        CBManagerState::Unknown | CBManagerState::Resetting => CentralState::Unknown,
+        CBManagerState::Unsupported => CentralState::Unsupported,
+        CBManagerState::Unauthorized => CentralState::Unauthorized,
+        CBManagerState::PoweredOff => CentralState::PoweredOff,
+        CBManagerState::PoweredOn => CentralState::PoweredOn,
+    }
+}
+
 fn scan_filter_to_service_uuids(filter: ScanFilter) -> id {
     if filter.services.is_empty() {
         nil
@@ -13,10 +13,16 @@ use super::{
 };
 use crate::{
     api::{
-        self, BDAddr, CentralEvent, CharPropFlags, Characteristic, Descriptor,
-        PeripheralProperties, Service, ValueNotification, WriteType,
+        self, AdvertisementRecord, BDAddr, CentralEvent, CharPropFlags, Characteristic, Descriptor,
+        PairingOptions, PeripheralProperties, PropertiesDiff, Service, Subscription,
+        SubscriptionKind, ValueNotification, WriteType,
+    },
+    common::{
+        adapter_manager::AdapterManager,
+        advertisement_history::{hash_advertised_data, AdvertisementHistory},
+        subscription::SubscriptionTracker,
+        util::notifications_stream_from_broadcast_receiver,
     },
-    common::{adapter_manager::AdapterManager, util::notifications_stream_from_broadcast_receiver},
     Error, Result,
 };
 use async_trait::async_trait;
@@ -31,7 +37,9 @@ use std::{
     collections::{BTreeSet, HashMap},
     fmt::{self, Debug, Display, Formatter},
     pin::Pin,
+    sync::atomic::{AtomicU64, Ordering},
     sync::{Arc, Mutex},
+    time::Instant,
 };
 use tokio::sync::broadcast;
 use tokio::task;
@@ -58,6 +66,9 @@ pub struct Peripheral {
 
 struct Shared {
     notifications_channel: broadcast::Sender<ValueNotification>,
+    notifications_sequence: AtomicU64,
+    subscriptions: SubscriptionTracker,
+    advertisements: AdvertisementHistory,
     manager: Weak<AdapterManager<Peripheral>>,
     uuid: Uuid,
     services: Mutex<BTreeSet<Service>>,
@@ -107,18 +118,44 @@ impl Peripheral {
             manager,
             services: Mutex::new(BTreeSet::new()),
             notifications_channel,
+            notifications_sequence: AtomicU64::new(0),
+            subscriptions: SubscriptionTracker::default(),
+            advertisements: AdvertisementHistory::default(),
             uuid,
             message_sender,
         });
-        let shared_clone = shared.clone();
+        let peripheral = Self { shared };
+        peripheral.spawn_event_loop(event_receiver);
+        peripheral
+    }
+
+    /// Handles the events CoreBluetooth sends for this peripheral on `event_receiver`, until it's
+    /// closed. CoreBluetooth forgets peripherals when they disconnect, so this is called again with
+    /// a new receiver when one is discovered again.
+    pub(super) fn spawn_event_loop(&self, event_receiver: Receiver<CBPeripheralEvent>) {
+        let shared = self.shared.clone();
         task::spawn(async move {
             let mut event_receiver = event_receiver;
-            let shared = shared_clone;
 
             loop {
                 match event_receiver.next().await {
-                    Some(CBPeripheralEvent::Notification(uuid, data)) => {
-                        let notification = ValueNotification { uuid, value: data };
+                    Some(CBPeripheralEvent::Notification {
+                        service_uuid,
+                        characteristic_uuid,
+                        data,
+                        received_at,
+                    }) => {
+                        let notification = ValueNotification {
+                            uuid: characteristic_uuid,
+                            service_uuid,
+                            value: data,
+                            kind: shared.subscriptions.kind(service_uuid, characteristic_uuid),
+                            received_at,
+                            sequence: shared
+                                .notifications_sequence
+                                .fetch_add(1, Ordering::Relaxed),
+                            missed: 0,
+                        };
 
                         // Note: we ignore send errors here which may happen while there are no
                         // receivers...
@@ -155,6 +192,17 @@ impl Peripheral {
                             services,
                         });
                     }
+                    Some(CBPeripheralEvent::Advertisement(rssi)) => {
+                        let properties = shared.properties.lock().unwrap();
+                        shared.advertisements.record(
+                            Some(rssi),
+                            hash_advertised_data(
+                                &properties.manufacturer_data,
+                                &properties.service_data,
+                                &properties.services,
+                            ),
+                        );
+                    }
                     Some(CBPeripheralEvent::Disconnected) => (),
                     None => {
                         info!("Event receiver died, breaking out of corebluetooth device loop.");
@@ -163,11 +211,14 @@ impl Peripheral {
                 }
             }
         });
-        Self { shared: shared }
     }
 
-    pub(super) fn update_name(&self, name: &str) {
-        self.shared.properties.lock().unwrap().local_name = Some(name.to_string());
+    /// Updates the name, returning what changed.
+    pub(super) fn update_name(&self, name: &str) -> PropertiesDiff {
+        let mut properties = self.shared.properties.lock().unwrap();
+        let old = properties.clone();
+        properties.local_name = Some(name.to_string());
+        PropertiesDiff::between(&old, &properties)
     }
 }
 
@@ -206,6 +257,14 @@ impl api::Peripheral for Peripheral {
         Ok(Some(self.shared.properties.lock().unwrap().clone()))
     }
 
+    fn last_seen(&self) -> Option<Instant> {
+        self.shared.advertisements.last_seen()
+    }
+
+    fn advertisement_history(&self) -> Vec<AdvertisementRecord> {
+        self.shared.advertisements.records()
+    }
+
     fn services(&self) -> BTreeSet<Service> {
         self.shared.services.lock().unwrap().clone()
     }
@@ -350,9 +409,23 @@ impl api::Peripheral for Peripheral {
             CoreBluetoothReply::Ok => trace!("subscribed!"),
             _ => panic!("Didn't subscribe!"),
         }
+        if let Some(kind) = SubscriptionKind::preferring_notify(characteristic.properties) {
+            self.shared.subscriptions.set_kind(characteristic, kind);
+        }
         Ok(())
     }
 
+    async fn subscribe_with(
+        &self,
+        characteristic: &Characteristic,
+        kind: SubscriptionKind,
+    ) -> Result<()> {
+        // setNotifyValue enables notifications whenever the characteristic supports them, and
+        // CoreBluetooth doesn't allow writing the CCCD directly.
+        kind.check_platform_choice(characteristic, SubscriptionKind::preferring_notify)?;
+        self.subscribe(characteristic).await
+    }
+
     async fn unsubscribe(&self, characteristic: &Characteristic) -> Result<()> {
         let fut = CoreBluetoothReplyFuture::default();
         self.shared
@@ -369,6 +442,7 @@ impl api::Peripheral for Peripheral {
             CoreBluetoothReply::Ok => {}
             _ => panic!("Didn't unsubscribe!"),
         }
+        self.shared.subscriptions.clear_kind(characteristic);
         Ok(())
     }
 
@@ -377,6 +451,13 @@ impl api::Peripheral for Peripheral {
         Ok(notifications_stream_from_broadcast_receiver(receiver))
     }
 
+    async fn subscribe_stream(&self, characteristic: &Characteristic) -> Result<Subscription> {
+        self.shared
+            .subscriptions
+            .subscribe(self, characteristic)
+            .await
+    }
+
     async fn write_descriptor(&self, descriptor: &Descriptor, data: &[u8]) -> Result<()> {
         let fut = CoreBluetoothReplyFuture::default();
         self.shared
@@ -418,6 +499,24 @@ impl api::Peripheral for Peripheral {
             }
         }
     }
+
+    async fn pair(&self, _options: PairingOptions) -> Result<()> {
+        Err(Error::NotSupported(
+            "CoreBluetooth pairs on its own when a characteristic needs it".to_string(),
+        ))
+    }
+
+    async fn unpair(&self) -> Result<()> {
+        Err(Error::NotSupported(
+            "CoreBluetooth pairs on its own when a characteristic needs it".to_string(),
+        ))
+    }
+
+    async fn is_bonded(&self) -> Result<bool> {
+        Err(Error::NotSupported(
+            "CoreBluetooth pairs on its own when a characteristic needs it".to_string(),
+        ))
+    }
 }
 
 impl From<Uuid> for PeripheralId {
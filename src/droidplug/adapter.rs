@@ -6,8 +6,14 @@ use super::{
     peripheral::{Peripheral, PeripheralId},
 };
 use crate::{
-    api::{BDAddr, Central, CentralEvent, PeripheralProperties, ScanFilter},
-    common::adapter_manager::AdapterManager,
+    api::{
+        AdapterFeatures, AdapterInfo, AdapterRoles, AddressType, BDAddr, Central, CentralEvent,
+        CentralState, EventBufferConfig, HonouredScanOptions, PeripheralProperties, ScanFilter,
+        ScanMode, ScanOptions, ScanSession, ScanTransport,
+    },
+    common::{
+        adapter_manager::AdapterManager, scan_filter::ActiveScanFilter, scan_session::ScanSessions,
+    },
     Error, Result,
 };
 use async_trait::async_trait;
@@ -15,7 +21,7 @@ use futures::stream::Stream;
 use jni::{
     objects::{GlobalRef, JObject, JString},
     strings::JavaStr,
-    sys::jboolean,
+    sys::{jboolean, jint},
     JNIEnv,
 };
 use std::{
@@ -23,12 +29,25 @@ use std::{
     pin::Pin,
     str::FromStr,
     sync::Arc,
+    time::Duration,
 };
 
+// The states reported by the Java adapter's getState.
+const STATE_POWERED_OFF: jint = 1;
+const STATE_POWERED_ON: jint = 2;
+
+// The features reported by the Java adapter's getFeatures.
+const FEATURE_EXTENDED_ADVERTISING: jint = 0x01;
+const FEATURE_LE_2M_PHY: jint = 0x02;
+const FEATURE_LE_CODED_PHY: jint = 0x04;
+const FEATURE_PERIPHERAL: jint = 0x08;
+
 #[derive(Clone)]
 pub struct Adapter {
     manager: Arc<AdapterManager<Peripheral>>,
     internal: GlobalRef,
+    scan_filter: ActiveScanFilter,
+    scan_sessions: ScanSessions,
 }
 
 impl Debug for Adapter {
@@ -52,6 +71,8 @@ impl Adapter {
         let adapter = Self {
             manager: Arc::new(AdapterManager::default()),
             internal,
+            scan_filter: ActiveScanFilter::default(),
+            scan_sessions: ScanSessions::default(),
         };
         env.set_rust_field(obj, "handle", adapter.clone())?;
 
@@ -101,11 +122,14 @@ impl Adapter {
         properties: PeripheralProperties,
         new: bool,
     ) {
-        peripheral.report_properties(properties.clone());
+        let changes = peripheral.report_properties(properties.clone());
         self.manager.emit(if new {
             CentralEvent::DeviceDiscovered(PeripheralId(properties.address))
         } else {
-            CentralEvent::DeviceUpdated(PeripheralId(properties.address))
+            CentralEvent::DeviceUpdated {
+                id: PeripheralId(properties.address),
+                changes,
+            }
         });
         self.manager
             .emit(CentralEvent::ManufacturerDataAdvertisement {
@@ -127,16 +151,57 @@ impl Adapter {
 impl Central for Adapter {
     type Peripheral = Peripheral;
 
-    async fn adapter_info(&self) -> Result<String> {
-        // TODO: Get information about the adapter.
-        Ok("Android".to_string())
+    async fn adapter_info(&self) -> Result<AdapterInfo> {
+        let mut info = AdapterInfo::new("Android");
+        info.powered = self.state().await?.is_powered();
+        let env = global_jvm().get_env()?;
+        let name = env
+            .call_method(&self.internal, "getName", "()Ljava/lang/String;", &[])?
+            .l()?;
+        if !name.is_null() {
+            info.name = Some(env.get_string(name.into())?.into());
+        }
+        let features = env
+            .call_method(&self.internal, "getFeatures", "()I", &[])?
+            .i()?;
+        let has = |feature: jint| features & feature != 0;
+        info.roles = Some(if has(FEATURE_PERIPHERAL) {
+            AdapterRoles::CENTRAL | AdapterRoles::PERIPHERAL
+        } else {
+            AdapterRoles::CENTRAL
+        });
+        let supported = &mut info.capabilities.features;
+        supported.set(
+            AdapterFeatures::EXTENDED_ADVERTISING,
+            has(FEATURE_EXTENDED_ADVERTISING),
+        );
+        supported.set(AdapterFeatures::LE_2M_PHY, has(FEATURE_LE_2M_PHY));
+        supported.set(AdapterFeatures::LE_CODED_PHY, has(FEATURE_LE_CODED_PHY));
+        Ok(info)
     }
 
     async fn events(&self) -> Result<Pin<Box<dyn Stream<Item = CentralEvent> + Send>>> {
-        Ok(self.manager.event_stream())
+        Ok(Box::pin(
+            self.scan_filter
+                .filter_events(self.clone(), self.manager.event_stream()),
+        ))
+    }
+
+    async fn set_event_buffer(&self, config: EventBufferConfig) -> Result<()> {
+        self.manager.set_event_buffer(config)
     }
 
-    async fn start_scan(&self, filter: ScanFilter) -> Result<()> {
+    async fn set_peripheral_retention(&self, retention: Option<Duration>) -> Result<()> {
+        self.manager.set_retention(retention);
+        Ok(())
+    }
+
+    async fn start_scan_with_options(
+        &self,
+        filter: ScanFilter,
+        options: ScanOptions,
+    ) -> Result<HonouredScanOptions> {
+        self.scan_filter.set(&filter)?;
         let env = global_jvm().get_env()?;
         let filter = JScanFilter::new(&env, filter)?;
         env.call_method(
@@ -145,7 +210,17 @@ impl Central for Adapter {
             "(Lcom/nonpolynomial/btleplug/android/impl/ScanFilter;)V",
             &[filter.into()],
         )?;
-        Ok(())
+        // The scanner reports all matches of an active LE scan, with its default settings.
+        Ok(HonouredScanOptions {
+            mode: options.mode == ScanMode::Active,
+            duplicates: options.duplicates,
+            min_rssi: options.min_rssi.is_none(),
+            max_pathloss: options.max_pathloss.is_none(),
+            transport: options.transport != ScanTransport::BrEdr,
+            interval: options.interval.is_none(),
+            window: options.window.is_none(),
+            rssi_monitor: options.rssi_monitor.is_none(),
+        })
     }
 
     async fn stop_scan(&self) -> Result<()> {
@@ -154,6 +229,22 @@ impl Central for Adapter {
         Ok(())
     }
 
+    async fn state(&self) -> Result<CentralState> {
+        let env = global_jvm().get_env()?;
+        let state = env
+            .call_method(&self.internal, "getState", "()I", &[])?
+            .i()?;
+        Ok(match state {
+            STATE_POWERED_OFF => CentralState::PoweredOff,
+            STATE_POWERED_ON => CentralState::PoweredOn,
+            _ => CentralState::Unsupported,
+        })
+    }
+
+    async fn scan(&self, filter: ScanFilter) -> Result<ScanSession<Peripheral>> {
+        self.scan_sessions.scan(self, filter).await
+    }
+
     async fn peripherals(&self) -> Result<Vec<Peripheral>> {
         Ok(self.manager.peripherals())
     }
@@ -167,6 +258,17 @@ impl Central for Adapter {
     async fn add_peripheral(&self, address: &PeripheralId) -> Result<Peripheral> {
         self.add(address.0)
     }
+
+    async fn peripheral_by_address(
+        &self,
+        address: BDAddr,
+        _address_type: AddressType,
+    ) -> Result<Peripheral> {
+        match self.manager.peripheral(&PeripheralId(address)) {
+            Some(peripheral) => Ok(peripheral),
+            None => self.add(address),
+        }
+    }
 }
 
 pub(crate) fn adapter_report_scan_result_internal(
@@ -6,6 +6,7 @@ import android.bluetooth.le.ScanCallback;
 import android.bluetooth.le.ScanFilter.Builder;
 import android.bluetooth.le.ScanResult;
 import android.bluetooth.le.ScanSettings;
+import android.os.Build;
 import android.os.ParcelUuid;
 
 import java.util.ArrayList;
@@ -13,11 +14,62 @@ import java.util.List;
 
 @SuppressWarnings("unused") // Native code uses this class.
 class Adapter {
+    // The states getState reports, which native code maps to its own.
+    private static final int STATE_UNSUPPORTED = 0;
+    private static final int STATE_POWERED_OFF = 1;
+    private static final int STATE_POWERED_ON = 2;
+
+    // The features getFeatures reports, which native code maps to its own.
+    private static final int FEATURE_EXTENDED_ADVERTISING = 0x01;
+    private static final int FEATURE_LE_2M_PHY = 0x02;
+    private static final int FEATURE_LE_CODED_PHY = 0x04;
+    private static final int FEATURE_PERIPHERAL = 0x08;
+
     private long handle;
     private final Callback callback = new Callback();
 
     public Adapter() {}
 
+    @SuppressLint("MissingPermission")
+    public int getState() {
+        BluetoothAdapter adapter = BluetoothAdapter.getDefaultAdapter();
+        if (adapter == null) {
+            return STATE_UNSUPPORTED;
+        }
+        return adapter.isEnabled() ? STATE_POWERED_ON : STATE_POWERED_OFF;
+    }
+
+    @SuppressLint("MissingPermission")
+    public String getName() {
+        BluetoothAdapter adapter = BluetoothAdapter.getDefaultAdapter();
+        return adapter == null ? null : adapter.getName();
+    }
+
+    // The adapter only reports its features while it is powered on.
+    public int getFeatures() {
+        BluetoothAdapter adapter = BluetoothAdapter.getDefaultAdapter();
+        if (adapter == null) {
+            return 0;
+        }
+        int features = 0;
+        if (Build.VERSION.SDK_INT >= Build.VERSION_CODES.O) {
+            if (adapter.isLeExtendedAdvertisingSupported()) {
+                features |= FEATURE_EXTENDED_ADVERTISING;
+            }
+            if (adapter.isLe2MPhySupported()) {
+                features |= FEATURE_LE_2M_PHY;
+            }
+            if (adapter.isLeCodedPhySupported()) {
+                features |= FEATURE_LE_CODED_PHY;
+            }
+        }
+        // Advertising is what the peripheral role needs.
+        if (adapter.isMultipleAdvertisementSupported()) {
+            features |= FEATURE_PERIPHERAL;
+        }
+        return features;
+    }
+
     @SuppressLint("MissingPermission")
     public void startScan(ScanFilter filter) {
         ArrayList<android.bluetooth.le.ScanFilter> filters = null;
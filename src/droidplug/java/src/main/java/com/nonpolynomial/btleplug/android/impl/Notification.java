@@ -0,0 +1,23 @@
+package com.nonpolynomial.btleplug.android.impl;
+
+import android.bluetooth.BluetoothGattCharacteristic;
+
+@SuppressWarnings("unused") // Native code uses this class.
+class Notification {
+    private final BluetoothGattCharacteristic characteristic;
+    // When the value was received, from System.nanoTime().
+    private final long receivedAt;
+
+    public Notification(BluetoothGattCharacteristic characteristic, long receivedAt) {
+        this.characteristic = characteristic;
+        this.receivedAt = receivedAt;
+    }
+
+    public BluetoothGattCharacteristic getCharacteristic() {
+        return this.characteristic;
+    }
+
+    public long getReceivedAt() {
+        return this.receivedAt;
+    }
+}
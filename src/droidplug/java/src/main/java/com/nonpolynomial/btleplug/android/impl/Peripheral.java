@@ -32,7 +32,7 @@ class Peripheral {
     private boolean connected = false;
 
     private final Queue<Runnable> commandQueue = new LinkedList<>();
-    private final LinkedList<WeakReference<QueueStream<BluetoothGattCharacteristic>>> notificationStreams = new LinkedList<>();
+    private final LinkedList<WeakReference<QueueStream<Notification>>> notificationStreams = new LinkedList<>();
     private boolean executingCommand = false;
     private CommandCallback commandCallback;
 
@@ -315,8 +315,8 @@ class Peripheral {
         return future;
     }
 
-    public Stream<BluetoothGattCharacteristic> getNotifications() {
-        QueueStream<BluetoothGattCharacteristic> stream = new QueueStream<>();
+    public Stream<Notification> getNotifications() {
+        QueueStream<Notification> stream = new QueueStream<>();
         synchronized (this) {
             this.notificationStreams.add(new WeakReference<>(stream));
         }
@@ -516,13 +516,14 @@ class Peripheral {
 
         @Override
         public void onCharacteristicChanged(BluetoothGatt gatt, BluetoothGattCharacteristic characteristic) {
+            long receivedAt = System.nanoTime();
             BluetoothGattCharacteristic characteristic2 = new BluetoothGattCharacteristic(characteristic.getUuid(), characteristic.getProperties(), characteristic.getPermissions());
             characteristic2.setValue(characteristic.getValue());
             synchronized (Peripheral.this) {
-                for (WeakReference<QueueStream<BluetoothGattCharacteristic>> ref : Peripheral.this.notificationStreams) {
-                    QueueStream<BluetoothGattCharacteristic> stream = ref.get();
+                for (WeakReference<QueueStream<Notification>> ref : Peripheral.this.notificationStreams) {
+                    QueueStream<Notification> stream = ref.get();
                     if (stream != null) {
-                        stream.add(characteristic2);
+                        stream.add(new Notification(characteristic2, receivedAt));
                     }
                 }
             }
@@ -362,6 +362,7 @@ pub struct JBluetoothGattCharacteristic<'a: 'b, 'b> {
     get_properties: JMethodID<'a>,
     get_value: JMethodID<'a>,
     get_descriptors: JMethodID<'a>,
+    get_service: JMethodID<'a>,
     env: &'b JNIEnv<'a>,
 }
 
@@ -374,12 +375,18 @@ impl<'a: 'b, 'b> JBluetoothGattCharacteristic<'a, 'b> {
         let get_properties = env.get_method_id(&class, "getProperties", "()I")?;
         let get_descriptors = env.get_method_id(&class, "getDescriptors", "()Ljava/util/List;")?;
         let get_value = env.get_method_id(&class, "getValue", "()[B")?;
+        let get_service = env.get_method_id(
+            &class,
+            "getService",
+            "()Landroid/bluetooth/BluetoothGattService;",
+        )?;
         Ok(Self {
             internal: obj,
             get_uuid,
             get_properties,
             get_value,
             get_descriptors,
+            get_service,
             env,
         })
     }
@@ -441,6 +448,19 @@ impl<'a: 'b, 'b> JBluetoothGattCharacteristic<'a, 'b> {
         }
         Ok(desc_vec)
     }
+
+    pub fn get_service(&self) -> Result<JBluetoothGattService<'a, 'b>> {
+        let obj = self
+            .env
+            .call_method_unchecked(
+                self.internal,
+                self.get_service,
+                JavaType::Object("Landroid/bluetooth/BluetoothGattService;".to_string()),
+                &[],
+            )?
+            .l()?;
+        JBluetoothGattService::from_env(self.env, obj)
+    }
 }
 
 pub struct JBluetoothGattDescriptor<'a: 'b, 'b> {
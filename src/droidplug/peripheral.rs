@@ -1,7 +1,12 @@
 use crate::{
     api::{
-        self, BDAddr, Characteristic, Descriptor, PeripheralProperties, Service, ValueNotification,
-        WriteType,
+        self, bleuuid::uuid_from_u16, AdvertisementRecord, BDAddr, Characteristic, Descriptor,
+        PairingOptions, PeripheralProperties, PropertiesDiff, Service, Subscription,
+        SubscriptionKind, ValueNotification, WriteType,
+    },
+    common::{
+        advertisement_history::{hash_advertised_data, AdvertisementHistory},
+        subscription::SubscriptionTracker,
     },
     Error, Result,
 };
@@ -24,6 +29,7 @@ use std::{
     fmt::{self, Debug, Display, Formatter},
     pin::Pin,
     sync::{Arc, Mutex},
+    time::{Duration, Instant},
 };
 
 use super::jni::{
@@ -31,6 +37,11 @@ use super::jni::{
     objects::{JBluetoothGattCharacteristic, JBluetoothGattService, JPeripheral},
 };
 use jni::objects::JClass;
+use uuid::Uuid;
+
+/// UUID of the Client Characteristic Configuration descriptor.
+const CCCD_UUID: Uuid = uuid_from_u16(0x2902);
+
 #[cfg_attr(
     feature = "serde",
     derive(Serialize, Deserialize)
@@ -43,6 +54,12 @@ impl Display for PeripheralId {
     }
 }
 
+impl From<BDAddr> for PeripheralId {
+    fn from(address: BDAddr) -> Self {
+        PeripheralId(address)
+    }
+}
+
 fn get_poll_result<'a: 'b, 'b>(
     env: &'b JNIEnv<'a>,
     result: JPollResult<'a, 'b>,
@@ -146,6 +163,8 @@ pub struct Peripheral {
     addr: BDAddr,
     internal: GlobalRef,
     shared: Arc<Mutex<PeripheralShared>>,
+    subscriptions: Arc<SubscriptionTracker>,
+    advertisements: Arc<AdvertisementHistory>,
 }
 
 impl Peripheral {
@@ -159,13 +178,28 @@ impl Peripheral {
                 characteristics: BTreeSet::new(),
                 properties: None,
             })),
+            subscriptions: Arc::new(SubscriptionTracker::default()),
+            advertisements: Arc::new(AdvertisementHistory::default()),
         })
     }
 
-    pub(crate) fn report_properties(&self, mut properties: PeripheralProperties) {
+    /// Records the properties of a scan result, returning what changed since the last one.
+    pub(crate) fn report_properties(&self, mut properties: PeripheralProperties) -> PropertiesDiff {
+        // Properties are reported for each scan result.
+        self.advertisements.record(
+            properties.rssi,
+            hash_advertised_data(
+                &properties.manufacturer_data,
+                &properties.service_data,
+                &properties.services,
+            ),
+        );
         let mut guard = self.shared.lock().unwrap();
 
+        let changes =
+            PropertiesDiff::between(&guard.properties.take().unwrap_or_default(), &properties);
         guard.properties = Some(properties);
+        changes
     }
 
     fn with_obj<T, E>(
@@ -219,6 +253,14 @@ impl api::Peripheral for Peripheral {
         Ok((&guard.properties).clone())
     }
 
+    fn last_seen(&self) -> Option<Instant> {
+        self.advertisements.last_seen()
+    }
+
+    fn advertisement_history(&self) -> Vec<AdvertisementRecord> {
+        self.advertisements.records()
+    }
+
     fn characteristics(&self) -> BTreeSet<Characteristic> {
         let guard = self.shared.lock().unwrap();
         (&guard.characteristics).clone()
@@ -340,26 +382,82 @@ impl api::Peripheral for Peripheral {
 
     async fn subscribe(&self, characteristic: &Characteristic) -> Result<()> {
         self.set_characteristic_notification(characteristic, true)
-            .await
+            .await?;
+        if let Some(kind) = SubscriptionKind::preferring_notify(characteristic.properties) {
+            self.subscriptions.set_kind(characteristic, kind);
+        }
+        Ok(())
+    }
+
+    async fn subscribe_with(
+        &self,
+        characteristic: &Characteristic,
+        kind: SubscriptionKind,
+    ) -> Result<()> {
+        kind.check_supported(characteristic)?;
+        self.set_characteristic_notification(characteristic, true)
+            .await?;
+        // Overwrite whatever the Java side wrote to the CCCD with exactly the requested kind.
+        let cccd = Descriptor {
+            uuid: CCCD_UUID,
+            service_uuid: characteristic.service_uuid,
+            characteristic_uuid: characteristic.uuid,
+        };
+        self.write_descriptor(&cccd, &kind.descriptor_value())
+            .await?;
+        self.subscriptions.set_kind(characteristic, kind);
+        Ok(())
     }
 
     async fn unsubscribe(&self, characteristic: &Characteristic) -> Result<()> {
         self.set_characteristic_notification(characteristic, false)
-            .await
+            .await?;
+        self.subscriptions.clear_kind(characteristic);
+        Ok(())
     }
 
     async fn notifications(&self) -> Result<Pin<Box<dyn Stream<Item = ValueNotification> + Send>>> {
         use futures::stream::StreamExt;
         let stream = self.with_obj(|_env, obj| JSendStream::try_from(obj.get_notifications()?))?;
+        let subscriptions = self.subscriptions.clone();
+        // The Java side hands every stream its own queue, so values are numbered per stream.
+        let mut sequence = 0;
         let stream = stream
-            .map(|item| match item {
+            .map(move |item| match item {
                 Ok(item) => {
                     let env = global_jvm().get_env()?;
                     let item = item.as_obj();
+                    // The Java side stamps values with System.nanoTime() as they are received, so
+                    // how long ago that was tells when they were received.
+                    let stamped = env.call_method(item, "getReceivedAt", "()J", &[])?.j()?;
+                    let now = env
+                        .call_static_method("java/lang/System", "nanoTime", "()J", &[])?
+                        .j()?;
+                    let age = Duration::from_nanos(now.saturating_sub(stamped).max(0) as u64);
+                    let received_at = Instant::now().checked_sub(age).unwrap_or_else(Instant::now);
+                    let item = env
+                        .call_method(
+                            item,
+                            "getCharacteristic",
+                            "()Landroid/bluetooth/BluetoothGattCharacteristic;",
+                            &[],
+                        )?
+                        .l()?;
                     let characteristic = JBluetoothGattCharacteristic::from_env(&env, item)?;
                     let uuid = characteristic.get_uuid()?;
+                    let service_uuid = characteristic.get_service()?.get_uuid()?;
                     let value = characteristic.get_value()?;
-                    Ok(ValueNotification { uuid, value })
+                    let notification = ValueNotification {
+                        uuid,
+                        service_uuid,
+                        value,
+                        kind: subscriptions.kind(service_uuid, uuid),
+                        received_at,
+                        sequence,
+                        missed: 0,
+                    };
+                    sequence += 1;
+                    Ok(notification)
                 }
                 Err(err) => Err(err),
             })
@@ -367,6 +465,10 @@ impl api::Peripheral for Peripheral {
         Ok(Box::pin(stream))
     }
 
+    async fn subscribe_stream(&self, characteristic: &Characteristic) -> Result<Subscription> {
+        self.subscriptions.subscribe(self, characteristic).await
+    }
+
     async fn write_descriptor(&self, descriptor: &Descriptor, data: &[u8]) -> Result<()> {
         let future = self.with_obj(|env, obj| {
             let characteristic = JUuid::new(env, descriptor.characteristic_uuid)?;
@@ -394,4 +496,22 @@ impl api::Peripheral for Peripheral {
             Ok(byte_array_to_vec(env, bytes.into_inner())?)
         })
     }
+
+    async fn pair(&self, _options: PairingOptions) -> Result<()> {
+        Err(Error::NotSupported(
+            "Android pairs on its own when a characteristic needs it".to_string(),
+        ))
+    }
+
+    async fn unpair(&self) -> Result<()> {
+        Err(Error::NotSupported(
+            "Android pairs on its own when a characteristic needs it".to_string(),
+        ))
+    }
+
+    async fn is_bonded(&self) -> Result<bool> {
+        Err(Error::NotSupported(
+            "Android pairs on its own when a characteristic needs it".to_string(),
+        ))
+    }
 }
@@ -20,8 +20,11 @@
 //! An example of how to use the library to control some BLE smart lights:
 //!
 //! ```rust,no_run
-//! use btleplug::api::{bleuuid::uuid_from_u16, Central, Manager as _, Peripheral as _, ScanFilter, WriteType};
-//! use btleplug::platform::{Adapter, Manager, Peripheral};
+//! use btleplug::api::{
+//!     bleuuid::uuid_from_u16, Central, Manager as _, Peripheral as _, PeripheralProperties,
+//!     ScanFilter, WriteType,
+//! };
+//! use btleplug::platform::Manager;
 //! use rand::{Rng, thread_rng};
 //! use std::error::Error;
 //! use std::thread;
@@ -41,12 +44,11 @@
 //!
 //!     // start scanning for devices
 //!     central.start_scan(ScanFilter::default()).await?;
-//!     // instead of waiting, you can use central.events() to get a stream which will
-//!     // notify you of new devices, for an example of that see examples/event_driven_discovery.rs
-//!     time::sleep(Duration::from_secs(2)).await;
 //!
-//!     // find the device we're interested in
-//!     let light = find_light(&central).await.unwrap();
+//!     // wait for the device we're interested in
+//!     let light = central
+//!         .find_peripheral(is_light, Duration::from_secs(10))
+//!         .await?;
 //!
 //!     // connect to the device
 //!     light.connect().await?;
@@ -68,20 +70,11 @@
 //!     Ok(())
 //! }
 //!
-//! async fn find_light(central: &Adapter) -> Option<Peripheral> {
-//!     for p in central.peripherals().await.unwrap() {
-//!         if p.properties()
-//!             .await
-//!             .unwrap()
-//!             .unwrap()
-//!             .local_name
-//!             .iter()
-//!             .any(|name| name.contains("LEDBlue"))
-//!         {
-//!             return Some(p);
-//!         }
-//!     }
-//!     None
+//! fn is_light(properties: &PeripheralProperties) -> bool {
+//!     properties
+//!         .local_name
+//!         .iter()
+//!         .any(|name| name.contains("LEDBlue"))
 //! }
 //! ```
 
@@ -96,13 +89,15 @@ use crate::api::ParseBDAddrError;
 use std::result;
 use std::time::Duration;
 
+#[cfg(not(target_arch = "xtensa"))]
+pub mod aggregate;
 #[cfg(not(target_arch = "xtensa"))]
 pub mod api;
 #[cfg(target_arch = "wasm32")]
 mod web;
 #[cfg(target_os = "linux")]
 mod bluez;
-#[cfg(not(any(target_os = "linux", target_arch = "xtensa")))]
+#[cfg(not(target_arch = "xtensa"))]
 mod common;
 #[cfg(any(target_os = "macos", target_os = "ios"))]
 mod corebluetooth;
@@ -110,6 +105,12 @@ mod corebluetooth;
 mod droidplug;
 #[cfg(not(target_arch = "xtensa"))]
 pub mod platform;
+#[cfg(not(target_arch = "xtensa"))]
+pub mod positioning;
+#[cfg(not(target_arch = "xtensa"))]
+pub mod presence;
+#[cfg(not(target_arch = "xtensa"))]
+pub mod proximity;
 #[cfg(feature = "serde")]
 pub mod serde;
 #[cfg(target_os = "windows")]
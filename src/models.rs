@@ -227,3 +227,33 @@ pub enum ScanFilter {
     /// Matches if the device advertises the specified manufacturer data.
     ManufacturerData(u16, Vec<u8>),
 }
+
+impl From<ScanFilter> for crate::api::ScanFilter {
+    fn from(filter: ScanFilter) -> Self {
+        use crate::api::ScanCondition;
+
+        let (services, condition) = match filter {
+            ScanFilter::None => (Vec::new(), None),
+            ScanFilter::Service(uuid) => (vec![uuid], None),
+            ScanFilter::AnyService(uuids) => (uuids, None),
+            ScanFilter::AllServices(uuids) => (
+                Vec::new(),
+                Some(ScanCondition::All(
+                    uuids.into_iter().map(ScanCondition::Service).collect(),
+                )),
+            ),
+            ScanFilter::ManufacturerData(company_id, data) => (
+                Vec::new(),
+                Some(ScanCondition::ManufacturerData {
+                    company_id,
+                    data,
+                    mask: Vec::new(),
+                }),
+            ),
+        };
+        Self {
+            services,
+            condition,
+        }
+    }
+}
@@ -0,0 +1,426 @@
+// btleplug Source Code File
+//
+// Copyright 2020 Nonpolynomial Labs LLC. All rights reserved.
+//
+// Licensed under the BSD 3-Clause license. See LICENSE file in the project root
+// for full license information.
+
+//! Estimating the position of a peripheral from the RSSI received by adapters at known positions.
+//!
+//! Each reading is turned into a distance with a [`PathLossModel`], and the position that best fits
+//! the distances is found by weighted least squares, weighting nearer readings more as the error of
+//! a distance estimated from RSSI grows with the distance. With an
+//! [`AggregateCentral`](crate::aggregate::AggregateCentral), a [`Locator`] holds the position of
+//! each of its adapters and locates its peripherals from the RSSI each adapter last received.
+
+use crate::aggregate::{AggregatePeripheral, RssiReading};
+use crate::api::{Central, Peripheral as _};
+use crate::proximity::PathLossModel;
+use crate::{Error, Result};
+use std::time::{Duration, Instant};
+
+/// The most Gauss-Newton iterations to fit a position.
+const MAX_ITERATIONS: usize = 50;
+/// Distances are clamped to at least this many metres when weighting, so that a reading right
+/// next to an adapter doesn't outweigh all others.
+const MIN_WEIGHTED_DISTANCE: f64 = 0.1;
+
+/// A point in space, in metres. For 2-D positioning `z` is ignored.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Position {
+    pub x: f64,
+    pub y: f64,
+    pub z: f64,
+}
+
+impl Position {
+    pub fn new(x: f64, y: f64, z: f64) -> Self {
+        Self { x, y, z }
+    }
+
+    fn coordinates(&self) -> [f64; 3] {
+        [self.x, self.y, self.z]
+    }
+
+    /// The distance to another point, in the given number of dimensions.
+    pub fn distance(&self, other: &Position, dimensions: Dimensions) -> f64 {
+        let (a, b) = (self.coordinates(), other.coordinates());
+        (0..dimensions.count())
+            .map(|i| (a[i] - b[i]).powi(2))
+            .sum::<f64>()
+            .sqrt()
+    }
+}
+
+/// Whether to estimate positions in a plane or in space.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum Dimensions {
+    #[default]
+    Two,
+    Three,
+}
+
+impl Dimensions {
+    fn count(self) -> usize {
+        match self {
+            Dimensions::Two => 2,
+            Dimensions::Three => 3,
+        }
+    }
+}
+
+/// An estimated distance from a point of known position.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Range {
+    pub anchor: Position,
+    /// The distance in metres.
+    pub distance: f64,
+}
+
+/// An estimated position.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PositionEstimate {
+    pub position: Position,
+    /// The weighted RMS difference, in metres, between the distances of the readings and the
+    /// distances from the estimated position. It is 0 if the readings agree exactly, and grows
+    /// with their disagreement.
+    pub confidence_radius: f64,
+}
+
+/// Finds the position that best fits the given ranges by weighted least squares. Needs at least
+/// one range more than there are dimensions, from anchors that don't all lie on a line (or in 3-D,
+/// a plane); otherwise the position can't be determined and `None` is returned.
+pub fn multilaterate(ranges: &[Range], dimensions: Dimensions) -> Option<PositionEstimate> {
+    let n = dimensions.count();
+    if ranges.len() <= n {
+        return None;
+    }
+    let weights: Vec<f64> = ranges
+        .iter()
+        .map(|range| range.distance.max(MIN_WEIGHTED_DISTANCE).powi(-2))
+        .collect();
+    let total_weight: f64 = weights.iter().sum();
+
+    // Start from the weighted centroid of the anchors.
+    let mut position = [0.0; 3];
+    for (range, weight) in ranges.iter().zip(&weights) {
+        for (p, a) in position.iter_mut().zip(range.anchor.coordinates()).take(n) {
+            *p += a * weight / total_weight;
+        }
+    }
+
+    for _ in 0..MAX_ITERATIONS {
+        // Solve the normal equations (JᵀWJ) step = -JᵀWr of the linearised problem.
+        let mut jtj = [[0.0; 3]; 3];
+        let mut jtr = [0.0; 3];
+        for (range, weight) in ranges.iter().zip(&weights) {
+            let anchor = range.anchor.coordinates();
+            let offset: Vec<f64> = (0..n).map(|i| position[i] - anchor[i]).collect();
+            let distance = offset.iter().map(|d| d * d).sum::<f64>().sqrt();
+            if distance == 0.0 {
+                continue;
+            }
+            let residual = distance - range.distance;
+            for i in 0..n {
+                let ji = offset[i] / distance;
+                jtr[i] -= weight * ji * residual;
+                for j in 0..n {
+                    jtj[i][j] += weight * ji * offset[j] / distance;
+                }
+            }
+        }
+        let step = solve(jtj, jtr, n)?;
+        for i in 0..n {
+            position[i] += step[i];
+        }
+        if step.iter().map(|s| s * s).sum::<f64>() < 1e-18 {
+            break;
+        }
+    }
+
+    let position = Position::new(position[0], position[1], position[2]);
+    let squared_error: f64 = ranges
+        .iter()
+        .zip(&weights)
+        .map(|(range, weight)| {
+            weight * (position.distance(&range.anchor, dimensions) - range.distance).powi(2)
+        })
+        .sum();
+    Some(PositionEstimate {
+        position,
+        confidence_radius: (squared_error / total_weight).sqrt(),
+    })
+}
+
+/// Solves the `n` by `n` system `a x = b` by Gaussian elimination, or returns `None` if it is
+/// singular.
+fn solve(mut a: [[f64; 3]; 3], mut b: [f64; 3], n: usize) -> Option<[f64; 3]> {
+    let scale = (0..n).map(|i| a[i][i].abs()).fold(0.0, f64::max);
+    for column in 0..n {
+        let pivot =
+            (column..n).max_by(|&i, &j| a[i][column].abs().total_cmp(&a[j][column].abs()))?;
+        if a[pivot][column].abs() <= scale * 1e-12 {
+            return None;
+        }
+        a.swap(column, pivot);
+        b.swap(column, pivot);
+        for row in column + 1..n {
+            let factor = a[row][column] / a[column][column];
+            let pivot_row = a[column];
+            for (value, pivot_value) in a[row][column..n].iter_mut().zip(&pivot_row[column..n]) {
+                *value -= factor * pivot_value;
+            }
+            b[row] -= factor * b[column];
+        }
+    }
+    let mut x = [0.0; 3];
+    for row in (0..n).rev() {
+        let sum: f64 = (row + 1..n).map(|k| a[row][k] * x[k]).sum();
+        x[row] = (b[row] - sum) / a[row][row];
+    }
+    Some(x)
+}
+
+/// Locates the peripherals of an [`AggregateCentral`](crate::aggregate::AggregateCentral) from
+/// the positions of its adapters.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Locator {
+    /// The position of each adapter, in the order of the aggregate's adapters.
+    pub anchors: Vec<Position>,
+    pub dimensions: Dimensions,
+    pub path_loss: PathLossModel,
+    /// The RSSI at 1 m to assume for peripherals that don't advertise their TX power or measured
+    /// power, in dBm.
+    pub default_measured_power: f64,
+    /// How old a reading may be for [`locate_peripheral`](Self::locate_peripheral) to use it, as
+    /// one from a peripheral that has since moved would be misleading.
+    pub max_reading_age: Duration,
+}
+
+/// The error [`Locator::locate_peripheral`] fails with when too few adapters have received the
+/// peripheral recently to locate it.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, thiserror::Error)]
+#[error("Only {fresh} recent readings, of the {needed} needed to locate the peripheral")]
+pub struct NotEnoughReadings {
+    pub fresh: usize,
+    pub needed: usize,
+}
+
+impl Locator {
+    pub fn new(anchors: Vec<Position>, dimensions: Dimensions) -> Self {
+        Self {
+            anchors,
+            dimensions,
+            path_loss: PathLossModel::default(),
+            default_measured_power: -59.0,
+            max_reading_age: Duration::from_secs(5),
+        }
+    }
+
+    /// Estimates a position from the RSSI received by each adapter, indexed like the anchors, for
+    /// a peripheral with the given RSSI at 1 m. Adapters without a reading are left out.
+    pub fn locate(&self, rssi: &[Option<i16>], measured_power: f64) -> Option<PositionEstimate> {
+        let ranges: Vec<Range> = self
+            .anchors
+            .iter()
+            .zip(rssi)
+            .filter_map(|(&anchor, rssi)| {
+                Some(Range {
+                    anchor,
+                    distance: self.path_loss.distance((*rssi)?.into(), measured_power),
+                })
+            })
+            .collect();
+        multilaterate(&ranges, self.dimensions)
+    }
+
+    /// Estimates the position of a peripheral from the RSSI its adapters last received from it,
+    /// within the last [`max_reading_age`](Self::max_reading_age). Fails with
+    /// [`NotEnoughReadings`] if fewer adapters than needed have received it since.
+    pub async fn locate_peripheral<C: Central + 'static>(
+        &self,
+        peripheral: &AggregatePeripheral<C>,
+    ) -> Result<Option<PositionEstimate>> {
+        let rssi = self.fresh_rssi(&peripheral.adapter_readings(), Instant::now());
+        let fresh = rssi.iter().flatten().count();
+        let needed = self.dimensions.count() + 1;
+        if fresh < needed {
+            return Err(Error::Other(Box::new(NotEnoughReadings { fresh, needed })));
+        }
+        let measured_power = peripheral
+            .properties()
+            .await?
+            .and_then(|properties| self.path_loss.measured_power(&properties))
+            .unwrap_or(self.default_measured_power);
+        Ok(self.locate(&rssi, measured_power))
+    }
+
+    /// The RSSI of the readings received within the maximum age as of `now`.
+    fn fresh_rssi(&self, readings: &[Option<RssiReading>], now: Instant) -> Vec<Option<i16>> {
+        readings
+            .iter()
+            .map(|reading| {
+                reading
+                    .filter(|reading| {
+                        now.saturating_duration_since(reading.received_at) <= self.max_reading_age
+                    })
+                    .map(|reading| reading.rssi)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::aggregate::{AggregateCentral, RoutingPolicy};
+    use crate::api::BDAddr;
+    use crate::common::mock::{peripheral_id, MockCentral, MockPeripheral};
+
+    fn ranges(anchors: &[Position], target: Position, dimensions: Dimensions) -> Vec<Range> {
+        anchors
+            .iter()
+            .map(|&anchor| Range {
+                anchor,
+                distance: anchor.distance(&target, dimensions),
+            })
+            .collect()
+    }
+
+    fn square() -> Vec<Position> {
+        vec![
+            Position::new(0.0, 0.0, 0.0),
+            Position::new(10.0, 0.0, 0.0),
+            Position::new(0.0, 10.0, 0.0),
+            Position::new(10.0, 10.0, 0.0),
+        ]
+    }
+
+    fn assert_near(actual: Position, expected: Position, tolerance: f64) {
+        assert!(
+            actual.distance(&expected, Dimensions::Three) < tolerance,
+            "{:?} isn't near {:?}",
+            actual,
+            expected
+        );
+    }
+
+    #[test]
+    fn exact_ranges_in_a_plane() {
+        let target = Position::new(3.0, 7.0, 0.0);
+        let estimate =
+            multilaterate(&ranges(&square(), target, Dimensions::Two), Dimensions::Two).unwrap();
+        assert_near(estimate.position, target, 1e-6);
+        assert!(estimate.confidence_radius < 1e-6);
+    }
+
+    #[test]
+    fn exact_ranges_in_space() {
+        let mut anchors = square();
+        anchors.push(Position::new(5.0, 5.0, 3.0));
+        let target = Position::new(2.0, 4.0, 1.5);
+        let estimate = multilaterate(
+            &ranges(&anchors, target, Dimensions::Three),
+            Dimensions::Three,
+        )
+        .unwrap();
+        assert_near(estimate.position, target, 1e-6);
+    }
+
+    #[test]
+    fn noisy_ranges_have_a_confidence_radius() {
+        let target = Position::new(4.0, 5.0, 0.0);
+        let mut ranges = ranges(&square(), target, Dimensions::Two);
+        for (range, error) in ranges.iter_mut().zip([0.5, -0.4, 0.3, -0.6]) {
+            range.distance += error;
+        }
+        let estimate = multilaterate(&ranges, Dimensions::Two).unwrap();
+        assert_near(estimate.position, target, 1.0);
+        assert!(estimate.confidence_radius > 0.0 && estimate.confidence_radius < 1.0);
+    }
+
+    #[test]
+    fn undetermined_positions() {
+        let target = Position::new(3.0, 7.0, 0.0);
+        let anchors = square();
+        assert_eq!(
+            multilaterate(
+                &ranges(&anchors[..2], target, Dimensions::Two),
+                Dimensions::Two
+            ),
+            None
+        );
+        // All anchors in a plane can't tell above from below.
+        assert_eq!(
+            multilaterate(
+                &ranges(&anchors, target, Dimensions::Three),
+                Dimensions::Three
+            ),
+            None
+        );
+    }
+
+    #[test]
+    fn locates_from_rssi() {
+        let locator = Locator::new(square(), Dimensions::Two);
+        let target = Position::new(6.0, 2.0, 0.0);
+        let measured_power = -59.0;
+        // The RSSI each anchor would receive according to the path loss model.
+        let mut rssi: Vec<Option<i16>> = square()
+            .iter()
+            .map(|anchor| {
+                let distance = anchor.distance(&target, Dimensions::Two);
+                Some((measured_power - 20.0 * distance.log10()).round() as i16)
+            })
+            .collect();
+        let estimate = locator.locate(&rssi, measured_power).unwrap();
+        // Rounding the RSSI to whole dBm costs some accuracy.
+        assert_near(estimate.position, target, 0.5);
+
+        rssi[0] = None;
+        assert!(locator.locate(&rssi, measured_power).is_some());
+        rssi[1] = None;
+        assert!(locator.locate(&rssi, measured_power).is_none());
+    }
+
+    #[test]
+    fn leaves_out_old_readings() {
+        let locator = Locator::new(square(), Dimensions::Two);
+        let now = Instant::now();
+        let reading = |rssi, age| {
+            Some(RssiReading {
+                rssi,
+                received_at: now - Duration::from_secs(age),
+            })
+        };
+        assert_eq!(
+            locator.fresh_rssi(
+                &[reading(-60, 1), None, reading(-70, 6), reading(-80, 5)],
+                now
+            ),
+            vec![Some(-60), None, None, Some(-80)]
+        );
+    }
+
+    #[tokio::test]
+    async fn needs_enough_fresh_readings() {
+        let central = MockCentral::default();
+        central.add(MockPeripheral::with_address(
+            1,
+            BDAddr::from([0x11, 0x22, 0x33, 0x44, 0x55, 0x66]),
+        ));
+        let aggregate = AggregateCentral::new(vec![central], RoutingPolicy::default());
+        let peripheral = aggregate.peripheral(&peripheral_id(1)).await.unwrap();
+        let locator = Locator::new(square(), Dimensions::Two);
+        match locator.locate_peripheral(&peripheral).await {
+            Err(Error::Other(e)) => assert_eq!(
+                e.downcast_ref(),
+                Some(&NotEnoughReadings {
+                    fresh: 0,
+                    needed: 3
+                })
+            ),
+            result => panic!("Expected too few readings, got {:?}", result),
+        }
+    }
+}
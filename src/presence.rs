@@ -0,0 +1,333 @@
+// btleplug Source Code File
+//
+// Copyright 2020 Nonpolynomial Labs LLC. All rights reserved.
+//
+// Licensed under the BSD 3-Clause license. See LICENSE file in the project root
+// for full license information.
+
+//! Presence of devices, and the zone they are in, from their advertisements.
+//!
+//! A [`PresenceEngine`] smooths the RSSI of each device like a
+//! [`ProximityEstimator`](crate::proximity::ProximityEstimator), and reports when a device comes
+//! into range, moves between the [`Zone`]s set by RSSI thresholds, and leaves. A device has to
+//! cross a threshold by the configured hysteresis before it leaves a zone, so that one hovering
+//! around the threshold doesn't flap between two zones.
+
+use crate::api::{Central, CentralEvent};
+use crate::platform::PeripheralId;
+use crate::proximity::{PathLossModel, ProximityEstimator, RssiFilter};
+use log::warn;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// How close a present device is, by its smoothed RSSI.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum Zone {
+    Far,
+    Mid,
+    Near,
+}
+
+/// A change in the presence of a device.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum PresenceEvent {
+    /// The device came into range.
+    Entered { id: PeripheralId, zone: Zone },
+    /// The device moved from one zone to another.
+    ZoneChanged {
+        id: PeripheralId,
+        from: Zone,
+        to: Zone,
+    },
+    /// The device went out of range, or wasn't heard from for the absence timeout.
+    Exited(PeripheralId),
+}
+
+/// The thresholds and timeout of a [`PresenceEngine`]. The RSSI thresholds are in dBm, and
+/// compared with the smoothed RSSI of a device.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PresenceConfig {
+    /// The RSSI from which a device is in range, in the [`Zone::Far`] zone.
+    pub enter_rssi: i16,
+    /// The RSSI from which a device is in the [`Zone::Mid`] zone.
+    pub mid_rssi: i16,
+    /// The RSSI from which a device is in the [`Zone::Near`] zone.
+    pub near_rssi: i16,
+    /// How many dB below a threshold the RSSI must fall before a device leaves the zone above it.
+    pub hysteresis: f64,
+    /// How long a device may go without being heard before it is considered to have left.
+    pub absence_timeout: Duration,
+}
+
+impl Default for PresenceConfig {
+    fn default() -> Self {
+        Self {
+            enter_rssi: -90,
+            mid_rssi: -75,
+            near_rssi: -60,
+            hysteresis: 5.0,
+            absence_timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+impl PresenceConfig {
+    /// The zone a device with the given RSSI is in, going by the thresholds alone.
+    fn zone(&self, rssi: f64) -> Option<Zone> {
+        if rssi >= self.near_rssi.into() {
+            Some(Zone::Near)
+        } else if rssi >= self.mid_rssi.into() {
+            Some(Zone::Mid)
+        } else if rssi >= self.enter_rssi.into() {
+            Some(Zone::Far)
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Debug)]
+struct DeviceState {
+    zone: Option<Zone>,
+    last_heard: Instant,
+}
+
+/// Tracks which devices are present and in which zone.
+#[derive(Debug)]
+pub struct PresenceEngine<F> {
+    config: PresenceConfig,
+    estimator: ProximityEstimator<F>,
+    devices: HashMap<PeripheralId, DeviceState>,
+}
+
+impl<F: RssiFilter + Clone> PresenceEngine<F> {
+    /// Smooths the RSSI of each device with a clone of `filter`.
+    pub fn new(config: PresenceConfig, filter: F) -> Self {
+        Self {
+            config,
+            estimator: ProximityEstimator::new(filter, PathLossModel::default()),
+            devices: HashMap::new(),
+        }
+    }
+
+    /// Adds an RSSI reading of a device received at `now`, returning the change in its presence
+    /// if there is one.
+    pub fn record(&mut self, id: &PeripheralId, rssi: i16, now: Instant) -> Option<PresenceEvent> {
+        let rssi = self.estimator.record(id, rssi, None).rssi;
+        self.update(id, rssi, now)
+    }
+
+    /// Updates the presence of devices from an event from
+    /// [`Central::events`](crate::api::Central::events), also reporting any devices that have
+    /// timed out. Failing to read the properties of the device the event is about is logged, so
+    /// that the devices that timed out are still reported.
+    pub async fn handle_event<C: Central>(
+        &mut self,
+        central: &C,
+        event: &CentralEvent,
+    ) -> Vec<PresenceEvent> {
+        let now = Instant::now();
+        let mut events = self.expire(now);
+        if let CentralEvent::DeviceLost(id) = event {
+            events.extend(self.remove(id));
+        } else {
+            match self.estimator.handle_event(central, event).await {
+                Ok(Some((id, proximity))) => events.extend(self.update(&id, proximity.rssi, now)),
+                Ok(None) => {}
+                Err(e) => warn!("Failed to read the device for {:?}: {}", event, e),
+            }
+        }
+        events
+    }
+
+    fn update(&mut self, id: &PeripheralId, rssi: f64, now: Instant) -> Option<PresenceEvent> {
+        let config = self.config;
+        let state = self
+            .devices
+            .entry(id.clone())
+            .or_insert_with(|| DeviceState {
+                zone: None,
+                last_heard: now,
+            });
+        state.last_heard = now;
+        let mut zone = config.zone(rssi);
+        if zone < state.zone {
+            // Only move down as far as the hysteresis allows.
+            zone = config.zone(rssi + config.hysteresis).min(state.zone);
+        }
+        let event = match (state.zone, zone) {
+            (None, Some(zone)) => PresenceEvent::Entered {
+                id: id.clone(),
+                zone,
+            },
+            (Some(from), Some(to)) if from != to => PresenceEvent::ZoneChanged {
+                id: id.clone(),
+                from,
+                to,
+            },
+            (Some(_), None) => PresenceEvent::Exited(id.clone()),
+            _ => return None,
+        };
+        state.zone = zone;
+        Some(event)
+    }
+}
+
+impl<F> PresenceEngine<F> {
+    /// Reports the devices that haven't been heard from for the absence timeout as of `now` as
+    /// having left, and forgets them. As this is otherwise only checked when an event is handled,
+    /// it should be called periodically so that devices leave even when nothing else is heard.
+    pub fn expire(&mut self, now: Instant) -> Vec<PresenceEvent> {
+        let timeout = self.config.absence_timeout;
+        let expired: Vec<PeripheralId> = self
+            .devices
+            .iter()
+            .filter(|(_, state)| now.saturating_duration_since(state.last_heard) >= timeout)
+            .map(|(id, _)| id.clone())
+            .collect();
+        expired.iter().filter_map(|id| self.remove(id)).collect()
+    }
+
+    /// Forgets a device, returning [`PresenceEvent::Exited`] if it was present.
+    pub fn remove(&mut self, id: &PeripheralId) -> Option<PresenceEvent> {
+        self.estimator.remove(id);
+        self.devices
+            .remove(id)?
+            .zone
+            .map(|_| PresenceEvent::Exited(id.clone()))
+    }
+
+    /// The zone of a device, if it is present.
+    pub fn zone(&self, id: &PeripheralId) -> Option<Zone> {
+        self.devices.get(id).and_then(|state| state.zone)
+    }
+
+    /// The devices that are present, with their zones.
+    pub fn present(&self) -> impl Iterator<Item = (&PeripheralId, Zone)> {
+        self.devices
+            .iter()
+            .filter_map(|(id, state)| Some((id, state.zone?)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::PeripheralProperties;
+    use crate::common::mock::{peripheral_id, MockCentral, MockPeripheral};
+    use crate::proximity::MovingAverage;
+
+    /// An engine that doesn't smooth, so that tests control the RSSI exactly.
+    fn engine() -> PresenceEngine<MovingAverage> {
+        PresenceEngine::new(PresenceConfig::default(), MovingAverage::new(1))
+    }
+
+    #[test]
+    fn enters_and_changes_zone() {
+        let mut engine = engine();
+        let id = peripheral_id(1);
+        let now = Instant::now();
+        assert_eq!(engine.record(&id, -95, now), None);
+        assert_eq!(
+            engine.record(&id, -80, now),
+            Some(PresenceEvent::Entered {
+                id: id.clone(),
+                zone: Zone::Far
+            })
+        );
+        assert_eq!(
+            engine.record(&id, -55, now),
+            Some(PresenceEvent::ZoneChanged {
+                id: id.clone(),
+                from: Zone::Far,
+                to: Zone::Near
+            })
+        );
+        assert_eq!(engine.zone(&id), Some(Zone::Near));
+        assert_eq!(
+            engine.present().collect::<Vec<_>>(),
+            vec![(&id, Zone::Near)]
+        );
+    }
+
+    #[test]
+    fn hysteresis_prevents_flapping() {
+        let mut engine = engine();
+        let id = peripheral_id(1);
+        let now = Instant::now();
+        engine.record(&id, -60, now);
+        for rssi in [-62, -64, -60, -63, -59, -64] {
+            assert_eq!(engine.record(&id, rssi, now), None);
+        }
+        assert_eq!(
+            engine.record(&id, -66, now),
+            Some(PresenceEvent::ZoneChanged {
+                id: id.clone(),
+                from: Zone::Near,
+                to: Zone::Mid
+            })
+        );
+        // Dropping far below a threshold skips the zones in between.
+        assert_eq!(
+            engine.record(&id, -100, now),
+            Some(PresenceEvent::Exited(id.clone()))
+        );
+        assert_eq!(engine.zone(&id), None);
+    }
+
+    #[test]
+    fn exits_after_absence_timeout() {
+        let mut engine = engine();
+        let first = peripheral_id(1);
+        let second = peripheral_id(2);
+        let start = Instant::now();
+        engine.record(&first, -70, start);
+        engine.record(&second, -70, start + Duration::from_secs(5));
+        assert_eq!(engine.expire(start + Duration::from_secs(9)), vec![]);
+        assert_eq!(
+            engine.expire(start + Duration::from_secs(10)),
+            vec![PresenceEvent::Exited(first.clone())]
+        );
+        assert_eq!(engine.zone(&second), Some(Zone::Mid));
+        // A device that comes back enters again.
+        assert_eq!(
+            engine.record(&first, -70, start + Duration::from_secs(11)),
+            Some(PresenceEvent::Entered {
+                id: first,
+                zone: Zone::Mid
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn reports_timeouts_when_reading_a_device_fails() {
+        let mut engine = PresenceEngine::new(
+            PresenceConfig {
+                absence_timeout: Duration::ZERO,
+                ..Default::default()
+            },
+            MovingAverage::new(1),
+        );
+        let central = MockCentral::default();
+        let peripheral = MockPeripheral::new(1);
+        peripheral.set_properties(PeripheralProperties {
+            rssi: Some(-50),
+            ..Default::default()
+        });
+        central.add(peripheral.clone());
+        let event = CentralEvent::DeviceDiscovered(peripheral_id(1));
+        assert_eq!(
+            engine.handle_event(&central, &event).await,
+            vec![PresenceEvent::Entered {
+                id: peripheral_id(1),
+                zone: Zone::Near
+            }]
+        );
+
+        peripheral.set_lost();
+        assert_eq!(
+            engine.handle_event(&central, &event).await,
+            vec![PresenceEvent::Exited(peripheral_id(1))]
+        );
+    }
+}
@@ -0,0 +1,457 @@
+// btleplug Source Code File
+//
+// Copyright 2020 Nonpolynomial Labs LLC. All rights reserved.
+//
+// Licensed under the BSD 3-Clause license. See LICENSE file in the project root
+// for full license information.
+
+//! Smoothing of RSSI readings and estimation of the distance to peripherals.
+//!
+//! The RSSI of a device commonly jumps by 10 dBm from one advertisement to the next, so a
+//! [`ProximityEstimator`] passes the readings of each device through an [`RssiFilter`] before
+//! estimating the distance from them with a [`PathLossModel`]. It can be fed from
+//! [`Central::events`](crate::api::Central::events) with
+//! [`handle_event`](ProximityEstimator::handle_event), or with readings from anywhere else with
+//! [`record`](ProximityEstimator::record).
+
+use crate::api::bleuuid::uuid_from_u16;
+use crate::api::{Central, CentralEvent, Peripheral as _, PeripheralProperties};
+use crate::platform::PeripheralId;
+use crate::{Error, Result};
+use std::collections::{HashMap, VecDeque};
+use std::fmt::Debug;
+use std::time::Instant;
+use uuid::Uuid;
+
+/// Company ID of Apple, which defined the iBeacon format.
+const APPLE_COMPANY_ID: u16 = 0x004c;
+/// Service UUID of Eddystone beacons.
+const EDDYSTONE_UUID: Uuid = uuid_from_u16(0xfeaa);
+
+/// Smooths a series of RSSI readings of one device.
+pub trait RssiFilter: Debug + Send {
+    /// Adds a reading, in dBm, and returns the smoothed RSSI.
+    fn update(&mut self, rssi: f64) -> f64;
+}
+
+/// The mean of the last `window` readings.
+#[derive(Clone, Debug)]
+pub struct MovingAverage {
+    window: usize,
+    readings: VecDeque<f64>,
+}
+
+impl MovingAverage {
+    /// Averages over the given number of readings, which is at least 1.
+    pub fn new(window: usize) -> Self {
+        Self {
+            window: window.max(1),
+            readings: VecDeque::new(),
+        }
+    }
+}
+
+impl RssiFilter for MovingAverage {
+    fn update(&mut self, rssi: f64) -> f64 {
+        if self.readings.len() == self.window {
+            self.readings.pop_front();
+        }
+        self.readings.push_back(rssi);
+        self.readings.iter().sum::<f64>() / self.readings.len() as f64
+    }
+}
+
+/// An exponentially weighted moving average, which gives each new reading the weight `alpha` and
+/// the previous average `1 - alpha`.
+#[derive(Clone, Debug)]
+pub struct Exponential {
+    alpha: f64,
+    value: Option<f64>,
+}
+
+impl Exponential {
+    /// `alpha` is clamped to the range 0 to 1. Lower values smooth more, but follow actual changes
+    /// more slowly.
+    pub fn new(alpha: f64) -> Self {
+        Self {
+            alpha: alpha.clamp(0.0, 1.0),
+            value: None,
+        }
+    }
+}
+
+impl RssiFilter for Exponential {
+    fn update(&mut self, rssi: f64) -> f64 {
+        let value = match self.value {
+            Some(value) => value + self.alpha * (rssi - value),
+            None => rssi,
+        };
+        self.value = Some(value);
+        value
+    }
+}
+
+/// A one-dimensional Kalman filter, which models the RSSI as a constant disturbed by noise.
+#[derive(Clone, Debug)]
+pub struct Kalman {
+    process_noise: f64,
+    measurement_noise: f64,
+    /// The estimate and its variance.
+    estimate: Option<(f64, f64)>,
+}
+
+impl Kalman {
+    /// `process_noise` is the variance of the actual changes in RSSI between readings, e.g. from
+    /// the device moving, and `measurement_noise` the variance of the readings around the actual
+    /// RSSI.
+    pub fn new(process_noise: f64, measurement_noise: f64) -> Self {
+        Self {
+            process_noise,
+            measurement_noise,
+            estimate: None,
+        }
+    }
+}
+
+impl Default for Kalman {
+    fn default() -> Self {
+        Self::new(0.01, 4.0)
+    }
+}
+
+impl RssiFilter for Kalman {
+    fn update(&mut self, rssi: f64) -> f64 {
+        let (value, variance) = match self.estimate {
+            Some((value, variance)) => {
+                let variance = variance + self.process_noise;
+                let gain = variance / (variance + self.measurement_noise);
+                (value + gain * (rssi - value), (1.0 - gain) * variance)
+            }
+            None => (rssi, self.measurement_noise),
+        };
+        self.estimate = Some((value, variance));
+        value
+    }
+}
+
+/// The log-distance path loss model, relating the RSSI at some distance to the RSSI at 1 m.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PathLossModel {
+    /// How quickly the signal weakens with distance: 2 in free space, and commonly 2.5 to 4
+    /// indoors.
+    pub exponent: f64,
+    /// The loss in dB over the first metre, to get the RSSI at 1 m from an advertised TX power
+    /// level, which is the power at 0 m.
+    pub loss_at_one_metre: f64,
+}
+
+impl Default for PathLossModel {
+    fn default() -> Self {
+        Self {
+            exponent: 2.0,
+            loss_at_one_metre: 41.0,
+        }
+    }
+}
+
+impl PathLossModel {
+    /// Estimates the distance in metres at which the given RSSI is received from a device whose
+    /// RSSI at 1 m is `measured_power`, both in dBm.
+    pub fn distance(&self, rssi: f64, measured_power: f64) -> f64 {
+        10f64.powf((measured_power - rssi) / (10.0 * self.exponent))
+    }
+
+    /// The RSSI at 1 m from a device, from its beacon data if it is an iBeacon or Eddystone
+    /// beacon, or else its advertised TX power level.
+    pub fn measured_power(&self, properties: &PeripheralProperties) -> Option<f64> {
+        if let Some(data) = properties.manufacturer_data.get(&APPLE_COMPANY_ID)
+            && data.len() == 23
+            && let [0x02, 0x15, .., measured_power] = data[..]
+        {
+            return Some(measured_power as i8 as f64);
+        }
+        // The UID, URL and EID frames have the TX power at 0 m after the frame type.
+        if let Some(data) = properties.service_data.get(&EDDYSTONE_UUID)
+            && let [0x00 | 0x10 | 0x30, tx_power, ..] = data[..]
+        {
+            return Some(tx_power as i8 as f64 - self.loss_at_one_metre);
+        }
+        properties
+            .tx_power_level
+            .map(|tx_power| tx_power as f64 - self.loss_at_one_metre)
+    }
+}
+
+/// The estimated proximity of a device.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Proximity {
+    /// The smoothed RSSI, in dBm.
+    pub rssi: f64,
+    /// The estimated distance in metres, if the device advertises its TX power or measured power.
+    pub distance: Option<f64>,
+}
+
+#[derive(Debug)]
+struct DeviceState<F> {
+    filter: F,
+    proximity: Proximity,
+    /// When the reading last fed to the filter was received, to skip readings already seen.
+    last_seen: Option<Instant>,
+}
+
+/// Keeps a smoothed RSSI and estimated distance for each device.
+#[derive(Debug)]
+pub struct ProximityEstimator<F> {
+    filter: F,
+    path_loss: PathLossModel,
+    devices: HashMap<PeripheralId, DeviceState<F>>,
+}
+
+impl<F: RssiFilter + Clone> ProximityEstimator<F> {
+    /// Smooths the readings of each device with a clone of `filter`.
+    pub fn new(filter: F, path_loss: PathLossModel) -> Self {
+        Self {
+            filter,
+            path_loss,
+            devices: HashMap::new(),
+        }
+    }
+
+    /// Adds an RSSI reading of a device, with its RSSI at 1 m if known, and returns its updated
+    /// proximity.
+    pub fn record(
+        &mut self,
+        id: &PeripheralId,
+        rssi: i16,
+        measured_power: Option<f64>,
+    ) -> Proximity {
+        let state = self
+            .devices
+            .entry(id.clone())
+            .or_insert_with(|| DeviceState {
+                filter: self.filter.clone(),
+                proximity: Proximity {
+                    rssi: rssi.into(),
+                    distance: None,
+                },
+                last_seen: None,
+            });
+        let rssi = state.filter.update(rssi.into());
+        state.proximity = Proximity {
+            rssi,
+            distance: measured_power.map(|power| self.path_loss.distance(rssi, power)),
+        };
+        state.proximity
+    }
+
+    /// Adds the RSSI reading in the properties of a device, if there is one.
+    pub fn record_properties(
+        &mut self,
+        id: &PeripheralId,
+        properties: &PeripheralProperties,
+    ) -> Option<Proximity> {
+        let rssi = properties.rssi?;
+        let measured_power = self.path_loss.measured_power(properties);
+        Some(self.record(id, rssi, measured_power))
+    }
+
+    /// Updates the proximity of the device an event from
+    /// [`Central::events`](crate::api::Central::events) is about, from its current properties.
+    /// Returns the device and its updated proximity if there was a new RSSI reading.
+    pub async fn handle_event<C: Central>(
+        &mut self,
+        central: &C,
+        event: &CentralEvent,
+    ) -> Result<Option<(PeripheralId, Proximity)>> {
+        let id = match event {
+            CentralEvent::DeviceDiscovered(id)
+            | CentralEvent::DeviceUpdated { id, .. }
+            | CentralEvent::ManufacturerDataAdvertisement { id, .. }
+            | CentralEvent::ServiceDataAdvertisement { id, .. }
+            | CentralEvent::ServicesAdvertisement { id, .. } => id,
+            CentralEvent::DeviceLost(id) => {
+                self.remove(id);
+                return Ok(None);
+            }
+            _ => return Ok(None),
+        };
+        // The device may have been forgotten since the event was emitted.
+        let peripheral = match central.peripheral(id).await {
+            Ok(peripheral) => peripheral,
+            Err(Error::DeviceNotFound) => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        // A single advertisement may produce several events, which shouldn't all count as a
+        // reading.
+        let last_seen = peripheral.last_seen();
+        if last_seen.is_some()
+            && self
+                .devices
+                .get(id)
+                .is_some_and(|state| state.last_seen == last_seen)
+        {
+            return Ok(None);
+        }
+        let Some(properties) = peripheral.properties().await? else {
+            return Ok(None);
+        };
+        let proximity = self.record_properties(id, &properties);
+        if let Some(state) = self.devices.get_mut(id) {
+            state.last_seen = last_seen;
+        }
+        Ok(proximity.map(|proximity| (id.clone(), proximity)))
+    }
+}
+
+impl<F> ProximityEstimator<F> {
+    /// The latest proximity of a device, if there has been an RSSI reading of it.
+    pub fn proximity(&self, id: &PeripheralId) -> Option<Proximity> {
+        self.devices.get(id).map(|state| state.proximity)
+    }
+
+    /// Forgets a device, so that its readings are smoothed afresh if it comes back.
+    pub fn remove(&mut self, id: &PeripheralId) {
+        self.devices.remove(id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::mock::{peripheral_id, MockCentral, MockPeripheral};
+
+    /// Readings around -60 dBm, jumping by up to 10 dBm either way.
+    const NOISY: [i16; 10] = [-60, -52, -68, -61, -50, -70, -59, -63, -55, -62];
+
+    fn smooth(filter: &mut impl RssiFilter) -> Vec<f64> {
+        NOISY
+            .iter()
+            .map(|&rssi| filter.update(rssi.into()))
+            .collect()
+    }
+
+    fn assert_smoothed(filter: &mut impl RssiFilter) {
+        let smoothed = smooth(filter);
+        assert_eq!(smoothed[0], -60.0);
+        for value in &smoothed[3..] {
+            assert!((value + 60.0).abs() < 5.0, "{:?}", smoothed);
+        }
+    }
+
+    #[test]
+    fn moving_average() {
+        let mut filter = MovingAverage::new(3);
+        assert_eq!(filter.update(-60.0), -60.0);
+        assert_eq!(filter.update(-50.0), -55.0);
+        assert_eq!(filter.update(-70.0), -60.0);
+        assert_eq!(filter.update(-30.0), -50.0);
+        assert_smoothed(&mut MovingAverage::new(5));
+    }
+
+    #[test]
+    fn exponential() {
+        let mut filter = Exponential::new(0.25);
+        assert_eq!(filter.update(-60.0), -60.0);
+        assert_eq!(filter.update(-40.0), -55.0);
+        assert_smoothed(&mut Exponential::new(0.25));
+    }
+
+    #[test]
+    fn kalman() {
+        assert_smoothed(&mut Kalman::default());
+        // Converges on a constant signal.
+        let mut filter = Kalman::default();
+        filter.update(-80.0);
+        let mut value = 0.0;
+        for _ in 0..100 {
+            value = filter.update(-60.0);
+        }
+        assert!((value + 60.0).abs() < 1.0);
+    }
+
+    #[test]
+    fn distance_from_path_loss() {
+        let model = PathLossModel::default();
+        assert!((model.distance(-59.0, -59.0) - 1.0).abs() < 1e-9);
+        assert!((model.distance(-79.0, -59.0) - 10.0).abs() < 1e-9);
+        let indoors = PathLossModel {
+            exponent: 4.0,
+            ..model
+        };
+        assert!((indoors.distance(-79.0, -59.0) - 10f64.sqrt()).abs() < 1e-9);
+    }
+
+    #[test]
+    fn measured_power_from_advertisement() {
+        let model = PathLossModel::default();
+        let mut properties = PeripheralProperties {
+            tx_power_level: Some(4),
+            ..Default::default()
+        };
+        assert_eq!(model.measured_power(&properties), Some(-37.0));
+
+        properties
+            .service_data
+            .insert(EDDYSTONE_UUID, vec![0x00, -20i8 as u8, 0, 0]);
+        assert_eq!(model.measured_power(&properties), Some(-61.0));
+
+        let mut ibeacon = vec![0x02, 0x15];
+        ibeacon.extend([0; 20]);
+        ibeacon.push(-59i8 as u8);
+        properties
+            .manufacturer_data
+            .insert(APPLE_COMPANY_ID, ibeacon);
+        assert_eq!(model.measured_power(&properties), Some(-59.0));
+    }
+
+    #[test]
+    fn estimates_each_device_separately() {
+        let mut estimator =
+            ProximityEstimator::new(MovingAverage::new(2), PathLossModel::default());
+        let near = peripheral_id(1);
+        let far = peripheral_id(2);
+        estimator.record(&near, -50, Some(-50.0));
+        let proximity = estimator.record(&near, -70, Some(-50.0));
+        assert_eq!(proximity.rssi, -60.0);
+        assert!((proximity.distance.unwrap() - 10f64.sqrt()).abs() < 1e-9);
+        estimator.record(&far, -90, None);
+        assert_eq!(
+            estimator.proximity(&far),
+            Some(Proximity {
+                rssi: -90.0,
+                distance: None
+            })
+        );
+        assert_eq!(estimator.proximity(&near), Some(proximity));
+
+        estimator.remove(&near);
+        assert_eq!(estimator.proximity(&near), None);
+        assert_eq!(estimator.record(&near, -40, None).rssi, -40.0);
+    }
+
+    #[tokio::test]
+    async fn ignores_events_for_forgotten_devices() {
+        let central = MockCentral::default();
+        let peripheral = MockPeripheral::new(1);
+        peripheral.set_properties(PeripheralProperties {
+            rssi: Some(-60),
+            ..Default::default()
+        });
+        central.add(peripheral);
+        let mut estimator =
+            ProximityEstimator::new(MovingAverage::new(2), PathLossModel::default());
+
+        let event = CentralEvent::DeviceDiscovered(peripheral_id(1));
+        let (id, proximity) = estimator
+            .handle_event(&central, &event)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!((id, proximity.rssi), (peripheral_id(1), -60.0));
+        let event = CentralEvent::DeviceDiscovered(peripheral_id(2));
+        assert_eq!(
+            estimator.handle_event(&central, &event).await.unwrap(),
+            None
+        );
+    }
+}
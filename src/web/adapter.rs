@@ -1,7 +1,15 @@
 use std::{cell::RefCell, collections::HashMap, pin::Pin, sync::Arc, time::Duration};
-use crate::{common::adapter_manager::AdapterManager, web::tauri, Error, Result};
+use crate::{
+  common::{
+    adapter_manager::AdapterManager, scan_filter::ActiveScanFilter, scan_session::ScanSessions,
+  },
+  web::tauri, Error, Result,
+};
 use super::peripheral::{Peripheral, PeripheralId};
-use crate::api::{Central, CentralEvent, ScanFilter};
+use crate::api::{
+  AdapterInfo, AdapterRoles, Central, CentralEvent, CentralState, EventBufferConfig,
+  HonouredScanOptions, ScanFilter, ScanMode, ScanOptions, ScanSession, ScanTransport,
+};
 use async_trait::async_trait;
 use futures::Stream;
 use gloo_console::{error, log};
@@ -24,7 +32,9 @@ thread_local! {
 #[derive(Clone, Debug)]
 pub struct Adapter {
     manager: Arc<AdapterManager<Peripheral>>,
-    ids: Arc<std::sync::Mutex<BiMap<Uuid, String>>>
+    ids: Arc<std::sync::Mutex<BiMap<Uuid, String>>>,
+    scan_filter: ActiveScanFilter,
+    scan_sessions: ScanSessions,
 }
 
 impl Adapter {
@@ -43,7 +53,9 @@ impl Adapter {
 
         Ok(Adapter {
             manager,
-            ids: Arc::new(std::sync::Mutex::new(Default::default()))
+            ids: Arc::new(std::sync::Mutex::new(Default::default())),
+            scan_filter: ActiveScanFilter::default(),
+            scan_sessions: ScanSessions::default(),
         })
     }
 }
@@ -64,10 +76,28 @@ impl Central for Adapter {
 	type Peripheral = Peripheral;
 
     async fn events(&self) -> Result<Pin<Box<dyn Stream<Item = CentralEvent> + Send>>> {
-      Ok(self.manager.event_stream())
+      Ok(Box::pin(self.scan_filter.filter_events(self.clone(), self.manager.event_stream())))
     }
 
-    async fn start_scan(&self, filter: ScanFilter) -> Result<()> {
+    async fn set_event_buffer(&self, config: EventBufferConfig) -> Result<()> {
+      self.manager.set_event_buffer(config)
+    }
+
+    async fn set_peripheral_retention(&self, retention: Option<Duration>) -> Result<()> {
+      // Evicting peripherals needs a clock, which std doesn't have on wasm.
+      if retention.is_some() {
+        return Err(Error::NotSupported("Peripheral retention".to_string()));
+      }
+      self.manager.set_retention(retention);
+      Ok(())
+    }
+
+    async fn start_scan_with_options(
+      &self,
+      filter: ScanFilter,
+      options: ScanOptions,
+    ) -> Result<HonouredScanOptions> {
+      self.scan_filter.set(&filter)?;
 
       if is_tauri() {
 
@@ -84,7 +114,7 @@ impl Central for Adapter {
             for device in devices {
               let id = device.address;
               let uuid = add_or_get_uuid(ids.clone(), id.clone());
-              if let Some(mut entry) = manager_clone.peripheral_mut(&uuid.into()) {
+              if let Some(_peripheral) = manager_clone.peripheral(&uuid.into()) {
                 // TODO: Update peripheral if it already is registered by the manager
               } else {
                 log!(format!("Bluetooth device name: {}", device.name));
@@ -143,11 +173,15 @@ impl Central for Adapter {
               // Can't get device address (as on other platforms)--devices have unique IDs instead
               //let id = _id.unwrap();
               
-              if let Some(mut entry) = manager_clone.peripheral_mut(&uuid.into()) {
+              if let Some(peripheral) = manager_clone.peripheral(&uuid.into()) {
                 log!(format!("Device found, updating properties."));
   
-                entry.value_mut().update_properties().await;
-                manager_clone.emit(CentralEvent::DeviceUpdated(uuid.into()));
+                peripheral.update_properties().await;
+                // Only the GATT services are refreshed, none of the advertised properties.
+                manager_clone.emit(CentralEvent::DeviceUpdated {
+                  id: uuid.into(),
+                  changes: Default::default(),
+                });
               } else {
                 DEVICES.with_borrow_mut(|devices| {
                   devices.insert(id.clone(), device);
@@ -168,7 +202,17 @@ impl Central for Adapter {
         log!(format!("Done scanning."));
       }
 
-      Ok(())
+      // The browser scans as it sees fit, and reports each chosen device once.
+      Ok(HonouredScanOptions {
+        mode: options.mode == ScanMode::Active,
+        duplicates: !options.duplicates,
+        min_rssi: options.min_rssi.is_none(),
+        max_pathloss: options.max_pathloss.is_none(),
+        transport: options.transport != ScanTransport::BrEdr,
+        interval: options.interval.is_none(),
+        window: options.window.is_none(),
+        rssi_monitor: options.rssi_monitor.is_none(),
+      })
     }
 
     async fn stop_scan(&self) -> Result<()> {
@@ -176,6 +220,10 @@ impl Central for Adapter {
 		  todo!()
     }
 
+    async fn scan(&self, filter: ScanFilter) -> Result<ScanSession<Peripheral>> {
+      self.scan_sessions.scan(self, filter).await
+    }
+
     async fn peripherals(&self) -> Result<Vec<Peripheral>> {
       Ok(self.manager.peripherals())
     }
@@ -190,7 +238,31 @@ impl Central for Adapter {
       ))
     }
 
-    async fn adapter_info(&self) -> Result<String> {
-      Ok("WebBluetooth".to_string())
+    async fn state(&self) -> Result<CentralState> {
+      if is_tauri() {
+        // The plugin doesn't report the state of the adapter.
+        return Ok(CentralState::Unknown);
+      }
+      let (tx, rx) = oneshot::channel::<bool>();
+      spawn_local(async move {
+        let available = JsFuture::from(get_bluetooth_api().get_availability()).await
+          .map(|available| available.as_bool().unwrap_or(false))
+          .unwrap_or(false);
+        let _ = tx.send(available);
+      });
+      // Browsers don't tell an adapter that is off from a missing one.
+      Ok(if rx.await.unwrap_or(false) {
+        CentralState::PoweredOn
+      } else {
+        CentralState::PoweredOff
+      })
+    }
+
+    async fn adapter_info(&self) -> Result<AdapterInfo> {
+      let mut info = AdapterInfo::new("WebBluetooth");
+      info.powered = self.state().await?.is_powered();
+      // Web Bluetooth can only scan for and connect to peripherals.
+      info.roles = Some(AdapterRoles::CENTRAL);
+      Ok(info)
     }
 }
\ No newline at end of file
@@ -1,4 +1,4 @@
-use std::{collections::{BTreeSet, HashMap}, default, fmt::{self, Debug, Display, Formatter}, pin::Pin, str::FromStr, sync::{Arc, Mutex}};
+use std::{collections::{BTreeSet, HashMap}, default, fmt::{self, Debug, Display, Formatter}, pin::Pin, str::FromStr, sync::{Arc, Mutex}, time::Instant};
 use async_trait::async_trait;
 use js_sys::{Array, DataView};
 use serde::{Deserialize, Serialize};
@@ -9,10 +9,14 @@ use wasm_bindgen_futures::{spawn_local, JsFuture};
 use web_sys::{BluetoothDevice, BluetoothRemoteGattCharacteristic, BluetoothRemoteGattDescriptor, BluetoothRemoteGattServer, BluetoothRemoteGattService, DomException};
 use crate::{
     api::{
-        self, BDAddr, CentralEvent, CharPropFlags, Characteristic, Descriptor,
-        PeripheralProperties, Service, ValueNotification, WriteType,
+        self, AdvertisementRecord, BDAddr, CentralEvent, CharPropFlags, Characteristic, Descriptor,
+        PairingOptions, PeripheralProperties, Service, Subscription, SubscriptionKind,
+        ValueNotification, WriteType,
+    },
+    common::{
+        adapter_manager::AdapterManager, subscription::SubscriptionTracker,
+        util::notifications_stream_from_broadcast_receiver,
     },
-    common::{adapter_manager::AdapterManager, util::notifications_stream_from_broadcast_receiver},
     Error, Result,
 };
 use std::sync::Weak;
@@ -53,6 +57,7 @@ impl Peripheral {
             id: id,
             services: Default::default(),
             properties: properties,
+            subscriptions: Default::default(),
         }),
     }
   }
@@ -183,6 +188,7 @@ struct Shared {
     id: String,
     services: Mutex<BTreeSet<Service>>,
     properties: Mutex<PeripheralProperties>,
+    subscriptions: SubscriptionTracker,
     //message_sender: Sender<CoreBluetoothMessage>,
     // We're not actually holding a peripheral object here, that's held out in
     // the objc thread. We'll just communicate with it through our
@@ -224,6 +230,15 @@ impl api::Peripheral for Peripheral {
       Ok(Some(self.shared.properties.lock().unwrap().clone()))
   }
 
+    fn last_seen(&self) -> Option<Instant> {
+      // Web Bluetooth doesn't report advertisements of the devices it picks.
+      None
+    }
+
+    fn advertisement_history(&self) -> Vec<AdvertisementRecord> {
+      Vec::new()
+    }
+
     fn services(&self) -> BTreeSet<Service> {
       self.shared.services.lock().unwrap().clone()
     }
@@ -304,8 +319,8 @@ impl api::Peripheral for Peripheral {
                   }
                 };
 
-                if let Some(mut entry) = manager.peripheral_mut(&address.into()) {
-                    entry.value_mut().update_properties(args);
+                if let Some(peripheral) = manager.peripheral(&address.into()) {
+                    peripheral.update_properties(args);
                     manager.emit(CentralEvent::DeviceUpdated(address.into()));
                 } else {
                     let peripheral = Peripheral::new(Arc::downgrade(&manager), address);
@@ -398,6 +413,16 @@ impl api::Peripheral for Peripheral {
       todo!()
     }
 
+    async fn subscribe_with(
+        &self,
+        characteristic: &Characteristic,
+        kind: SubscriptionKind,
+    ) -> Result<()> {
+      // startNotifications() enables notifications whenever the characteristic supports them.
+      kind.check_platform_choice(characteristic, SubscriptionKind::preferring_notify)?;
+      self.subscribe(characteristic).await
+    }
+
     async fn unsubscribe(&self, characteristic: &Characteristic) -> Result<()> {
       let device_id = self.shared.id.clone();
       let service_id = characteristic.service_uuid.clone();
@@ -419,6 +444,10 @@ impl api::Peripheral for Peripheral {
       //Ok(notifications_stream_from_broadcast_receiver(receiver))
     }
 
+    async fn subscribe_stream(&self, characteristic: &Characteristic) -> Result<Subscription> {
+      self.shared.subscriptions.subscribe(self, characteristic).await
+    }
+
     async fn write_descriptor(&self, descriptor: &Descriptor, data: &[u8]) -> Result<()> {
       let device_id = self.shared.id.clone();
       let service_id = descriptor.service_uuid.clone();
@@ -455,6 +484,24 @@ impl api::Peripheral for Peripheral {
       });
       todo!()
     }
+
+    async fn pair(&self, _options: PairingOptions) -> Result<()> {
+      Err(Error::NotSupported(
+        "WebBluetooth doesn't support pairing".to_string(),
+      ))
+    }
+
+    async fn unpair(&self) -> Result<()> {
+      Err(Error::NotSupported(
+        "WebBluetooth doesn't support pairing".to_string(),
+      ))
+    }
+
+    async fn is_bonded(&self) -> Result<bool> {
+      Err(Error::NotSupported(
+        "WebBluetooth doesn't support pairing".to_string(),
+      ))
+    }
 }
 
 #[cfg_attr(
@@ -13,29 +13,87 @@
 
 use super::{ble::watcher::BLEWatcher, peripheral::Peripheral, peripheral::PeripheralId};
 use crate::{
-    api::{BDAddr, Central, CentralEvent, ScanFilter},
-    common::adapter_manager::AdapterManager,
+    api::{
+        AdapterFeatures, AdapterInfo, AdapterRoles, BDAddr, Central, CentralEvent, CentralState,
+        EventBufferConfig, HonouredScanOptions, ScanFilter, ScanOptions, ScanSession,
+    },
+    common::{
+        adapter_manager::AdapterManager, scan_filter::ActiveScanFilter, scan_session::ScanSessions,
+    },
     Error, Result,
 };
 use async_trait::async_trait;
 use futures::stream::Stream;
+use log::debug;
 use std::convert::TryInto;
 use std::fmt::{self, Debug, Formatter};
 use std::pin::Pin;
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use windows::core::IInspectable;
+use windows::Devices::Bluetooth::BluetoothAdapter;
+use windows::Devices::Radios::{Radio, RadioAccessStatus, RadioState};
+use windows::Foundation::{EventRegistrationToken, TypedEventHandler};
 
 /// Implementation of [api::Central](crate::api::Central).
 #[derive(Clone)]
 pub struct Adapter {
     watcher: Arc<Mutex<BLEWatcher>>,
     manager: Arc<AdapterManager<Peripheral>>,
+    radio: Radio,
+    scan_filter: ActiveScanFilter,
+    scan_sessions: ScanSessions,
+    _state_changed: Arc<StateChangedRegistration>,
+}
+
+/// The adapter's handler for the radio's state changes, which is removed once the last clone of
+/// the adapter is dropped.
+struct StateChangedRegistration {
+    radio: Radio,
+    token: EventRegistrationToken,
+}
+
+impl Drop for StateChangedRegistration {
+    fn drop(&mut self) {
+        if let Err(err) = self.radio.RemoveStateChanged(self.token) {
+            debug!("Drop:remove_state_changed {:?}", err);
+        }
+    }
 }
 
 impl Adapter {
-    pub(crate) fn new() -> Self {
+    pub(crate) fn new(radio: Radio) -> Result<Self> {
         let watcher = Arc::new(Mutex::new(BLEWatcher::new()));
         let manager = Arc::new(AdapterManager::default());
-        Adapter { watcher, manager }
+        let weak_manager = Arc::downgrade(&manager);
+        let handler: TypedEventHandler<Radio, IInspectable> =
+            TypedEventHandler::new(move |radio: &Option<Radio>, _| {
+                if let (Some(manager), Some(radio)) = (weak_manager.upgrade(), radio) {
+                    manager.emit(CentralEvent::StateUpdate(central_state(radio.State()?)));
+                }
+                Ok(())
+            });
+        let state_changed = Arc::new(StateChangedRegistration {
+            radio: radio.clone(),
+            token: radio.StateChanged(&handler)?,
+        });
+        Ok(Adapter {
+            watcher,
+            manager,
+            radio,
+            scan_filter: ActiveScanFilter::default(),
+            scan_sessions: ScanSessions::default(),
+            _state_changed: state_changed,
+        })
+    }
+}
+
+fn central_state(state: RadioState) -> CentralState {
+    match state {
+        RadioState::On => CentralState::PoweredOn,
+        // Disabled radios are turned off by airplane mode or a hardware switch.
+        RadioState::Off | RadioState::Disabled => CentralState::PoweredOff,
+        _ => CentralState::Unknown,
     }
 }
 
@@ -52,20 +110,41 @@ impl Central for Adapter {
     type Peripheral = Peripheral;
 
     async fn events(&self) -> Result<Pin<Box<dyn Stream<Item = CentralEvent> + Send>>> {
-        Ok(self.manager.event_stream())
+        Ok(Box::pin(
+            self.scan_filter
+                .filter_events(self.clone(), self.manager.event_stream()),
+        ))
     }
 
-    async fn start_scan(&self, filter: ScanFilter) -> Result<()> {
+    async fn set_event_buffer(&self, config: EventBufferConfig) -> Result<()> {
+        self.manager.set_event_buffer(config)
+    }
+
+    async fn set_peripheral_retention(&self, retention: Option<Duration>) -> Result<()> {
+        self.manager.set_retention(retention);
+        Ok(())
+    }
+
+    async fn start_scan_with_options(
+        &self,
+        filter: ScanFilter,
+        options: ScanOptions,
+    ) -> Result<HonouredScanOptions> {
+        self.scan_filter.set(&filter)?;
         let watcher = self.watcher.lock().unwrap();
         let manager = self.manager.clone();
         watcher.start(
             filter,
+            options,
             Box::new(move |args| {
                 let bluetooth_address = args.BluetoothAddress().unwrap();
                 let address: BDAddr = bluetooth_address.try_into().unwrap();
-                if let Some(mut entry) = manager.peripheral_mut(&address.into()) {
-                    entry.value_mut().update_properties(args);
-                    manager.emit(CentralEvent::DeviceUpdated(address.into()));
+                if let Some(peripheral) = manager.peripheral(&address.into()) {
+                    let changes = peripheral.update_properties(args);
+                    manager.emit(CentralEvent::DeviceUpdated {
+                        id: address.into(),
+                        changes,
+                    });
                 } else {
                     let peripheral = Peripheral::new(Arc::downgrade(&manager), address);
                     peripheral.update_properties(args);
@@ -82,6 +161,10 @@ impl Central for Adapter {
         Ok(())
     }
 
+    async fn scan(&self, filter: ScanFilter) -> Result<ScanSession<Peripheral>> {
+        self.scan_sessions.scan(self, filter).await
+    }
+
     async fn peripherals(&self) -> Result<Vec<Peripheral>> {
         Ok(self.manager.peripherals())
     }
@@ -96,8 +179,51 @@ impl Central for Adapter {
         ))
     }
 
-    async fn adapter_info(&self) -> Result<String> {
-        // TODO: Get information about the adapter.
-        Ok("WinRT".to_string())
+    async fn state(&self) -> Result<CentralState> {
+        Ok(central_state(self.radio.State()?))
+    }
+
+    async fn set_powered(&self, powered: bool) -> Result<()> {
+        let state = if powered {
+            RadioState::On
+        } else {
+            RadioState::Off
+        };
+        match self.radio.SetStateAsync(state)?.await? {
+            RadioAccessStatus::Allowed => Ok(()),
+            RadioAccessStatus::DeniedByUser | RadioAccessStatus::DeniedBySystem => {
+                Err(Error::PermissionDenied)
+            }
+            status => Err(Error::Other(
+                format!("Failed to power the radio on or off: {:?}", status).into(),
+            )),
+        }
+    }
+
+    async fn adapter_info(&self) -> Result<AdapterInfo> {
+        let mut info = AdapterInfo::new("WinRT");
+        info.name = Some(self.radio.Name()?.to_string());
+        info.powered = central_state(self.radio.State()?).is_powered();
+        // Windows only tells what the default adapter supports, which is the only one it uses.
+        let adapter = BluetoothAdapter::GetDefaultAsync()?.await?;
+        info.address = adapter.BluetoothAddress()?.try_into().ok();
+        let mut roles = AdapterRoles::empty();
+        roles.set(AdapterRoles::CENTRAL, adapter.IsCentralRoleSupported()?);
+        roles.set(
+            AdapterRoles::PERIPHERAL,
+            adapter.IsPeripheralRoleSupported()?,
+        );
+        info.roles = Some(roles);
+        let features = &mut info.capabilities.features;
+        // Only reported since Windows 10 version 2004.
+        features.set(
+            AdapterFeatures::EXTENDED_ADVERTISING,
+            adapter.IsExtendedAdvertisingSupported().unwrap_or(false),
+        );
+        features.set(
+            AdapterFeatures::ADVERTISING_OFFLOAD,
+            adapter.IsAdvertisementOffloadSupported()?,
+        );
+        Ok(info)
     }
 }
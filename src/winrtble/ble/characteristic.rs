@@ -13,7 +13,7 @@
 
 use super::{super::utils::to_descriptor_value, descriptor::BLEDescriptor};
 use crate::{
-    api::{Characteristic, WriteType},
+    api::{Characteristic, SubscriptionKind, WriteType},
     winrtble::utils,
     Error, Result,
 };
@@ -98,7 +98,11 @@ impl BLECharacteristic {
         }
     }
 
-    pub async fn subscribe(&mut self, on_value_changed: NotifiyEventHandler) -> Result<()> {
+    pub async fn subscribe(
+        &mut self,
+        kind: SubscriptionKind,
+        on_value_changed: NotifiyEventHandler,
+    ) -> Result<()> {
         {
             let value_handler = TypedEventHandler::new(
                 move |_: &Option<GattCharacteristic>, args: &Option<GattValueChangedEventArgs>| {
@@ -117,11 +121,7 @@ impl BLECharacteristic {
             let token = self.characteristic.ValueChanged(&value_handler)?;
             self.notify_token = Some(token);
         }
-        let config = to_descriptor_value(self.characteristic.CharacteristicProperties()?);
-        if config == GattClientCharacteristicConfigurationDescriptorValue::None {
-            return Err(Error::NotSupported("Can not subscribe to attribute".into()));
-        }
-
+        let config = to_descriptor_value(kind);
         let status = self
             .characteristic
             .WriteClientCharacteristicConfigurationDescriptorAsync(config)?
@@ -11,8 +11,15 @@
 //
 // Copyright (c) 2014 The Rust Project Developers
 
-use crate::{api::ScanFilter, Error, Result};
-use windows::{Devices::Bluetooth::Advertisement::*, Foundation::TypedEventHandler};
+use crate::{
+    api::{HonouredScanOptions, ScanFilter, ScanMode, ScanOptions, ScanTransport},
+    Error, Result,
+};
+use windows::{
+    core::ComInterface,
+    Devices::Bluetooth::{Advertisement::*, BluetoothSignalStrengthFilter},
+    Foundation::{IReference, PropertyValue, TypedEventHandler},
+};
 
 pub type AdvertismentEventHandler = Box<dyn Fn(&BluetoothLEAdvertisementReceivedEventArgs) + Send>;
 
@@ -33,8 +40,13 @@ impl BLEWatcher {
         BLEWatcher { watcher }
     }
 
-    pub fn start(&self, filter: ScanFilter, on_received: AdvertismentEventHandler) -> Result<()> {
-        let ScanFilter { services } = filter;
+    pub fn start(
+        &self,
+        filter: ScanFilter,
+        options: ScanOptions,
+        on_received: AdvertismentEventHandler,
+    ) -> Result<HonouredScanOptions> {
+        let ScanFilter { services, .. } = filter;
         let ad = self
             .watcher
             .AdvertisementFilter()
@@ -48,9 +60,16 @@ impl BLEWatcher {
                 .Append(windows::core::GUID::from(service.as_u128()))
                 .unwrap();
         }
-        self.watcher
-            .SetScanningMode(BluetoothLEScanningMode::Active)
-            .unwrap();
+        self.watcher.SetScanningMode(match options.mode {
+            ScanMode::Active => BluetoothLEScanningMode::Active,
+            ScanMode::Passive => BluetoothLEScanningMode::Passive,
+        })?;
+        let signal_strength = BluetoothSignalStrengthFilter::new()?;
+        if let Some(min_rssi) = options.min_rssi {
+            let threshold: IReference<i16> = PropertyValue::CreateInt16(min_rssi)?.cast()?;
+            signal_strength.SetInRangeThresholdInDBm(&threshold)?;
+        }
+        self.watcher.SetSignalStrengthFilter(&signal_strength)?;
         self.watcher.SetAllowExtendedAdvertisements(true)?;
         let handler: TypedEventHandler<
             BluetoothLEAdvertisementWatcher,
@@ -66,7 +85,17 @@ impl BLEWatcher {
 
         self.watcher.Received(&handler)?;
         self.watcher.Start()?;
-        Ok(())
+        // The watcher reports every advertisement, and has no way to set the scan parameters.
+        Ok(HonouredScanOptions {
+            mode: true,
+            duplicates: options.duplicates,
+            min_rssi: true,
+            max_pathloss: options.max_pathloss.is_none(),
+            transport: options.transport != ScanTransport::BrEdr,
+            interval: options.interval.is_none(),
+            window: options.window.is_none(),
+            rssi_monitor: options.rssi_monitor.is_none(),
+        })
     }
 
     pub fn stop(&self) -> Result<()> {
@@ -32,10 +32,10 @@ impl api::Manager for Manager {
 
     async fn adapters(&self) -> Result<Vec<Adapter>> {
         let radios = Radio::GetRadiosAsync()?.await?;
-        Ok(radios
+        radios
             .into_iter()
             .filter(|radio| radio.Kind() == Ok(RadioKind::Bluetooth))
-            .map(|_| Adapter::new())
-            .collect())
+            .map(Adapter::new)
+            .collect()
     }
 }
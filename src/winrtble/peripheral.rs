@@ -18,10 +18,14 @@ use super::{
 use crate::{
     api::{
         bleuuid::{uuid_from_u16, uuid_from_u32},
-        AddressType, BDAddr, CentralEvent, Characteristic, Descriptor, Peripheral as ApiPeripheral,
-        PeripheralProperties, Service, ValueNotification, WriteType,
+        AddressType, AdvertisementRecord, BDAddr, CentralEvent, Characteristic, Descriptor,
+        PairingOptions, Peripheral as ApiPeripheral, PeripheralProperties, PropertiesDiff, Service,
+        Subscription, SubscriptionKind, ValueNotification, WriteType,
+    },
+    common::{
+        adapter_manager::AdapterManager, advertisement_history::AdvertisementHistory,
+        subscription::SubscriptionTracker, util::notifications_stream_from_broadcast_receiver,
     },
-    common::{adapter_manager::AdapterManager, util::notifications_stream_from_broadcast_receiver},
     Error, Result,
 };
 use async_trait::async_trait;
@@ -31,12 +35,14 @@ use log::{trace, warn};
 #[cfg(feature = "serde")]
 use serde::{Deserialize, Serialize};
 use std::{
-    collections::{BTreeSet, HashMap, HashSet},
+    collections::{hash_map::DefaultHasher, BTreeSet, HashMap, HashSet},
     convert::TryInto,
     fmt::{self, Debug, Display, Formatter},
+    hash::{Hash, Hasher},
     pin::Pin,
-    sync::atomic::{AtomicBool, Ordering},
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
     sync::{Arc, RwLock},
+    time::Instant,
 };
 use tokio::sync::broadcast;
 use uuid::Uuid;
@@ -70,6 +76,9 @@ struct Shared {
     connected: AtomicBool,
     ble_services: DashMap<Uuid, BLEService>,
     notifications_channel: broadcast::Sender<ValueNotification>,
+    notifications_sequence: Arc<AtomicU64>,
+    subscriptions: SubscriptionTracker,
+    advertisements: AdvertisementHistory,
 
     // Mutable, advertised, state...
     address_type: RwLock<Option<AddressType>>,
@@ -93,6 +102,9 @@ impl Peripheral {
                 connected: AtomicBool::new(false),
                 ble_services: DashMap::new(),
                 notifications_channel: broadcast_sender,
+                notifications_sequence: Arc::new(AtomicU64::new(0)),
+                subscriptions: SubscriptionTracker::default(),
+                advertisements: AdvertisementHistory::default(),
                 address_type: RwLock::new(None),
                 local_name: RwLock::new(None),
                 last_tx_power_level: RwLock::new(None),
@@ -128,7 +140,12 @@ impl Peripheral {
         }
     }
 
-    pub(crate) fn update_properties(&self, args: &BluetoothLEAdvertisementReceivedEventArgs) {
+    /// Updates the properties from an advertisement, returning what changed.
+    pub(crate) fn update_properties(
+        &self,
+        args: &BluetoothLEAdvertisementReceivedEventArgs,
+    ) -> PropertiesDiff {
+        let old = self.derive_properties();
         let advertisement = args.Advertisement().unwrap();
 
         // Advertisements are cumulative: set/replace data only if it's set
@@ -274,10 +291,56 @@ impl Peripheral {
                 *tx_power_level_guard = Some(tx);
             }
         }
-        if let Ok(rssi) = args.RawSignalStrengthInDBm() {
+        let rssi = args.RawSignalStrengthInDBm().ok();
+        if let Some(rssi) = rssi {
             let mut rssi_guard = self.shared.last_rssi.write().unwrap();
             *rssi_guard = Some(rssi);
         }
+        self.shared
+            .advertisements
+            .record(rssi, hash_data_sections(&advertisement));
+        PropertiesDiff::between(&old, &self.derive_properties())
+    }
+
+    async fn enable_value_updates(
+        &self,
+        characteristic: &Characteristic,
+        kind: SubscriptionKind,
+    ) -> Result<()> {
+        let ble_service = &mut *self
+            .shared
+            .ble_services
+            .get_mut(&characteristic.service_uuid)
+            .ok_or_else(|| Error::NotSupported("Service not found for subscribe".into()))?;
+        let ble_characteristic = ble_service
+            .characteristics
+            .get_mut(&characteristic.uuid)
+            .ok_or_else(|| Error::NotSupported("Characteristic not found for subscribe".into()))?;
+        let notifications_sender = self.shared.notifications_channel.clone();
+        let notifications_sequence = self.shared.notifications_sequence.clone();
+        let uuid = characteristic.uuid;
+        let service_uuid = characteristic.service_uuid;
+        ble_characteristic
+            .subscribe(
+                kind,
+                Box::new(move |value| {
+                    let notification = ValueNotification {
+                        uuid,
+                        service_uuid,
+                        value,
+                        kind,
+                        received_at: Instant::now(),
+                        sequence: notifications_sequence.fetch_add(1, Ordering::Relaxed),
+                        missed: 0,
+                    };
+                    // Note: we ignore send errors here which may happen while there are no
+                    // receivers...
+                    let _ = notifications_sender.send(notification);
+                }),
+            )
+            .await?;
+        self.shared.subscriptions.set_kind(characteristic, kind);
+        Ok(())
     }
 
     fn emit_event(&self, event: CentralEvent) {
@@ -289,6 +352,22 @@ impl Peripheral {
     }
 }
 
+/// Hashes the raw data sections of an advertisement, which make up its whole payload.
+fn hash_data_sections(advertisement: &BluetoothLEAdvertisement) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    if let Ok(data_sections) = advertisement.DataSections() {
+        for section in data_sections {
+            section.DataType().ok().hash(&mut hasher);
+            section
+                .Data()
+                .ok()
+                .map(|data| utils::to_vec(&data))
+                .hash(&mut hasher);
+        }
+    }
+    hasher.finish()
+}
+
 impl Display for Peripheral {
     fn fmt(&self, f: &mut Formatter) -> fmt::Result {
         let connected = if self.shared.connected.load(Ordering::Relaxed) {
@@ -344,6 +423,14 @@ impl ApiPeripheral for Peripheral {
         Ok(Some(self.derive_properties()))
     }
 
+    fn last_seen(&self) -> Option<Instant> {
+        self.shared.advertisements.last_seen()
+    }
+
+    fn advertisement_history(&self) -> Vec<AdvertisementRecord> {
+        self.shared.advertisements.records()
+    }
+
     fn services(&self) -> BTreeSet<Service> {
         self.shared
             .ble_services
@@ -477,27 +564,20 @@ impl ApiPeripheral for Peripheral {
     }
 
     /// Enables either notify or indicate (depending on support) for the specified characteristic.
-    /// This is a synchronous call.
+    /// Indications are preferred if the characteristic supports both. This is a synchronous call.
     async fn subscribe(&self, characteristic: &Characteristic) -> Result<()> {
-        let ble_service = &mut *self
-            .shared
-            .ble_services
-            .get_mut(&characteristic.service_uuid)
-            .ok_or_else(|| Error::NotSupported("Service not found for subscribe".into()))?;
-        let ble_characteristic = ble_service
-            .characteristics
-            .get_mut(&characteristic.uuid)
-            .ok_or_else(|| Error::NotSupported("Characteristic not found for subscribe".into()))?;
-        let notifications_sender = self.shared.notifications_channel.clone();
-        let uuid = characteristic.uuid;
-        ble_characteristic
-            .subscribe(Box::new(move |value| {
-                let notification = ValueNotification { uuid, value };
-                // Note: we ignore send errors here which may happen while there are no
-                // receivers...
-                let _ = notifications_sender.send(notification);
-            }))
-            .await
+        let kind = SubscriptionKind::preferring_indicate(characteristic.properties)
+            .ok_or_else(|| Error::NotSupported("Can not subscribe to attribute".into()))?;
+        self.enable_value_updates(characteristic, kind).await
+    }
+
+    async fn subscribe_with(
+        &self,
+        characteristic: &Characteristic,
+        kind: SubscriptionKind,
+    ) -> Result<()> {
+        kind.check_supported(characteristic)?;
+        self.enable_value_updates(characteristic, kind).await
     }
 
     /// Disables either notify or indicate (depending on support) for the specified characteristic.
@@ -514,7 +594,9 @@ impl ApiPeripheral for Peripheral {
             .ok_or_else(|| {
                 Error::NotSupported("Characteristic not found for unsubscribe".into())
             })?;
-        ble_characteristic.unsubscribe().await
+        ble_characteristic.unsubscribe().await?;
+        self.shared.subscriptions.clear_kind(characteristic);
+        Ok(())
     }
 
     async fn read(&self, characteristic: &Characteristic) -> Result<Vec<u8>> {
@@ -535,6 +617,13 @@ impl ApiPeripheral for Peripheral {
         Ok(notifications_stream_from_broadcast_receiver(receiver))
     }
 
+    async fn subscribe_stream(&self, characteristic: &Characteristic) -> Result<Subscription> {
+        self.shared
+            .subscriptions
+            .subscribe(self, characteristic)
+            .await
+    }
+
     async fn write_descriptor(&self, descriptor: &Descriptor, data: &[u8]) -> Result<()> {
         let ble_service = &*self
             .shared
@@ -568,6 +657,24 @@ impl ApiPeripheral for Peripheral {
             .ok_or_else(|| Error::NotSupported("Descriptor not found for write".into()))?;
         ble_descriptor.read_value().await
     }
+
+    async fn pair(&self, _options: PairingOptions) -> Result<()> {
+        Err(Error::NotSupported(
+            "Windows pairs on its own when a characteristic needs it".to_string(),
+        ))
+    }
+
+    async fn unpair(&self) -> Result<()> {
+        Err(Error::NotSupported(
+            "Windows pairs on its own when a characteristic needs it".to_string(),
+        ))
+    }
+
+    async fn is_bonded(&self) -> Result<bool> {
+        Err(Error::NotSupported(
+            "Windows pairs on its own when a characteristic needs it".to_string(),
+        ))
+    }
 }
 
 impl From<BDAddr> for PeripheralId {
@@ -11,7 +11,10 @@
 //
 // Copyright (c) 2014 The Rust Project Developers
 
-use crate::{api::CharPropFlags, Error, Result};
+use crate::{
+    api::{CharPropFlags, SubscriptionKind},
+    Error, Result,
+};
 use std::str::FromStr;
 use uuid::Uuid;
 use windows::core::GUID;
@@ -38,16 +41,18 @@ pub fn to_error(status: GattCommunicationStatus) -> Result<()> {
 }
 
 pub fn to_descriptor_value(
-    properties: GattCharacteristicProperties,
+    kind: SubscriptionKind,
 ) -> GattClientCharacteristicConfigurationDescriptorValue {
-    let notify = GattCharacteristicProperties::Notify;
-    let indicate = GattCharacteristicProperties::Indicate;
-    if properties & indicate == indicate {
-        GattClientCharacteristicConfigurationDescriptorValue::Indicate
-    } else if properties & notify == notify {
-        GattClientCharacteristicConfigurationDescriptorValue::Notify
-    } else {
-        GattClientCharacteristicConfigurationDescriptorValue::None
+    match kind {
+        SubscriptionKind::Notify => GattClientCharacteristicConfigurationDescriptorValue::Notify,
+        SubscriptionKind::Indicate => {
+            GattClientCharacteristicConfigurationDescriptorValue::Indicate
+        }
+        // The enum has no variant for both, but the value is written to the CCCD as-is.
+        SubscriptionKind::Both => GattClientCharacteristicConfigurationDescriptorValue(
+            GattClientCharacteristicConfigurationDescriptorValue::Notify.0
+                | GattClientCharacteristicConfigurationDescriptorValue::Indicate.0,
+        ),
     }
 }
 
@@ -130,4 +135,16 @@ mod tests {
         let uuid_expected = Uuid::from_str(uuid_str).unwrap();
         assert_eq!(uuid_converted, uuid_expected);
     }
+
+    #[test]
+    fn check_subscription_kind_to_descriptor_value() {
+        for kind in [
+            SubscriptionKind::Notify,
+            SubscriptionKind::Indicate,
+            SubscriptionKind::Both,
+        ] {
+            let value = to_descriptor_value(kind);
+            assert_eq!(value.0, i32::from(kind.descriptor_value()[0]));
+        }
+    }
 }